@@ -0,0 +1,164 @@
+//! Requests through the proxy, answered from fixtures so only the proxy's own
+//! work is measured.
+//!
+//! Besides criterion's timings, the allocations each kind of request makes
+//! are printed before it's measured. Background tasks allocate as well, so
+//! they're a rough count.
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use http::{Method, Request};
+use hyper::{body, Body};
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    env, fs,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use tokio::runtime::{Builder, Runtime};
+use tower_service::Service;
+use twilight_http_proxy::{Config, Proxy, ProxyService};
+
+/// Requests made to count allocations.
+const SAMPLE: u64 = 1000;
+
+const CHANNEL: &str = r#"{
+    "status": 200,
+    "body": { "id": "1", "name": "general", "type": 0, "position": 0 }
+}"#;
+
+const MESSAGE: &str = r#"{
+    "status": 200,
+    "body": { "id": "2", "channel_id": "1", "content": "hi" }
+}"#;
+
+struct Counting;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+/// A request to send, built again every time as bodies can't be reused.
+struct Case {
+    name: &'static str,
+    method: Method,
+    uri: &'static str,
+    body: &'static str,
+}
+
+const CASES: &[Case] = &[
+    Case {
+        name: "get message",
+        method: Method::GET,
+        uri: "/api/v6/channels/1/messages/2",
+        body: "",
+    },
+    Case {
+        name: "create message",
+        method: Method::POST,
+        uri: "/api/v6/channels/1/messages",
+        body: r#"{"content":"hello, this is a message of a typical length for a bot"}"#,
+    },
+    Case {
+        name: "get cached channel",
+        method: Method::GET,
+        uri: "/api/v6/channels/1",
+        body: "",
+    },
+];
+
+fn bench(c: &mut Criterion) {
+    let dir = fixtures();
+    let mut runtime = Builder::new()
+        .basic_scheduler()
+        .enable_all()
+        .build()
+        .expect("failed to build the runtime");
+    let proxy = runtime
+        .block_on(Proxy::start(config(&dir), None))
+        .expect("proxy failed to start");
+    let mut service = proxy.service();
+
+    let mut group = c.benchmark_group("proxy");
+    group.throughput(Throughput::Elements(1));
+
+    for case in CASES {
+        let before = ALLOCATIONS.load(Ordering::Relaxed);
+
+        for _ in 0..SAMPLE {
+            send(&mut runtime, &mut service, case);
+        }
+
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+        println!(
+            "{}: {} allocations per request",
+            case.name,
+            allocations / SAMPLE
+        );
+
+        group.bench_function(case.name, |b| {
+            b.iter(|| send(&mut runtime, &mut service, case))
+        });
+    }
+
+    group.finish();
+    runtime.block_on(proxy.shutdown());
+    let _ = fs::remove_dir_all(dir);
+}
+
+fn send(runtime: &mut Runtime, service: &mut ProxyService, case: &Case) {
+    let request = Request::builder()
+        .method(case.method.clone())
+        .uri(case.uri)
+        .header("content-type", "application/json")
+        .body(Body::from(case.body))
+        .expect("invalid request");
+
+    runtime.block_on(async {
+        let response = service.call(request).await.expect("request failed");
+        assert!(response.status().is_success(), "{}", response.status());
+        body::to_bytes(response.into_body())
+            .await
+            .expect("failed to read the body");
+    });
+}
+
+/// Directory with the fixtures the proxy answers from.
+fn fixtures() -> PathBuf {
+    let dir = env::temp_dir().join(format!("twilight-http-proxy-bench-{}", process::id()));
+    fs::create_dir_all(&dir).expect("failed to create the fixture directory");
+    fs::write(dir.join("channel.json"), CHANNEL).expect("failed to write a fixture");
+    fs::write(dir.join("channel_message.json"), MESSAGE).expect("failed to write a fixture");
+
+    dir
+}
+
+fn config(dir: &Path) -> Config {
+    let fixtures = dir.display().to_string();
+
+    // Only the channel is cached, so the other cases still reach the
+    // fixtures.
+    Config::from_settings(vec![
+        ("DISCORD_TOKEN", "bench"),
+        ("MOCK_UPSTREAM", "true"),
+        ("MOCK_FIXTURES", &fixtures),
+        ("METRICS_ON_LISTENER", "true"),
+        ("CACHE_ROUTES", "Channel"),
+    ])
+    .expect("invalid config")
+}
+
+criterion_group!(benches, bench);
+criterion_main!(benches);
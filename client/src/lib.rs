@@ -0,0 +1,459 @@
+//! Client for bots sending their requests to Discord through
+//! twilight-http-proxy.
+//!
+//! It points requests at the proxy, sets the headers for the proxy's own
+//! features, like priorities, background jobs and idempotency keys, and reads
+//! the proxy's error responses, so bots don't have to do it by hand.
+
+use reqwest::{
+    header::{HeaderMap, HeaderValue, CONTENT_TYPE, RETRY_AFTER},
+    Client as HttpClient, Error as ReqwestError, Method, StatusCode,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Error as JsonError;
+use snafu::{ResultExt, Snafu};
+use std::{
+    fmt::{Display, Formatter, Result as FmtResult},
+    time::{Duration, SystemTime},
+};
+
+/// Header classifying a request, see [`Priority`].
+pub const PRIORITY_HEADER: &str = "x-proxy-priority";
+
+/// Header asking the proxy to run a request in the background.
+pub const ASYNC_HEADER: &str = "x-proxy-async";
+
+/// Header asking the proxy to run a request in the background at a given
+/// time.
+pub const EXECUTE_AT_HEADER: &str = "x-proxy-execute-at";
+
+/// Header asking the proxy to run a request in the background after a delay.
+pub const DELAY_HEADER: &str = "x-proxy-delay";
+
+/// Header asking the proxy not to send a request to Discord.
+pub const DRY_RUN_HEADER: &str = "x-proxy-dry-run";
+
+/// Header naming the tenant a request is sent for.
+pub const KEY_HEADER: &str = "x-proxy-key";
+
+/// Header making retries of a request safe.
+pub const IDEMPOTENCY_HEADER: &str = "idempotency-key";
+
+const BACKPRESSURE_HEADER: &str = "x-proxy-backpressure";
+const CACHE_HEADER: &str = "x-proxy-cache";
+const FAULT_HEADER: &str = "x-proxy-fault";
+const REPLAY_HEADER: &str = "x-proxy-idempotent-replay";
+const RETRIES_HEADER: &str = "x-proxy-retries";
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Failed to create the HTTP client: {}", source))]
+    BuildingClient { source: ReqwestError },
+    #[snafu(display("Failed to serialize the request body: {}", source))]
+    SerializingBody { source: JsonError },
+    #[snafu(display("Failed to send the request: {}", source))]
+    Sending { source: ReqwestError },
+    #[snafu(display("Failed to parse the response: {}", source))]
+    ParsingResponse { source: JsonError },
+    #[snafu(display("{} answered {}: {}", origin, status, message))]
+    Status {
+        status: StatusCode,
+        origin: Origin,
+        message: String,
+        /// Discord's error code, or the proxy's for its own errors.
+        code: Option<serde_json::Value>,
+        /// How long to wait before trying again, for ratelimits.
+        retry_after: Option<Duration>,
+        body: Vec<u8>,
+    },
+}
+
+/// Who answered with an error.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Origin {
+    /// The proxy itself, e.g. because it's under load or the request was
+    /// invalid.
+    Proxy,
+    Discord,
+}
+
+impl Display for Origin {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Origin::Proxy => f.write_str("the proxy"),
+            Origin::Discord => f.write_str("Discord"),
+        }
+    }
+}
+
+/// How urgent a request is; the proxy lets more urgent requests waiting on
+/// the same ratelimit go first.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Priority {
+    Low,
+    Normal,
+    High,
+}
+
+impl Priority {
+    pub fn name(self) -> &'static str {
+        match self {
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+        }
+    }
+}
+
+/// Reply to a request the proxy runs in the background.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Accepted {
+    /// Id to look the job up with.
+    pub id: String,
+    pub estimated_wait_ms: Option<u64>,
+    /// When the request will run, in RFC 3339, if it was scheduled.
+    pub execute_at: Option<String>,
+}
+
+/// A request running in the background.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Job {
+    pub id: String,
+    #[serde(flatten)]
+    pub status: JobStatus,
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    Scheduled { execute_at: String },
+    Running,
+    Completed { status: u16 },
+    Failed { error: String },
+    Cancelled,
+}
+
+impl JobStatus {
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            JobStatus::Completed { .. } | JobStatus::Failed { .. } | JobStatus::Cancelled
+        )
+    }
+}
+
+/// Body of the errors the proxy and Discord answer with.
+#[derive(Debug, Default, Deserialize)]
+struct ErrorBody {
+    #[serde(default)]
+    message: String,
+    code: Option<serde_json::Value>,
+    retry_after: Option<f64>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Client {
+    http: HttpClient,
+    base: String,
+    key: Option<String>,
+}
+
+impl Client {
+    /// Create a client for the proxy at the base URL, like
+    /// `http://twilight-http-proxy:80`.
+    pub fn new(base: impl Into<String>) -> Result<Self, Error> {
+        Ok(Self {
+            http: HttpClient::builder().build().context(BuildingClient)?,
+            base: base.into().trim_end_matches('/').to_owned(),
+            key: None,
+        })
+    }
+
+    /// Send requests as the tenant the key belongs to.
+    pub fn with_key(mut self, key: impl Into<String>) -> Self {
+        self.key = Some(key.into());
+
+        self
+    }
+
+    /// Start a request for a path of Discord's API, like `channels/123`.
+    pub fn request(&self, method: Method, path: &str) -> RequestBuilder<'_> {
+        RequestBuilder {
+            client: self,
+            method,
+            url: format!("{}/api/v6/{}", self.base, path.trim_start_matches('/')),
+            headers: self.key_headers(),
+            body: None,
+            error: None,
+        }
+    }
+
+    pub fn get(&self, path: &str) -> RequestBuilder<'_> {
+        self.request(Method::GET, path)
+    }
+
+    pub fn post(&self, path: &str) -> RequestBuilder<'_> {
+        self.request(Method::POST, path)
+    }
+
+    pub fn patch(&self, path: &str) -> RequestBuilder<'_> {
+        self.request(Method::PATCH, path)
+    }
+
+    pub fn put(&self, path: &str) -> RequestBuilder<'_> {
+        self.request(Method::PUT, path)
+    }
+
+    pub fn delete(&self, path: &str) -> RequestBuilder<'_> {
+        self.request(Method::DELETE, path)
+    }
+
+    /// Look up a request running in the background. Jobs sent with a key
+    /// can only be looked up with it.
+    pub async fn job(&self, id: &str) -> Result<Job, Error> {
+        let url = format!("{}/proxy/jobs/{}", self.base, id);
+
+        self.send(self.http.get(&url).headers(self.key_headers()))
+            .await?
+            .json()
+    }
+
+    /// Wait up to `timeout` for a request running in the background to
+    /// finish, returning it as it is then.
+    pub async fn wait(&self, id: &str, timeout: Duration) -> Result<Job, Error> {
+        let url = format!(
+            "{}/proxy/jobs/{}/wait?timeout={}ms",
+            self.base,
+            id,
+            timeout.as_millis()
+        );
+
+        self.send(self.http.get(&url).headers(self.key_headers()))
+            .await?
+            .json()
+    }
+
+    /// The tenant's key, if the client has one.
+    fn key_headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+
+        if let Some(key) = self.key.as_deref().and_then(header_value) {
+            headers.insert(KEY_HEADER, key);
+        }
+
+        headers
+    }
+
+    async fn send(&self, request: reqwest::RequestBuilder) -> Result<Response, Error> {
+        let response = request.send().await.context(Sending)?;
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = response.bytes().await.context(Sending)?.to_vec();
+
+        if !status.is_success() {
+            return Err(error(status, &headers, body));
+        }
+
+        Ok(Response {
+            status,
+            headers,
+            body,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct RequestBuilder<'a> {
+    client: &'a Client,
+    method: Method,
+    url: String,
+    headers: HeaderMap,
+    body: Option<Vec<u8>>,
+    /// Error building the request, returned when it's sent.
+    error: Option<Error>,
+}
+
+impl RequestBuilder<'_> {
+    pub fn priority(mut self, priority: Priority) -> Self {
+        self.headers
+            .insert(PRIORITY_HEADER, HeaderValue::from_static(priority.name()));
+
+        self
+    }
+
+    /// Make retrying the request safe with the key: the proxy answers a
+    /// retry with the first response instead of sending it again.
+    pub fn idempotency_key(mut self, key: &str) -> Self {
+        if let Some(key) = header_value(key) {
+            self.headers.insert(IDEMPOTENCY_HEADER, key);
+        }
+
+        self
+    }
+
+    /// Make retrying the request safe with a random idempotency key.
+    pub fn idempotent(self) -> Self {
+        let key = format!("{:032x}", rand::random::<u128>());
+
+        self.idempotency_key(&key)
+    }
+
+    /// Have the proxy go through the motions without sending the request to
+    /// Discord.
+    pub fn dry_run(mut self) -> Self {
+        self.headers
+            .insert(DRY_RUN_HEADER, HeaderValue::from_static("true"));
+
+        self
+    }
+
+    /// Run the request at a given time. Scheduled requests are sent with
+    /// [`send_in_background`].
+    ///
+    /// [`send_in_background`]: Self::send_in_background
+    pub fn execute_at(mut self, at: SystemTime) -> Self {
+        let at = humantime::format_rfc3339_seconds(at).to_string();
+
+        if let Some(at) = header_value(&at) {
+            self.headers.insert(EXECUTE_AT_HEADER, at);
+        }
+
+        self
+    }
+
+    /// Run the request after a delay. Scheduled requests are sent with
+    /// [`send_in_background`].
+    ///
+    /// [`send_in_background`]: Self::send_in_background
+    pub fn delay(mut self, delay: Duration) -> Self {
+        let delay = humantime::format_duration(delay).to_string();
+
+        if let Some(delay) = header_value(&delay) {
+            self.headers.insert(DELAY_HEADER, delay);
+        }
+
+        self
+    }
+
+    pub fn json<T: Serialize + ?Sized>(mut self, body: &T) -> Self {
+        match serde_json::to_vec(body) {
+            Ok(body) => {
+                self.headers
+                    .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+                self.body = Some(body);
+            }
+            Err(source) => self.error = Some(Error::SerializingBody { source }),
+        }
+
+        self
+    }
+
+    /// Send the request and wait for Discord's response.
+    pub async fn send(self) -> Result<Response, Error> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+
+        let mut request = self
+            .client
+            .http
+            .request(self.method, &self.url)
+            .headers(self.headers);
+
+        if let Some(body) = self.body {
+            request = request.body(body);
+        }
+
+        self.client.send(request).await
+    }
+
+    /// Have the proxy run the request in the background, returning the id
+    /// of the job to look it up with.
+    pub async fn send_in_background(mut self) -> Result<Accepted, Error> {
+        self.headers
+            .insert(ASYNC_HEADER, HeaderValue::from_static("true"));
+
+        self.send().await?.json()
+    }
+}
+
+/// A successful response.
+#[derive(Clone, Debug)]
+pub struct Response {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, Error> {
+        serde_json::from_slice(&self.body).context(ParsingResponse)
+    }
+
+    /// Whether the proxy answered with the response to an earlier request
+    /// with the same idempotency key.
+    pub fn is_replay(&self) -> bool {
+        self.header(REPLAY_HEADER) == Some("true")
+    }
+
+    pub fn is_dry_run(&self) -> bool {
+        self.header(DRY_RUN_HEADER) == Some("true")
+    }
+
+    /// How many times the proxy retried the request.
+    pub fn retries(&self) -> u32 {
+        self.header(RETRIES_HEADER)
+            .and_then(|retries| retries.parse().ok())
+            .unwrap_or_default()
+    }
+
+    /// Whether the response came from the proxy's cache, like `hit` or
+    /// `miss`, for cached routes.
+    pub fn cache(&self) -> Option<&str> {
+        self.header(CACHE_HEADER)
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)?.to_str().ok()
+    }
+}
+
+fn header_value(value: &str) -> Option<HeaderValue> {
+    HeaderValue::from_str(value).ok()
+}
+
+/// Work out who answered with an error, and what it was.
+fn error(status: StatusCode, headers: &HeaderMap, body: Vec<u8>) -> Error {
+    let parsed = serde_json::from_slice::<ErrorBody>(&body).unwrap_or_default();
+    // Discord's error codes are numbers, the proxy's are strings.
+    let origin = if headers.contains_key(BACKPRESSURE_HEADER)
+        || headers.contains_key(FAULT_HEADER)
+        || parsed.code.as_ref().is_some_and(|code| code.is_string())
+    {
+        Origin::Proxy
+    } else {
+        Origin::Discord
+    };
+    let retry_after = parsed
+        .retry_after
+        .or_else(|| headers.get(RETRY_AFTER)?.to_str().ok()?.parse().ok())
+        .map(Duration::from_secs_f64);
+    let message = if parsed.message.is_empty() {
+        status
+            .canonical_reason()
+            .unwrap_or("unknown error")
+            .to_owned()
+    } else {
+        parsed.message
+    };
+
+    Error::Status {
+        status,
+        origin,
+        message,
+        code: parsed.code,
+        retry_after,
+        body,
+    }
+}
@@ -0,0 +1,71 @@
+//! Arbitrary methods, paths and queries through the proxy's path handling,
+//! checking it doesn't panic and sends requests to Discord with paths that
+//! are routed the same way again.
+
+#![no_main]
+
+use http::{Method, Uri};
+use libfuzzer_sys::fuzz_target;
+use std::convert::TryFrom;
+use twilight_http_proxy::route_request;
+
+const METHODS: &[Method] = &[
+    Method::GET,
+    Method::POST,
+    Method::PUT,
+    Method::PATCH,
+    Method::DELETE,
+    Method::HEAD,
+];
+
+fuzz_target!(|data: &[u8]| {
+    let (method, uri) = match data.split_first() {
+        Some((method, uri)) => (&METHODS[usize::from(*method) % METHODS.len()], uri),
+        None => return,
+    };
+    let uri = match Uri::try_from(uri) {
+        Ok(uri) => uri,
+        Err(_) => return,
+    };
+
+    let known = route_request(method.clone(), &uri, false);
+    let passed_through = route_request(method.clone(), &uri, true);
+
+    if let Ok(known) = &known {
+        assert_eq!(
+            passed_through.as_ref().ok(),
+            Some(known),
+            "passing through unknown routes changed a known one"
+        );
+    }
+
+    let (route, path_and_query) = match passed_through {
+        Ok(routed) => routed,
+        Err(_) => return,
+    };
+    let path = path_and_query.split('?').next().unwrap_or_default();
+
+    assert!(!path.starts_with('/'), "{:?} has a leading slash", path);
+    assert!(
+        path.split('/').all(|segment| {
+            let segment = segment.to_ascii_lowercase().replace("%2e", ".");
+
+            !segment.is_empty() && segment != "." && segment != ".."
+        }),
+        "{:?} wasn't normalized",
+        path
+    );
+
+    // What Discord is sent has to be routed the same way as what the client
+    // sent.
+    let sent = Uri::try_from(format!("/api/v6/{}", path_and_query))
+        .expect("the path sent to Discord isn't a valid URI");
+    let again = route_request(method.clone(), &sent, true)
+        .expect("the path sent to Discord isn't routed anymore");
+
+    assert_eq!(
+        again,
+        (route, path_and_query.clone()),
+        "routed differently when sent on"
+    );
+});
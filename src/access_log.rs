@@ -0,0 +1,44 @@
+//! One line per request sent on to Discord, written to a file of its own so
+//! it can be kept for audits regardless of what happens to the regular logs.
+
+use crate::listener::Peer;
+use http::{Method, StatusCode};
+use std::time::{Duration, SystemTime};
+
+/// A request as it ends up in the access log.
+#[derive(Debug)]
+pub struct Entry<'a> {
+    pub peer: Option<Peer>,
+    pub tenant: Option<&'a str>,
+    pub method: &'a Method,
+    pub route: &'a str,
+    /// Missing if no response was received.
+    pub status: Option<StatusCode>,
+    pub bytes: usize,
+    pub latency: Duration,
+}
+
+impl Entry<'_> {
+    /// Render the entry as a line, with fields separated by spaces and the
+    /// route quoted, as route names contain spaces.
+    pub fn line(&self) -> String {
+        let peer = self
+            .peer
+            .map_or_else(|| "-".to_owned(), |peer| peer.to_string());
+        let status = self
+            .status
+            .map_or_else(|| "-".to_owned(), |status| status.as_u16().to_string());
+
+        format!(
+            "{} {} {} {} \"{}\" {} {} {:.1}ms\n",
+            humantime::format_rfc3339_millis(SystemTime::now()),
+            peer,
+            self.tenant.unwrap_or("-"),
+            self.method,
+            self.route,
+            status,
+            self.bytes,
+            self.latency.as_secs_f64() * 1000.0,
+        )
+    }
+}
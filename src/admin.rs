@@ -0,0 +1,567 @@
+use crate::{
+    batch,
+    boot::BootReport,
+    config::{Config, Role},
+    exporters::constant_time_eq,
+    build_info::{self, BuildInfo},
+    drill,
+    faults,
+    hooks::{self, Event},
+    jobs::Jobs,
+    proxy,
+    readiness::Readiness,
+    error::{ChunkingRequest, MakingResponseBody, RequestError, SerializingJson},
+    reload,
+    replay::{self, Classification},
+    response_cache,
+    snapshot::Snapshot,
+    listener::Peer,
+    tenant::{self, Identity},
+    upstream, State,
+};
+use http::{
+    header::{HeaderMap, AUTHORIZATION},
+    Method, StatusCode,
+};
+use hyper::{body::Body, Request, Response};
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use std::{sync::Arc, time::Duration};
+use tracing::info;
+
+/// Prefix under which the proxy's own endpoints are served.
+///
+/// Nothing in Discord's API lives under this prefix, so it's safe to
+/// intercept before path parsing.
+pub const PREFIX: &str = "/proxy/";
+
+/// Document describing which proxy-specific features this build supports, so
+/// client libraries can feature-detect instead of hardcoding behavior.
+#[derive(Debug, Serialize)]
+pub struct Capabilities {
+    pub version: &'static str,
+    pub api_versions: &'static [u8],
+    pub features: Features,
+    pub limits: Limits,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Features {
+    pub priority: bool,
+    pub deadlines: bool,
+    pub batch: bool,
+    #[serde(rename = "async")]
+    pub run_async: bool,
+    pub idempotency: bool,
+    pub cache: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Limits {
+    pub max_batch_size: usize,
+}
+
+impl Capabilities {
+    pub fn current() -> Self {
+        Self {
+            version: build_info::VERSION,
+            api_versions: &[6],
+            features: Features {
+                priority: true,
+                deadlines: false,
+                batch: true,
+                run_async: true,
+                idempotency: true,
+                cache: true,
+            },
+            limits: Limits {
+                max_batch_size: batch::MAX_REQUESTS,
+            },
+        }
+    }
+}
+
+/// Default and maximum time a job long-poll is held open.
+const WAIT_DEFAULT: Duration = Duration::from_secs(30);
+const WAIT_MAX: Duration = Duration::from_secs(120);
+
+#[derive(Debug, Default, Deserialize)]
+struct WaitQuery {
+    timeout: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CacheQuery {
+    path: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Invalidated {
+    /// Cached responses dropped for the path.
+    removed: usize,
+}
+
+/// Longest a token swap waits for requests using the old token.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Deserialize)]
+struct TokenBody {
+    token: String,
+    /// Whether to check the token with Discord before switching to it.
+    #[serde(default = "default_verify")]
+    verify: bool,
+}
+
+fn default_verify() -> bool {
+    true
+}
+
+#[derive(Debug, Serialize)]
+struct TokenSwap {
+    /// Whether requests using the old token finished before replying.
+    drained: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct Imported {
+    /// Background requests taken over from the snapshot.
+    imported: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct MaintenanceStatus {
+    enabled: bool,
+    /// Requests still being handled, to tell when they've drained.
+    in_flight: usize,
+}
+
+fn maintenance_status(state: &State) -> MaintenanceStatus {
+    MaintenanceStatus {
+        enabled: state.maintenance.is_enabled(),
+        in_flight: state.load.in_flight(),
+    }
+}
+
+pub fn is_admin_path(path: &str) -> bool {
+    path.starts_with(PREFIX)
+}
+
+/// Error body for requests the proxy itself rejects.
+#[derive(Debug, Serialize)]
+pub struct ErrorBody<'a> {
+    pub message: &'a str,
+}
+
+pub async fn handle(
+    state: Arc<State>,
+    request: Request<Body>,
+) -> Result<Response<Body>, RequestError> {
+    let (parts, body) = request.into_parts();
+    let uri = parts.uri;
+    let route = uri.path().trim_start_matches(PREFIX).trim_end_matches('/');
+    let segments = route.split('/').collect::<Vec<_>>();
+
+    if let Some(required) = required_role(&parts.method, &segments) {
+        if let Err((status, message)) = authorize(&state.config(), &parts.headers, required) {
+            return error(status, message);
+        }
+    }
+
+    if let ["jobs", id, ..] = segments.as_slice() {
+        let config = state.config();
+        if let Err((status, message)) =
+            authorize_job(&config, &state.jobs, &parts.method, &parts.headers, id)
+        {
+            return error(status, message);
+        }
+    }
+
+    match (&parts.method, segments.as_slice()) {
+        (&Method::GET, ["capabilities"]) => json(StatusCode::OK, &Capabilities::current()),
+        (&Method::GET, ["version"]) => json(StatusCode::OK, &BuildInfo::current(&state)),
+        (&Method::GET, ["info"]) => json(StatusCode::OK, &BootReport::current(&state)),
+        (&Method::GET, ["ready"]) => {
+            let readiness = Readiness::current(&state);
+            let status = if readiness.ready {
+                StatusCode::OK
+            } else {
+                StatusCode::SERVICE_UNAVAILABLE
+            };
+
+            json(status, &readiness)
+        }
+        (&Method::GET, ["classify"]) => {
+            let query = match uri
+                .query()
+                .and_then(|query| serde_urlencoded::from_str::<replay::Query>(query).ok())
+            {
+                Some(query) => query,
+                None => return error(StatusCode::BAD_REQUEST, "method and path are required"),
+            };
+
+            match Classification::of(&state.config(), &query) {
+                Ok(classification) => json(StatusCode::OK, &classification),
+                Err(message) => error(StatusCode::BAD_REQUEST, &message),
+            }
+        }
+        (&Method::GET, ["config"]) => json(StatusCode::OK, &*state.config()),
+        (&Method::GET, ["jobs", id]) => match state.jobs.get(id) {
+            Some(job) => json(StatusCode::OK, &job),
+            None => empty(StatusCode::NOT_FOUND),
+        },
+        (&Method::DELETE, ["jobs", id]) => match state.jobs.cancel(id) {
+            Some(true) => {
+                if let Some(spool) = state.spool.as_ref() {
+                    spool.remove(id).await;
+                }
+
+                match state.jobs.get(id) {
+                    Some(job) => json(StatusCode::OK, &job),
+                    None => empty(StatusCode::NOT_FOUND),
+                }
+            }
+            Some(false) => error(StatusCode::CONFLICT, "only scheduled jobs can be cancelled"),
+            None => empty(StatusCode::NOT_FOUND),
+        },
+        (&Method::GET, ["jobs", id, "wait"]) => {
+            let query = uri
+                .query()
+                .and_then(|query| serde_urlencoded::from_str::<WaitQuery>(query).ok())
+                .unwrap_or_default();
+            let timeout = match query.timeout {
+                Some(raw) => match humantime::parse_duration(&raw) {
+                    Ok(timeout) => timeout.min(WAIT_MAX),
+                    Err(_) => return empty(StatusCode::BAD_REQUEST),
+                },
+                None => WAIT_DEFAULT,
+            };
+
+            match state.jobs.wait(id, timeout).await {
+                Some(job) => json(StatusCode::OK, &job),
+                None => empty(StatusCode::NOT_FOUND),
+            }
+        }
+        (&Method::GET, ["breakers"]) => json(
+            StatusCode::OK,
+            &state.breakers.snapshot(&state.config().breaker),
+        ),
+        (&Method::GET, ["buckets"]) => json(
+            StatusCode::OK,
+            &state.ratelimits.snapshot(&state.queue.queued()),
+        ),
+        (&Method::GET, ["queues", bucket]) => {
+            let bucket = percent_decode_str(bucket).decode_utf8_lossy();
+
+            match state.queue.pending(&bucket) {
+                Some(pending) => json(StatusCode::OK, &pending),
+                None => error(StatusCode::NOT_FOUND, "nothing is queued for this bucket"),
+            }
+        }
+        (&Method::DELETE, ["cache"]) => {
+            let query = match uri
+                .query()
+                .and_then(|query| serde_urlencoded::from_str::<CacheQuery>(query).ok())
+            {
+                Some(query) => query,
+                None => return error(StatusCode::BAD_REQUEST, "path is required"),
+            };
+            let path = match proxy::normalize_path(&query.path) {
+                Some(path) => path,
+                None => return error(StatusCode::BAD_REQUEST, "invalid path"),
+            };
+            let removed = response_cache::invalidate(&state, &path, "admin").await;
+
+            json(StatusCode::OK, &Invalidated { removed })
+        }
+        (&Method::GET, ["heatmap"]) => json(StatusCode::OK, &state.heatmap.snapshot()),
+        (&Method::GET, ["digest"]) => json(StatusCode::OK, &state.digest.summary()),
+        (&Method::GET, ["maintenance"]) => json(StatusCode::OK, &maintenance_status(&state)),
+        (&Method::PUT, ["maintenance"]) => {
+            let body = hyper::body::to_bytes(body).await.context(ChunkingRequest)?;
+            let body = if body.is_empty() {
+                None
+            } else if serde_json::from_slice::<serde_json::Value>(&body).is_ok() {
+                Some(body)
+            } else {
+                return error(StatusCode::BAD_REQUEST, "invalid maintenance body");
+            };
+            state.maintenance.enable(body, &state.config());
+
+            json(StatusCode::OK, &maintenance_status(&state))
+        }
+        (&Method::DELETE, ["maintenance"]) => {
+            state.maintenance.disable();
+
+            json(StatusCode::OK, &maintenance_status(&state))
+        }
+        (&Method::GET, ["drill"]) => match state.drill.status() {
+            Some(status) => json(StatusCode::OK, &status),
+            None => empty(StatusCode::NOT_FOUND),
+        },
+        (&Method::PUT, ["drill"]) => {
+            let body = hyper::body::to_bytes(body).await.context(ChunkingRequest)?;
+            let settings = match serde_json::from_slice::<drill::Settings>(&body) {
+                Ok(settings) => settings,
+                Err(_) => return error(StatusCode::BAD_REQUEST, "invalid drill body"),
+            };
+            state.drill.start(settings);
+
+            match state.drill.status() {
+                Some(status) => json(StatusCode::OK, &status),
+                None => empty(StatusCode::NO_CONTENT),
+            }
+        }
+        (&Method::DELETE, ["drill"]) => {
+            state.drill.stop();
+
+            empty(StatusCode::NO_CONTENT)
+        }
+        (&Method::GET, ["faults"]) => json(StatusCode::OK, &state.faults.rules()),
+        (&Method::PUT, ["faults"]) => {
+            let body = hyper::body::to_bytes(body).await.context(ChunkingRequest)?;
+            let rules = match serde_json::from_slice::<Vec<faults::Rule>>(&body) {
+                Ok(rules) => rules,
+                Err(_) => return error(StatusCode::BAD_REQUEST, "invalid fault rules"),
+            };
+            state.faults.set(rules);
+
+            json(StatusCode::OK, &state.faults.rules())
+        }
+        (&Method::DELETE, ["faults"]) => {
+            state.faults.set(Vec::new());
+
+            empty(StatusCode::NO_CONTENT)
+        }
+        (&Method::GET, ["state"]) => {
+            let spool = match state.spool.as_ref() {
+                Some(spool) => spool,
+                None => return error(StatusCode::CONFLICT, "QUEUE_DIR isn't set"),
+            };
+
+            match Snapshot::export(spool).await {
+                Ok(snapshot) => json(StatusCode::OK, &snapshot),
+                Err(source) => error(StatusCode::INTERNAL_SERVER_ERROR, &source.to_string()),
+            }
+        }
+        (&Method::POST, ["state"]) => {
+            let body = hyper::body::to_bytes(body).await.context(ChunkingRequest)?;
+            let snapshot = match Snapshot::parse(&body) {
+                Ok(snapshot) => snapshot,
+                Err(source) => return error(StatusCode::BAD_REQUEST, &source.to_string()),
+            };
+
+            match snapshot.import_live(&state).await {
+                Ok(imported) => json(StatusCode::OK, &Imported { imported }),
+                Err(source) => error(StatusCode::INTERNAL_SERVER_ERROR, &source.to_string()),
+            }
+        }
+        (&Method::POST, ["reload"]) => match reload::reload(&state) {
+            Ok(()) => empty(StatusCode::NO_CONTENT),
+            Err(source) => error(StatusCode::BAD_REQUEST, &source.to_string()),
+        },
+        (&Method::PUT, ["token"]) => {
+            let body = hyper::body::to_bytes(body).await.context(ChunkingRequest)?;
+            let body = match serde_json::from_slice::<TokenBody>(&body) {
+                Ok(body) if !body.token.is_empty() => body,
+                _ => return error(StatusCode::BAD_REQUEST, "invalid token body"),
+            };
+
+            let client = match upstream::client(&body.token, &state.config().upstream) {
+                Ok(client) => client,
+                Err(source) => return error(StatusCode::BAD_REQUEST, &source.to_string()),
+            };
+
+            if body.verify && client.current_user().await.is_err() {
+                return error(StatusCode::BAD_REQUEST, "Discord didn't accept the token");
+            }
+
+            info!("Swapping the Discord client for a new token");
+            let old = reload::swap_client(&state, client);
+            hooks::fire(&state.config(), Event::TokenRotated);
+            let drained = reload::drain(old, DRAIN_TIMEOUT).await;
+
+            json(StatusCode::OK, &TokenSwap { drained })
+        }
+        (&Method::POST, ["batch"]) => {
+            if let Some(response) = state.maintenance.response() {
+                return response.into_response();
+            }
+
+            let query = match uri.query() {
+                Some(query) => match serde_urlencoded::from_str::<batch::Query>(query) {
+                    Ok(query) => query,
+                    Err(_) => return error(StatusCode::BAD_REQUEST, "invalid batch mode"),
+                },
+                None => batch::Query::default(),
+            };
+            let body = hyper::body::to_bytes(body).await.context(ChunkingRequest)?;
+            let requests = match serde_json::from_slice::<batch::Body>(&body) {
+                Ok(body) => match body.into_requests() {
+                    Ok(requests) => requests,
+                    Err(message) => return error(StatusCode::BAD_REQUEST, &message),
+                },
+                Err(_) => return error(StatusCode::BAD_REQUEST, "invalid batch body"),
+            };
+
+            if requests.len() > batch::MAX_REQUESTS {
+                return error(StatusCode::PAYLOAD_TOO_LARGE, "too many sub-requests");
+            }
+
+            // Sub-requests are sent on the caller's behalf, so they're logged
+            // and audited as theirs.
+            let config = state.config();
+            let mut headers = parts.headers;
+            let tenant = match tenant::identify(&config, &mut headers) {
+                Identity::Anonymous => None,
+                Identity::Tenant(tenant) => Some(tenant),
+                Identity::Unknown => return error(StatusCode::UNAUTHORIZED, "unknown API key"),
+            };
+            let caller = batch::Caller {
+                peer: parts.extensions.get::<Peer>().copied(),
+                tenant,
+            };
+
+            json(
+                StatusCode::OK,
+                &batch::run(&state, caller, query.atomic, requests).await,
+            )
+        }
+        _ => empty(StatusCode::NOT_FOUND),
+    }
+}
+
+/// Role needed for an endpoint, if any.
+///
+/// Feature detection and batches are for clients of the proxy, who can
+/// already send requests without a token.
+fn required_role(method: &Method, segments: &[&str]) -> Option<Role> {
+    match (method, segments) {
+        (&Method::GET, ["capabilities"])
+        | (&Method::GET, ["version"])
+        | (&Method::GET, ["ready"])
+        | (&Method::GET, ["classify"])
+        | (&Method::POST, ["batch"]) => None,
+        // Clients are pointed at their jobs, so those are checked against
+        // the job instead.
+        (&Method::GET, ["jobs", ..]) | (&Method::DELETE, ["jobs", _]) => None,
+        // Snapshots hold the headers and bodies of requests.
+        (&Method::GET, ["state"]) => Some(Role::Operator),
+        (&Method::GET, _) => Some(Role::Viewer),
+        (&Method::PUT, ["token"]) => Some(Role::Admin),
+        _ => Some(Role::Operator),
+    }
+}
+
+/// Check the request carries an admin token with at least the required role,
+/// unless no tokens are configured.
+fn authorize(
+    config: &Config,
+    headers: &HeaderMap,
+    required: Role,
+) -> Result<(), (StatusCode, &'static str)> {
+    if config.admin_tokens.is_empty() {
+        return Ok(());
+    }
+
+    let provided = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or((StatusCode::UNAUTHORIZED, "an admin token is required"))?;
+    let token = config
+        .admin_tokens
+        .iter()
+        .find(|token| constant_time_eq(&token.token, provided))
+        .ok_or((StatusCode::UNAUTHORIZED, "unknown admin token"))?;
+
+    if token.role < required {
+        return Err((StatusCode::FORBIDDEN, "the admin token's role doesn't allow this"));
+    }
+
+    Ok(())
+}
+
+/// Check the caller may look at or cancel a job.
+///
+/// Job ids are hard to guess, so anyone holding the id of a job sent without
+/// a key may look at it. The tenant that sent a job may also cancel it, and
+/// operators may do either with any job.
+fn authorize_job(
+    config: &Config,
+    jobs: &Jobs,
+    method: &Method,
+    headers: &HeaderMap,
+    id: &str,
+) -> Result<(), (StatusCode, &'static str)> {
+    let mut key = headers.clone();
+    let caller = match tenant::identify(config, &mut key) {
+        Identity::Anonymous => None,
+        Identity::Tenant(tenant) => Some(tenant.name.as_str()),
+        Identity::Unknown => return Err((StatusCode::UNAUTHORIZED, "unknown API key")),
+    };
+    let job = match jobs.get(id) {
+        Some(job) => job,
+        None => return Ok(()),
+    };
+
+    if caller.is_some_and(|caller| job.is_from(caller))
+        || (*method == Method::GET && job.tenant.is_none())
+    {
+        return Ok(());
+    }
+
+    authorize(config, headers, Role::Operator).map_err(|denied| {
+        // Other tenants aren't let on that the job exists.
+        if job.tenant.is_some() {
+            (StatusCode::NOT_FOUND, "no such job")
+        } else {
+            denied
+        }
+    })
+}
+
+pub fn json<T: Serialize>(status: StatusCode, value: &T) -> Result<Response<Body>, RequestError> {
+    let body = serde_json::to_vec(value).context(SerializingJson)?;
+
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body))
+        .context(MakingResponseBody)
+}
+
+pub fn error(status: StatusCode, message: &str) -> Result<Response<Body>, RequestError> {
+    json(status, &ErrorBody { message })
+}
+
+pub fn empty(status: StatusCode) -> Result<Response<Body>, RequestError> {
+    Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .context(MakingResponseBody)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_endpoints_stay_open() {
+        for (method, segments) in &[
+            (Method::GET, &["capabilities"][..]),
+            (Method::GET, &["version"][..]),
+            (Method::GET, &["ready"][..]),
+            (Method::GET, &["classify"][..]),
+            (Method::POST, &["batch"][..]),
+            (Method::GET, &["jobs", "1"][..]),
+            (Method::GET, &["jobs", "1", "wait"][..]),
+            (Method::DELETE, &["jobs", "1"][..]),
+        ] {
+            assert_eq!(required_role(method, segments), None);
+        }
+
+        assert_eq!(required_role(&Method::GET, &["stats"]), Some(Role::Viewer));
+        assert_eq!(required_role(&Method::PUT, &["token"]), Some(Role::Admin));
+    }
+}
@@ -0,0 +1,92 @@
+//! Record of the requests that changed something on Discord, to tell after
+//! the fact which service deleted a channel or banned a member.
+
+use crate::{headers, hooks, proxy::ProxyRequest, State};
+use http::{Method, StatusCode};
+use serde::Serialize;
+use std::{collections::BTreeMap, time::SystemTime};
+use tokio::time;
+use tracing::warn;
+
+/// A mutating request as it ends up in the audit log.
+#[derive(Debug, Serialize)]
+pub struct Entry<'a> {
+    pub timestamp: String,
+    pub method: &'a str,
+    pub route: &'a str,
+    pub path: &'a str,
+    /// Ids in the path, keyed by the segment before them, e.g. `channels`.
+    pub resources: BTreeMap<&'a str, &'a str>,
+    pub tenant: Option<&'a str>,
+    pub peer: Option<String>,
+    pub reason: Option<String>,
+    /// Missing if no response was received.
+    pub status: Option<u16>,
+}
+
+fn is_mutating(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::POST | Method::PUT | Method::PATCH | Method::DELETE
+    )
+}
+
+/// Record the request if it's mutating, and there's anywhere to record it.
+pub fn record(state: &State, request: &ProxyRequest, status: Option<StatusCode>) {
+    let config = state.config();
+    let webhook = config.audit.webhook.as_ref();
+
+    if !is_mutating(&request.method) || (state.audit_log.is_none() && webhook.is_none()) {
+        return;
+    }
+
+    let path = request
+        .path_and_query
+        .split('?')
+        .next()
+        .unwrap_or_default();
+    let entry = Entry {
+        timestamp: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+        method: request.method.as_str(),
+        route: request.route,
+        path,
+        resources: resources(path),
+        tenant: request.tenant.as_deref(),
+        peer: request.peer.map(|peer| peer.to_string()),
+        reason: headers::audit_log_reason(&request.headers),
+        status: status.map(|status| status.as_u16()),
+    };
+    let payload = serde_json::to_string(&entry).expect("audit entries serialize");
+
+    if let Some(audit_log) = &state.audit_log {
+        audit_log.write_line(format!("{}\n", payload));
+    }
+
+    if let Some(webhook) = webhook {
+        let webhook = webhook.clone();
+
+        tokio::spawn(async move {
+            match time::timeout(hooks::TIMEOUT, hooks::post(&webhook, payload)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(source)) => warn!("Failed to post audit entry: {}", source),
+                Err(_) => warn!("Posting audit entry timed out"),
+            }
+        });
+    }
+}
+
+/// Ids in the path, like `{"channels": "1", "messages": "2"}` for
+/// `channels/1/messages/2`.
+fn resources(path: &str) -> BTreeMap<&str, &str> {
+    let segments = path.split('/').collect::<Vec<_>>();
+
+    segments
+        .windows(2)
+        .filter(|pair| {
+            !pair[0].is_empty()
+                && pair[0].parse::<u64>().is_err()
+                && pair[1].parse::<u64>().is_ok()
+        })
+        .map(|pair| (pair[0], pair[1]))
+        .collect()
+}
@@ -0,0 +1,302 @@
+use crate::{
+    config::Tenant,
+    headers, idempotency,
+    listener::Peer,
+    proxy::{self, Checked, ProxyRequest},
+    response_cache, State,
+};
+use http::{
+    header::{HeaderMap, HeaderName, HeaderValue},
+    Method, Uri,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{collections::HashMap, sync::Arc};
+
+/// Most sub-requests accepted in a single batch, after template expansion.
+pub const MAX_REQUESTS: usize = 1000;
+
+/// What to do with the rest of a batch once a sub-request fails.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    /// Run every sub-request regardless of earlier failures.
+    #[default]
+    #[serde(alias = "false")]
+    BestEffort,
+    /// Skip every sub-request after the first failure.
+    StopOnError,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Query {
+    #[serde(default)]
+    pub atomic: Mode,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct SubRequest {
+    pub method: String,
+    pub path: String,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    #[serde(default)]
+    pub body: Option<Value>,
+}
+
+/// Body of a batch: either the sub-requests themselves, or one templated
+/// sub-request applied to a list of values.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Body {
+    Requests(Vec<SubRequest>),
+    Template(Template),
+}
+
+impl Body {
+    pub fn into_requests(self) -> Result<Vec<SubRequest>, String> {
+        match self {
+            Body::Requests(requests) => Ok(requests),
+            Body::Template(template) => template.expand(),
+        }
+    }
+}
+
+/// Sub-request with `{name}` placeholders in its path, header values and body
+/// strings, expanded once per value.
+///
+/// Values are either objects, whose fields fill the placeholders of the same
+/// name, or plain strings and numbers, which fill `{value}`.
+#[derive(Debug, Deserialize)]
+pub struct Template {
+    pub template: SubRequest,
+    pub values: Vec<Value>,
+}
+
+impl Template {
+    fn expand(self) -> Result<Vec<SubRequest>, String> {
+        if self.values.len() > MAX_REQUESTS {
+            return Err("too many sub-requests".to_owned());
+        }
+
+        self.values
+            .iter()
+            .map(|value| {
+                let bindings = bindings(value)?;
+                let mut request = self.template.clone();
+
+                request.path = substitute(&request.path, &bindings);
+
+                for header in request.headers.values_mut() {
+                    *header = substitute(header, &bindings);
+                }
+
+                if let Some(body) = request.body.as_mut() {
+                    substitute_value(body, &bindings);
+                }
+
+                Ok(request)
+            })
+            .collect()
+    }
+}
+
+fn bindings(value: &Value) -> Result<Vec<(String, String)>, String> {
+    match value {
+        Value::Object(fields) => fields
+            .iter()
+            .map(|(name, field)| Ok((name.clone(), scalar(field)?)))
+            .collect(),
+        other => Ok(vec![("value".to_owned(), scalar(other)?)]),
+    }
+}
+
+fn scalar(value: &Value) -> Result<String, String> {
+    match value {
+        Value::String(string) => Ok(string.clone()),
+        Value::Number(number) => Ok(number.to_string()),
+        Value::Bool(boolean) => Ok(boolean.to_string()),
+        _ => Err("template values must be strings, numbers or booleans".to_owned()),
+    }
+}
+
+fn substitute(text: &str, bindings: &[(String, String)]) -> String {
+    bindings
+        .iter()
+        .fold(text.to_owned(), |text, (name, value)| {
+            text.replace(&format!("{{{}}}", name), value)
+        })
+}
+
+fn substitute_value(value: &mut Value, bindings: &[(String, String)]) {
+    match value {
+        Value::String(string) => *string = substitute(string, bindings),
+        Value::Array(items) => items
+            .iter_mut()
+            .for_each(|item| substitute_value(item, bindings)),
+        Value::Object(fields) => fields
+            .values_mut()
+            .for_each(|field| substitute_value(field, bindings)),
+        _ => {}
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "outcome", rename_all = "snake_case")]
+pub enum Outcome {
+    Completed { status: u16, body: Value },
+    Failed { error: String },
+    Skipped,
+}
+
+impl Outcome {
+    fn is_success(&self) -> bool {
+        matches!(self, Outcome::Completed { status, .. } if *status < 400)
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Summary {
+    pub total: usize,
+    pub succeeded: usize,
+    pub failed: usize,
+    pub skipped: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Batch {
+    pub results: Vec<Outcome>,
+    pub summary: Summary,
+}
+
+/// Who sent a batch, identified once for all of its sub-requests.
+#[derive(Clone, Copy, Debug)]
+pub struct Caller<'a> {
+    pub peer: Option<Peer>,
+    pub tenant: Option<&'a Tenant>,
+}
+
+/// Run the sub-requests in order, each going through the same checks, cache
+/// and ratelimiter as any other request.
+pub async fn run(
+    state: &Arc<State>,
+    caller: Caller<'_>,
+    mode: Mode,
+    requests: Vec<SubRequest>,
+) -> Batch {
+    let mut results = Vec::with_capacity(requests.len());
+    let mut summary = Summary {
+        total: requests.len(),
+        ..Summary::default()
+    };
+    let mut stopped = false;
+
+    for request in requests {
+        if stopped {
+            summary.skipped += 1;
+            results.push(Outcome::Skipped);
+
+            continue;
+        }
+
+        let outcome = execute(state, caller, request).await;
+
+        if outcome.is_success() {
+            summary.succeeded += 1;
+        } else {
+            summary.failed += 1;
+            stopped = mode == Mode::StopOnError;
+        }
+
+        results.push(outcome);
+    }
+
+    Batch { results, summary }
+}
+
+async fn execute(state: &Arc<State>, caller: Caller<'_>, request: SubRequest) -> Outcome {
+    let (request, idempotency_key) = match build(state, caller, request) {
+        Ok(built) => built,
+        Err(error) => return Outcome::Failed { error },
+    };
+
+    let result = match proxy::check(state, &request, idempotency_key).await {
+        Ok(Checked::Answered(response)) => Ok(response),
+        Ok(Checked::Forward(guard)) => {
+            let result = response_cache::forward(state, request).await;
+
+            if let (Some(guard), Ok(response)) = (guard, &result) {
+                guard.complete(response);
+            }
+
+            result
+        }
+        Err(source) => Err(source),
+    };
+
+    match result {
+        Ok(response) => {
+            let body = if response.body.is_empty() {
+                Value::Null
+            } else {
+                serde_json::from_slice(&response.body).unwrap_or_else(|_| {
+                    Value::String(String::from_utf8_lossy(&response.body).into_owned())
+                })
+            };
+
+            Outcome::Completed {
+                status: response.status.as_u16(),
+                body,
+            }
+        }
+        Err(source) => Outcome::Failed {
+            error: source.to_string(),
+        },
+    }
+}
+
+/// Build the sub-request as sent by the batch's caller, taking its
+/// idempotency key and leaving it only the headers Discord should see.
+fn build(
+    state: &State,
+    caller: Caller<'_>,
+    request: SubRequest,
+) -> Result<(ProxyRequest, Option<String>), String> {
+    let config = state.config();
+    let method = Method::from_bytes(request.method.to_ascii_uppercase().as_bytes())
+        .map_err(|_| format!("invalid method: {}", request.method))?;
+    let uri = request
+        .path
+        .parse::<Uri>()
+        .map_err(|_| format!("invalid path: {}", request.path))?;
+
+    let mut headers = HeaderMap::with_capacity(request.headers.len());
+
+    for (name, value) in &request.headers {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| format!("invalid header name: {}", name))?;
+        let value = HeaderValue::from_str(value)
+            .map_err(|_| format!("invalid value for header {}", name))?;
+
+        headers.insert(name, value);
+    }
+
+    headers::normalize_audit_log_reason(&mut headers).map_err(str::to_owned)?;
+    let idempotency_key = idempotency::Store::take_key(&mut headers)
+        .map(|key| idempotency::scoped(caller.tenant, key));
+
+    let body = match request.body {
+        Some(body) => serde_json::to_vec(&body).map_err(|source| source.to_string())?,
+        None => Vec::new(),
+    };
+
+    let passthrough_unknown = config.unknown_routes.passthrough;
+    let mut request =
+        ProxyRequest::from_parts(method, &uri, headers, body.into(), passthrough_unknown)
+            .map_err(|source| source.to_string())?;
+    request.tenant = caller.tenant.map(|tenant| tenant.name.clone());
+    request.peer = caller.peer;
+    headers::sanitize_for(&config, &request.path_and_query, &mut request.headers);
+
+    Ok((request, idempotency_key))
+}
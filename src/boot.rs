@@ -0,0 +1,121 @@
+//! What the proxy is running with, so fleet tooling can check every instance
+//! booted with the intended configuration.
+
+use crate::{
+    build_info::BuildInfo,
+    config::{Config, ExporterKind},
+    State,
+};
+use serde::Serialize;
+use tracing::info;
+
+/// Listeners, subsystems and identity of the running proxy.
+#[derive(Debug, Serialize)]
+pub struct BootReport {
+    #[serde(flatten)]
+    pub build: BuildInfo,
+    pub listeners: Vec<String>,
+    /// Addresses redirecting plain HTTP to the proxy.
+    pub redirects: Vec<String>,
+    /// Id of the bot the token belongs to, as encoded in the token itself.
+    pub bot_id: Option<String>,
+    pub metrics_exporters: Vec<ExporterKind>,
+    pub subsystems: Subsystems,
+    pub tenants: usize,
+    pub admin_tokens: usize,
+    pub hooks: usize,
+}
+
+/// Which optional parts of the proxy are on.
+#[derive(Debug, Serialize)]
+pub struct Subsystems {
+    pub spool: bool,
+    pub access_log: bool,
+    pub audit_log: bool,
+    pub audit_webhook: bool,
+    pub mirror: bool,
+    pub mock_upstream: bool,
+    pub dry_run: bool,
+    pub gateway: bool,
+    pub compression: bool,
+    pub upstream_compression: bool,
+    pub http2: bool,
+    pub trace_upstream: bool,
+    pub maintenance: bool,
+}
+
+impl BootReport {
+    pub fn current(state: &State) -> Self {
+        let config = state.config();
+
+        Self {
+            build: BuildInfo::current(state),
+            listeners: config.listen.iter().map(ToString::to_string).collect(),
+            redirects: config
+                .redirect
+                .iter()
+                .flat_map(|redirect| &redirect.listen)
+                .map(|address| format!("http://{}", address))
+                .collect(),
+            bot_id: bot_id(&config.token),
+            metrics_exporters: config.metrics.exporters.clone(),
+            subsystems: Subsystems::of(state, &config),
+            tenants: config.tenants.len(),
+            admin_tokens: config.admin_tokens.len(),
+            hooks: config.hooks.len(),
+        }
+    }
+}
+
+impl Subsystems {
+    fn of(state: &State, config: &Config) -> Self {
+        Self {
+            spool: state.spool.is_some(),
+            access_log: state.access_log.is_some(),
+            audit_log: state.audit_log.is_some(),
+            audit_webhook: config.audit.webhook.is_some(),
+            mirror: config.mirror.percent > 0,
+            mock_upstream: state.mock.is_some(),
+            dry_run: config.dry_run,
+            gateway: config.gateway.enabled,
+            compression: config.compression.enabled,
+            upstream_compression: config.compression.upstream,
+            http2: config.http2.enabled,
+            trace_upstream: config.trace_upstream,
+            maintenance: state.maintenance.is_enabled(),
+        }
+    }
+}
+
+/// Log a banner and the boot report, as a single line of JSON for log
+/// collectors.
+pub fn log(state: &State) {
+    let report = BootReport::current(state);
+
+    info!(
+        "Starting twilight-http-proxy {} ({}) on {}",
+        report.build.version,
+        report.build.commit.unwrap_or("unknown commit"),
+        report.listeners.join(", ")
+    );
+
+    if let Ok(json) = serde_json::to_string(&report) {
+        info!("Boot report: {}", json);
+    }
+}
+
+/// The bot's user id, which the first part of a token encodes in base64.
+pub fn bot_id(token: &str) -> Option<String> {
+    let token = token.strip_prefix("Bot ").unwrap_or(token);
+    let encoded = token.split('.').next()?.trim_end_matches('=');
+    let decoded = base64::decode_config(encoded, base64::STANDARD_NO_PAD)
+        .or_else(|_| base64::decode_config(encoded, base64::URL_SAFE_NO_PAD))
+        .ok()?;
+    let id = String::from_utf8(decoded).ok()?;
+
+    if id.is_empty() || !id.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+
+    Some(id)
+}
@@ -0,0 +1,231 @@
+use crate::config::BreakerConfig;
+use metrics::gauge;
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+/// Every family a route can belong to.
+pub const FAMILIES: &[&str] = &[
+    "channels", "gateway", "guilds", "invites", "oauth2", "users", "voice", "webhooks", "other",
+];
+
+/// Group of routes sharing a circuit breaker, named after the first segment
+/// of the path.
+pub fn family(path_and_query: &str) -> &'static str {
+    let first = path_and_query
+        .trim_start_matches('/')
+        .split(['/', '?'])
+        .next()
+        .unwrap_or_default();
+
+    match first {
+        "channels" => "channels",
+        "gateway" => "gateway",
+        "guilds" => "guilds",
+        "invites" => "invites",
+        "oauth2" => "oauth2",
+        "users" => "users",
+        "voice" => "voice",
+        "webhooks" => "webhooks",
+        _ => "other",
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Status {
+    Closed,
+    /// Letting a single trial request through to see if Discord recovered.
+    HalfOpen,
+    Open,
+}
+
+impl Status {
+    fn gauge(self) -> i64 {
+        match self {
+            Status::Closed => 0,
+            Status::HalfOpen => 1,
+            Status::Open => 2,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Breaker {
+    status: Status,
+    failures: u32,
+    changed_at: Instant,
+}
+
+/// Change of a breaker worth telling anyone about.
+#[derive(Clone, Copy, Debug)]
+pub enum Transition {
+    Opened,
+    Closed,
+}
+
+/// Whether a request may be sent.
+#[derive(Debug)]
+pub enum Admission {
+    Allowed,
+    Rejected { retry_after: Duration },
+}
+
+#[derive(Debug, Serialize)]
+pub struct Snapshot {
+    status: Status,
+    failures: u32,
+    retry_after_secs: Option<u64>,
+}
+
+/// Circuit breakers per route family.
+///
+/// After `threshold` consecutive failures (server errors or failed
+/// connections) requests for the family fail fast for `cooldown`, after which
+/// a single trial request decides whether to close the breaker again.
+#[derive(Debug, Default)]
+pub struct Breakers {
+    breakers: Mutex<HashMap<&'static str, Breaker>>,
+}
+
+impl Breakers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn admit(&self, family: &'static str, config: &BreakerConfig) -> Admission {
+        if config.threshold == 0 {
+            return Admission::Allowed;
+        }
+
+        let mut breakers = self.breakers.lock().expect("breakers poisoned");
+        let breaker = match breakers.get_mut(family) {
+            Some(breaker) => breaker,
+            None => return Admission::Allowed,
+        };
+        let elapsed = breaker.changed_at.elapsed();
+
+        match breaker.status {
+            Status::Closed => Admission::Allowed,
+            // A trial request that never reported back, e.g. because the
+            // client went away, doesn't keep the breaker half open forever.
+            Status::Open | Status::HalfOpen if elapsed >= config.cooldown => {
+                set(breaker, family, Status::HalfOpen);
+
+                Admission::Allowed
+            }
+            Status::Open | Status::HalfOpen => Admission::Rejected {
+                retry_after: config.cooldown - elapsed,
+            },
+        }
+    }
+
+    pub fn record(
+        &self,
+        family: &'static str,
+        success: bool,
+        config: &BreakerConfig,
+    ) -> Option<Transition> {
+        if config.threshold == 0 {
+            return None;
+        }
+
+        let mut breakers = self.breakers.lock().expect("breakers poisoned");
+
+        if success {
+            if let Some(breaker) = breakers.get_mut(family) {
+                breaker.failures = 0;
+
+                if !matches!(breaker.status, Status::Closed) {
+                    info!("Closing circuit breaker for {}", family);
+                    set(breaker, family, Status::Closed);
+
+                    return Some(Transition::Closed);
+                }
+            }
+
+            return None;
+        }
+
+        let breaker = breakers.entry(family).or_insert_with(|| Breaker {
+            status: Status::Closed,
+            failures: 0,
+            changed_at: Instant::now(),
+        });
+        breaker.failures += 1;
+
+        let trip = match breaker.status {
+            Status::Closed => breaker.failures >= config.threshold,
+            Status::HalfOpen => true,
+            Status::Open => false,
+        };
+
+        if trip {
+            warn!(
+                "Opening circuit breaker for {} after {} failures",
+                family, breaker.failures
+            );
+            set(breaker, family, Status::Open);
+
+            return Some(Transition::Opened);
+        }
+
+        None
+    }
+
+    /// Families whose breaker is failing their requests.
+    pub fn open(&self, config: &BreakerConfig) -> Vec<&'static str> {
+        let breakers = self.breakers.lock().expect("breakers poisoned");
+        let mut open = breakers
+            .iter()
+            .filter(|(_, breaker)| {
+                matches!(breaker.status, Status::Open)
+                    && breaker.changed_at.elapsed() < config.cooldown
+            })
+            .map(|(family, _)| *family)
+            .collect::<Vec<_>>();
+        open.sort_unstable();
+
+        open
+    }
+
+    pub fn snapshot(&self, config: &BreakerConfig) -> BTreeMap<&'static str, Snapshot> {
+        let breakers = self.breakers.lock().expect("breakers poisoned");
+
+        breakers
+            .iter()
+            .map(|(family, breaker)| {
+                let retry_after_secs = match breaker.status {
+                    Status::Open => Some(
+                        config
+                            .cooldown
+                            .checked_sub(breaker.changed_at.elapsed())
+                            .unwrap_or_default()
+                            .as_secs(),
+                    ),
+                    _ => None,
+                };
+
+                (
+                    *family,
+                    Snapshot {
+                        status: breaker.status,
+                        failures: breaker.failures,
+                        retry_after_secs,
+                    },
+                )
+            })
+            .collect()
+    }
+}
+
+fn set(breaker: &mut Breaker, family: &'static str, status: Status) {
+    breaker.status = status;
+    breaker.changed_at = Instant::now();
+
+    gauge!("gearbot_proxy_circuit_state", status.gauge(), "family" => family);
+}
@@ -0,0 +1,78 @@
+//! Which build of the proxy is running, and for how long.
+
+use crate::State;
+use metrics::{counter, gauge};
+use serde::Serialize;
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::time;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Commit the proxy was built from, if `GIT_COMMIT` was set while building.
+pub const COMMIT: Option<&str> = option_env!("GIT_COMMIT");
+
+/// How often uptime is added to its counter.
+const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Build metadata, as shown by the admin API.
+#[derive(Debug, Serialize)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    pub commit: Option<&'static str>,
+    /// `debug` or `release`.
+    pub profile: &'static str,
+    /// Unix timestamp in milliseconds.
+    pub started_at: u64,
+    pub uptime_seconds: u64,
+}
+
+impl BuildInfo {
+    pub fn current(state: &State) -> Self {
+        let uptime = state.started.elapsed();
+        let started_at = SystemTime::now()
+            .checked_sub(uptime)
+            .and_then(|at| at.duration_since(UNIX_EPOCH).ok())
+            .unwrap_or_default();
+
+        Self {
+            version: VERSION,
+            commit: COMMIT,
+            profile: profile(),
+            started_at: started_at.as_millis() as u64,
+            uptime_seconds: uptime.as_secs(),
+        }
+    }
+}
+
+fn profile() -> &'static str {
+    if cfg!(debug_assertions) {
+        "debug"
+    } else {
+        "release"
+    }
+}
+
+/// Report the build as a metric and keep counting uptime.
+pub async fn report(state: Arc<State>) {
+    let mut interval = time::interval(REPORT_INTERVAL);
+    let mut reported = 0;
+
+    loop {
+        interval.tick().await;
+
+        gauge!(
+            "gearbot_proxy_build_info",
+            1,
+            "version" => VERSION,
+            "commit" => COMMIT.unwrap_or("unknown"),
+            "profile" => profile()
+        );
+
+        let uptime = state.started.elapsed().as_secs();
+        counter!("gearbot_proxy_uptime_seconds", uptime - reported);
+        reported = uptime;
+    }
+}
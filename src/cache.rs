@@ -0,0 +1,281 @@
+//! Size-bounded cache of responses, kept in memory or on disk.
+//!
+//! When full, the least recently used responses make room for new ones.
+//! Expired responses are kept until then, so callers can still serve them
+//! while fetching a fresh copy.
+
+use crate::proxy::ProxyResponse;
+use http::{header::HeaderMap, StatusCode};
+use hyper::body::Bytes;
+use metrics::gauge;
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tokio::fs;
+use tracing::{debug, warn};
+
+/// Header saying whether a response came from a cache.
+pub const HEADER: &str = "x-proxy-cache";
+
+/// A cached response.
+#[derive(Debug)]
+pub struct Hit {
+    pub response: ProxyResponse,
+    /// How long ago the response was stored.
+    pub age: Duration,
+    /// Whether the response is still within its time to live.
+    pub fresh: bool,
+    pub ttl: Duration,
+}
+
+#[derive(Debug)]
+pub struct Cache {
+    /// Name of the cache in the `cache` label of metrics.
+    name: &'static str,
+    /// Directory bodies are written to, or `None` to keep them in memory.
+    dir: Option<PathBuf>,
+    max_size: usize,
+    inner: Mutex<Inner>,
+    /// Keys being fetched again in the background.
+    refreshing: Mutex<HashSet<String>>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    entries: HashMap<String, Entry>,
+    size: usize,
+    /// Ticks on every use, ordering entries by when they were last used.
+    clock: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Stored,
+    size: usize,
+    stored: Instant,
+    ttl: Duration,
+    used: u64,
+}
+
+#[derive(Clone, Debug)]
+enum Stored {
+    Memory(Bytes),
+    Disk(PathBuf),
+}
+
+impl Cache {
+    /// Open a cache holding up to `max_size` bytes of bodies.
+    ///
+    /// Files left in the directory by a previous run are removed, as nothing
+    /// records what they were for. Only files named like the cache's own are
+    /// touched, in case the directory is shared.
+    pub async fn open(name: &'static str, dir: Option<&Path>, max_size: usize) -> io::Result<Self> {
+        if let Some(dir) = dir {
+            fs::create_dir_all(dir).await?;
+            let mut entries = fs::read_dir(dir).await?;
+
+            while let Some(entry) = entries.next_entry().await? {
+                let ours = entry
+                    .file_name()
+                    .to_str()
+                    .is_some_and(|name| name.parse::<u64>().is_ok());
+
+                if ours && entry.file_type().await?.is_file() {
+                    fs::remove_file(entry.path()).await?;
+                }
+            }
+        }
+
+        Ok(Self {
+            name,
+            dir: dir.map(Path::to_path_buf),
+            max_size,
+            inner: Mutex::new(Inner::default()),
+            refreshing: Mutex::new(HashSet::new()),
+        })
+    }
+
+    pub async fn get(&self, key: &str) -> Option<Hit> {
+        let (status, headers, body, age, ttl) = {
+            let mut inner = self.inner.lock().expect("cache poisoned");
+            inner.clock += 1;
+            let clock = inner.clock;
+            let entry = inner.entries.get_mut(key)?;
+            entry.used = clock;
+            let age = entry.stored.elapsed();
+            let body = entry.body.clone();
+
+            (entry.status, entry.headers.clone(), body, age, entry.ttl)
+        };
+
+        let body = match body {
+            Stored::Memory(body) => body,
+            Stored::Disk(path) => match fs::read(&path).await {
+                Ok(body) => body.into(),
+                Err(source) => {
+                    warn!("Failed to read cached response {:?}: {}", path, source);
+                    self.remove(key).await;
+
+                    return None;
+                }
+            },
+        };
+
+        Some(Hit {
+            response: ProxyResponse {
+                status,
+                headers,
+                body,
+            },
+            age,
+            fresh: age < ttl,
+            ttl,
+        })
+    }
+
+    /// Store a response for `ttl`, unless it's too large to be worth it.
+    pub async fn insert(&self, key: String, response: &ProxyResponse, ttl: Duration) {
+        let size = response.body.len();
+
+        if size > self.max_size {
+            return;
+        }
+
+        let body = match &self.dir {
+            Some(dir) => {
+                let id = {
+                    let mut inner = self.inner.lock().expect("cache poisoned");
+                    inner.clock += 1;
+                    inner.clock
+                };
+                let path = dir.join(id.to_string());
+
+                if let Err(source) = fs::write(&path, &response.body).await {
+                    warn!("Failed to write cached response {:?}: {}", path, source);
+
+                    return;
+                }
+
+                Stored::Disk(path)
+            }
+            None => Stored::Memory(response.body.clone()),
+        };
+
+        let evicted = {
+            let mut inner = self.inner.lock().expect("cache poisoned");
+            inner.clock += 1;
+            let mut evicted = inner.take(&key).into_iter().collect::<Vec<_>>();
+
+            while inner.size + size > self.max_size {
+                let oldest = inner
+                    .entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.used)
+                    .map(|(key, _)| key.clone());
+
+                match oldest.and_then(|oldest| inner.take(&oldest)) {
+                    Some(entry) => evicted.push(entry),
+                    None => break,
+                }
+            }
+
+            let used = inner.clock;
+            inner.size += size;
+            inner.entries.insert(
+                key,
+                Entry {
+                    status: response.status,
+                    headers: response.headers.clone(),
+                    body,
+                    size,
+                    stored: Instant::now(),
+                    ttl,
+                    used,
+                },
+            );
+            gauge!("gearbot_proxy_cache_bytes", inner.size as i64, "cache" => self.name);
+
+            evicted
+        };
+
+        if !evicted.is_empty() {
+            debug!(
+                "Evicted {} responses from the {} cache",
+                evicted.len(),
+                self.name
+            );
+        }
+
+        for entry in evicted {
+            delete(entry).await;
+        }
+    }
+
+    pub async fn remove(&self, key: &str) {
+        let entry = self.inner.lock().expect("cache poisoned").take(key);
+
+        if let Some(entry) = entry {
+            delete(entry).await;
+        }
+    }
+
+    /// Remove every entry whose key matches, returning how many there were.
+    pub async fn remove_where(&self, matches: impl Fn(&str) -> bool) -> usize {
+        let removed = {
+            let mut inner = self.inner.lock().expect("cache poisoned");
+            let keys = inner
+                .entries
+                .keys()
+                .filter(|key| matches(key))
+                .cloned()
+                .collect::<Vec<_>>();
+
+            keys.iter()
+                .filter_map(|key| inner.take(key))
+                .collect::<Vec<_>>()
+        };
+        let count = removed.len();
+
+        for entry in removed {
+            delete(entry).await;
+        }
+
+        count
+    }
+
+    /// Claim fetching the key again in the background, returning whether
+    /// nobody else is already.
+    pub fn start_refresh(&self, key: &str) -> bool {
+        self.refreshing
+            .lock()
+            .expect("cache poisoned")
+            .insert(key.to_owned())
+    }
+
+    pub fn finish_refresh(&self, key: &str) {
+        self.refreshing.lock().expect("cache poisoned").remove(key);
+    }
+}
+
+impl Inner {
+    fn take(&mut self, key: &str) -> Option<Entry> {
+        let entry = self.entries.remove(key)?;
+        self.size -= entry.size;
+
+        Some(entry)
+    }
+}
+
+async fn delete(entry: Entry) {
+    if let Stored::Disk(path) = entry.body {
+        if let Err(source) = fs::remove_file(&path).await {
+            debug!("Failed to remove cached response {:?}: {}", path, source);
+        }
+    }
+}
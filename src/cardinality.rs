@@ -0,0 +1,97 @@
+//! Protection against metrics with unbounded label values.
+//!
+//! Every distinct set of labels becomes its own series in the exporters, kept
+//! for as long as the proxy runs. A label fed something unbounded by mistake,
+//! like an ID, would grow memory and scrapes without limit, so past a number
+//! of series per metric new label sets are folded into a single one.
+
+use metrics::{Key, Label, Recorder};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+};
+use tracing::warn;
+
+/// Value replacing every label of a series past the limit.
+pub const OTHER: &str = "other";
+
+/// Recorder passing metrics on, with label sets clamped per metric.
+pub struct Guard<R> {
+    inner: R,
+    max_series: usize,
+    seen: Mutex<HashMap<Cow<'static, str>, HashSet<Key>>>,
+}
+
+impl<R: Recorder> Guard<R> {
+    /// Guard the recorder, allowing up to `max_series` label sets per metric,
+    /// or any number if it's `0`.
+    pub fn new(inner: R, max_series: usize) -> Self {
+        Self {
+            inner,
+            max_series,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn clamp(&self, key: Key) -> Key {
+        if self.max_series == 0 || key.labels().len() == 0 {
+            return key;
+        }
+
+        let mut seen = self.seen.lock().expect("cardinality poisoned");
+        let series = seen.entry(key.name()).or_default();
+
+        if series.contains(&key) {
+            return key;
+        }
+
+        if series.len() < self.max_series {
+            series.insert(key.clone());
+
+            return key;
+        }
+
+        let (name, labels) = key.into_parts();
+        let clamped = Key::from_name_and_labels(
+            name.clone(),
+            labels
+                .into_iter()
+                .map(|label| Label::new(label.into_parts().0, OTHER))
+                .collect::<Vec<_>>(),
+        );
+
+        // The first series to be clamped takes the last slot, so the limit
+        // is overshot by at most one series per metric.
+        if series.insert(clamped.clone()) {
+            warn!(
+                "Metric {} has more than {} label sets, folding new ones into \"{}\"",
+                name, self.max_series, OTHER
+            );
+        }
+
+        self.inner.increment_counter(
+            Key::from_name_and_labels(
+                "gearbot_proxy_metric_series_clamped",
+                vec![Label::new("metric", name)],
+            ),
+            1,
+        );
+
+        clamped
+    }
+}
+
+impl<R: Recorder> Recorder for Guard<R> {
+    fn increment_counter(&self, key: Key, value: u64) {
+        self.inner.increment_counter(self.clamp(key), value);
+    }
+
+    fn update_gauge(&self, key: Key, value: i64) {
+        self.inner.update_gauge(self.clamp(key), value);
+    }
+
+    fn record_histogram(&self, key: Key, value: u64) {
+        self.inner.record_histogram(self.clamp(key), value);
+    }
+}
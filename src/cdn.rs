@@ -0,0 +1,146 @@
+//! Fetching avatars, emojis and attachments from Discord's CDN through the
+//! proxy, caching them so every pod of an image-heavy bot doesn't download
+//! them again.
+
+use crate::{
+    admin,
+    cache::{self, Cache},
+    config::{CdnConfig, UpstreamConfig},
+    error::RequestError,
+    proxy::ProxyResponse,
+    upstream,
+};
+use http::{
+    header::{
+        HeaderMap, HeaderName, HeaderValue, AGE, CACHE_CONTROL, CONTENT_TYPE, ETAG, LAST_MODIFIED,
+    },
+    Method, Response, StatusCode, Uri,
+};
+use hyper::body::{Body, Bytes};
+use metrics::counter;
+use reqwest::{Client as HttpClient, Error as ReqwestError};
+use std::error::Error;
+use tracing::{debug, warn};
+
+/// Path prefix of requests for the CDN.
+pub const PREFIX: &str = "/cdn/";
+
+/// Headers of the CDN's responses kept for clients.
+const KEPT: &[HeaderName] = &[CACHE_CONTROL, CONTENT_TYPE, ETAG, LAST_MODIFIED];
+
+#[derive(Debug)]
+pub struct Cdn {
+    client: HttpClient,
+    cache: Cache,
+}
+
+impl Cdn {
+    pub async fn open(
+        config: &CdnConfig,
+        upstream: &UpstreamConfig,
+    ) -> Result<Self, Box<dyn Error>> {
+        // Unlike requests to the API, these don't carry the token.
+        let client = upstream::http_client(upstream)?.build()?;
+
+        Ok(Self {
+            client,
+            cache: Cache::open("cdn", config.cache_dir.as_deref(), config.cache_size).await?,
+        })
+    }
+
+    /// Answer a request for the CDN from the cache, or fetch it.
+    pub async fn handle(
+        &self,
+        config: &CdnConfig,
+        method: &Method,
+        uri: &Uri,
+    ) -> Result<Response<Body>, RequestError> {
+        if method != Method::GET && method != Method::HEAD {
+            return admin::error(StatusCode::METHOD_NOT_ALLOWED, "the CDN is read-only");
+        }
+
+        // Queries like `?size=128` pick different images, so they're part
+        // of the key.
+        let path = uri
+            .path_and_query()
+            .map_or("", |path| path.as_str())
+            .trim_start_matches(PREFIX.trim_end_matches('/'));
+
+        let response = match self.cache.get(path).await {
+            Some(hit) if hit.fresh => {
+                counter!("gearbot_proxy_cdn_requests", 1, "result" => "hit");
+                let mut response = hit.response;
+                response
+                    .headers
+                    .insert(AGE, HeaderValue::from(hit.age.as_secs()));
+                response
+                    .headers
+                    .insert(cache::HEADER, HeaderValue::from_static("hit"));
+
+                response
+            }
+            _ => {
+                let mut response = match self.fetch(config, path).await {
+                    Ok(response) => response,
+                    Err(source) => {
+                        warn!("Failed to fetch {} from the CDN: {}", path, source);
+                        counter!("gearbot_proxy_cdn_requests", 1, "result" => "error");
+
+                        return admin::error(StatusCode::BAD_GATEWAY, "couldn't reach the CDN");
+                    }
+                };
+                counter!("gearbot_proxy_cdn_requests", 1, "result" => "miss");
+
+                if response.status == StatusCode::OK
+                    && response.body.len() <= config.max_object_size
+                {
+                    self.cache
+                        .insert(path.to_owned(), &response, config.ttl)
+                        .await;
+                }
+
+                response
+                    .headers
+                    .insert(cache::HEADER, HeaderValue::from_static("miss"));
+
+                response
+            }
+        };
+
+        if method == Method::HEAD {
+            return ProxyResponse {
+                body: Bytes::new(),
+                ..response
+            }
+            .into_response();
+        }
+
+        response.into_response()
+    }
+
+    async fn fetch(&self, config: &CdnConfig, path: &str) -> Result<ProxyResponse, ReqwestError> {
+        debug!("Fetching {} from the CDN", path);
+
+        let response = self
+            .client
+            .get(&format!("{}{}", config.url, path))
+            .send()
+            .await?;
+        let status = response.status();
+        let mut headers = HeaderMap::new();
+
+        for name in KEPT {
+            if let Some(value) = response.headers().get(name) {
+                headers.insert(name, value.clone());
+            }
+        }
+
+        let body = response.bytes().await?;
+
+        Ok(ProxyResponse {
+            status,
+            headers,
+            body,
+        })
+    }
+}
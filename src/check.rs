@@ -0,0 +1,63 @@
+//! Validating the configuration without starting the proxy, so CI can catch
+//! a bad change before it's deployed.
+//!
+//! Settings that don't parse already failed when the configuration was
+//! loaded; this checks the rest and prints what the proxy would run with.
+
+use crate::{
+    boot,
+    config::{Config, SettingSource},
+    upstream,
+};
+use std::error::Error;
+
+pub fn command(config: &Config) -> Result<(), Box<dyn Error>> {
+    // Bearer tokens are OAuth2 tokens, which don't encode the user's id.
+    let bot_id = if config.token.starts_with("Bearer ") {
+        None
+    } else {
+        Some(boot::bot_id(&config.token).ok_or("DISCORD_TOKEN isn't a valid bot token")?)
+    };
+
+    // Adds the CA certificates and puts the token in a header, checking both.
+    upstream::client(&config.token, &config.upstream)?;
+
+    let listeners = config
+        .listen
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>();
+
+    println!("Configuration is valid");
+    println!("Listeners: {}", listeners.join(", "));
+    println!(
+        "Bot: {}",
+        bot_id.as_deref().unwrap_or("unknown, bearer token")
+    );
+    println!(
+        "Upstream CA certificates: {}",
+        config.upstream.ca_certificates.len()
+    );
+    println!(
+        "Tenants: {}, admin tokens: {}, hooks: {}",
+        config.tenants.len(),
+        config.admin_tokens.len(),
+        config.hooks.len()
+    );
+    println!(
+        "Trusted proxies: {}, metrics allowed from: {}",
+        config.trusted_proxies.networks.len(),
+        config.metrics.access.allow.len()
+    );
+
+    for (name, source) in &config.sources {
+        let source = match source {
+            SettingSource::File => "CONFIG_FILE",
+            SettingSource::Environment => "the environment",
+        };
+
+        println!("{} is set in {}", name, source);
+    }
+
+    Ok(())
+}
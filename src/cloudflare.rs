@@ -0,0 +1,53 @@
+//! Errors from Cloudflare, in front of Discord, rather than from Discord
+//! itself.
+//!
+//! These come with HTML pages meant for browsers and usually go away when the
+//! request is simply sent again.
+
+use http::{
+    header::{HeaderMap, HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE},
+    StatusCode,
+};
+use hyper::body::Bytes;
+
+/// Response header set on errors that came from Cloudflare.
+pub const HEADER: &str = "x-proxy-cloudflare-error";
+
+/// Whether a response is an error page from Cloudflare.
+///
+/// Besides its own `52x` codes, Cloudflare answers with an HTML page, which
+/// Discord's API never does.
+pub fn is_error(status: StatusCode, headers: &HeaderMap) -> bool {
+    let code = status.as_u16();
+    let html = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.trim_start().starts_with("text/html"));
+
+    (520..=527).contains(&code) || code == 530 || (status.is_server_error() && html)
+}
+
+/// Replace the HTML page of a Cloudflare error with a JSON error like
+/// Discord's, so clients don't have to handle both.
+pub fn translate(status: StatusCode, headers: &mut HeaderMap) -> Bytes {
+    let reason = match status.as_u16() {
+        520 => "Discord returned an unknown error",
+        521 => "Discord refused the connection",
+        522 => "connecting to Discord timed out",
+        523 => "Discord is unreachable",
+        524 => "Discord took too long to respond",
+        525 | 526 => "the TLS connection to Discord failed",
+        _ => "Cloudflare couldn't get a response from Discord",
+    };
+    let body = serde_json::json!({
+        "message": format!("{} ({} from Cloudflare)", reason, status.as_u16()),
+        "code": 0,
+    });
+
+    headers.remove(CONTENT_LENGTH);
+    headers.remove(CONTENT_ENCODING);
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    headers.insert(HEADER, HeaderValue::from_static("true"));
+
+    Bytes::from(body.to_string())
+}
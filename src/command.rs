@@ -0,0 +1,126 @@
+//! Commands the proxy can be run with instead of starting it.
+//!
+//! There are only a few, taking at most two arguments, so they're told apart
+//! by hand.
+
+/// Printed for `--help`, and after arguments that don't make a command.
+pub const USAGE: &str = "\
+Usage: twilight-http-proxy [COMMAND]
+
+Starts the proxy, configured through the environment or CONFIG_FILE, unless a
+command is given.
+
+Commands:
+    check                        Validate the configuration and print a summary
+    replay <HAR file> <target>   Replay recorded requests against the proxy at
+                                 the target URL and compare how it routes them
+    export-state [file]          Write a snapshot of QUEUE_DIR to the file, or
+                                 to standard output
+    import-state [file]          Load a snapshot into QUEUE_DIR from the file,
+                                 or from standard input
+
+Options:
+    -h, --help                   Print this help
+    -V, --version                Print the version
+";
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum Command {
+    /// Also given as `--check-config`.
+    Check,
+    Replay {
+        file: String,
+        target: String,
+    },
+    ExportState {
+        file: Option<String>,
+    },
+    ImportState {
+        file: Option<String>,
+    },
+    Help,
+    Version,
+}
+
+impl Command {
+    /// The command in the arguments, not including the program's name, or
+    /// `None` to start the proxy.
+    ///
+    /// Fails with why the arguments don't make a command.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Option<Self>, String> {
+        let mut args = args.into_iter();
+        let name = match args.next() {
+            Some(name) => name,
+            None => return Ok(None),
+        };
+        let args = args.collect::<Vec<_>>();
+
+        let command = match (name.as_str(), args.as_slice()) {
+            ("-h" | "--help" | "help", []) => Command::Help,
+            ("-V" | "--version", []) => Command::Version,
+            ("check" | "--check-config", []) => Command::Check,
+            ("replay", [file, target]) => Command::Replay {
+                file: file.clone(),
+                target: target.clone(),
+            },
+            ("export-state", [] | [_]) => Command::ExportState {
+                file: args.first().cloned(),
+            },
+            ("import-state", [] | [_]) => Command::ImportState {
+                file: args.first().cloned(),
+            },
+            ("-h" | "--help" | "help" | "-V" | "--version" | "check" | "--check-config", _) => {
+                return Err(format!("{} takes no arguments", name))
+            }
+            ("replay", _) => return Err("replay takes a HAR file and a target URL".to_owned()),
+            ("export-state" | "import-state", _) => {
+                return Err(format!("{} takes at most a file", name))
+            }
+            _ => return Err(format!("Unknown command {}", name)),
+        };
+
+        Ok(Some(command))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Command;
+
+    fn parse(args: &[&str]) -> Result<Option<Command>, String> {
+        Command::parse(args.iter().map(|arg| (*arg).to_owned()))
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(parse(&[]), Ok(None));
+        assert_eq!(parse(&["--help"]), Ok(Some(Command::Help)));
+        assert_eq!(parse(&["-V"]), Ok(Some(Command::Version)));
+        assert_eq!(parse(&["--check-config"]), Ok(Some(Command::Check)));
+        assert_eq!(
+            parse(&["replay", "traffic.har", "http://localhost:3000"]),
+            Ok(Some(Command::Replay {
+                file: "traffic.har".to_owned(),
+                target: "http://localhost:3000".to_owned(),
+            }))
+        );
+        assert_eq!(
+            parse(&["export-state"]),
+            Ok(Some(Command::ExportState { file: None }))
+        );
+        assert_eq!(
+            parse(&["import-state", "state.json"]),
+            Ok(Some(Command::ImportState {
+                file: Some("state.json".to_owned())
+            }))
+        );
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        assert!(parse(&["check", "extra"]).is_err());
+        assert!(parse(&["replay", "traffic.har"]).is_err());
+        assert!(parse(&["export-state", "a.json", "b.json"]).is_err());
+        assert_eq!(parse(&["start"]), Err("Unknown command start".to_owned()));
+    }
+}
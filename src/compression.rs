@@ -0,0 +1,204 @@
+//! Compression of responses toward clients.
+//!
+//! Discord is asked for compressed responses, which are passed through to
+//! clients that accept the same encoding and decompressed for the others.
+//! Responses that come back uncompressed are compressed by the proxy itself
+//! for clients that accept it.
+
+use crate::{config::CompressionConfig, proxy::ProxyResponse};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use http::{
+    header::{HeaderMap, HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, VARY},
+    StatusCode,
+};
+use metrics::counter;
+use std::io::{Read, Write};
+use tracing::warn;
+
+/// Brotli quality, low enough to compress about as fast as gzip.
+const BROTLI_QUALITY: u32 = 4;
+const BROTLI_WINDOW: u32 = 22;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        let name = name.trim();
+
+        if name.eq_ignore_ascii_case("br") {
+            Some(Encoding::Brotli)
+        } else if name.eq_ignore_ascii_case("gzip") {
+            Some(Encoding::Gzip)
+        } else {
+            None
+        }
+    }
+}
+
+/// `Accept-Encoding` to send Discord, preferring what the client accepts so
+/// the response can be passed through as is.
+pub fn upstream_accepted(accepted: Option<Encoding>) -> HeaderValue {
+    match accepted {
+        Some(Encoding::Gzip) => HeaderValue::from_static("gzip, br;q=0.5"),
+        _ => HeaderValue::from_static("br, gzip;q=0.5"),
+    }
+}
+
+/// Take `Accept-Encoding` out of the request headers, so Discord replies
+/// uncompressed, and pick the best encoding the client accepts.
+pub fn take_accepted(headers: &mut HeaderMap) -> Option<Encoding> {
+    let accepted = headers.remove(ACCEPT_ENCODING)?;
+    let accepted = accepted.to_str().ok()?;
+    let mut best = None;
+
+    for item in accepted.split(',') {
+        let mut parameters = item.split(';');
+        let coding = parameters.next().unwrap_or_default().trim();
+        let refused = parameters.any(|parameter| {
+            parameter
+                .trim()
+                .strip_prefix("q=")
+                .and_then(|q| q.parse::<f32>().ok())
+                .is_some_and(|q| q <= 0.0)
+        });
+
+        if refused {
+            continue;
+        }
+
+        if coding.eq_ignore_ascii_case("br") {
+            best = Some(Encoding::Brotli);
+        } else if coding.eq_ignore_ascii_case("gzip") && best.is_none() {
+            best = Some(Encoding::Gzip);
+        }
+    }
+
+    best
+}
+
+/// Compress the response body if it's worth it.
+pub fn compress(
+    response: &mut ProxyResponse,
+    encoding: Option<Encoding>,
+    config: &CompressionConfig,
+) {
+    let encoding = match encoding {
+        Some(encoding) if config.enabled => encoding,
+        _ => return,
+    };
+
+    if response.body.len() < config.min_size
+        || response.headers.contains_key(CONTENT_ENCODING)
+        || response.status == StatusCode::NO_CONTENT
+        || response.status == StatusCode::NOT_MODIFIED
+    {
+        return;
+    }
+
+    let compressed = match encode(&response.body, encoding) {
+        Ok(compressed) if compressed.len() < response.body.len() => compressed,
+        _ => return,
+    };
+
+    counter!(
+        "gearbot_proxy_compression_saved_bytes",
+        (response.body.len() - compressed.len()) as u64,
+        "encoding" => encoding.name()
+    );
+
+    response.body = compressed.into();
+    response.headers.remove(CONTENT_LENGTH);
+    response
+        .headers
+        .insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.name()));
+    response
+        .headers
+        .append(VARY, HeaderValue::from_static("accept-encoding"));
+}
+
+/// Decompress a response from Discord unless the client accepts its encoding.
+///
+/// Responses in an encoding the proxy doesn't know are left alone, as
+/// Discord was never asked for those.
+pub fn decode(response: &mut ProxyResponse, accepted: Option<Encoding>) {
+    let encoding = match response
+        .headers
+        .get(CONTENT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .and_then(Encoding::from_name)
+    {
+        Some(encoding) => encoding,
+        None => return,
+    };
+
+    if accepted == Some(encoding) {
+        response
+            .headers
+            .append(VARY, HeaderValue::from_static("accept-encoding"));
+
+        return;
+    }
+
+    let decoded = match decompress(&response.body, encoding) {
+        Ok(decoded) => decoded,
+        Err(source) => {
+            warn!(
+                "Failed to decompress a {} response: {}",
+                encoding.name(),
+                source
+            );
+
+            return;
+        }
+    };
+
+    counter!(
+        "gearbot_proxy_decompressed_bytes",
+        decoded.len() as u64,
+        "encoding" => encoding.name()
+    );
+
+    response.body = decoded.into();
+    response.headers.remove(CONTENT_LENGTH);
+    response.headers.remove(CONTENT_ENCODING);
+}
+
+fn decompress(body: &[u8], encoding: Encoding) -> std::io::Result<Vec<u8>> {
+    let mut decoded = Vec::new();
+
+    match encoding {
+        Encoding::Brotli => brotli::Decompressor::new(body, 4096).read_to_end(&mut decoded)?,
+        Encoding::Gzip => GzDecoder::new(body).read_to_end(&mut decoded)?,
+    };
+
+    Ok(decoded)
+}
+
+fn encode(body: &[u8], encoding: Encoding) -> std::io::Result<Vec<u8>> {
+    match encoding {
+        Encoding::Brotli => {
+            let mut encoder =
+                brotli::CompressorWriter::new(Vec::new(), 4096, BROTLI_QUALITY, BROTLI_WINDOW);
+            encoder.write_all(body)?;
+
+            Ok(encoder.into_inner())
+        }
+        Encoding::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(body)?;
+
+            encoder.finish()
+        }
+    }
+}
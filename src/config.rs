@@ -0,0 +1,1676 @@
+use crate::{breaker, error::ClientError, gateway};
+use http::{header::HeaderName, Uri};
+use reqwest::{Certificate, Client as HttpClient, Error as ReqwestError, Proxy};
+use serde::{Serialize, Serializer};
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    env, fs, io,
+    fmt::{Display, Formatter, Result as FmtResult},
+    net::{AddrParseError, IpAddr, SocketAddr},
+    num::ParseIntError,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+use tracing_subscriber::filter::{EnvFilter, ParseError};
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(crate)))]
+pub enum ConfigError {
+    #[snafu(display("Failed to read config file {}: {}", path.display(), source))]
+    ReadingFile { path: PathBuf, source: io::Error },
+    #[snafu(display("Line {} of config file {} is not NAME=value", line, path.display()))]
+    InvalidLine { path: PathBuf, line: usize },
+    #[snafu(display("DISCORD_TOKEN or DISCORD_TOKEN_FILE must be set"))]
+    MissingToken,
+    #[snafu(display("Failed to read token file {}: {}", path.display(), source))]
+    ReadingTokenFile { path: PathBuf, source: io::Error },
+    #[snafu(display("{} is not a valid address: {}", name, source))]
+    InvalidAddress {
+        name: String,
+        source: AddrParseError,
+    },
+    #[snafu(display("{} is not a valid duration: {}", name, source))]
+    InvalidDuration {
+        name: String,
+        source: humantime::DurationError,
+    },
+    #[snafu(display("{} in {} is not route=duration", value, name))]
+    InvalidRouteDuration { name: String, value: String },
+    #[snafu(display("{} is not a valid number: {}", name, source))]
+    InvalidNumber {
+        name: String,
+        source: ParseIntError,
+    },
+    #[snafu(display("{} must be true or false", name))]
+    InvalidFlag { name: String },
+    #[snafu(display("{} is not an address or unix:<path>", value))]
+    InvalidListener { value: String },
+    #[snafu(display("HTTPS_REDIRECT_URL must be set to redirect to it"))]
+    MissingRedirectUrl,
+    #[snafu(display("SEED can only be set along with MOCK_UPSTREAM"))]
+    SeedWithoutMock,
+    #[snafu(display("{} is not an https:// URL", value))]
+    InvalidRedirectUrl { value: String },
+    #[snafu(display("{} is not an http:// or https:// URL", name))]
+    InvalidUrl { name: String },
+    #[snafu(display("{} is not a ws:// or wss:// URL", value))]
+    InvalidGatewayUrl { value: String },
+    #[snafu(display("{} is not a known metrics exporter", value))]
+    UnknownExporter { value: String },
+    #[snafu(display("{} is not a known admin role", value))]
+    UnknownRole { value: String },
+    #[snafu(display("{} is not a valid header name in {}", value, name))]
+    InvalidHeaderName { name: String, value: String },
+    #[snafu(display("{} is not a known digest format", value))]
+    UnknownDigestFormat { value: String },
+    #[snafu(display("{} is not auto, 1.1 or 2", value))]
+    UnknownHttpVersion { value: String },
+    #[snafu(display("{} is not a known hook event", value))]
+    UnknownHookEvent { value: String },
+    #[snafu(display("Hook {} can't have both a webhook and a command", name))]
+    ConflictingHookActions { name: String },
+    #[snafu(display("{} is not a valid log filter: {}", name, source))]
+    InvalidLogLevel {
+        name: String,
+        source: ParseError,
+    },
+    #[snafu(display("{} is not valid JSON: {}", name, source))]
+    InvalidJson {
+        name: String,
+        source: serde_json::Error,
+    },
+    #[snafu(display("{} is not a valid address or network in {}", value, name))]
+    InvalidNetwork { name: String, value: String },
+    #[snafu(display("{} is not a valid proxy URL: {}", name, source))]
+    InvalidProxy { name: String, source: ReqwestError },
+    #[snafu(display("Failed to read certificate file {}: {}", path.display(), source))]
+    ReadingCertificate { path: PathBuf, source: io::Error },
+    #[snafu(display("{} holds no PEM certificates", path.display()))]
+    NoCertificate { path: PathBuf },
+    #[snafu(display("{} holds an invalid certificate: {}", path.display(), source))]
+    InvalidCertificate { path: PathBuf, source: ReqwestError },
+    #[snafu(display("Failed to create the Discord client: {}", source))]
+    BuildingClient { source: ClientError },
+}
+
+/// Settings of the proxy.
+///
+/// Most can be changed while running by reloading the configuration; the
+/// Reloading section of the README lists the ones only read on startup.
+///
+/// Serializes with secrets redacted.
+#[derive(Clone, Debug, Serialize)]
+pub struct Config {
+    pub host: IpAddr,
+    pub port: u16,
+    /// Addresses to accept connections on, `HOST` and `PORT` by default.
+    #[serde(serialize_with = "display_all")]
+    pub listen: Vec<Listener>,
+    /// Only read on startup.
+    pub redirect: Option<RedirectConfig>,
+    pub trusted_proxies: TrustedProxies,
+    /// Only read on startup.
+    pub http2: Http2Config,
+    /// Only read on startup.
+    pub limits: LimitsConfig,
+    /// Only read on startup.
+    pub keep_alive: KeepAliveConfig,
+    #[serde(serialize_with = "redacted")]
+    pub token: String,
+    /// File the token was read from, watched for changes.
+    pub token_file: Option<PathBuf>,
+    /// Log filter, in the same format as `RUST_LOG`.
+    pub log_level: Option<String>,
+    pub queue_dir: Option<PathBuf>,
+    pub access_log: Option<LogFileConfig>,
+    pub audit: AuditConfig,
+    #[serde(serialize_with = "duration")]
+    pub idempotency_ttl: Duration,
+    /// Estimated queue wait past which requests asking for it are run in
+    /// the background instead.
+    #[serde(serialize_with = "duration")]
+    pub async_wait_threshold: Duration,
+    /// Body of responses to requests made in maintenance mode.
+    pub maintenance_body: Option<String>,
+    /// How long requests in flight are given to finish when shutting down.
+    #[serde(serialize_with = "duration")]
+    pub shutdown_timeout: Duration,
+    pub retry: RetryConfig,
+    /// How long a `GET` can go unanswered before it's sent a second time, if
+    /// ever.
+    #[serde(serialize_with = "optional_duration")]
+    pub hedge_after: Option<Duration>,
+    /// How long Discord can take to answer before the request is logged as
+    /// slow, if ever.
+    #[serde(serialize_with = "optional_duration")]
+    pub slow_request_threshold: Option<Duration>,
+    pub breaker: BreakerConfig,
+    pub readiness: ReadinessConfig,
+    pub unknown_routes: UnknownRoutesConfig,
+    pub shedding: SheddingConfig,
+    pub slow_routes: SlowRoutesConfig,
+    pub header_log: HeaderLogConfig,
+    pub cache: CacheConfig,
+    pub compression: CompressionConfig,
+    /// Whether `traceparent` and `tracestate` are forwarded to Discord.
+    pub trace_upstream: bool,
+    /// Whether every request is treated as a dry run, never reaching Discord.
+    pub dry_run: bool,
+    /// Whether Discord is asked if it accepts the token before the listeners
+    /// are bound. Only read on startup.
+    pub verify_token: bool,
+    pub mirror: MirrorConfig,
+    pub mock: MockConfig,
+    pub emulated_ratelimits: EmulatedRatelimitsConfig,
+    pub gateway: GatewayConfig,
+    /// Only read on startup.
+    pub cdn: CdnConfig,
+    /// Client headers never forwarded to Discord.
+    #[serde(serialize_with = "display_all")]
+    pub strip_headers: Vec<HeaderName>,
+    /// Which client headers are forwarded to Discord, by route family.
+    pub forward_headers: BTreeMap<String, ForwardPolicy>,
+    pub upstream: UpstreamConfig,
+    pub metrics: MetricsConfig,
+    /// Clients identifying themselves with an API key.
+    pub tenants: Vec<Tenant>,
+    /// Tokens for the proxy's own endpoints; they're open to anyone if none
+    /// are configured.
+    pub admin_tokens: Vec<AdminToken>,
+    pub hooks: Vec<Hook>,
+    pub digest: DigestConfig,
+    /// Seed for request and job ids, making them the same on every run. Only
+    /// allowed in mock mode, and only read on startup.
+    pub seed: Option<u64>,
+    /// Where each setting that was set came from; the others have their
+    /// defaults.
+    pub sources: BTreeMap<String, SettingSource>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SettingSource {
+    File,
+    Environment,
+}
+
+/// Where connections are accepted.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Listener {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for Listener {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+
+        match s.strip_prefix("unix:") {
+            Some(path) => Ok(Listener::Unix(PathBuf::from(path))),
+            None => s
+                .parse()
+                .map(Listener::Tcp)
+                .ok()
+                .context(InvalidListener { value: s }),
+        }
+    }
+}
+
+impl Display for Listener {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Listener::Tcp(address) => write!(f, "http://{}", address),
+            Listener::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Plain HTTP listeners sending clients on to the HTTPS address of the proxy.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct RedirectConfig {
+    #[serde(serialize_with = "display_all")]
+    pub listen: Vec<SocketAddr>,
+    /// URL the paths of requests are appended to, without a trailing slash.
+    pub url: String,
+}
+
+/// Retries of idempotent requests that failed for transient reasons.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct RetryConfig {
+    /// Total attempts, including the first one.
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled for each retry after it.
+    #[serde(serialize_with = "duration")]
+    pub backoff: Duration,
+    #[serde(serialize_with = "duration")]
+    pub max_backoff: Duration,
+    /// Whether to randomize delays, so retries from many requests failing at
+    /// once don't all land at the same time.
+    pub jitter: bool,
+    /// Most retries a client can ask for on a single request.
+    pub override_limit: u32,
+}
+
+/// Circuit breakers per route family.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct BreakerConfig {
+    /// Consecutive failures that open a breaker, or 0 to disable them.
+    pub threshold: u32,
+    /// How long an open breaker fails requests before letting a trial
+    /// request through.
+    #[serde(serialize_with = "duration")]
+    pub cooldown: Duration,
+}
+
+/// When `/proxy/ready` tells Kubernetes to send requests elsewhere.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct ReadinessConfig {
+    /// How long the global ratelimit can be used up for before the proxy
+    /// isn't ready.
+    #[serde(serialize_with = "duration")]
+    pub global_ratelimit_threshold: Duration,
+}
+
+/// Requests for routes the proxy doesn't know.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct UnknownRoutesConfig {
+    /// Whether they're sent to Discord instead of failing.
+    pub passthrough: bool,
+    /// Least time between requests for the same path.
+    #[serde(serialize_with = "duration")]
+    pub interval: Duration,
+}
+
+/// HTTP/2 on the listeners, spoken by clients with prior knowledge.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+pub struct Http2Config {
+    pub enabled: bool,
+    /// Requests a client can have open on one connection.
+    pub max_streams: u32,
+    /// How often idle connections are pinged, if at all.
+    #[serde(serialize_with = "optional_duration")]
+    pub keep_alive: Option<Duration>,
+}
+
+/// Caps on what clients can have open at once across every listener.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+pub struct LimitsConfig {
+    /// Connections accepted at once, or 0 for no limit.
+    pub max_connections: usize,
+    /// Requests being handled at once, or 0 for no limit.
+    pub max_requests: usize,
+}
+
+/// How long client connections are kept open between requests.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+pub struct KeepAliveConfig {
+    /// How long a connection can go without a request before it's closed, if
+    /// ever.
+    #[serde(serialize_with = "optional_duration")]
+    pub idle_timeout: Option<Duration>,
+    /// Requests answered on an HTTP/1.1 connection before it's closed, or 0
+    /// for no limit.
+    pub max_requests: usize,
+    /// Whether `TCP_NODELAY` is set on accepted connections.
+    pub nodelay: bool,
+}
+
+/// A file lines are appended to, and when it's rotated.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct LogFileConfig {
+    pub path: PathBuf,
+    /// Size in bytes past which the log is rotated, or 0 for no limit.
+    pub max_size: u64,
+    /// How long a log is written to before it's rotated.
+    #[serde(serialize_with = "optional_duration")]
+    pub rotate_interval: Option<Duration>,
+    /// Rotated logs kept around, as `<path>.1` being the newest.
+    pub keep: usize,
+}
+
+/// Thresholds past which low priority requests are rejected.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct SheddingConfig {
+    /// Requests being handled at once, or 0 for no limit.
+    pub max_in_flight: usize,
+    /// Combined size of the bodies of requests being handled, or 0 for no
+    /// limit.
+    pub max_queued_bytes: usize,
+    /// Percentage of either limit at which low priority requests start being
+    /// rejected, more of them the closer the load gets to the limit.
+    pub start_percent: u8,
+}
+
+/// A client of the proxy, configured through `TENANT_<NAME>_*` settings.
+#[derive(Clone, Debug, Serialize)]
+pub struct Tenant {
+    /// Lowercased `<NAME>` of the settings.
+    pub name: String,
+    #[serde(serialize_with = "redacted")]
+    pub key: String,
+    /// Whether to strip whitespace from JSON bodies before forwarding them.
+    pub minify_json: bool,
+}
+
+/// What an admin token may do, each role allowing everything the ones
+/// before it do.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Look at stats, buckets and the redacted configuration.
+    Viewer,
+    /// Also look up and cancel any job, toggle maintenance mode, reload the
+    /// configuration and move state.
+    Operator,
+    /// Also swap the Discord token.
+    Admin,
+}
+
+impl FromStr for Role {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "viewer" => Ok(Role::Viewer),
+            "operator" => Ok(Role::Operator),
+            "admin" => Ok(Role::Admin),
+            other => UnknownRole { value: other }.fail(),
+        }
+    }
+}
+
+/// A token for the proxy's own endpoints, configured through
+/// `ADMIN_<NAME>_*` settings.
+#[derive(Clone, Debug, Serialize)]
+pub struct AdminToken {
+    /// Lowercased `<NAME>` of the settings.
+    pub name: String,
+    #[serde(serialize_with = "redacted")]
+    pub token: String,
+    pub role: Role,
+}
+
+/// An action run on lifecycle events, configured through `HOOK_<NAME>_*`
+/// settings.
+#[derive(Clone, Debug, Serialize)]
+pub struct Hook {
+    /// Lowercased `<NAME>` of the settings.
+    pub name: String,
+    pub events: Vec<HookEvent>,
+    pub action: HookAction,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HookEvent {
+    Startup,
+    Shutdown,
+    CircuitOpen,
+    CircuitClose,
+    TokenRotated,
+}
+
+impl HookEvent {
+    pub fn name(self) -> &'static str {
+        match self {
+            HookEvent::Startup => "startup",
+            HookEvent::Shutdown => "shutdown",
+            HookEvent::CircuitOpen => "circuit_open",
+            HookEvent::CircuitClose => "circuit_close",
+            HookEvent::TokenRotated => "token_rotated",
+        }
+    }
+}
+
+impl FromStr for HookEvent {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "startup" => Ok(HookEvent::Startup),
+            "shutdown" => Ok(HookEvent::Shutdown),
+            "circuit_open" => Ok(HookEvent::CircuitOpen),
+            "circuit_close" => Ok(HookEvent::CircuitClose),
+            "token_rotated" => Ok(HookEvent::TokenRotated),
+            other => UnknownHookEvent { value: other }.fail(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HookAction {
+    Log,
+    /// POST the event as JSON to the URL.
+    Webhook(#[serde(serialize_with = "redacted")] String),
+    /// Run the command with `sh -c`.
+    Exec(String),
+}
+
+/// Where copies of read-only requests are sent, e.g. to try a new version of
+/// the proxy on real traffic.
+#[derive(Clone, Debug, Serialize)]
+pub struct MirrorConfig {
+    /// Base URL the path of mirrored requests is appended to, like
+    /// `http://canary:8080/api/v6`. Mirrored requests are only logged if
+    /// unset.
+    #[serde(serialize_with = "without_credentials")]
+    pub url: Option<String>,
+    /// Percentage of read-only requests mirrored.
+    pub percent: u8,
+}
+
+/// Which client headers are forwarded to Discord for a family of routes.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "forward", rename_all = "snake_case")]
+pub enum ForwardPolicy {
+    /// Everything but `STRIP_HEADERS`.
+    All,
+    /// Only these headers, regardless of `STRIP_HEADERS`.
+    Only {
+        #[serde(serialize_with = "display_all")]
+        headers: Vec<HeaderName>,
+    },
+}
+
+impl ForwardPolicy {
+    /// Policy for families without one configured.
+    ///
+    /// Webhooks are executed with the token in the path, so they get nothing
+    /// but the body's type and the audit log reason, keeping stray client
+    /// headers away from them.
+    pub fn default_for(family: &str) -> Self {
+        match family {
+            "webhooks" => ForwardPolicy::Only {
+                headers: vec![
+                    HeaderName::from_static("content-type"),
+                    HeaderName::from_static("x-audit-log-reason"),
+                ],
+            },
+            _ => ForwardPolicy::All,
+        }
+    }
+}
+
+/// Answering requests from fixtures instead of Discord, for testing bots.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct MockConfig {
+    pub enabled: bool,
+    /// Directory of fixture files named after routes.
+    pub fixtures: Option<PathBuf>,
+}
+
+/// Ratelimit headers made up for dry runs and mocked responses, per bucket.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct EmulatedRatelimitsConfig {
+    /// Requests allowed per window, or `0` to not make any up.
+    pub limit: u64,
+    #[serde(serialize_with = "duration")]
+    pub window: Duration,
+    /// Percentage of requests answered with a `429` anyway.
+    pub percent: u8,
+}
+
+/// Passing gateway connections through to Discord.
+#[derive(Clone, Debug, Serialize)]
+pub struct GatewayConfig {
+    pub enabled: bool,
+    /// `ws://` or `wss://` URL of the gateway.
+    pub url: String,
+}
+
+/// Fetching files from Discord's CDN through the proxy.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CdnConfig {
+    pub enabled: bool,
+    /// Base URL of the CDN, without a trailing slash.
+    pub url: String,
+    /// Directory cached files are kept in, or `None` to keep them in memory.
+    pub cache_dir: Option<PathBuf>,
+    /// Most bytes of files kept in the cache.
+    pub cache_size: usize,
+    /// Largest file cached, in bytes.
+    pub max_object_size: usize,
+    #[serde(serialize_with = "duration")]
+    pub ttl: Duration,
+}
+
+/// Where mutating requests are recorded.
+#[derive(Clone, Debug, Serialize)]
+pub struct AuditConfig {
+    pub file: Option<LogFileConfig>,
+    /// Where to post every entry, if anywhere.
+    #[serde(serialize_with = "redacted_option")]
+    pub webhook: Option<String>,
+}
+
+/// Summary of activity posted periodically.
+#[derive(Clone, Debug, Serialize)]
+pub struct DigestConfig {
+    /// Where to post the summary; none is posted if unset.
+    #[serde(serialize_with = "redacted_option")]
+    pub webhook: Option<String>,
+    #[serde(serialize_with = "duration")]
+    pub interval: Duration,
+    pub format: DigestFormat,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DigestFormat {
+    Json,
+    Markdown,
+}
+
+impl FromStr for DigestFormat {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "json" => Ok(DigestFormat::Json),
+            "markdown" => Ok(DigestFormat::Markdown),
+            other => UnknownDigestFormat { value: other }.fail(),
+        }
+    }
+}
+
+/// Routes whose responses from Discord have all their headers logged, e.g. to
+/// work out undocumented ratelimits.
+#[derive(Clone, Debug, Serialize)]
+pub struct HeaderLogConfig {
+    /// Names of the routes, as in the `route` label of metrics.
+    pub routes: Vec<String>,
+    /// Percentage of responses on those routes logged.
+    pub percent: u8,
+}
+
+/// Caching responses to `GET` requests.
+#[derive(Clone, Debug, Serialize)]
+pub struct CacheConfig {
+    /// Names of the routes cached, as in the `route` label of metrics.
+    pub routes: Vec<String>,
+    /// Cached routes whose responses are the same whoever asks, so tenants
+    /// share them instead of each having their own.
+    pub shared_routes: Vec<String>,
+    #[serde(serialize_with = "duration")]
+    pub ttl: Duration,
+    /// How long expired responses are still served by route, while they're
+    /// fetched again in the background.
+    #[serde(serialize_with = "durations")]
+    pub stale_while_revalidate: BTreeMap<String, Duration>,
+    /// Most bytes of responses cached. Only read on startup.
+    pub size: usize,
+}
+
+impl CacheConfig {
+    pub fn contains(&self, route: &str) -> bool {
+        self.routes.iter().any(|cached| cached == route)
+    }
+
+    pub fn is_shared(&self, route: &str) -> bool {
+        self.shared_routes.iter().any(|shared| shared == route)
+    }
+
+    pub fn stale_while_revalidate(&self, route: &str) -> Duration {
+        self.stale_while_revalidate
+            .get(route)
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+/// Routes known to take long, kept apart from interactive ones.
+#[derive(Clone, Debug, Serialize)]
+pub struct SlowRoutesConfig {
+    /// Names of the routes, as in the `route` label of metrics.
+    pub routes: Vec<String>,
+    /// Slow requests sent to Discord at once. Only read on startup.
+    pub concurrency: usize,
+}
+
+impl SlowRoutesConfig {
+    pub fn contains(&self, route: &str) -> bool {
+        self.routes.iter().any(|slow| slow == route)
+    }
+}
+
+/// Compression of responses toward clients that accept it.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct CompressionConfig {
+    pub enabled: bool,
+    /// Smallest body worth compressing, in bytes.
+    pub min_size: usize,
+    /// Whether Discord is asked for compressed responses.
+    pub upstream: bool,
+}
+
+/// How Discord is reached.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct UpstreamConfig {
+    /// Base URL of the API to send requests to instead of Discord's, like a
+    /// fake Discord in tests or a canary host.
+    pub url: Option<String>,
+    /// Forward proxy to send requests through, instead of the one in
+    /// `HTTPS_PROXY`, if any.
+    #[serde(serialize_with = "without_credentials")]
+    pub proxy: Option<String>,
+    /// PEM encoded certificates to trust besides the usual roots.
+    #[serde(serialize_with = "count")]
+    pub ca_certificates: Vec<Vec<u8>>,
+    /// Age after which the connections to Discord are replaced.
+    #[serde(serialize_with = "optional_duration")]
+    pub max_connection_age: Option<Duration>,
+    /// Requests after which the connections to Discord are replaced.
+    pub max_connection_requests: Option<u64>,
+    /// Idle connections kept open per host, or all of them if unset.
+    pub pool_max_idle: Option<usize>,
+    /// How long idle connections are kept open.
+    #[serde(serialize_with = "duration")]
+    pub pool_idle_timeout: Duration,
+    /// How long connecting can take, apart from the timeout of the whole
+    /// request.
+    #[serde(serialize_with = "optional_duration")]
+    pub connect_timeout: Option<Duration>,
+    pub http_version: UpstreamHttpVersion,
+    /// How often the host is looked up again to notice it moving, if at all.
+    #[serde(serialize_with = "optional_duration")]
+    pub dns_refresh: Option<Duration>,
+    /// How long the addresses looked up for `dns_refresh` and gateway
+    /// connects are cached for. The client resolves hosts for API requests
+    /// by itself, without this cache.
+    #[serde(serialize_with = "duration")]
+    pub dns_ttl: Duration,
+    /// How long a failed lookup for `dns_refresh` or a gateway connect is
+    /// cached for, before the host is asked for again.
+    #[serde(serialize_with = "duration")]
+    pub dns_negative_ttl: Duration,
+}
+
+/// HTTP version spoken to Discord.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+pub enum UpstreamHttpVersion {
+    /// HTTP/2 if the server offers it when connecting over TLS, HTTP/1.1
+    /// otherwise.
+    #[default]
+    #[serde(rename = "auto")]
+    Auto,
+    #[serde(rename = "1.1")]
+    Http1,
+    /// HTTP/2 only, without asking the server first, also over plain HTTP.
+    #[serde(rename = "2")]
+    Http2,
+}
+
+impl FromStr for UpstreamHttpVersion {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "auto" => Ok(UpstreamHttpVersion::Auto),
+            "1.1" => Ok(UpstreamHttpVersion::Http1),
+            "2" => Ok(UpstreamHttpVersion::Http2),
+            other => UnknownHttpVersion { value: other }.fail(),
+        }
+    }
+}
+
+/// Routes treated as slow unless configured otherwise.
+const SLOW_ROUTES: &[&str] = &[
+    "Guild prune",
+    "Guild members",
+    "Guild audit logs",
+    "Guild bans",
+    "Bulk delete message",
+];
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExporterKind {
+    Prometheus,
+    Log,
+    Statsd,
+}
+
+impl FromStr for ExporterKind {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "prometheus" => Ok(ExporterKind::Prometheus),
+            "log" => Ok(ExporterKind::Log),
+            "statsd" => Ok(ExporterKind::Statsd),
+            other => UnknownExporter { value: other }.fail(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct MetricsConfig {
+    /// Exporters to run; every metric is sent to all of them.
+    pub exporters: Vec<ExporterKind>,
+    /// Where the Prometheus exporter listens, unless it's served on the
+    /// proxy's own listeners.
+    pub address: SocketAddr,
+    /// Path Prometheus metrics are served at, or any path if unset.
+    pub path: Option<String>,
+    /// Whether Prometheus metrics are served on the proxy's own listeners
+    /// instead of a separate one.
+    pub on_listener: bool,
+    /// How often the log exporter writes out metrics.
+    #[serde(serialize_with = "duration")]
+    pub log_interval: Duration,
+    /// Label sets kept per metric before new ones are folded together, `0`
+    /// for no limit.
+    pub max_series: usize,
+    /// Whether to measure how well the runtime keeps up.
+    pub runtime: bool,
+    /// Who may scrape the Prometheus exporter.
+    pub access: MetricsAccess,
+    pub statsd: StatsdConfig,
+}
+
+/// Where the StatsD exporter pushes metrics to.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct StatsdConfig {
+    /// Host of the StatsD server, resolved again on every flush so agents
+    /// can move.
+    pub host: String,
+    pub port: u16,
+    /// Prepended to every metric name, e.g. `gearbot.`.
+    pub prefix: String,
+    /// DogStatsD tags added to every metric, as `key:value`.
+    pub tags: Vec<String>,
+    /// How often metrics are pushed.
+    #[serde(serialize_with = "duration")]
+    pub interval: Duration,
+}
+
+/// Access control on the Prometheus exporter.
+///
+/// Route labels can reveal activity patterns of guilds, so the endpoint can
+/// require credentials and be limited to known addresses.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct MetricsAccess {
+    /// Token accepted as `Authorization: Bearer <token>`.
+    #[serde(serialize_with = "redacted_option")]
+    pub token: Option<String>,
+    /// Username and password accepted as basic auth.
+    #[serde(serialize_with = "redacted_option")]
+    pub basic_auth: Option<(String, String)>,
+    /// Addresses allowed to connect, or anyone if empty.
+    #[serde(serialize_with = "display_all")]
+    pub allow: Vec<Network>,
+}
+
+/// Peers whose `X-Forwarded-*` headers are believed, e.g. TLS terminating
+/// load balancers.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct TrustedProxies {
+    #[serde(serialize_with = "display_all")]
+    pub networks: Vec<Network>,
+    /// Whether connections on unix sockets are trusted.
+    pub unix: bool,
+}
+
+/// An IP address with a prefix length, e.g. `10.0.0.0/8`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Network {
+    address: IpAddr,
+    prefix: u8,
+}
+
+impl Network {
+    fn parse(raw: &str) -> Option<Self> {
+        let (address, prefix) = match raw.trim().split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (raw.trim(), None),
+        };
+        let address = address.parse::<IpAddr>().ok()?;
+        let max = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u8>().ok().filter(|prefix| *prefix <= max)?,
+            None => max,
+        };
+
+        Some(Self { address, prefix })
+    }
+
+    pub fn contains(&self, address: IpAddr) -> bool {
+        match (self.address, address) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+
+                u32::from(network) & mask == u32::from(address) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+
+                u128::from(network) & mask == u128::from(address) & mask
+            }
+            (IpAddr::V6(_), IpAddr::V4(address)) => {
+                self.contains(IpAddr::V6(address.to_ipv6_mapped()))
+            }
+            (IpAddr::V4(_), IpAddr::V6(address)) => address
+                .to_ipv4_mapped()
+                .is_some_and(|address| self.contains(IpAddr::V4(address))),
+        }
+    }
+}
+
+impl Display for Network {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "{}/{}", self.address, self.prefix)
+    }
+}
+
+impl Config {
+    /// Read the configuration from the environment and, if `CONFIG_FILE` is
+    /// set, the file it points to.
+    pub fn load() -> Result<Self, ConfigError> {
+        Self::read(Source::new(env::var_os("CONFIG_FILE").map(PathBuf::from))?)
+    }
+
+    /// Read the configuration from the settings given, as if they were in
+    /// `CONFIG_FILE`, for running the proxy embedded like in tests and
+    /// benchmarks.
+    pub fn from_settings<'a>(
+        settings: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> Result<Self, ConfigError> {
+        Self::read(Source {
+            file: settings
+                .into_iter()
+                .map(|(name, value)| (name.to_owned(), value.to_owned()))
+                .collect(),
+            sources: RefCell::default(),
+        })
+    }
+
+    fn read(source: Source) -> Result<Self, ConfigError> {
+        let token_file = source.var("DISCORD_TOKEN_FILE").map(PathBuf::from);
+        let token = match &token_file {
+            Some(path) => read_token(path)?,
+            None => source.var("DISCORD_TOKEN").context(MissingToken)?,
+        };
+
+        let host = source
+            .var("HOST")
+            .unwrap_or_else(|| "0.0.0.0".to_owned())
+            .parse()
+            .context(InvalidAddress { name: "HOST" })?;
+        let port = source.number("PORT")?.unwrap_or(80);
+        let listen = source
+            .var("LISTEN")
+            .map(|raw| raw.split(',').map(str::parse).collect())
+            .transpose()?
+            .unwrap_or_else(|| vec![Listener::Tcp(SocketAddr::from((host, port)))]);
+        let metrics_on_listener = source.flag("METRICS_ON_LISTENER")?.unwrap_or(false);
+
+        Ok(Self {
+            host,
+            port,
+            listen,
+            redirect: source.redirect()?,
+            trusted_proxies: source.trusted_proxies("TRUSTED_PROXIES")?,
+            http2: Http2Config {
+                enabled: source.flag("HTTP2")?.unwrap_or(true),
+                max_streams: source.number("HTTP2_MAX_STREAMS")?.unwrap_or(1000),
+                keep_alive: source.duration("HTTP2_KEEP_ALIVE")?,
+            },
+            limits: LimitsConfig {
+                max_connections: source.number("MAX_CONNECTIONS")?.unwrap_or(0),
+                max_requests: source.number("MAX_REQUESTS")?.unwrap_or(0),
+            },
+            keep_alive: KeepAliveConfig {
+                idle_timeout: source.duration("KEEP_ALIVE_TIMEOUT")?,
+                max_requests: source.number("KEEP_ALIVE_MAX_REQUESTS")?.unwrap_or(0),
+                nodelay: source.flag("TCP_NODELAY")?.unwrap_or(false),
+            },
+            token,
+            token_file,
+            log_level: source.log_level("LOG_LEVEL")?,
+            queue_dir: source.var("QUEUE_DIR").map(PathBuf::from),
+            access_log: source.log_file("ACCESS_LOG")?,
+            audit: AuditConfig {
+                file: source.log_file("AUDIT_LOG")?,
+                webhook: source.http_url("AUDIT_WEBHOOK")?,
+            },
+            idempotency_ttl: source
+                .duration("IDEMPOTENCY_TTL")?
+                .unwrap_or_else(|| Duration::from_secs(10 * 60)),
+            async_wait_threshold: source
+                .duration("ASYNC_WAIT_THRESHOLD")?
+                .unwrap_or_else(|| Duration::from_secs(10)),
+            maintenance_body: source.json("MAINTENANCE_BODY")?,
+            shutdown_timeout: source
+                .duration("SHUTDOWN_TIMEOUT")?
+                .unwrap_or_else(|| Duration::from_secs(30)),
+            retry: RetryConfig {
+                max_attempts: source.number("RETRY_MAX_ATTEMPTS")?.unwrap_or(3),
+                backoff: source
+                    .duration("RETRY_BACKOFF")?
+                    .unwrap_or_else(|| Duration::from_millis(100)),
+                max_backoff: source
+                    .duration("RETRY_MAX_BACKOFF")?
+                    .unwrap_or_else(|| Duration::from_secs(2)),
+                jitter: source.flag("RETRY_JITTER")?.unwrap_or(true),
+                override_limit: source.number("RETRY_OVERRIDE_LIMIT")?.unwrap_or(5),
+            },
+            hedge_after: source.duration("HEDGE_AFTER")?,
+            slow_request_threshold: source.duration("SLOW_REQUEST_THRESHOLD")?,
+            breaker: BreakerConfig {
+                threshold: source.number("BREAKER_THRESHOLD")?.unwrap_or(5),
+                cooldown: source
+                    .duration("BREAKER_COOLDOWN")?
+                    .unwrap_or_else(|| Duration::from_secs(30)),
+            },
+            unknown_routes: UnknownRoutesConfig {
+                passthrough: source.flag("PASSTHROUGH_UNKNOWN_ROUTES")?.unwrap_or(false),
+                interval: source
+                    .duration("UNKNOWN_ROUTES_INTERVAL")?
+                    .unwrap_or_else(|| Duration::from_secs(1)),
+            },
+            readiness: ReadinessConfig {
+                global_ratelimit_threshold: source
+                    .duration("READINESS_GLOBAL_RATELIMIT_THRESHOLD")?
+                    .unwrap_or_else(|| Duration::from_secs(10)),
+            },
+            shedding: SheddingConfig {
+                max_in_flight: source.number("SHED_MAX_IN_FLIGHT")?.unwrap_or(10_000),
+                max_queued_bytes: source
+                    .number("SHED_MAX_QUEUED_BYTES")?
+                    .unwrap_or(256 * 1024 * 1024),
+                start_percent: source
+                    .number::<u8>("SHED_START_PERCENT")?
+                    .unwrap_or(80)
+                    .min(100),
+            },
+            header_log: HeaderLogConfig {
+                routes: source
+                    .var("LOG_HEADERS_ROUTES")
+                    .map(|raw| {
+                        raw.split(',')
+                            .map(|route| route.trim().to_owned())
+                            .filter(|route| !route.is_empty())
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+                percent: source.number::<u8>("LOG_HEADERS_PERCENT")?.unwrap_or(10).min(100),
+            },
+            cache: CacheConfig {
+                routes: source
+                    .var("CACHE_ROUTES")
+                    .map(|raw| {
+                        raw.split(',')
+                            .map(|route| route.trim().to_owned())
+                            .filter(|route| !route.is_empty())
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+                shared_routes: source
+                    .var("CACHE_SHARED_ROUTES")
+                    .map(|raw| {
+                        raw.split(',')
+                            .map(|route| route.trim().to_owned())
+                            .filter(|route| !route.is_empty())
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+                ttl: source
+                    .duration("CACHE_TTL")?
+                    .unwrap_or_else(|| Duration::from_secs(10)),
+                stale_while_revalidate: source
+                    .route_durations("CACHE_STALE_WHILE_REVALIDATE")?,
+                size: source.number("CACHE_SIZE")?.unwrap_or(64 * 1024 * 1024),
+            },
+            slow_routes: SlowRoutesConfig {
+                routes: source
+                    .var("SLOW_ROUTES")
+                    .map(|raw| {
+                        raw.split(',')
+                            .map(|route| route.trim().to_owned())
+                            .collect()
+                    })
+                    .unwrap_or_else(|| {
+                        SLOW_ROUTES
+                            .iter()
+                            .map(|route| (*route).to_owned())
+                            .collect()
+                    }),
+                concurrency: source.number("SLOW_ROUTE_CONCURRENCY")?.unwrap_or(4),
+            },
+            compression: CompressionConfig {
+                enabled: source.flag("COMPRESSION")?.unwrap_or(true),
+                min_size: source.number("COMPRESSION_MIN_SIZE")?.unwrap_or(1024),
+                upstream: source.flag("UPSTREAM_COMPRESSION")?.unwrap_or(true),
+            },
+            trace_upstream: source.flag("TRACE_UPSTREAM")?.unwrap_or(true),
+            dry_run: source.flag("DRY_RUN")?.unwrap_or(false),
+            verify_token: source.flag("VERIFY_TOKEN")?.unwrap_or(true),
+            mirror: MirrorConfig {
+                url: source
+                    .http_url("MIRROR_URL")?
+                    .map(|url| url.trim_end_matches('/').to_owned()),
+                percent: source.number::<u8>("MIRROR_PERCENT")?.unwrap_or(0).min(100),
+            },
+            mock: MockConfig {
+                enabled: source.flag("MOCK_UPSTREAM")?.unwrap_or(false),
+                fixtures: source.var("MOCK_FIXTURES").map(PathBuf::from),
+            },
+            emulated_ratelimits: EmulatedRatelimitsConfig {
+                limit: source.number("EMULATED_RATELIMIT_LIMIT")?.unwrap_or(5),
+                window: source
+                    .duration("EMULATED_RATELIMIT_WINDOW")?
+                    .unwrap_or_else(|| Duration::from_secs(5)),
+                percent: source
+                    .number::<u8>("EMULATED_RATELIMIT_PERCENT")?
+                    .unwrap_or(0)
+                    .min(100),
+            },
+            gateway: source.gateway()?,
+            cdn: CdnConfig {
+                enabled: source.flag("CDN_PROXY")?.unwrap_or(false),
+                url: source
+                    .http_url("CDN_URL")?
+                    .map_or_else(
+                        || "https://cdn.discordapp.com".to_owned(),
+                        |url| url.trim_end_matches('/').to_owned(),
+                    ),
+                cache_dir: source.var("CDN_CACHE_DIR").map(PathBuf::from),
+                cache_size: source
+                    .number("CDN_CACHE_SIZE")?
+                    .unwrap_or(256 * 1024 * 1024),
+                max_object_size: source
+                    .number("CDN_CACHE_MAX_OBJECT_SIZE")?
+                    .unwrap_or(8 * 1024 * 1024),
+                ttl: source
+                    .duration("CDN_CACHE_TTL")?
+                    .unwrap_or(Duration::from_secs(60 * 60)),
+            },
+            strip_headers: source.header_names("STRIP_HEADERS")?,
+            forward_headers: source.forward_headers()?,
+            upstream: UpstreamConfig {
+                url: source
+                    .http_url("UPSTREAM_URL")?
+                    .map(|url| url.trim_end_matches('/').to_owned()),
+                proxy: source.proxy("UPSTREAM_PROXY")?,
+                ca_certificates: source.certificates("UPSTREAM_CA_FILES")?,
+                max_connection_age: source.duration("UPSTREAM_MAX_CONNECTION_AGE")?,
+                max_connection_requests: source.number("UPSTREAM_MAX_CONNECTION_REQUESTS")?,
+                pool_max_idle: source.number("UPSTREAM_POOL_MAX_IDLE")?,
+                pool_idle_timeout: source
+                    .duration("UPSTREAM_POOL_IDLE_TIMEOUT")?
+                    .unwrap_or_else(|| Duration::from_secs(90)),
+                connect_timeout: source.duration("UPSTREAM_CONNECT_TIMEOUT")?,
+                http_version: source
+                    .var("UPSTREAM_HTTP_VERSION")
+                    .map(|raw| raw.parse())
+                    .transpose()?
+                    .unwrap_or_default(),
+                dns_refresh: source.duration("UPSTREAM_DNS_REFRESH")?,
+                dns_ttl: source
+                    .duration("UPSTREAM_DNS_TTL")?
+                    .unwrap_or_else(|| Duration::from_secs(30)),
+                dns_negative_ttl: source
+                    .duration("UPSTREAM_DNS_NEGATIVE_TTL")?
+                    .unwrap_or_else(|| Duration::from_secs(5)),
+            },
+            metrics: MetricsConfig {
+                exporters: source
+                    .var("METRICS_EXPORTERS")
+                    .map(|raw| raw.split(',').map(str::parse).collect())
+                    .transpose()?
+                    .unwrap_or_else(|| vec![ExporterKind::Prometheus]),
+                address: source
+                    .var("METRICS_ADDRESS")
+                    .map(|raw| raw.trim().parse())
+                    .transpose()
+                    .context(InvalidAddress {
+                        name: "METRICS_ADDRESS",
+                    })?
+                    .unwrap_or_else(|| SocketAddr::from((host, port.saturating_add(1)))),
+                path: match source.var("METRICS_PATH") {
+                    Some(path) if path.starts_with('/') => Some(path),
+                    Some(path) => Some(format!("/{}", path)),
+                    None if metrics_on_listener => Some("/metrics".to_owned()),
+                    None => None,
+                },
+                on_listener: metrics_on_listener,
+                log_interval: source
+                    .duration("METRICS_LOG_INTERVAL")?
+                    .unwrap_or_else(|| Duration::from_secs(60)),
+                max_series: source.number("METRICS_MAX_SERIES")?.unwrap_or(1000),
+                runtime: source.flag("METRICS_RUNTIME")?.unwrap_or(false),
+                access: MetricsAccess {
+                    token: source.var("METRICS_TOKEN"),
+                    basic_auth: source
+                        .var("METRICS_USERNAME")
+                        .zip(source.var("METRICS_PASSWORD")),
+                    allow: source.networks("METRICS_ALLOW")?,
+                },
+                statsd: StatsdConfig {
+                    host: source
+                        .var("STATSD_HOST")
+                        .unwrap_or_else(|| "127.0.0.1".to_owned()),
+                    port: source.number("STATSD_PORT")?.unwrap_or(8125),
+                    prefix: source.var("STATSD_PREFIX").unwrap_or_default(),
+                    tags: source
+                        .var("STATSD_TAGS")
+                        .map(|raw| {
+                            raw.split(',')
+                                .map(str::trim)
+                                .filter(|tag| !tag.is_empty())
+                                .map(str::to_owned)
+                                .collect()
+                        })
+                        .unwrap_or_default(),
+                    interval: source
+                        .duration("STATSD_INTERVAL")?
+                        .unwrap_or_else(|| Duration::from_secs(10)),
+                },
+            },
+            tenants: source.tenants()?,
+            admin_tokens: source.admin_tokens()?,
+            hooks: source.hooks()?,
+            digest: DigestConfig {
+                webhook: source.http_url("DIGEST_WEBHOOK")?,
+                interval: source
+                    .duration("DIGEST_INTERVAL")?
+                    .unwrap_or_else(|| Duration::from_secs(24 * 60 * 60)),
+                format: source
+                    .var("DIGEST_FORMAT")
+                    .map(|raw| raw.parse())
+                    .transpose()?
+                    .unwrap_or(DigestFormat::Json),
+            },
+            seed: source.seed()?,
+            sources: source.sources.into_inner(),
+        })
+    }
+}
+
+/// Read a token from a file, such as a mounted Docker or Kubernetes secret.
+pub fn read_token(path: &Path) -> Result<String, ConfigError> {
+    let token = fs::read_to_string(path).context(ReadingTokenFile { path })?;
+    let token = token.trim();
+
+    if token.is_empty() {
+        return MissingToken.fail();
+    }
+
+    Ok(token.to_owned())
+}
+
+/// Where settings are read from: the config file if there is one, falling
+/// back to the environment.
+///
+/// The config file holds a `NAME=value` line per setting, using the same
+/// names as the environment variables. Empty lines and lines starting with `#`
+/// are ignored.
+struct Source {
+    file: HashMap<String, String>,
+    /// Where each setting read so far came from.
+    sources: RefCell<BTreeMap<String, SettingSource>>,
+}
+
+impl Source {
+    fn new(path: Option<PathBuf>) -> Result<Self, ConfigError> {
+        let path = match path {
+            Some(path) => path,
+            None => {
+                return Ok(Self {
+                    file: HashMap::new(),
+                    sources: RefCell::default(),
+                })
+            }
+        };
+        let contents = fs::read_to_string(&path).context(ReadingFile { path: &path })?;
+        let mut file = HashMap::new();
+
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (name, value) = line.split_once('=').context(InvalidLine {
+                path: &path,
+                line: index + 1,
+            })?;
+            file.insert(name.trim().to_owned(), value.trim().to_owned());
+        }
+
+        Ok(Self {
+            file,
+            sources: RefCell::default(),
+        })
+    }
+
+    /// Names of all settings, whether from the file or the environment.
+    fn names(&self) -> BTreeSet<String> {
+        self.file
+            .keys()
+            .cloned()
+            .chain(env::vars().map(|(name, _)| name))
+            .collect()
+    }
+
+    fn tenants(&self) -> Result<Vec<Tenant>, ConfigError> {
+        self.names()
+            .iter()
+            .filter_map(|name| name.strip_prefix("TENANT_")?.strip_suffix("_KEY"))
+            .filter_map(|tenant| {
+                let key = self.var(&format!("TENANT_{}_KEY", tenant))?;
+                let minify_json = self.flag(&format!("TENANT_{}_MINIFY_JSON", tenant));
+
+                Some(minify_json.map(|minify_json| Tenant {
+                    name: tenant.to_ascii_lowercase(),
+                    key,
+                    minify_json: minify_json.unwrap_or(false),
+                }))
+            })
+            .collect()
+    }
+
+    fn admin_tokens(&self) -> Result<Vec<AdminToken>, ConfigError> {
+        self.names()
+            .iter()
+            .filter_map(|name| name.strip_prefix("ADMIN_")?.strip_suffix("_TOKEN"))
+            .filter_map(|admin| {
+                let token = self.var(&format!("ADMIN_{}_TOKEN", admin))?;
+                let role = self
+                    .var(&format!("ADMIN_{}_ROLE", admin))
+                    .map(|raw| raw.parse())
+                    .transpose();
+
+                Some(role.map(|role| AdminToken {
+                    name: admin.to_ascii_lowercase(),
+                    token,
+                    role: role.unwrap_or(Role::Viewer),
+                }))
+            })
+            .collect()
+    }
+
+    fn hooks(&self) -> Result<Vec<Hook>, ConfigError> {
+        self.names()
+            .iter()
+            .filter_map(|name| name.strip_prefix("HOOK_")?.strip_suffix("_EVENTS"))
+            .filter_map(|hook| {
+                let events = self.var(&format!("HOOK_{}_EVENTS", hook))?;
+
+                Some(self.hook(hook, &events))
+            })
+            .collect()
+    }
+
+    fn hook(&self, hook: &str, events: &str) -> Result<Hook, ConfigError> {
+        let webhook = self.http_url(&format!("HOOK_{}_WEBHOOK", hook))?;
+        let exec = self.var(&format!("HOOK_{}_EXEC", hook));
+        let action = match (webhook, exec) {
+            (Some(_), Some(_)) => return ConflictingHookActions { name: hook }.fail(),
+            (Some(url), None) => HookAction::Webhook(url),
+            (None, Some(command)) => HookAction::Exec(command),
+            (None, None) => HookAction::Log,
+        };
+
+        Ok(Hook {
+            name: hook.to_ascii_lowercase(),
+            events: events.split(',').map(str::parse).collect::<Result<_, _>>()?,
+            action,
+        })
+    }
+
+    fn redirect(&self) -> Result<Option<RedirectConfig>, ConfigError> {
+        let listen = match self.var("HTTPS_REDIRECT_LISTEN") {
+            Some(listen) => listen,
+            None => return Ok(None),
+        };
+        let url = self.var("HTTPS_REDIRECT_URL").context(MissingRedirectUrl)?;
+        let valid = url.parse::<Uri>().ok().is_some_and(|uri| {
+            uri.scheme_str() == Some("https") && uri.authority().is_some()
+        });
+        ensure!(valid, InvalidRedirectUrl { value: &url });
+
+        Ok(Some(RedirectConfig {
+            listen: listen
+                .split(',')
+                .map(|address| {
+                    address.trim().parse().context(InvalidAddress {
+                        name: "HTTPS_REDIRECT_LISTEN",
+                    })
+                })
+                .collect::<Result<_, _>>()?,
+            url: url.trim_end_matches('/').to_owned(),
+        }))
+    }
+
+    fn gateway(&self) -> Result<GatewayConfig, ConfigError> {
+        let url = self
+            .var("GATEWAY_URL")
+            .unwrap_or_else(|| "wss://gateway.discord.gg".to_owned());
+        ensure!(
+            gateway::Target::parse(&url).is_some(),
+            InvalidGatewayUrl { value: url }
+        );
+
+        Ok(GatewayConfig {
+            enabled: self.flag("GATEWAY_PROXY")?.unwrap_or(false),
+            url,
+        })
+    }
+
+    fn var(&self, name: &str) -> Option<String> {
+        let (value, source) = match self.file.get(name) {
+            Some(value) => (value.clone(), SettingSource::File),
+            None => (env::var(name).ok()?, SettingSource::Environment),
+        };
+
+        if value.is_empty() {
+            return None;
+        }
+
+        self.sources.borrow_mut().insert(name.to_owned(), source);
+
+        Some(value)
+    }
+
+    fn number<T: FromStr<Err = ParseIntError>>(
+        &self,
+        name: &str,
+    ) -> Result<Option<T>, ConfigError> {
+        self.var(name)
+            .map(|raw| raw.parse().context(InvalidNumber { name }))
+            .transpose()
+    }
+
+    /// A file written to through `<name>`, rotated as the `<name>_*`
+    /// settings say.
+    fn log_file(&self, name: &str) -> Result<Option<LogFileConfig>, ConfigError> {
+        let path = match self.var(name) {
+            Some(path) => PathBuf::from(path),
+            None => return Ok(None),
+        };
+
+        Ok(Some(LogFileConfig {
+            path,
+            max_size: self
+                .number(&format!("{}_MAX_SIZE", name))?
+                .unwrap_or(100 * 1024 * 1024),
+            rotate_interval: self.duration(&format!("{}_ROTATE_INTERVAL", name))?,
+            keep: self.number(&format!("{}_KEEP", name))?.unwrap_or(5),
+        }))
+    }
+
+    /// An `http://` or `https://` URL. The value is left out of errors, as
+    /// webhook URLs hold their token.
+    fn http_url(&self, name: &str) -> Result<Option<String>, ConfigError> {
+        let url = match self.var(name) {
+            Some(url) => url,
+            None => return Ok(None),
+        };
+        let valid = url
+            .parse::<Uri>()
+            .ok()
+            .filter(|uri| uri.host().is_some())
+            .is_some_and(|uri| matches!(uri.scheme_str(), Some("http") | Some("https")));
+        ensure!(valid, InvalidUrl { name });
+
+        Ok(Some(url))
+    }
+
+    /// Durations by route name, written like `Channel=30s,Guild=1m`.
+    fn route_durations(&self, name: &str) -> Result<BTreeMap<String, Duration>, ConfigError> {
+        self.var(name)
+            .iter()
+            .flat_map(|raw| raw.split(','))
+            .filter(|pair| !pair.trim().is_empty())
+            .map(|pair| {
+                let (route, duration) = pair.split_once('=').context(InvalidRouteDuration {
+                    name,
+                    value: pair.trim(),
+                })?;
+                let duration = humantime::parse_duration(duration.trim())
+                    .context(InvalidDuration { name })?;
+
+                Ok((route.trim().to_owned(), duration))
+            })
+            .collect()
+    }
+
+    fn duration(&self, name: &str) -> Result<Option<Duration>, ConfigError> {
+        self.var(name)
+            .map(|raw| humantime::parse_duration(&raw).context(InvalidDuration { name }))
+            .transpose()
+    }
+
+    fn networks(&self, name: &str) -> Result<Vec<Network>, ConfigError> {
+        self.var(name)
+            .map(|raw| {
+                raw.split(',')
+                    .map(|value| {
+                        Network::parse(value).context(InvalidNetwork {
+                            name,
+                            value: value.trim(),
+                        })
+                    })
+                    .collect()
+            })
+            .transpose()
+            .map(Option::unwrap_or_default)
+    }
+
+    /// Networks like `networks`, plus `unix` for unix sockets.
+    fn trusted_proxies(&self, name: &str) -> Result<TrustedProxies, ConfigError> {
+        let mut trusted = TrustedProxies::default();
+
+        for value in self.var(name).iter().flat_map(|raw| raw.split(',')) {
+            if value.trim() == "unix" {
+                trusted.unix = true;
+            } else {
+                trusted.networks.push(Network::parse(value).context(InvalidNetwork {
+                    name,
+                    value: value.trim(),
+                })?);
+            }
+        }
+
+        Ok(trusted)
+    }
+
+    /// Seed for ids, which is only taken in mock mode: seeded ids are the
+    /// same on every run, so a client could guess the job ids of others.
+    fn seed(&self) -> Result<Option<u64>, ConfigError> {
+        let seed = self.number("SEED")?;
+
+        if seed.is_some() && !self.flag("MOCK_UPSTREAM")?.unwrap_or(false) {
+            return SeedWithoutMock.fail();
+        }
+
+        Ok(seed)
+    }
+
+    fn forward_headers(&self) -> Result<BTreeMap<String, ForwardPolicy>, ConfigError> {
+        breaker::FAMILIES
+            .iter()
+            .map(|family| {
+                let name = format!("FORWARD_HEADERS_{}", family.to_ascii_uppercase());
+                let policy = match self.var(&name).as_deref().map(str::trim) {
+                    Some("*") => ForwardPolicy::All,
+                    Some(_) => ForwardPolicy::Only {
+                        headers: self.header_names(&name)?,
+                    },
+                    None => ForwardPolicy::default_for(family),
+                };
+
+                Ok(((*family).to_owned(), policy))
+            })
+            .collect()
+    }
+
+    fn header_names(&self, name: &str) -> Result<Vec<HeaderName>, ConfigError> {
+        self.var(name)
+            .map(|raw| {
+                raw.split(',')
+                    .map(|value| {
+                        value.trim().parse().ok().context(InvalidHeaderName {
+                            name,
+                            value: value.trim(),
+                        })
+                    })
+                    .collect()
+            })
+            .transpose()
+            .map(Option::unwrap_or_default)
+    }
+
+    fn proxy(&self, name: &str) -> Result<Option<String>, ConfigError> {
+        self.var(name)
+            .map(|raw| {
+                Proxy::all(&raw).context(InvalidProxy { name })?;
+
+                Ok(raw)
+            })
+            .transpose()
+    }
+
+    /// Read the PEM files listed in the variable, which may hold several
+    /// certificates each.
+    fn certificates(&self, name: &str) -> Result<Vec<Vec<u8>>, ConfigError> {
+        self.var(name)
+            .map(|raw| {
+                raw.split(',')
+                    .map(|path| {
+                        let path = PathBuf::from(path.trim());
+                        let pem =
+                            fs::read_to_string(&path).context(ReadingCertificate { path: &path })?;
+                        ensure!(
+                            pem.contains("-----BEGIN CERTIFICATE-----"),
+                            NoCertificate { path: &path }
+                        );
+
+                        // Certificates are only parsed once they're added to
+                        // a client.
+                        Certificate::from_pem(pem.as_bytes())
+                            .and_then(|certificate| {
+                                HttpClient::builder()
+                                    .add_root_certificate(certificate)
+                                    .build()
+                            })
+                            .context(InvalidCertificate { path })?;
+
+                        Ok(pem.into_bytes())
+                    })
+                    .collect()
+            })
+            .transpose()
+            .map(Option::unwrap_or_default)
+    }
+
+    fn log_level(&self, name: &str) -> Result<Option<String>, ConfigError> {
+        self.var(name)
+            .map(|raw| {
+                EnvFilter::try_new(&raw).context(InvalidLogLevel { name })?;
+
+                Ok(raw)
+            })
+            .transpose()
+    }
+
+    fn json(&self, name: &str) -> Result<Option<String>, ConfigError> {
+        self.var(name)
+            .map(|raw| {
+                serde_json::from_str::<serde_json::Value>(&raw).context(InvalidJson { name })?;
+
+                Ok(raw)
+            })
+            .transpose()
+    }
+
+    fn flag(&self, name: &str) -> Result<Option<bool>, ConfigError> {
+        self.var(name)
+            .map(|raw| match raw.to_ascii_lowercase().as_str() {
+                "true" | "1" | "yes" => Ok(true),
+                "false" | "0" | "no" => Ok(false),
+                _ => InvalidFlag { name }.fail(),
+            })
+            .transpose()
+    }
+}
+
+/// Stands in for secrets when the configuration is serialized.
+const REDACTED: &str = "<redacted>";
+
+fn redacted<T, S: Serializer>(_: &T, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(REDACTED)
+}
+
+fn redacted_option<T, S: Serializer>(value: &Option<T>, serializer: S) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(_) => serializer.serialize_str(REDACTED),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// The URL with the username and password in it, if any, redacted.
+fn without_credentials<S: Serializer>(
+    url: &Option<String>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let url = match url {
+        Some(url) => url,
+        None => return serializer.serialize_none(),
+    };
+
+    match url.split_once("://") {
+        Some((scheme, rest)) => match rest.split_once('@') {
+            Some((_, host)) => {
+                serializer.collect_str(&format_args!("{}://{}@{}", scheme, REDACTED, host))
+            }
+            None => serializer.serialize_str(url),
+        },
+        None => serializer.serialize_str(url),
+    }
+}
+
+fn duration<S: Serializer>(value: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(&humantime::format_duration(*value))
+}
+
+fn optional_duration<S: Serializer>(
+    value: &Option<Duration>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(value) => duration(value, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn durations<S: Serializer>(
+    values: &BTreeMap<String, Duration>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(
+        values
+            .iter()
+            .map(|(route, value)| (route, humantime::format_duration(*value).to_string())),
+    )
+}
+
+fn display_all<T: Display, S: Serializer>(values: &[T], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(values.iter().map(ToString::to_string))
+}
+
+fn count<T, S: Serializer>(values: &[T], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(values.len() as u64)
+}
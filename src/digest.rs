@@ -0,0 +1,252 @@
+//! Periodic summary of what went through the proxy, posted to a webhook for
+//! teams that want a heartbeat without dashboards.
+
+use crate::{
+    config::{DigestConfig, DigestFormat},
+    State,
+};
+use http::{header::CONTENT_TYPE, StatusCode};
+use serde::Serialize;
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    mem,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::time;
+use tracing::{info, warn};
+
+/// Routes listed in a summary.
+const TOP_ROUTES: usize = 10;
+/// Incidents kept per period, so a flapping breaker can't grow it forever.
+const MAX_INCIDENTS: usize = 100;
+/// Incidents listed in the markdown version, which chat webhooks limit in
+/// length.
+const MARKDOWN_INCIDENTS: usize = 10;
+
+#[derive(Debug)]
+struct Period {
+    since: SystemTime,
+    requests: u64,
+    errors: u64,
+    rate_limited: u64,
+    routes: HashMap<&'static str, u64>,
+    incidents: Vec<(SystemTime, String)>,
+}
+
+impl Period {
+    fn new() -> Self {
+        Self {
+            since: SystemTime::now(),
+            requests: 0,
+            errors: 0,
+            rate_limited: 0,
+            routes: HashMap::new(),
+            incidents: Vec::new(),
+        }
+    }
+}
+
+/// Counts for the current period.
+#[derive(Debug)]
+pub struct Digest {
+    period: Mutex<Period>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Summary {
+    /// Start and end of the period, in seconds since the epoch.
+    since: u64,
+    until: u64,
+    requests: u64,
+    /// Requests that failed or got a server error.
+    errors: u64,
+    /// Requests Discord answered with `429 Too Many Requests`.
+    rate_limited: u64,
+    top_routes: Vec<RouteCount>,
+    incidents: Vec<Incident>,
+}
+
+#[derive(Debug, Serialize)]
+struct RouteCount {
+    route: &'static str,
+    requests: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct Incident {
+    at: u64,
+    description: String,
+}
+
+impl Digest {
+    pub fn new() -> Self {
+        Self {
+            period: Mutex::new(Period::new()),
+        }
+    }
+
+    /// Count a finished request, with the status Discord replied with if it
+    /// replied at all.
+    pub fn record(&self, route: &'static str, status: Option<StatusCode>) {
+        let mut period = self.period.lock().expect("digest poisoned");
+        period.requests += 1;
+        *period.routes.entry(route).or_default() += 1;
+
+        match status {
+            Some(StatusCode::TOO_MANY_REQUESTS) => period.rate_limited += 1,
+            Some(status) if status.is_server_error() => period.errors += 1,
+            Some(_) => {}
+            None => period.errors += 1,
+        }
+    }
+
+    /// Note something worth a mention in the summary.
+    pub fn incident(&self, description: String) {
+        let mut period = self.period.lock().expect("digest poisoned");
+
+        if period.incidents.len() < MAX_INCIDENTS {
+            period.incidents.push((SystemTime::now(), description));
+        }
+    }
+
+    /// Summary of the current period so far.
+    pub fn summary(&self) -> Summary {
+        summarize(&self.period.lock().expect("digest poisoned"))
+    }
+
+    /// Summary of the current period, starting a new one.
+    fn finish(&self) -> Summary {
+        let mut period = self.period.lock().expect("digest poisoned");
+
+        summarize(&mem::replace(&mut *period, Period::new()))
+    }
+}
+
+fn summarize(period: &Period) -> Summary {
+    let mut top_routes = period
+        .routes
+        .iter()
+        .map(|(route, requests)| RouteCount {
+            route,
+            requests: *requests,
+        })
+        .collect::<Vec<_>>();
+    top_routes.sort_by(|a, b| b.requests.cmp(&a.requests).then(a.route.cmp(b.route)));
+    top_routes.truncate(TOP_ROUTES);
+
+    Summary {
+        since: seconds(period.since),
+        until: seconds(SystemTime::now()),
+        requests: period.requests,
+        errors: period.errors,
+        rate_limited: period.rate_limited,
+        top_routes,
+        incidents: period
+            .incidents
+            .iter()
+            .map(|(at, description)| Incident {
+                at: seconds(*at),
+                description: description.clone(),
+            })
+            .collect(),
+    }
+}
+
+impl Summary {
+    pub fn to_markdown(&self) -> String {
+        let mut text = String::new();
+        let _ = writeln!(
+            text,
+            "**Proxy digest** for {} to {}",
+            timestamp(self.since),
+            timestamp(self.until)
+        );
+        let _ = writeln!(
+            text,
+            "{} requests, {} errors, {} ratelimited",
+            self.requests, self.errors, self.rate_limited
+        );
+
+        if !self.top_routes.is_empty() {
+            let _ = writeln!(text, "\n**Top routes**");
+
+            for route in &self.top_routes {
+                let _ = writeln!(text, "- {}: {}", route.route, route.requests);
+            }
+        }
+
+        if !self.incidents.is_empty() {
+            let _ = writeln!(text, "\n**Incidents**");
+
+            for incident in self.incidents.iter().take(MARKDOWN_INCIDENTS) {
+                let _ = writeln!(
+                    text,
+                    "- {} {}",
+                    timestamp(incident.at),
+                    incident.description
+                );
+            }
+
+            if self.incidents.len() > MARKDOWN_INCIDENTS {
+                let _ = writeln!(
+                    text,
+                    "- and {} more",
+                    self.incidents.len() - MARKDOWN_INCIDENTS
+                );
+            }
+        }
+
+        text
+    }
+}
+
+/// Post a summary to the configured webhook once per interval.
+pub async fn report(state: Arc<State>) {
+    loop {
+        time::delay_for(state.config().digest.interval).await;
+
+        let config = state.config();
+        let url = match &config.digest.webhook {
+            Some(url) => url,
+            None => continue,
+        };
+        let summary = state.digest.finish();
+
+        match post(url, &summary, &config.digest).await {
+            Ok(()) => info!("Posted digest of {} requests", summary.requests),
+            Err(source) => warn!("Failed to post digest: {}", source),
+        }
+    }
+}
+
+async fn post(url: &str, summary: &Summary, config: &DigestConfig) -> Result<(), reqwest::Error> {
+    let body = match config.format {
+        DigestFormat::Json => json!(summary),
+        // Understood by Discord webhooks, among others.
+        DigestFormat::Markdown => json!({ "content": summary.to_markdown() }),
+    };
+
+    reqwest::Client::new()
+        .post(url)
+        .header(CONTENT_TYPE, "application/json")
+        .body(body.to_string())
+        .send()
+        .await?
+        .error_for_status()?;
+
+    Ok(())
+}
+
+fn seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn timestamp(seconds: u64) -> String {
+    humantime::format_rfc3339_seconds(UNIX_EPOCH + std::time::Duration::from_secs(seconds))
+        .to_string()
+}
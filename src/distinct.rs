@@ -0,0 +1,206 @@
+//! Approximate counts of the distinct guilds, channels and webhooks requests
+//! were made for, so operators can see how much of Discord the bot touches.
+
+use crate::State;
+use metrics::gauge;
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::time;
+use twilight_http::routing::Path;
+
+/// How often the estimates are reported as metrics.
+const REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Bits of the hash picking a register; 4096 registers give estimates within
+/// about 2% while taking 4 KiB per counter.
+const PRECISION: u32 = 12;
+const REGISTERS: usize = 1 << PRECISION;
+
+const WINDOWS: &[(&str, u64)] = &[("hour", 60 * 60), ("day", 24 * 60 * 60)];
+
+/// The resource a route acts on, which Discord ratelimits separately.
+pub fn major_parameter(path: &Path) -> Option<(&'static str, u64)> {
+    match path {
+        Path::ChannelsId(id)
+        | Path::ChannelsIdInvites(id)
+        | Path::ChannelsIdMessages(id)
+        | Path::ChannelsIdMessagesBulkDelete(id)
+        | Path::ChannelsIdMessagesId(_, id)
+        | Path::ChannelsIdMessagesIdReactions(id)
+        | Path::ChannelsIdMessagesIdReactionsUserIdType(id)
+        | Path::ChannelsIdPermissionsOverwriteId(id)
+        | Path::ChannelsIdPins(id)
+        | Path::ChannelsIdPinsMessageId(id)
+        | Path::ChannelsIdTyping(id)
+        | Path::ChannelsIdWebhooks(id) => Some(("channel", *id)),
+        Path::GuildsId(id)
+        | Path::GuildsIdBans(id)
+        | Path::GuildsIdBansId(id)
+        | Path::GuildsIdAuditLogs(id)
+        | Path::GuildsIdBansUserId(id)
+        | Path::GuildsIdChannels(id)
+        | Path::GuildsIdWidget(id)
+        | Path::GuildsIdEmojis(id)
+        | Path::GuildsIdEmojisId(id)
+        | Path::GuildsIdIntegrations(id)
+        | Path::GuildsIdIntegrationsId(id)
+        | Path::GuildsIdIntegrationsIdSync(id)
+        | Path::GuildsIdInvites(id)
+        | Path::GuildsIdMembers(id)
+        | Path::GuildsIdMembersId(id)
+        | Path::GuildsIdMembersIdRolesId(id)
+        | Path::GuildsIdMembersMeNick(id)
+        | Path::GuildsIdPreview(id)
+        | Path::GuildsIdPrune(id)
+        | Path::GuildsIdRegions(id)
+        | Path::GuildsIdRoles(id)
+        | Path::GuildsIdRolesId(id)
+        | Path::GuildsIdVanityUrl(id)
+        | Path::GuildsIdWebhooks(id) => Some(("guild", *id)),
+        Path::WebhooksId(id) => Some(("webhook", *id)),
+        _ => None,
+    }
+}
+
+/// HyperLogLog estimate of how many distinct values were added.
+#[derive(Debug)]
+struct HyperLogLog {
+    registers: Box<[u8; REGISTERS]>,
+}
+
+impl HyperLogLog {
+    fn new() -> Self {
+        Self {
+            registers: Box::new([0; REGISTERS]),
+        }
+    }
+
+    fn insert(&mut self, value: u64) {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        let index = (hash >> (64 - PRECISION)) as usize;
+        // Position of the first set bit in the rest of the hash, with a
+        // sentinel bit so it never runs past the end.
+        let rank = ((hash << PRECISION) | (1 << (PRECISION - 1))).leading_zeros() + 1;
+
+        if self.registers[index] < rank as u8 {
+            self.registers[index] = rank as u8;
+        }
+    }
+
+    fn estimate(&self) -> u64 {
+        let m = REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum = self
+            .registers
+            .iter()
+            .map(|register| 2f64.powi(-i32::from(*register)))
+            .sum::<f64>();
+        let raw = alpha * m * m / sum;
+        let zeros = self
+            .registers
+            .iter()
+            .filter(|register| **register == 0)
+            .count();
+
+        // Small counts are estimated better by how many registers are unused.
+        if raw <= 2.5 * m && zeros > 0 {
+            (m * (m / zeros as f64).ln()).round() as u64
+        } else {
+            raw.round() as u64
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Window {
+    /// Index of the window since the epoch.
+    number: u64,
+    counter: HyperLogLog,
+}
+
+/// Distinct major parameters seen in the current hour and day.
+#[derive(Debug, Default)]
+pub struct Distinct {
+    windows: Mutex<HashMap<(&'static str, &'static str), Window>>,
+}
+
+impl Distinct {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, path: &Path) {
+        let (kind, id) = match major_parameter(path) {
+            Some(parameter) => parameter,
+            None => return,
+        };
+        let now = now();
+        let mut windows = self.windows.lock().expect("distinct counters poisoned");
+
+        for (name, length) in WINDOWS {
+            let window = current(&mut windows, kind, name, now / length);
+            window.counter.insert(id);
+        }
+    }
+
+    fn report(&self) {
+        let now = now();
+        let mut windows = self.windows.lock().expect("distinct counters poisoned");
+        let kinds = windows.keys().map(|(kind, _)| *kind).collect::<Vec<_>>();
+
+        for kind in kinds {
+            for (name, length) in WINDOWS {
+                let estimate = current(&mut windows, kind, name, now / length)
+                    .counter
+                    .estimate();
+
+                gauge!("gearbot_proxy_distinct", estimate as i64, "kind" => kind, "window" => *name);
+            }
+        }
+    }
+}
+
+/// The window with the given number, starting it over if the previous one
+/// ended.
+fn current<'a>(
+    windows: &'a mut HashMap<(&'static str, &'static str), Window>,
+    kind: &'static str,
+    name: &'static str,
+    number: u64,
+) -> &'a mut Window {
+    let window = windows.entry((kind, name)).or_insert_with(|| Window {
+        number,
+        counter: HyperLogLog::new(),
+    });
+
+    if window.number != number {
+        window.number = number;
+        window.counter = HyperLogLog::new();
+    }
+
+    window
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Periodically report the estimates as metrics.
+pub async fn report(state: Arc<State>) {
+    let mut interval = time::interval(REPORT_INTERVAL);
+
+    loop {
+        interval.tick().await;
+        state.distinct.report();
+    }
+}
@@ -0,0 +1,189 @@
+//! Pretending the proxy fails, so bots' handling of that can be rehearsed.
+
+use crate::proxy::ProxyResponse;
+use http::{
+    header::{HeaderMap, HeaderValue, CONTENT_TYPE, RETRY_AFTER},
+    StatusCode,
+};
+use hyper::body::Bytes;
+use metrics::counter;
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+/// Longest a drill can run, so a forgotten one ends on its own.
+const MAX_DURATION: u64 = 3600;
+
+const UNREADY_BODY: &[u8] = br#"{"message":"the proxy is starting, try again later"}"#;
+
+/// What a drill does, as set through the admin endpoint.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    /// Percentage of requests whose connection is dropped without a response.
+    #[serde(default)]
+    pub drop_percent: u8,
+    /// How long every connection is dropped at the start, as if the proxy
+    /// had crashed.
+    #[serde(default)]
+    pub down_seconds: u64,
+    /// How long requests are then answered with a `503`, as if the proxy was
+    /// still starting after a restart.
+    #[serde(default)]
+    pub unready_seconds: u64,
+    /// How long the drill lasts in total.
+    #[serde(default = "default_duration")]
+    pub duration_seconds: u64,
+}
+
+fn default_duration() -> u64 {
+    300
+}
+
+/// A drill as reported by the admin endpoint.
+#[derive(Debug, Serialize)]
+pub struct Status {
+    #[serde(flatten)]
+    pub settings: Settings,
+    pub phase: Phase,
+    pub remaining_seconds: u64,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    Down,
+    Unready,
+    Dropping,
+}
+
+#[derive(Debug)]
+struct Active {
+    settings: Settings,
+    started: Instant,
+}
+
+impl Active {
+    fn phase(&self) -> Option<Phase> {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let down = self.settings.down_seconds as f64;
+
+        if elapsed >= self.settings.duration_seconds as f64 {
+            None
+        } else if elapsed < down {
+            Some(Phase::Down)
+        } else if elapsed < down + self.settings.unready_seconds as f64 {
+            Some(Phase::Unready)
+        } else {
+            Some(Phase::Dropping)
+        }
+    }
+}
+
+/// What to do with a request during a drill.
+#[derive(Debug)]
+pub enum Fault {
+    /// Close the connection without answering.
+    Drop,
+    /// Answer with this instead of handling the request.
+    Respond(ProxyResponse),
+}
+
+/// The drill currently running, if any.
+///
+/// The proxy's own endpoints are never affected, so a drill can always be
+/// stopped early.
+#[derive(Debug, Default)]
+pub struct Drill {
+    active: Mutex<Option<Active>>,
+}
+
+impl Drill {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn start(&self, mut settings: Settings) {
+        settings.drop_percent = settings.drop_percent.min(100);
+        settings.duration_seconds = settings.duration_seconds.min(MAX_DURATION);
+
+        warn!(
+            "Starting a drill for {}s: down for {}s, unready for {}s, dropping {}% of requests",
+            settings.duration_seconds,
+            settings.down_seconds,
+            settings.unready_seconds,
+            settings.drop_percent
+        );
+
+        *self.active.lock().expect("drill poisoned") = Some(Active {
+            settings,
+            started: Instant::now(),
+        });
+    }
+
+    pub fn stop(&self) {
+        if self.active.lock().expect("drill poisoned").take().is_some() {
+            info!("Stopped the drill");
+        }
+    }
+
+    pub fn status(&self) -> Option<Status> {
+        let active = self.active.lock().expect("drill poisoned");
+        let active = active.as_ref()?;
+        let phase = active.phase()?;
+        let total = Duration::from_secs(active.settings.duration_seconds);
+
+        Some(Status {
+            settings: active.settings.clone(),
+            phase,
+            remaining_seconds: total.saturating_sub(active.started.elapsed()).as_secs(),
+        })
+    }
+
+    /// The fault to inject into a request, if the drill calls for one.
+    pub fn fault(&self) -> Option<Fault> {
+        let mut active = self.active.lock().expect("drill poisoned");
+        let phase = match active.as_ref().map(Active::phase) {
+            Some(Some(phase)) => phase,
+            Some(None) => {
+                *active = None;
+                info!("The drill is over");
+
+                return None;
+            }
+            None => return None,
+        };
+        let drop_percent = active.as_ref()?.settings.drop_percent;
+        drop(active);
+
+        let fault = match phase {
+            Phase::Down => Fault::Drop,
+            Phase::Unready => {
+                let mut headers = HeaderMap::new();
+                headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+                headers.insert(RETRY_AFTER, HeaderValue::from(1));
+
+                Fault::Respond(ProxyResponse {
+                    status: StatusCode::SERVICE_UNAVAILABLE,
+                    headers,
+                    body: Bytes::from_static(UNREADY_BODY),
+                })
+            }
+            Phase::Dropping if rand::random::<f64>() * 100.0 < f64::from(drop_percent) => {
+                Fault::Drop
+            }
+            Phase::Dropping => return None,
+        };
+
+        let kind = match fault {
+            Fault::Drop => "drop",
+            Fault::Respond(_) => "unready",
+        };
+        counter!("gearbot_proxy_drill_faults", 1, "kind" => kind);
+
+        Some(fault)
+    }
+}
@@ -0,0 +1,205 @@
+//! Ratelimits made up for responses that don't come from Discord, so bots can
+//! exercise their ratelimit handling against dry runs and mocked responses.
+
+use crate::config::EmulatedRatelimitsConfig;
+use http::{
+    header::{HeaderMap, HeaderValue, CONTENT_TYPE, RETRY_AFTER},
+    StatusCode,
+};
+use metrics::counter;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use twilight_http::routing::Path;
+
+/// Requests counted against a bucket since its window started.
+#[derive(Debug)]
+struct Window {
+    used: u64,
+    resets_at: Instant,
+}
+
+/// Buckets of the requests answered without Discord, each allowing
+/// `EMULATED_RATELIMIT_LIMIT` requests per `EMULATED_RATELIMIT_WINDOW`.
+#[derive(Debug, Default)]
+pub struct EmulatedRatelimits {
+    windows: Mutex<HashMap<Path, Window>>,
+}
+
+/// What the bucket says about a request counted against it.
+#[derive(Debug)]
+pub struct Emulated {
+    pub headers: HeaderMap,
+    /// How long until the bucket resets, if the request is ratelimited.
+    pub retry_after: Option<Duration>,
+}
+
+impl EmulatedRatelimits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count a request against its bucket, or `None` if emulation is turned
+    /// off.
+    ///
+    /// Requests over the limit are ratelimited, as are
+    /// `EMULATED_RATELIMIT_PERCENT` of the others, which uses up the rest of
+    /// the bucket like a real `429` would.
+    pub fn take(
+        &self,
+        config: &EmulatedRatelimitsConfig,
+        path: &Path,
+        route: &'static str,
+    ) -> Option<Emulated> {
+        if config.limit == 0 {
+            return None;
+        }
+
+        let now = Instant::now();
+        let mut windows = self.windows.lock().expect("emulated ratelimits poisoned");
+        windows.retain(|_, window| window.resets_at > now);
+        let window = windows.entry(path.clone()).or_insert_with(|| Window {
+            used: 0,
+            resets_at: now + config.window,
+        });
+
+        let simulated = rand::random::<f64>() * 100.0 < f64::from(config.percent);
+        let limited = window.used >= config.limit || simulated;
+
+        if limited {
+            window.used = config.limit;
+            counter!("gearbot_proxy_emulated_ratelimits", 1, "route" => route, "simulated" => simulated.to_string());
+        } else {
+            window.used += 1;
+        }
+
+        let reset_after = window.resets_at - now;
+        let reset = SystemTime::now() + reset_after;
+        let mut headers = HeaderMap::new();
+        headers.insert("x-ratelimit-bucket", HeaderValue::from_static(route));
+        headers.insert("x-ratelimit-limit", HeaderValue::from(config.limit));
+        headers.insert(
+            "x-ratelimit-remaining",
+            HeaderValue::from(config.limit - window.used),
+        );
+        headers.insert(
+            "x-ratelimit-reset",
+            seconds(reset.duration_since(UNIX_EPOCH).unwrap_or_default()),
+        );
+        headers.insert("x-ratelimit-reset-after", seconds(reset_after));
+
+        Some(Emulated {
+            headers,
+            retry_after: Some(reset_after).filter(|_| limited),
+        })
+    }
+}
+
+impl Emulated {
+    /// Add the headers to a response, turning it into a `429` if the request
+    /// was ratelimited.
+    pub fn apply<B: From<Vec<u8>>>(
+        self,
+        status: &mut StatusCode,
+        headers: &mut HeaderMap,
+        body: &mut B,
+    ) {
+        if let Some(retry_after) = self.retry_after {
+            let ratelimited = serde_json::json!({
+                "message": "You are being rate limited.",
+                "retry_after": retry_after.as_secs_f64(),
+                "global": false,
+            });
+            *status = StatusCode::TOO_MANY_REQUESTS;
+            *body = B::from(serde_json::to_vec(&ratelimited).unwrap_or_default());
+            headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+            headers.insert(
+                RETRY_AFTER,
+                HeaderValue::from(retry_after.as_millis().div_ceil(1000) as u64),
+            );
+            headers.insert("x-ratelimit-global", HeaderValue::from_static("false"));
+            headers.insert("x-ratelimit-scope", HeaderValue::from_static("user"));
+        }
+
+        headers.extend(self.headers);
+    }
+}
+
+/// Seconds with millisecond precision, like Discord's ratelimit headers.
+fn seconds(duration: Duration) -> HeaderValue {
+    HeaderValue::from_str(&format!("{:.3}", duration.as_secs_f64()))
+        .expect("numbers are valid in a header")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EmulatedRatelimits;
+    use crate::config::EmulatedRatelimitsConfig;
+    use http::{header::HeaderMap, StatusCode};
+    use std::time::Duration;
+    use twilight_http::routing::Path;
+
+    fn config(limit: u64, percent: u8) -> EmulatedRatelimitsConfig {
+        EmulatedRatelimitsConfig {
+            limit,
+            window: Duration::from_secs(5),
+            percent,
+        }
+    }
+
+    fn remaining(headers: &HeaderMap) -> &str {
+        headers["x-ratelimit-remaining"].to_str().unwrap()
+    }
+
+    #[test]
+    fn counts_requests_per_bucket() {
+        let emulated = EmulatedRatelimits::new();
+        let config = config(2, 0);
+        let path = Path::ChannelsIdMessages(1);
+
+        let first = emulated.take(&config, &path, "ChannelMessages").unwrap();
+        assert_eq!(first.headers["x-ratelimit-limit"], "2");
+        assert_eq!(remaining(&first.headers), "1");
+        assert!(first.retry_after.is_none());
+
+        let other = emulated
+            .take(&config, &Path::ChannelsIdMessages(2), "ChannelMessages")
+            .unwrap();
+        assert_eq!(remaining(&other.headers), "1");
+
+        let second = emulated.take(&config, &path, "ChannelMessages").unwrap();
+        assert_eq!(remaining(&second.headers), "0");
+        assert!(second.retry_after.is_none());
+
+        let third = emulated.take(&config, &path, "ChannelMessages").unwrap();
+        assert_eq!(remaining(&third.headers), "0");
+        assert!(third.retry_after.is_some());
+    }
+
+    #[test]
+    fn simulates_ratelimits() {
+        let emulated = EmulatedRatelimits::new();
+        let taken = emulated
+            .take(&config(5, 100), &Path::Gateway, "Gateway")
+            .unwrap();
+        assert_eq!(remaining(&taken.headers), "0");
+
+        let (mut status, mut headers, mut body) = (StatusCode::OK, HeaderMap::new(), Vec::new());
+        taken.apply(&mut status, &mut headers, &mut body);
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert!(headers.contains_key("retry-after"));
+        assert_eq!(headers["x-ratelimit-global"], "false");
+        assert!(!body.is_empty());
+    }
+
+    #[test]
+    fn turns_off() {
+        let emulated = EmulatedRatelimits::new();
+
+        assert!(emulated
+            .take(&config(0, 100), &Path::Gateway, "Gateway")
+            .is_none());
+    }
+}
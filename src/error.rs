@@ -1,7 +1,9 @@
-use http::{Error as HttpError, Uri};
+use http::{header::InvalidHeaderValue, Error as HttpError, StatusCode, Uri};
 use hyper::Error as HyperError;
 use reqwest::Error as ReqwestError;
+use serde_json::Error as JsonError;
 use snafu::Snafu;
+use std::io::Error as IoError;
 use twilight_http::{error::Error as TwilightError, routing::PathParseError};
 
 #[derive(Debug, Snafu)]
@@ -9,8 +11,34 @@ use twilight_http::{error::Error as TwilightError, routing::PathParseError};
 pub enum RequestError {
     ChunkingRequest { source: HyperError },
     ChunkingResponse { source: ReqwestError },
+    DroppedByDrill,
+    InvalidHeader { source: InvalidHeaderValue },
     InvalidPath { source: PathParseError },
+    InvalidRecord { id: String },
     MakingResponseBody { source: HttpError },
     NoPath { uri: Uri },
     RequestIssue { source: TwilightError },
+    SerializingJson { source: JsonError },
+    Spooling { source: IoError },
+    TraversingPath { path: String },
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(crate)))]
+pub enum ClientError {
+    #[snafu(display("Failed to create the HTTP client: {}", source))]
+    BuildingHttpClient { source: ReqwestError },
+    #[snafu(display("The token can't be sent in a header: {}", source))]
+    InvalidToken { source: InvalidHeaderValue },
+    #[snafu(display("Failed to check the token: {}", source))]
+    CheckingToken { source: TwilightError },
+    #[snafu(display("Discord rejected the token with a {}", status))]
+    RejectedToken { status: StatusCode },
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(crate)))]
+pub enum ListenError {
+    #[snafu(display("Failed to listen on {}: {}", listener, source))]
+    Binding { listener: String, source: IoError },
 }
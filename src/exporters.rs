@@ -0,0 +1,473 @@
+//! Where metrics recorded through the `metrics` macros end up.
+//!
+//! Call sites only ever use the macros; which exporters receive the metrics is
+//! decided here from configuration, and several can run side by side, e.g.
+//! while migrating dashboards from one to another.
+
+use crate::{
+    cardinality,
+    config::{ExporterKind, MetricsAccess, MetricsConfig, StatsdConfig},
+};
+use http::{
+    header::{AUTHORIZATION, WWW_AUTHENTICATE},
+    HeaderMap, StatusCode,
+};
+use hyper::{
+    server::conn::AddrStream,
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server,
+};
+use metrics::SetRecorderError;
+use metrics_core::{Builder, Drain, Key, Observe, Observer};
+use metrics_runtime::{
+    exporters::LogExporter,
+    observers::{JsonBuilder, PrometheusBuilder},
+    Controller, Receiver,
+};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    net::{self, UdpSocket},
+    time,
+};
+use tracing::{error, warn};
+
+pub type Task = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// A metrics backend.
+pub trait Exporter {
+    /// Create the task exporting the metrics observed through the
+    /// controller, which runs for as long as the proxy does.
+    fn start(self: Box<Self>, controller: Controller) -> Task;
+}
+
+/// Prometheus metrics served over HTTP on a listener of their own.
+pub struct Prometheus {
+    pub address: SocketAddr,
+    pub scraper: Scraper,
+}
+
+impl Exporter for Prometheus {
+    fn start(self: Box<Self>, controller: Controller) -> Task {
+        let scraper = Arc::new(self.scraper.with_controller(controller));
+
+        let service = make_service_fn(move |connection: &AddrStream| {
+            let peer = connection.remote_addr().ip();
+            let scraper = Arc::clone(&scraper);
+
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let response = if scraper.handles(request.uri().path()) {
+                        scraper.scrape(Some(peer), request.headers())
+                    } else {
+                        status(StatusCode::NOT_FOUND)
+                    };
+
+                    async move { Ok::<_, Infallible>(response) }
+                }))
+            }
+        });
+        let server = Server::bind(&self.address).serve(service);
+
+        Box::pin(async move {
+            if let Err(source) = server.await {
+                error!("Prometheus exporter failed: {}", source);
+            }
+        })
+    }
+}
+
+/// Answers Prometheus scrapes, on a listener of its own or the proxy's.
+pub struct Scraper {
+    access: MetricsAccess,
+    path: Option<String>,
+    controller: Option<Controller>,
+}
+
+impl Scraper {
+    pub fn new(config: &MetricsConfig) -> Self {
+        Self {
+            access: config.access.clone(),
+            path: config.path.clone(),
+            controller: None,
+        }
+    }
+
+    pub fn with_controller(self, controller: Controller) -> Self {
+        Self {
+            controller: Some(controller),
+            ..self
+        }
+    }
+
+    /// Whether the path is where metrics are served.
+    pub fn handles(&self, path: &str) -> bool {
+        self.path.as_ref().is_none_or(|metrics| metrics == path)
+    }
+
+    /// Scrape the metrics, if the peer is allowed to. Peers on unix sockets
+    /// have no address, so they're refused if only some addresses are
+    /// allowed.
+    pub fn scrape(&self, peer: Option<IpAddr>, headers: &HeaderMap) -> Response<Body> {
+        let controller = match &self.controller {
+            Some(controller) => controller,
+            None => return status(StatusCode::NOT_FOUND),
+        };
+
+        scrape(&self.access, controller, peer, headers)
+    }
+}
+
+fn scrape(
+    access: &MetricsAccess,
+    controller: &Controller,
+    peer: Option<IpAddr>,
+    headers: &HeaderMap,
+) -> Response<Body> {
+    let allowed = access.allow.is_empty()
+        || peer.is_some_and(|peer| access.allow.iter().any(|network| network.contains(peer)));
+
+    if !allowed {
+        warn!("Refused metrics scrape from {:?}", peer);
+
+        return status(StatusCode::FORBIDDEN);
+    }
+
+    if !authorized(access, headers) {
+        let mut response = status(StatusCode::UNAUTHORIZED);
+        let challenge = if access.basic_auth.is_some() {
+            "Basic realm=\"metrics\""
+        } else {
+            "Bearer"
+        };
+        response
+            .headers_mut()
+            .insert(WWW_AUTHENTICATE, challenge.parse().expect("valid header value"));
+
+        return response;
+    }
+
+    let mut observer = PrometheusBuilder::new().build();
+    controller.observe(&mut observer);
+
+    Response::new(Body::from(observer.drain()))
+}
+
+/// Whether the request carries one of the configured credentials, or none are
+/// configured.
+fn authorized(access: &MetricsAccess, headers: &HeaderMap) -> bool {
+    if access.token.is_none() && access.basic_auth.is_none() {
+        return true;
+    }
+
+    let provided = match headers.get(AUTHORIZATION).and_then(|value| value.to_str().ok()) {
+        Some(provided) => provided,
+        None => return false,
+    };
+    let bearer = access
+        .token
+        .as_ref()
+        .is_some_and(|token| constant_time_eq(provided, &format!("Bearer {}", token)));
+    let basic = access.basic_auth.as_ref().is_some_and(|(username, password)| {
+        let encoded = base64::encode(format!("{}:{}", username, password));
+
+        constant_time_eq(provided, &format!("Basic {}", encoded))
+    });
+
+    bearer || basic
+}
+
+/// Compare secrets without leaking how much of them matched through timing.
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a
+            .bytes()
+            .zip(b.bytes())
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+
+    response
+}
+
+/// Metrics periodically written to the log as JSON.
+pub struct Log {
+    pub interval: Duration,
+}
+
+impl Exporter for Log {
+    fn start(self: Box<Self>, controller: Controller) -> Task {
+        let exporter = LogExporter::new(
+            controller,
+            JsonBuilder::new(),
+            log::Level::Info,
+            self.interval,
+        );
+
+        Box::pin(exporter.async_run())
+    }
+}
+
+/// Metrics pushed to a StatsD server, with labels as DogStatsD tags.
+pub struct Statsd {
+    pub config: StatsdConfig,
+}
+
+/// Largest datagram sent, small enough not to be fragmented on common
+/// networks.
+const MAX_DATAGRAM: usize = 1432;
+
+impl Exporter for Statsd {
+    fn start(self: Box<Self>, controller: Controller) -> Task {
+        let config = self.config;
+
+        Box::pin(async move {
+            let mut interval = time::interval(config.interval);
+            let mut socket: Option<UdpSocket> = None;
+            // StatsD counters are increments, so the totals sent last time
+            // are remembered to send the difference.
+            let mut counters = HashMap::new();
+
+            loop {
+                interval.tick().await;
+
+                let mut observer = StatsdObserver {
+                    config: &config,
+                    counters: &mut counters,
+                    lines: Vec::new(),
+                };
+                controller.observe(&mut observer);
+                let lines = observer.lines;
+
+                let address = match net::lookup_host((config.host.as_str(), config.port)).await {
+                    Ok(mut addresses) => match addresses.next() {
+                        Some(address) => address,
+                        None => {
+                            warn!("StatsD host {} has no addresses", config.host);
+                            continue;
+                        }
+                    },
+                    Err(source) => {
+                        warn!("Failed to resolve StatsD host {}: {}", config.host, source);
+                        continue;
+                    }
+                };
+
+                let reusable = socket.as_ref().is_some_and(|socket| {
+                    socket
+                        .local_addr()
+                        .is_ok_and(|local| local.is_ipv4() == address.is_ipv4())
+                });
+                if !reusable {
+                    let local: SocketAddr = if address.is_ipv4() {
+                        ([0, 0, 0, 0], 0).into()
+                    } else {
+                        ([0; 16], 0).into()
+                    };
+
+                    socket = match UdpSocket::bind(local).await {
+                        Ok(socket) => Some(socket),
+                        Err(source) => {
+                            error!("Failed to bind StatsD socket: {}", source);
+                            continue;
+                        }
+                    };
+                }
+
+                let socket = socket.as_mut().expect("socket bound above");
+                for datagram in datagrams(&lines) {
+                    if let Err(source) = socket.send_to(datagram.as_bytes(), &address).await {
+                        warn!("Failed to send metrics to StatsD: {}", source);
+                        break;
+                    }
+                }
+            }
+        })
+    }
+}
+
+/// Renders observed metrics as StatsD lines.
+struct StatsdObserver<'a> {
+    config: &'a StatsdConfig,
+    counters: &'a mut HashMap<String, u64>,
+    lines: Vec<String>,
+}
+
+impl StatsdObserver<'_> {
+    fn push(&mut self, name: &str, value: impl std::fmt::Display, kind: &str, tags: &str) {
+        self.lines.push(format!(
+            "{}{}:{}|{}{}",
+            self.config.prefix, name, value, kind, tags
+        ));
+    }
+
+    /// The metric's name and its labels along with the configured tags, in
+    /// DogStatsD's `|#key:value` format.
+    fn split(&self, key: Key) -> (String, String) {
+        let (name, labels) = key.into_parts();
+        let tags = self
+            .config
+            .tags
+            .iter()
+            .cloned()
+            .chain(labels.iter().map(|label| {
+                format!("{}:{}", sanitize(label.key()), sanitize(label.value()))
+            }))
+            .collect::<Vec<_>>();
+
+        let tags = if tags.is_empty() {
+            String::new()
+        } else {
+            format!("|#{}", tags.join(","))
+        };
+
+        (sanitize(&name), tags)
+    }
+}
+
+impl Observer for StatsdObserver<'_> {
+    fn observe_counter(&mut self, key: Key, value: u64) {
+        let (name, tags) = self.split(key);
+        let previous = self
+            .counters
+            .insert(format!("{}{}", name, tags), value)
+            .unwrap_or_default();
+
+        // A total below the last one means the series was folded away and
+        // started over.
+        let increment = value.checked_sub(previous).unwrap_or(value);
+        if increment > 0 {
+            self.push(&name, increment, "c", &tags);
+        }
+    }
+
+    fn observe_gauge(&mut self, key: Key, value: i64) {
+        let (name, tags) = self.split(key);
+
+        // A leading sign would make StatsD add to the gauge instead of
+        // setting it.
+        if value < 0 {
+            self.push(&name, 0, "g", &tags);
+        }
+        self.push(&name, value, "g", &tags);
+    }
+
+    /// Histograms are windowed and seen again on every flush, so they're sent
+    /// as gauges of their quantiles rather than as individual timings.
+    fn observe_histogram(&mut self, key: Key, values: &[u64]) {
+        if values.is_empty() {
+            return;
+        }
+
+        let (name, tags) = self.split(key);
+        let mut values = values.to_vec();
+        values.sort_unstable();
+
+        for (suffix, quantile) in &[("p50", 0.5), ("p99", 0.99), ("max", 1.0)] {
+            let index = ((values.len() - 1) as f64 * quantile).round() as usize;
+            self.push(&format!("{}.{}", name, suffix), values[index], "g", &tags);
+        }
+    }
+}
+
+/// Replace characters with a meaning in the StatsD protocol.
+fn sanitize(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            ':' | '|' | ',' | '#' | '@' | '\n' => '_',
+            c => c,
+        })
+        .collect()
+}
+
+/// Pack lines into as few datagrams as fit.
+fn datagrams(lines: &[String]) -> Vec<String> {
+    let mut datagrams = Vec::new();
+    let mut current = String::new();
+
+    for line in lines {
+        if !current.is_empty() && current.len() + 1 + line.len() > MAX_DATAGRAM {
+            datagrams.push(std::mem::take(&mut current));
+        }
+        if !current.is_empty() {
+            current.push('\n');
+        }
+        current.push_str(line);
+    }
+    if !current.is_empty() {
+        datagrams.push(current);
+    }
+
+    datagrams
+}
+
+/// Build the configured exporters.
+///
+/// Prometheus metrics served on the proxy's own listeners need no exporter of
+/// their own; see `install`.
+pub fn from_config(config: &MetricsConfig) -> Vec<Box<dyn Exporter>> {
+    config
+        .exporters
+        .iter()
+        .filter_map(|kind| -> Option<Box<dyn Exporter>> {
+            match kind {
+                ExporterKind::Prometheus if config.on_listener => None,
+                ExporterKind::Prometheus => Some(Box::new(Prometheus {
+                    address: config.address,
+                    scraper: Scraper::new(config),
+                })),
+                ExporterKind::Log => Some(Box::new(Log {
+                    interval: config.log_interval,
+                })),
+                ExporterKind::Statsd => Some(Box::new(Statsd {
+                    config: config.statsd.clone(),
+                })),
+            }
+        })
+        .collect()
+}
+
+/// Install the global recorder and start the exporters.
+///
+/// Returns the scraper for the proxy's own listeners, if Prometheus metrics
+/// are served there.
+///
+/// All exporters observe the same receiver: metrics-runtime caches its sinks
+/// per thread, so a second receiver in the process wouldn't see anything.
+pub fn install(
+    config: &MetricsConfig,
+    exporters: Vec<Box<dyn Exporter>>,
+) -> Result<Option<Scraper>, SetRecorderError> {
+    let receiver = Receiver::builder()
+        .build()
+        .expect("Failed to create receiver!");
+
+    for exporter in exporters {
+        tokio::spawn(exporter.start(receiver.controller()));
+    }
+
+    let scraper = if config.on_listener && config.exporters.contains(&ExporterKind::Prometheus) {
+        Some(Scraper::new(config).with_controller(receiver.controller()))
+    } else {
+        None
+    };
+
+    metrics::set_boxed_recorder(Box::new(cardinality::Guard::new(
+        receiver,
+        config.max_series,
+    )))?;
+
+    Ok(scraper)
+}
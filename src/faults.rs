@@ -0,0 +1,170 @@
+//! Latency and errors injected into requests on purpose, to check how bots
+//! cope with them without provoking Discord.
+
+use crate::proxy::{ProxyRequest, ProxyResponse};
+use http::{
+    header::{HeaderValue, RETRY_AFTER},
+    StatusCode,
+};
+use metrics::counter;
+use serde::{Deserialize, Serialize};
+use std::{sync::RwLock, time::Duration};
+use tokio::time;
+use tracing::{debug, info};
+
+/// Header set on responses made up by a fault, naming its kind.
+pub const HEADER: &str = "x-proxy-fault";
+
+/// What happens to a request a rule applies to.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Kind {
+    /// Hold the request back before handling it as usual.
+    Latency { latency_ms: u64 },
+    /// Answer with a `429` like Discord's.
+    Ratelimit {
+        #[serde(default = "default_retry_after")]
+        retry_after_ms: u64,
+    },
+    /// Answer with an error status, `500` by default.
+    Error {
+        #[serde(default = "default_status")]
+        status: u16,
+    },
+}
+
+fn default_retry_after() -> u64 {
+    1000
+}
+
+fn default_status() -> u16 {
+    500
+}
+
+impl Kind {
+    fn name(&self) -> &'static str {
+        match self {
+            Kind::Latency { .. } => "latency",
+            Kind::Ratelimit { .. } => "ratelimit",
+            Kind::Error { .. } => "error",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Rule {
+    /// Names of the routes the rule applies to, as in the `route` label of
+    /// metrics, or every route if empty.
+    #[serde(default)]
+    pub routes: Vec<String>,
+    /// Percentage of matching requests the rule applies to.
+    pub percent: u8,
+    #[serde(flatten)]
+    pub kind: Kind,
+}
+
+impl Rule {
+    fn applies(&self, route: &str) -> bool {
+        (self.routes.is_empty() || self.routes.iter().any(|matched| matched == route))
+            && rand::random::<f64>() * 100.0 < f64::from(self.percent)
+    }
+}
+
+/// Fault rules set through the admin endpoint, empty unless a test is
+/// running.
+#[derive(Debug, Default)]
+pub struct Faults {
+    rules: RwLock<Vec<Rule>>,
+}
+
+impl Faults {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn rules(&self) -> Vec<Rule> {
+        self.rules.read().expect("faults poisoned").clone()
+    }
+
+    pub fn set(&self, rules: Vec<Rule>) {
+        info!("Injecting faults with {} rules", rules.len());
+
+        *self.rules.write().expect("faults poisoned") = rules;
+    }
+
+    /// Apply the rules to a request, returning the response to answer it
+    /// with instead, if any.
+    ///
+    /// Latency from every rule that applies adds up, while the first error
+    /// that applies wins.
+    pub async fn inject(&self, request: &ProxyRequest) -> Option<ProxyResponse> {
+        let route = request.route;
+        let mut latency = Duration::from_millis(0);
+        let mut failure = None;
+
+        for rule in self.rules.read().expect("faults poisoned").iter() {
+            if !rule.applies(route) {
+                continue;
+            }
+
+            counter!("gearbot_proxy_injected_faults", 1, "route" => route, "kind" => rule.kind.name());
+
+            match &rule.kind {
+                Kind::Latency { latency_ms } => latency += Duration::from_millis(*latency_ms),
+                kind if failure.is_none() => failure = Some(kind.clone()),
+                _ => {}
+            }
+        }
+
+        if latency > Duration::from_millis(0) {
+            debug!("Injecting {:?} of latency into {}", latency, route);
+            time::delay_for(latency).await;
+        }
+
+        let kind = failure?;
+        let name = kind.name();
+        debug!("Injecting a {} fault into {}", name, route);
+
+        let mut response = match kind {
+            Kind::Ratelimit { retry_after_ms } => {
+                let mut response = ProxyResponse::json(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    &serde_json::json!({
+                        "message": "You are being rate limited.",
+                        "retry_after": retry_after_ms as f64 / 1000.0,
+                        "global": false,
+                    }),
+                )
+                .ok()?;
+                response.headers.insert(
+                    RETRY_AFTER,
+                    HeaderValue::from(retry_after_ms.div_ceil(1000)),
+                );
+                response
+                    .headers
+                    .insert("x-ratelimit-global", HeaderValue::from_static("false"));
+
+                response
+            }
+            Kind::Error { status } => {
+                let status =
+                    StatusCode::from_u16(status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+
+                ProxyResponse::json(
+                    status,
+                    &serde_json::json!({
+                        "message": status.to_string(),
+                        "code": 0,
+                    }),
+                )
+                .ok()?
+            }
+            Kind::Latency { .. } => return None,
+        };
+        response
+            .headers
+            .insert(HEADER, HeaderValue::from_static(name));
+
+        Some(response)
+    }
+}
@@ -0,0 +1,86 @@
+//! Where requests come from when they pass through load balancers.
+
+use crate::{config::TrustedProxies, listener::Peer};
+use http::{
+    header::{HeaderMap, HOST},
+    request::Parts,
+    uri::Authority,
+};
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+pub const PROTO_HEADER: &str = "x-forwarded-proto";
+pub const HOST_HEADER: &str = "x-forwarded-host";
+
+/// Scheme and host a client used to reach the proxy.
+#[derive(Debug)]
+pub struct Origin {
+    pub scheme: &'static str,
+    pub host: Option<String>,
+}
+
+impl Origin {
+    /// Work out the origin of a request, believing `X-Forwarded-*` headers
+    /// only if the peer is trusted.
+    pub fn of(trusted: &TrustedProxies, parts: &Parts) -> Self {
+        let trusted = parts
+            .extensions
+            .get::<Peer>()
+            .is_some_and(|peer| is_trusted(trusted, *peer));
+        let forwarded = |name| {
+            if trusted {
+                first_value(&parts.headers, name)
+            } else {
+                None
+            }
+        };
+
+        let scheme = match forwarded(PROTO_HEADER).map(str::to_ascii_lowercase) {
+            Some(scheme) if scheme == "https" => "https",
+            _ => "http",
+        };
+        let host = forwarded(HOST_HEADER)
+            .or_else(|| parts.headers.get(HOST)?.to_str().ok())
+            .or_else(|| parts.uri.authority().map(Authority::as_str))
+            .filter(|host| host.parse::<Authority>().is_ok())
+            .map(str::to_owned);
+
+        Self { scheme, host }
+    }
+
+    /// Absolute URL of a path on the proxy, or just the path if the host
+    /// isn't known.
+    pub fn url(&self, path: &str) -> String {
+        match &self.host {
+            Some(host) => format!("{}://{}{}", self.scheme, host, path),
+            None => path.to_owned(),
+        }
+    }
+}
+
+impl Display for Origin {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "{}://{}", self.scheme, self.host.as_deref().unwrap_or("?"))
+    }
+}
+
+fn is_trusted(trusted: &TrustedProxies, peer: Peer) -> bool {
+    match peer {
+        Peer::Tcp(address) => trusted
+            .networks
+            .iter()
+            .any(|network| network.contains(address)),
+        Peer::Unix => trusted.unix,
+    }
+}
+
+/// The value added by the proxy closest to the client, when several appended
+/// theirs.
+fn first_value<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    let value = headers.get(name)?.to_str().ok()?;
+
+    value
+        .split(',')
+        .next()
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+}
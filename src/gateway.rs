@@ -0,0 +1,304 @@
+//! Passing gateway WebSocket connections through to Discord, for shards that
+//! can only reach the internet through the proxy.
+//!
+//! The proxy only takes part in the handshake; after that the bytes are
+//! copied both ways as they are, so any gateway version and encoding works.
+
+use crate::{admin, config::UpstreamConfig, error::RequestError, upstream, State};
+use http::{
+    header::{HeaderValue, UPGRADE},
+    Method, Request, Response, StatusCode,
+};
+use hyper::{body::Body, upgrade::Upgraded};
+use metrics::{counter, gauge};
+use std::{
+    io::{self, ErrorKind},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+use tokio_rustls::{
+    webpki::{DNSNameRef, InvalidDNSNameError},
+    TlsConnector,
+};
+use tracing::{debug, info, warn};
+
+/// Path shards connect to instead of Discord's gateway URL.
+pub const PATH: &str = "/gateway";
+
+/// Handshake headers passed on to Discord. Everything else stays between the
+/// shard and the proxy.
+const FORWARDED: &[&str] = &[
+    "sec-websocket-extensions",
+    "sec-websocket-key",
+    "sec-websocket-protocol",
+    "sec-websocket-version",
+    "user-agent",
+];
+
+/// Largest handshake response read from Discord.
+const MAX_HEAD: usize = 16 * 1024;
+
+/// Where the gateway is, taken apart from a `ws://` or `wss://` URL.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Target {
+    pub tls: bool,
+    pub host: String,
+    pub port: u16,
+}
+
+impl Target {
+    pub fn parse(url: &str) -> Option<Self> {
+        let uri = url.parse::<http::Uri>().ok()?;
+        let tls = match uri.scheme_str()? {
+            "wss" => true,
+            "ws" => false,
+            _ => return None,
+        };
+        let host = uri.host()?.to_owned();
+        let port = uri.port_u16().unwrap_or(if tls { 443 } else { 80 });
+
+        Some(Self { tls, host, port })
+    }
+}
+
+/// Gateway connections currently open.
+#[derive(Debug, Default)]
+pub struct Connections {
+    open: AtomicUsize,
+}
+
+impl Connections {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn opened(self: &Arc<Self>) -> Open {
+        let open = self.open.fetch_add(1, Ordering::Relaxed) + 1;
+        gauge!("gearbot_proxy_gateway_connections", open as i64);
+        counter!("gearbot_proxy_gateway_connections_opened", 1);
+
+        Open(Arc::clone(self))
+    }
+}
+
+/// An open connection, counted until dropped.
+struct Open(Arc<Connections>);
+
+impl Drop for Open {
+    fn drop(&mut self) {
+        let open = self.0.open.fetch_sub(1, Ordering::Relaxed) - 1;
+        gauge!("gearbot_proxy_gateway_connections", open as i64);
+    }
+}
+
+trait Stream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Stream for T {}
+
+/// Whether the request is a shard asking to open a gateway connection.
+pub fn is_upgrade(request: &Request<Body>) -> bool {
+    request.method() == Method::GET
+        && request.uri().path().trim_end_matches('/') == PATH
+        && request
+            .headers()
+            .get(UPGRADE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.eq_ignore_ascii_case("websocket"))
+}
+
+/// Open a connection to the gateway for the shard and, once both sides agree
+/// to switch to WebSocket, copy between them until either closes.
+pub async fn proxy(
+    state: Arc<State>,
+    request: Request<Body>,
+) -> Result<Response<Body>, RequestError> {
+    let config = state.config();
+    let target = match Target::parse(&config.gateway.url) {
+        Some(target) => target,
+        None => return admin::error(StatusCode::BAD_GATEWAY, "GATEWAY_URL is invalid"),
+    };
+    let (parts, body) = request.into_parts();
+
+    let mut upstream = match connect(&state, &target, &config.upstream).await {
+        Ok(upstream) => upstream,
+        Err(source) => {
+            warn!(
+                "Failed to connect to the gateway at {}: {}",
+                target.host, source
+            );
+            counter!("gearbot_proxy_gateway_failures", 1, "reason" => "connect");
+
+            return admin::error(StatusCode::BAD_GATEWAY, "couldn't reach Discord's gateway");
+        }
+    };
+
+    // The shard's query, like `?v=6&encoding=json`, picks the gateway version.
+    let path = match parts.uri.query() {
+        Some(query) => format!("/?{}", query),
+        None => "/".to_owned(),
+    };
+    let mut head = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n",
+        path, target.host
+    );
+
+    for name in FORWARDED {
+        for value in parts.headers.get_all(*name) {
+            if let Ok(value) = value.to_str() {
+                head.push_str(&format!("{}: {}\r\n", name, value));
+            }
+        }
+    }
+
+    head.push_str("\r\n");
+
+    let (response, leftover) = match handshake(&mut upstream, head.as_bytes()).await {
+        Ok(handshake) => handshake,
+        Err(source) => {
+            warn!("Gateway handshake with {} failed: {}", target.host, source);
+            counter!("gearbot_proxy_gateway_failures", 1, "reason" => "handshake");
+
+            return admin::error(
+                StatusCode::BAD_GATEWAY,
+                "Discord's gateway handshake failed",
+            );
+        }
+    };
+
+    if response.status() != StatusCode::SWITCHING_PROTOCOLS {
+        warn!(
+            "Discord's gateway answered the handshake with {}",
+            response.status()
+        );
+        counter!("gearbot_proxy_gateway_failures", 1, "reason" => "refused");
+
+        return Ok(response);
+    }
+
+    let open = state.gateway.opened();
+
+    tokio::spawn(async move {
+        let client = match body.on_upgrade().await {
+            Ok(client) => client,
+            Err(source) => {
+                warn!("Shard went away during the gateway handshake: {}", source);
+
+                return;
+            }
+        };
+
+        info!("Gateway connection opened");
+
+        match splice(client, upstream, leftover).await {
+            Ok(()) => info!("Gateway connection closed"),
+            Err(source) => debug!("Gateway connection closed: {}", source),
+        }
+
+        drop(open);
+    });
+
+    Ok(response)
+}
+
+/// Open a connection to the gateway, looking its host up through the cache so
+/// shards reconnecting together don't each ask the resolver.
+async fn connect(
+    state: &State,
+    target: &Target,
+    upstream: &UpstreamConfig,
+) -> io::Result<Box<dyn Stream>> {
+    let lookup = state
+        .resolution
+        .lookup(upstream, &target.host, target.port)
+        .await?;
+    let tcp = TcpStream::connect(lookup.addresses.as_slice()).await?;
+    tcp.set_nodelay(true)?;
+
+    if !target.tls {
+        return Ok(Box::new(tcp));
+    }
+
+    let tls = upstream::tls_config(upstream);
+    let domain = DNSNameRef::try_from_ascii_str(&target.host)
+        .map_err(|InvalidDNSNameError| io::Error::new(ErrorKind::InvalidInput, "invalid host"))?;
+    let stream = TlsConnector::from(Arc::new(tls))
+        .connect(domain, tcp)
+        .await?;
+
+    Ok(Box::new(stream))
+}
+
+/// Send the handshake and read Discord's answer, returning it along with any
+/// WebSocket frames that came in right after it.
+async fn handshake(
+    upstream: &mut Box<dyn Stream>,
+    head: &[u8],
+) -> io::Result<(Response<Body>, Vec<u8>)> {
+    upstream.write_all(head).await?;
+
+    let mut buffer = Vec::with_capacity(1024);
+    let mut chunk = [0; 1024];
+
+    loop {
+        let read = upstream.read(&mut chunk).await?;
+
+        if read == 0 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+
+        buffer.extend_from_slice(&chunk[..read]);
+
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut parsed = httparse::Response::new(&mut headers);
+        let length = match parsed.parse(&buffer) {
+            Ok(httparse::Status::Complete(length)) => length,
+            Ok(httparse::Status::Partial) if buffer.len() < MAX_HEAD => continue,
+            Ok(httparse::Status::Partial) => {
+                return Err(io::Error::new(ErrorKind::InvalidData, "handshake too long"))
+            }
+            Err(source) => return Err(io::Error::new(ErrorKind::InvalidData, source)),
+        };
+
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = parsed
+            .code
+            .and_then(|code| StatusCode::from_u16(code).ok())
+            .unwrap_or(StatusCode::BAD_GATEWAY);
+
+        for header in parsed.headers.iter() {
+            if let (Ok(name), Ok(value)) = (
+                header.name.parse::<http::header::HeaderName>(),
+                HeaderValue::from_bytes(header.value),
+            ) {
+                response.headers_mut().append(name, value);
+            }
+        }
+
+        return Ok((response, buffer.split_off(length)));
+    }
+}
+
+/// Copy between the shard and Discord until either side closes.
+async fn splice(client: Upgraded, upstream: Box<dyn Stream>, leftover: Vec<u8>) -> io::Result<()> {
+    let (mut client_read, mut client_write) = tokio::io::split(client);
+    let (mut upstream_read, mut upstream_write) = tokio::io::split(upstream);
+
+    client_write.write_all(&leftover).await?;
+
+    let to_discord = tokio::io::copy(&mut client_read, &mut upstream_write);
+    let to_shard = tokio::io::copy(&mut upstream_read, &mut client_write);
+
+    // Once one side is done the other is dropped, closing both connections.
+    tokio::select! {
+        result = to_discord => result?,
+        result = to_shard => result?,
+    };
+
+    Ok(())
+}
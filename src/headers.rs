@@ -0,0 +1,293 @@
+//! Sanitation of headers passing through the proxy.
+
+use crate::{
+    breaker,
+    config::{Config, ForwardPolicy},
+    idempotency,
+};
+use http::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, CONNECTION, HOST};
+use percent_encoding::percent_decode;
+use std::fmt::Write;
+
+/// Headers describing a single connection rather than the request, which
+/// don't apply to the connection to Discord or back to the client.
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Prefix of the headers clients use to talk to the proxy itself.
+const PROXY_PREFIX: &str = "x-proxy-";
+
+/// Remove hop-by-hop headers, including any listed in `Connection`.
+pub fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let listed = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| name.trim().parse::<HeaderName>().ok())
+        .collect::<Vec<_>>();
+
+    for name in listed {
+        headers.remove(name);
+    }
+
+    for name in HOP_BY_HOP {
+        headers.remove(*name);
+    }
+}
+
+/// Prepare a client's headers for sending to Discord.
+///
+/// Besides hop-by-hop headers, `Host` is left to the HTTP client,
+/// `Authorization` is removed so the configured token is always the one
+/// used, and the headers meant for the proxy never reach Discord whatever
+/// the policy. What else is removed depends on the route family's policy:
+/// either the headers in the denylist, or everything that isn't allowed.
+pub fn sanitize_request(headers: &mut HeaderMap, denylist: &[HeaderName], policy: &ForwardPolicy) {
+    strip_hop_by_hop(headers);
+    headers.remove(HOST);
+    headers.remove(AUTHORIZATION);
+    headers.remove(idempotency::HEADER);
+
+    let proxy_only = headers
+        .keys()
+        .filter(|name| name.as_str().starts_with(PROXY_PREFIX))
+        .cloned()
+        .collect::<Vec<_>>();
+
+    for name in proxy_only {
+        headers.remove(name);
+    }
+
+    match policy {
+        ForwardPolicy::All => {
+            for name in denylist {
+                headers.remove(name);
+            }
+        }
+        ForwardPolicy::Only { headers: allowed } => {
+            let removed = headers
+                .keys()
+                .filter(|name| !allowed.contains(name))
+                .cloned()
+                .collect::<Vec<_>>();
+
+            for name in removed {
+                headers.remove(name);
+            }
+        }
+    }
+}
+
+/// Prepare a client's headers for sending to Discord on the path, under the
+/// policy of its route family.
+pub fn sanitize_for(config: &Config, path_and_query: &str, headers: &mut HeaderMap) {
+    let family = breaker::family(path_and_query);
+    let policy = config
+        .forward_headers
+        .get(family)
+        .unwrap_or(&ForwardPolicy::All);
+
+    sanitize_request(headers, &config.strip_headers, policy);
+}
+
+/// Header holding the reason shown in the guild's audit log.
+pub const AUDIT_LOG_REASON: &str = "x-audit-log-reason";
+
+/// Longest reason Discord accepts, in characters.
+const MAX_REASON_LENGTH: usize = 512;
+
+/// Check the audit log reason and percent-encode anything in it that isn't
+/// ASCII, as Discord requires.
+///
+/// Escapes already in the reason are kept. Returns why the reason was
+/// rejected if it's too long or isn't UTF-8.
+pub fn normalize_audit_log_reason(headers: &mut HeaderMap) -> Result<(), &'static str> {
+    let reason = match headers.get(AUDIT_LOG_REASON) {
+        Some(reason) => reason.as_bytes(),
+        None => return Ok(()),
+    };
+    let decoded = percent_decode(reason)
+        .decode_utf8()
+        .map_err(|_| "audit log reason isn't valid UTF-8")?;
+
+    if decoded.chars().count() > MAX_REASON_LENGTH {
+        return Err("audit log reason is longer than 512 characters");
+    }
+
+    let mut encoded = String::with_capacity(reason.len());
+
+    for (index, byte) in reason.iter().enumerate() {
+        let escape = reason
+            .get(index + 1..index + 3)
+            .is_some_and(|hex| hex.iter().all(u8::is_ascii_hexdigit));
+
+        if byte.is_ascii() && (*byte != b'%' || escape) {
+            encoded.push(char::from(*byte));
+        } else {
+            let _ = write!(encoded, "%{:02X}", byte);
+        }
+    }
+
+    let encoded = HeaderValue::from_str(&encoded).expect("percent-encoded reason is ASCII");
+    headers.insert(AUDIT_LOG_REASON, encoded);
+
+    Ok(())
+}
+
+/// The audit log reason of a request, decoded again after
+/// `normalize_audit_log_reason`.
+pub fn audit_log_reason(headers: &HeaderMap) -> Option<String> {
+    let reason = headers.get(AUDIT_LOG_REASON)?.as_bytes();
+
+    Some(percent_decode(reason).decode_utf8_lossy().into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::breaker::FAMILIES;
+
+    fn client_headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+
+        for (name, value) in &[
+            ("authorization", "Bot stray"),
+            ("host", "proxy.internal"),
+            ("connection", "keep-alive"),
+            ("content-type", "application/json"),
+            ("x-audit-log-reason", "spam"),
+            ("user-agent", "DiscordBot (gearbot, 2)"),
+            ("x-proxy-key", "tenant-key"),
+            ("x-proxy-priority", "high"),
+            ("x-proxy-async", "true"),
+            ("x-proxy-retry-on", "429"),
+            ("idempotency-key", "abc"),
+        ] {
+            headers.insert(*name, HeaderValue::from_static(value));
+        }
+
+        headers
+    }
+
+    #[test]
+    fn default_policies_never_forward_proxy_headers() {
+        for family in FAMILIES {
+            let mut headers = client_headers();
+            sanitize_request(&mut headers, &[], &ForwardPolicy::default_for(family));
+
+            for name in &[
+                "authorization",
+                "host",
+                "connection",
+                "x-proxy-key",
+                "x-proxy-priority",
+                "x-proxy-async",
+                "x-proxy-retry-on",
+                "idempotency-key",
+            ] {
+                assert!(
+                    !headers.contains_key(*name),
+                    "{} forwarded for {}",
+                    name,
+                    family
+                );
+            }
+
+            for name in &["content-type", "x-audit-log-reason"] {
+                assert!(
+                    headers.contains_key(*name),
+                    "{} not forwarded for {}",
+                    name,
+                    family
+                );
+            }
+
+            assert_eq!(
+                headers.contains_key("user-agent"),
+                *family != "webhooks",
+                "user-agent for {}",
+                family
+            );
+        }
+    }
+
+    #[test]
+    fn allowlists_dont_let_proxy_headers_through() {
+        let policy = ForwardPolicy::Only {
+            headers: vec![
+                HeaderName::from_static("x-proxy-priority"),
+                HeaderName::from_static("idempotency-key"),
+                HeaderName::from_static("x-audit-log-reason"),
+            ],
+        };
+        let mut headers = client_headers();
+        sanitize_request(&mut headers, &[], &policy);
+
+        assert_eq!(headers.len(), 1);
+        assert!(headers.contains_key("x-audit-log-reason"));
+    }
+
+    fn normalized(reason: &[u8]) -> Result<String, &'static str> {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUDIT_LOG_REASON, HeaderValue::from_bytes(reason).unwrap());
+        normalize_audit_log_reason(&mut headers)?;
+
+        Ok(headers[AUDIT_LOG_REASON].to_str().unwrap().to_owned())
+    }
+
+    #[test]
+    fn audit_log_reasons_are_percent_encoded() {
+        assert_eq!(normalized(b"spam").unwrap(), "spam");
+        assert_eq!(
+            normalized("caf\u{e9} \u{1f6ab}".as_bytes()).unwrap(),
+            "caf%C3%A9 %F0%9F%9A%AB"
+        );
+        // Escapes already there are kept, stray percent signs aren't.
+        assert_eq!(normalized(b"caf%C3%A9").unwrap(), "caf%C3%A9");
+        assert_eq!(normalized(b"100% sure").unwrap(), "100%25 sure");
+        assert_eq!(normalized(b"50%").unwrap(), "50%25");
+    }
+
+    #[test]
+    fn malformed_audit_log_reasons_are_rejected() {
+        assert!(normalized(b"%FF%FE").is_err());
+        assert!(normalized(&[b'a'; 513]).is_err());
+        assert!(normalized(&[b'a'; 512]).is_ok());
+        // Length is counted in characters, not bytes.
+        assert!(normalized("\u{e9}".repeat(512).as_bytes()).is_ok());
+    }
+
+    #[test]
+    fn audit_log_reasons_are_decoded_for_logs() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            AUDIT_LOG_REASON,
+            HeaderValue::from_bytes("caf\u{e9} 100%".as_bytes()).unwrap(),
+        );
+        normalize_audit_log_reason(&mut headers).unwrap();
+
+        assert_eq!(audit_log_reason(&headers).unwrap(), "caf\u{e9} 100%");
+        assert_eq!(audit_log_reason(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn denylist_is_applied_to_forwarding_everything() {
+        let denylist = [HeaderName::from_static("user-agent")];
+        let mut headers = client_headers();
+        sanitize_request(&mut headers, &denylist, &ForwardPolicy::All);
+
+        assert!(!headers.contains_key("user-agent"));
+        assert!(headers.contains_key("content-type"));
+        assert!(headers.contains_key("x-audit-log-reason"));
+    }
+}
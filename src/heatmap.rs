@@ -0,0 +1,59 @@
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const HOURS: usize = 24;
+
+/// Requests per route by hour of the day (UTC), to find quiet hours for
+/// heavy jobs like mass role syncs.
+#[derive(Debug)]
+pub struct Heatmap {
+    routes: Mutex<HashMap<&'static str, [u64; HOURS]>>,
+    since: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Snapshot {
+    /// When counting started, in seconds since the epoch.
+    since: u64,
+    /// Requests per hour of the day, starting at midnight UTC.
+    routes: BTreeMap<&'static str, [u64; HOURS]>,
+}
+
+impl Heatmap {
+    pub fn new() -> Self {
+        Self {
+            routes: Mutex::default(),
+            since: now(),
+        }
+    }
+
+    pub fn record(&self, route: &'static str) {
+        let hour = (now() / 3600 % HOURS as u64) as usize;
+        let mut routes = self.routes.lock().expect("heatmap poisoned");
+
+        routes.entry(route).or_insert([0; HOURS])[hour] += 1;
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let routes = self.routes.lock().expect("heatmap poisoned");
+
+        Snapshot {
+            since: self.since,
+            routes: routes
+                .iter()
+                .map(|(route, hours)| (*route, *hours))
+                .collect(),
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
@@ -0,0 +1,119 @@
+//! Actions run on lifecycle events, so operators can automate around the
+//! proxy without watching its logs.
+
+use crate::config::{Config, Hook, HookAction, HookEvent};
+use http::header::CONTENT_TYPE;
+use serde::Serialize;
+use std::{error::Error, process::Stdio, time::Duration};
+use tokio::{process::Command, task::JoinHandle, time};
+use tracing::{info, warn};
+
+/// Longest a webhook or command may take before it's given up on.
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Something that happened to the proxy, sent to hooks as JSON.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Startup,
+    Shutdown,
+    CircuitOpen { family: &'static str },
+    CircuitClose { family: &'static str },
+    TokenRotated,
+}
+
+impl Event {
+    fn kind(&self) -> HookEvent {
+        match self {
+            Event::Startup => HookEvent::Startup,
+            Event::Shutdown => HookEvent::Shutdown,
+            Event::CircuitOpen { .. } => HookEvent::CircuitOpen,
+            Event::CircuitClose { .. } => HookEvent::CircuitClose,
+            Event::TokenRotated => HookEvent::TokenRotated,
+        }
+    }
+}
+
+/// Run the hooks bound to the event in the background.
+pub fn fire(config: &Config, event: Event) {
+    spawn(config, &event);
+}
+
+/// Run the hooks bound to the event and wait for them to finish.
+pub async fn run(config: &Config, event: Event) {
+    for hook in spawn(config, &event) {
+        let _ = hook.await;
+    }
+}
+
+fn spawn(config: &Config, event: &Event) -> Vec<JoinHandle<()>> {
+    let kind = event.kind();
+
+    config
+        .hooks
+        .iter()
+        .filter(|hook| hook.events.contains(&kind))
+        .map(|hook| {
+            let hook = hook.clone();
+            let event = event.clone();
+
+            tokio::spawn(async move { execute(&hook, &event).await })
+        })
+        .collect()
+}
+
+async fn execute(hook: &Hook, event: &Event) {
+    let payload = serde_json::to_string(event).expect("events serialize");
+    let result = match &hook.action {
+        HookAction::Log => {
+            info!("Hook {}: {}", hook.name, payload);
+
+            return;
+        }
+        HookAction::Webhook(url) => time::timeout(TIMEOUT, post(url, payload)).await,
+        HookAction::Exec(command) => {
+            time::timeout(TIMEOUT, exec(command, event.kind(), payload)).await
+        }
+    };
+
+    match result {
+        Ok(Ok(())) => {}
+        Ok(Err(why)) => warn!("Hook {} failed: {}", hook.name, why),
+        Err(_) => warn!("Hook {} timed out", hook.name),
+    }
+}
+
+pub async fn post(url: &str, payload: String) -> Result<(), Box<dyn Error + Send + Sync>> {
+    reqwest::Client::new()
+        .post(url)
+        .header(CONTENT_TYPE, "application/json")
+        .body(payload)
+        .send()
+        .await?
+        .error_for_status()?;
+
+    Ok(())
+}
+
+async fn exec(
+    command: &str,
+    kind: HookEvent,
+    payload: String,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    // Killed if it runs into the timeout.
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("PROXY_EVENT", kind.name())
+        .env("PROXY_EVENT_JSON", payload)
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .status()
+        .await?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("command exited with {}", status).into())
+    }
+}
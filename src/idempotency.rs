@@ -0,0 +1,164 @@
+use crate::{config::Tenant, proxy::ProxyResponse};
+use http::{header::HeaderMap, Method, StatusCode};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Header clients set to make retries of a request safe.
+pub const HEADER: &str = "idempotency-key";
+
+/// Header marking a response as replayed from an earlier request.
+pub const REPLAY_HEADER: &str = "x-proxy-idempotent-replay";
+
+/// Most keys remembered at once.
+const CAPACITY: usize = 10_000;
+
+#[derive(Debug)]
+enum Slot {
+    InFlight,
+    Done(ProxyResponse),
+}
+
+#[derive(Debug)]
+struct Entry {
+    method: Method,
+    path_and_query: String,
+    slot: Slot,
+    created_at: Instant,
+}
+
+/// Outcome of presenting an idempotency key.
+#[derive(Debug)]
+pub enum Claim {
+    /// First time the key is seen; the caller runs the request and hands the
+    /// response to the guard.
+    New(Guard),
+    /// The key was used before, this is the response it got.
+    Replay(ProxyResponse),
+    /// A request with the key is still running.
+    InProgress,
+    /// The key was used before for a different request.
+    Mismatch,
+}
+
+/// Recently seen idempotency keys and the responses they got.
+#[derive(Clone, Debug, Default)]
+pub struct Store {
+    entries: Arc<Mutex<HashMap<String, Entry>>>,
+}
+
+impl Store {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Take the key out of the request headers, so it isn't forwarded to
+    /// Discord.
+    pub fn take_key(headers: &mut HeaderMap) -> Option<String> {
+        headers
+            .remove(HEADER)
+            .and_then(|value| value.to_str().ok().map(str::to_owned))
+    }
+
+    /// Claim the key for a request, forgetting keys older than the TTL.
+    pub fn claim(
+        &self,
+        key: String,
+        method: &Method,
+        path_and_query: &str,
+        ttl: Duration,
+    ) -> Claim {
+        let mut entries = self.entries.lock().expect("idempotency store poisoned");
+
+        entries.retain(|_, entry| entry.created_at.elapsed() < ttl);
+
+        if let Some(entry) = entries.get(&key) {
+            if entry.method != method || entry.path_and_query != path_and_query {
+                return Claim::Mismatch;
+            }
+
+            return match &entry.slot {
+                Slot::InFlight => Claim::InProgress,
+                Slot::Done(response) => Claim::Replay(response.clone()),
+            };
+        }
+
+        if entries.len() >= CAPACITY {
+            let oldest = entries
+                .iter()
+                .filter(|(_, entry)| matches!(entry.slot, Slot::Done(_)))
+                .min_by_key(|(_, entry)| entry.created_at)
+                .map(|(key, _)| key.clone());
+
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+
+        entries.insert(
+            key.clone(),
+            Entry {
+                method: method.clone(),
+                path_and_query: path_and_query.to_owned(),
+                slot: Slot::InFlight,
+                created_at: Instant::now(),
+            },
+        );
+
+        Claim::New(Guard {
+            store: self.clone(),
+            key: Some(key),
+        })
+    }
+}
+
+/// Key the store remembers a client's key under.
+///
+/// Keys are chosen by clients, so two tenants can pick the same one; scoping
+/// them keeps one tenant from being handed the response another got.
+pub fn scoped(tenant: Option<&Tenant>, key: String) -> String {
+    match tenant {
+        Some(tenant) => format!("{}\0{}", tenant.name, key),
+        None => format!("\0{}", key),
+    }
+}
+
+/// Reservation of a key for a running request.
+///
+/// If dropped without a response, e.g. because the request errored, the key
+/// is released so the client can retry with it.
+#[derive(Debug)]
+pub struct Guard {
+    store: Store,
+    key: Option<String>,
+}
+
+impl Guard {
+    pub fn complete(mut self, response: &ProxyResponse) {
+        let key = match self.key.take() {
+            Some(key) => key,
+            None => return,
+        };
+        let mut entries = self.store.entries.lock().expect("idempotency store poisoned");
+
+        // Ratelimits and server errors didn't take effect, so retrying them
+        // should actually retry.
+        if response.status == StatusCode::TOO_MANY_REQUESTS || response.status.is_server_error() {
+            entries.remove(&key);
+        } else if let Some(entry) = entries.get_mut(&key) {
+            entry.slot = Slot::Done(response.clone());
+        }
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            if let Ok(mut entries) = self.store.entries.lock() {
+                entries.remove(&key);
+            }
+        }
+    }
+}
@@ -0,0 +1,192 @@
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::watch::{self, Receiver, Sender},
+    time,
+};
+
+/// How long finished jobs are kept around for clients to look up.
+const RETENTION: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    /// Held back until the time the client asked for, in RFC 3339.
+    Scheduled { execute_at: String },
+    Running,
+    Completed { status: u16 },
+    Failed { error: String },
+    Cancelled,
+}
+
+impl JobStatus {
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            JobStatus::Completed { .. } | JobStatus::Failed { .. } | JobStatus::Cancelled
+        )
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Job {
+    pub id: String,
+    #[serde(flatten)]
+    pub status: JobStatus,
+    /// Name of the tenant that sent the request, if it sent a key.
+    #[serde(skip)]
+    pub tenant: Option<String>,
+    #[serde(skip)]
+    finished_at: Option<Instant>,
+}
+
+impl Job {
+    /// Whether the job was sent with the key of the tenant of that name.
+    pub fn is_from(&self, tenant: &str) -> bool {
+        self.tenant.as_deref() == Some(tenant)
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    job: Job,
+    tx: Sender<JobStatus>,
+    rx: Receiver<JobStatus>,
+}
+
+impl Entry {
+    fn set(&mut self, status: JobStatus) {
+        if status.is_finished() {
+            self.job.finished_at.replace(Instant::now());
+        }
+
+        self.job.status = status.clone();
+        // The entry holds a receiver itself, so this can't fail.
+        let _ = self.tx.broadcast(status);
+    }
+}
+
+/// Tracks requests executed away from the connection they came in on.
+#[derive(Clone, Debug, Default)]
+pub struct Jobs {
+    jobs: Arc<Mutex<HashMap<String, Entry>>>,
+}
+
+impl Jobs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a queued job, under a new id or one handed out by a previous
+    /// run.
+    pub fn insert(&self, id: &str, tenant: Option<&str>) {
+        let mut jobs = self.jobs.lock().expect("jobs poisoned");
+
+        jobs.retain(|_, entry| {
+            entry
+                .job
+                .finished_at
+                .is_none_or(|finished_at| finished_at.elapsed() < RETENTION)
+        });
+
+        let (tx, rx) = watch::channel(JobStatus::Queued);
+        jobs.insert(
+            id.to_owned(),
+            Entry {
+                job: Job {
+                    id: id.to_owned(),
+                    status: JobStatus::Queued,
+                    tenant: tenant.map(str::to_owned),
+                    finished_at: None,
+                },
+                tx,
+                rx,
+            },
+        );
+    }
+
+    pub fn set(&self, id: &str, status: JobStatus) {
+        let mut jobs = self.jobs.lock().expect("jobs poisoned");
+
+        if let Some(entry) = jobs.get_mut(id) {
+            entry.set(status);
+        }
+    }
+
+    /// Set the status of a job, unless it was cancelled already.
+    pub fn set_unless_cancelled(&self, id: &str, status: JobStatus) {
+        let mut jobs = self.jobs.lock().expect("jobs poisoned");
+
+        match jobs.get_mut(id) {
+            Some(entry) if matches!(entry.job.status, JobStatus::Cancelled) => {}
+            Some(entry) => entry.set(status),
+            None => {}
+        }
+    }
+
+    /// Mark a job as running, unless it was cancelled while it was waiting.
+    pub fn start(&self, id: &str) -> bool {
+        let mut jobs = self.jobs.lock().expect("jobs poisoned");
+
+        match jobs.get_mut(id) {
+            Some(entry) if matches!(entry.job.status, JobStatus::Cancelled) => false,
+            Some(entry) => {
+                entry.set(JobStatus::Running);
+
+                true
+            }
+            None => true,
+        }
+    }
+
+    /// Cancel a scheduled job, returning whether it was scheduled, or `None`
+    /// if there is no such job.
+    pub fn cancel(&self, id: &str) -> Option<bool> {
+        let mut jobs = self.jobs.lock().expect("jobs poisoned");
+        let entry = jobs.get_mut(id)?;
+        let scheduled = matches!(entry.job.status, JobStatus::Scheduled { .. });
+
+        if scheduled {
+            entry.set(JobStatus::Cancelled);
+        }
+
+        Some(scheduled)
+    }
+
+    pub fn get(&self, id: &str) -> Option<Job> {
+        self.jobs
+            .lock()
+            .expect("jobs poisoned")
+            .get(id)
+            .map(|entry| entry.job.clone())
+    }
+
+    /// Wait for a job to finish, for at most `timeout`.
+    ///
+    /// Returns the job in whatever state it's in once it finished or the
+    /// timeout elapsed, or `None` if there is no such job.
+    pub async fn wait(&self, id: &str, timeout: Duration) -> Option<Job> {
+        let mut rx = self
+            .jobs
+            .lock()
+            .expect("jobs poisoned")
+            .get(id)
+            .map(|entry| entry.rx.clone())?;
+
+        let finished = async {
+            while let Some(status) = rx.recv().await {
+                if status.is_finished() {
+                    break;
+                }
+            }
+        };
+        let _ = time::timeout(timeout, finished).await;
+
+        self.get(id)
+    }
+}
@@ -0,0 +1,804 @@
+mod access_log;
+mod admin;
+mod audit;
+mod batch;
+mod boot;
+mod breaker;
+mod build_info;
+mod cache;
+mod cardinality;
+mod cdn;
+mod check;
+mod cloudflare;
+mod command;
+mod compression;
+mod config;
+mod digest;
+mod distinct;
+mod drill;
+mod emulated_ratelimits;
+mod error;
+mod exporters;
+mod faults;
+mod forwarded;
+mod gateway;
+mod headers;
+mod heatmap;
+mod hooks;
+mod idempotency;
+mod jobs;
+mod lifecycle;
+mod limits;
+mod listener;
+mod log_file;
+mod load;
+mod maintenance;
+mod mirror;
+mod mock;
+mod proxy;
+mod queue;
+mod ratelimits;
+mod readiness;
+mod reload;
+mod replay;
+mod request_id;
+mod response_cache;
+mod retry;
+mod runtime;
+mod snapshot;
+mod spool;
+mod tenant;
+mod trace;
+mod unknown_routes;
+mod upstream;
+
+use log_file::LogFile;
+use breaker::Breakers;
+pub use config::Config;
+use digest::Digest;
+use distinct::Distinct;
+use drill::Drill;
+use emulated_ratelimits::EmulatedRatelimits;
+use exporters::Scraper;
+use faults::Faults;
+use error::{Binding, ChunkingRequest, InvalidHeader};
+pub use error::{ListenError, RequestError};
+use forwarded::Origin;
+use heatmap::Heatmap;
+use hooks::Event;
+use http::{
+    header::{HeaderMap, HeaderValue, LOCATION},
+    request::Parts,
+    Method, StatusCode, Uri,
+};
+use jobs::Jobs;
+use lifecycle::Lifecycle;
+use limits::Limits;
+pub use listener::Peer;
+use load::Load;
+use maintenance::Maintenance;
+use mock::Mock;
+use cache::Cache;
+use command::Command;
+use cdn::Cdn;
+use proxy::{Checked, Dispatch, ProxyRequest, ProxyResponse};
+use queue::Queue;
+use ratelimits::Ratelimits;
+use request_id::RequestIds;
+use spool::Spool;
+use tenant::Identity;
+use upstream::{Resolution, Rotation, Upstream};
+use trace::TraceParent;
+use serde::Serialize;
+use hyper::{
+    body::{Body, Bytes},
+    Request, Response,
+};
+use snafu::ResultExt;
+use std::{
+    env,
+    error::Error,
+    future::{self, Future},
+    pin::Pin,
+    sync::{Arc, RwLock},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use metrics::counter;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::{mpsc, Semaphore},
+    task::JoinHandle,
+};
+use tower_service::Service;
+use tracing::{debug, error, info, info_span, warn};
+use tracing_futures::Instrument;
+use tracing_log::LogTracer;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{fmt, reload::Handle, EnvFilter, Registry};
+use twilight_http::routing::Path;
+
+/// Shared state handed to every request.
+pub struct State {
+    /// Replaced as a whole when the configuration is reloaded.
+    pub config: RwLock<Arc<Config>>,
+    /// Replaced when the token changes.
+    pub client: RwLock<Arc<Upstream>>,
+    pub rotation: Rotation,
+    pub resolution: Resolution,
+    /// Missing when embedded without the proxy's own logging.
+    pub log_filter: Option<Handle<EnvFilter, Registry>>,
+    pub queue: Queue,
+    pub ratelimits: Ratelimits,
+    pub jobs: Jobs,
+    pub spool: Option<Spool>,
+    /// Set when requests are answered from fixtures instead of Discord.
+    pub mock: Option<Mock>,
+    /// Ratelimits of the dry runs and mocked responses.
+    pub emulated_ratelimits: EmulatedRatelimits,
+    pub cdn: Option<Cdn>,
+    /// Responses to `GET` requests on the routes in `CACHE_ROUTES`.
+    pub response_cache: Cache,
+    pub access_log: Option<LogFile>,
+    pub audit_log: Option<LogFile>,
+    pub idempotency: idempotency::Store,
+    pub breakers: Breakers,
+    pub load: Load,
+    /// Slots for requests on slow routes, so they can't crowd out fast ones.
+    pub slow_routes: Semaphore,
+    pub limits: Limits,
+    pub distinct: Distinct,
+    pub heatmap: Heatmap,
+    pub digest: Digest,
+    pub maintenance: Maintenance,
+    pub drill: Drill,
+    pub faults: Faults,
+    pub gateway: Arc<gateway::Connections>,
+    pub unknown_routes: unknown_routes::Pacer,
+    pub unknown_paths: unknown_routes::Seen,
+    pub request_ids: RequestIds,
+    /// Set when Prometheus metrics are served on the proxy's own listeners.
+    pub scraper: Option<Scraper>,
+    pub started: Instant,
+}
+
+impl State {
+    /// Configuration as of the last reload.
+    pub fn config(&self) -> Arc<Config> {
+        Arc::clone(&self.config.read().expect("config poisoned"))
+    }
+
+    pub fn client(&self) -> Arc<Upstream> {
+        Arc::clone(&self.client.read().expect("client poisoned"))
+    }
+}
+
+/// Body of the `202 Accepted` reply to a request run in the background.
+#[derive(Serialize)]
+struct Accepted {
+    id: String,
+    /// How long the request was expected to wait in the queue, when it was
+    /// run in the background because of that.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    estimated_wait_ms: Option<u64>,
+    /// When the request will run, if it was scheduled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    execute_at: Option<String>,
+}
+
+/// Run the proxy with the configuration from the environment until it's
+/// shut down, or the command given on the command line instead.
+pub async fn run() -> Result<(), Box<dyn Error>> {
+    // Parsed before anything else, so `--help` works without a configuration.
+    let command = Command::parse(env::args().skip(1))
+        .map_err(|why| format!("{}\n\n{}", why, command::USAGE.trim_end()))?;
+
+    match command {
+        Some(Command::Help) => {
+            print!("{}", command::USAGE);
+
+            return Ok(());
+        }
+        Some(Command::Version) => {
+            println!("twilight-http-proxy {}", build_info::VERSION);
+
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    LogTracer::init()?;
+
+    let log_filter_layer =
+        EnvFilter::try_from_default_env().or_else(|_| EnvFilter::try_new("info"))?;
+    let (log_filter_layer, log_filter) = tracing_subscriber::reload::Layer::new(log_filter_layer);
+    let log_fmt_layer = fmt::layer();
+
+    let log_subscriber = tracing_subscriber::registry()
+        .with(log_filter_layer)
+        .with(log_fmt_layer);
+
+    tracing::subscriber::set_global_default(log_subscriber)?;
+
+    let config = Config::load()?;
+
+    if let Some(level) = &config.log_level {
+        log_filter.reload(EnvFilter::new(level))?;
+    }
+
+    if let Some(command) = command {
+        return run_command(&config, command).await;
+    }
+
+    // Made up and mocked responses don't need a valid token.
+    if config.verify_token && !config.dry_run && !config.mock.enabled {
+        upstream::verify_token(&upstream::client(&config.token, &config.upstream)?).await?;
+    }
+
+    let proxy = Proxy::start(config, Some(log_filter)).await?;
+    let state = &proxy.state;
+    let mut servers = proxy.listen()?;
+
+    if let Some(redirect) = &state.config().redirect {
+        for address in &redirect.listen {
+            let listener = listener::bind_tcp(*address).context(Binding {
+                listener: format!("http://{}", address),
+            })?;
+            let server = listener::serve_redirect(*address, listener, redirect.url.clone());
+            servers.push(tokio::spawn(server));
+        }
+    }
+
+    // Any listener stopping stops the proxy, rather than leaving it running
+    // on the others without a word.
+    let (stopped, mut any_stopped) = mpsc::unbounded_channel();
+
+    for server in servers {
+        let stopped = stopped.clone();
+        tokio::spawn(async move {
+            let _ = stopped.send(server.await);
+        });
+    }
+
+    tokio::select! {
+        Some(result) = any_stopped.recv() => {
+            result?;
+
+            return Err("A listener stopped accepting connections".into());
+        }
+        () = shutdown_signal() => {
+            info!("Shutting down");
+            proxy.shutdown().await;
+        }
+    }
+
+    Ok(())
+}
+
+/// A running proxy, without any listeners of its own until [`listen`] is
+/// called.
+///
+/// The proxy's own server is just one way of handing it requests; another
+/// server can embed it through its [`service`], wrapped in whatever
+/// middleware it needs.
+///
+/// [`listen`]: Proxy::listen
+/// [`service`]: Proxy::service
+pub struct Proxy {
+    state: Arc<State>,
+    lifecycle: Lifecycle,
+}
+
+impl Proxy {
+    /// Set up the proxy and start its background tasks.
+    ///
+    /// The log filter is changed when the configuration is reloaded, if
+    /// given.
+    pub async fn start(
+        config: Config,
+        log_filter: Option<Handle<EnvFilter, Registry>>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let scraper = exporters::install(&config.metrics, exporters::from_config(&config.metrics))?;
+
+        let spool = match config.queue_dir.as_ref() {
+            Some(dir) => Some(Spool::open(dir).await?),
+            None => None,
+        };
+
+        let mock = if config.mock.enabled {
+            Some(Mock::load(config.mock.fixtures.as_deref()).await?)
+        } else {
+            None
+        };
+
+        let cdn = if config.cdn.enabled {
+            Some(Cdn::open(&config.cdn, &config.upstream).await?)
+        } else {
+            None
+        };
+
+        let access_log = match config.access_log.as_ref() {
+            Some(access_log) => Some(LogFile::open(access_log).await?),
+            None => None,
+        };
+
+        let audit_log = match config.audit.file.as_ref() {
+            Some(audit_log) => Some(LogFile::open(audit_log).await?),
+            None => None,
+        };
+
+        let state = Arc::new(State {
+            client: RwLock::new(Arc::new(upstream::client(&config.token, &config.upstream)?)),
+            rotation: Rotation::new(),
+            resolution: Resolution::new(),
+            log_filter,
+            queue: Queue::new(),
+            ratelimits: Ratelimits::new(),
+            jobs: Jobs::new(),
+            spool,
+            mock,
+            emulated_ratelimits: EmulatedRatelimits::new(),
+            cdn,
+            response_cache: Cache::open("api", None, config.cache.size).await?,
+            access_log,
+            audit_log,
+            idempotency: idempotency::Store::new(),
+            breakers: Breakers::new(),
+            load: Load::new(),
+            slow_routes: Semaphore::new(config.slow_routes.concurrency),
+            limits: Limits::new(&config),
+            distinct: Distinct::new(),
+            heatmap: Heatmap::new(),
+            digest: Digest::new(),
+            maintenance: Maintenance::new(),
+            drill: Drill::new(),
+            faults: Faults::new(),
+            gateway: Arc::new(gateway::Connections::new()),
+            unknown_routes: unknown_routes::Pacer::new(),
+            unknown_paths: unknown_routes::Seen::new(),
+            request_ids: RequestIds::new(config.seed),
+            scraper,
+            started: Instant::now(),
+            config: RwLock::new(Arc::new(config)),
+        });
+
+        let mut lifecycle = Lifecycle::new();
+
+        if state.access_log.is_some() || state.audit_log.is_some() {
+            let state = Arc::clone(&state);
+
+            lifecycle.started("log files", Duration::from_secs(5), async move {
+                for log in state.access_log.iter().chain(&state.audit_log) {
+                    log.close().await;
+                }
+            });
+        }
+
+        boot::log(&state);
+
+        tokio::spawn(distinct::report(Arc::clone(&state)));
+        tokio::spawn(unknown_routes::report(Arc::clone(&state)));
+        tokio::spawn(digest::report(Arc::clone(&state)));
+        tokio::spawn(ratelimits::report(Arc::clone(&state)));
+        tokio::spawn(build_info::report(Arc::clone(&state)));
+
+        if state.config().metrics.runtime {
+            tokio::spawn(runtime::report());
+        }
+
+        tokio::spawn(upstream::rotate(Arc::clone(&state)));
+        tokio::spawn(upstream::re_resolve(Arc::clone(&state)));
+        tokio::spawn(maintenance::watch_signal(Arc::clone(&state)));
+        tokio::spawn(reload::watch_signal(Arc::clone(&state)));
+        tokio::spawn(reload::watch_token_file(Arc::clone(&state)));
+        spool::replay(Arc::clone(&state)).await?;
+
+        {
+            let state = Arc::clone(&state);
+
+            lifecycle.started("requests", state.config().shutdown_timeout, async move {
+                // New requests are turned away while the ones already
+                // accepted, including background ones, finish.
+                state
+                    .maintenance
+                    .enable(Some(Bytes::from_static(SHUTDOWN_BODY)), &state.config());
+                state.load.drain().await;
+            });
+        }
+
+        hooks::fire(&state.config(), Event::Startup);
+
+        {
+            let state = Arc::clone(&state);
+
+            lifecycle.started("hooks", Duration::from_secs(30), async move {
+                hooks::run(&state.config(), Event::Shutdown).await;
+            });
+        }
+
+        Ok(Self { state, lifecycle })
+    }
+
+    /// Service handling requests as the proxy's own listeners do.
+    pub fn service(&self) -> ProxyService {
+        ProxyService {
+            state: Arc::clone(&self.state),
+        }
+    }
+
+    /// Accept connections on the configured listeners, until the returned
+    /// tasks fail.
+    ///
+    /// Every listener is bound before any is served, failing if one of them
+    /// can't be. They're all served by the same state, so requests share
+    /// ratelimits no matter which one they came in on.
+    pub fn listen(&self) -> Result<Vec<JoinHandle<()>>, ListenError> {
+        let bound = self
+            .state
+            .config()
+            .listen
+            .iter()
+            .map(listener::bind)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(bound
+            .into_iter()
+            .map(|bound| tokio::spawn(listener::serve(Arc::clone(&self.state), bound)))
+            .collect())
+    }
+
+    /// Finish the requests in flight and stop the background tasks.
+    pub async fn shutdown(self) {
+        self.lifecycle.shutdown().await;
+    }
+}
+
+/// The proxy as a tower `Service`.
+///
+/// Requests are expected to have their [`Peer`] in their extensions, without
+/// which tenants and admin tokens still work but trusted proxies don't.
+#[derive(Clone)]
+pub struct ProxyService {
+    state: Arc<State>,
+}
+
+impl Service<Request<Body>> for ProxyService {
+    type Response = Response<Body>;
+    type Error = RequestError;
+    type Future = Pin<Box<dyn Future<Output = Result<Response<Body>, RequestError>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), RequestError>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        Box::pin(handle_request(Arc::clone(&self.state), request))
+    }
+}
+
+/// Run a command given on the command line instead of starting the proxy.
+async fn run_command(config: &Config, command: Command) -> Result<(), Box<dyn Error>> {
+    match command {
+        Command::Check => check::command(config)?,
+        Command::Replay { file, target } => replay::command(config, &file, &target).await?,
+        Command::ExportState { file } => {
+            snapshot::export_command(&spool(config).await?, file).await?
+        }
+        Command::ImportState { file } => {
+            let count = snapshot::import_command(&spool(config).await?, file).await?;
+            info!("Imported {} background requests", count);
+        }
+        // Answered before the configuration is loaded.
+        Command::Help | Command::Version => {}
+    }
+
+    Ok(())
+}
+
+/// The background requests in `QUEUE_DIR`, for exporting or importing them.
+async fn spool(config: &Config) -> Result<Spool, Box<dyn Error>> {
+    let dir = config
+        .queue_dir
+        .as_ref()
+        .ok_or("QUEUE_DIR has to be set to export or import state")?;
+
+    Ok(Spool::open(dir).await?)
+}
+
+/// Body of responses to requests made while shutting down.
+const SHUTDOWN_BODY: &[u8] = br#"{"message":"the proxy is shutting down, try again later"}"#;
+
+/// Wait for `SIGTERM` or `SIGINT`.
+async fn shutdown_signal() {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(source) => {
+            error!("Failed to listen for SIGTERM: {}", source);
+
+            return future::pending().await;
+        }
+    };
+
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+}
+
+/// The route of a request for the URI, and the path and query it would be
+/// sent to Discord with, worked out as for requests from clients.
+///
+/// For the fuzz targets in `fuzz/`.
+#[doc(hidden)]
+pub fn route_request(
+    method: Method,
+    uri: &Uri,
+    passthrough_unknown: bool,
+) -> Result<(&'static str, String), RequestError> {
+    let request =
+        ProxyRequest::from_parts(method, uri, HeaderMap::new(), Bytes::new(), passthrough_unknown)?;
+
+    Ok((request.route, request.path_and_query))
+}
+
+pub fn path_name(path: &Path) -> &'static str {
+    match path {
+        &unknown_routes::BUCKET => unknown_routes::ROUTE,
+        Path::ChannelsId(..)=> "Channel",
+        Path::ChannelsIdInvites(..)=> "Channel invite",
+        Path::ChannelsIdMessages(..)=> "Channel message",
+        Path::ChannelsIdMessagesBulkDelete(..)=> "Bulk delete message",
+        Path::ChannelsIdMessagesId(..)=> "Channel message",
+        Path::ChannelsIdMessagesIdReactions(..)=> "Message reaction",
+        Path::ChannelsIdMessagesIdReactionsUserIdType(..)=> "Message reaction for user",
+        Path::ChannelsIdPermissionsOverwriteId(..)=> "Channel permission override",
+        Path::ChannelsIdPins(..)=> "Channel pins",
+        Path::ChannelsIdPinsMessageId(..)=> "Specific channel pin",
+        Path::ChannelsIdTyping(..)=> "Typing indicator",
+        Path::ChannelsIdWebhooks(..)=> "Webhook",
+        Path::Gateway=> "Gateway",
+        Path::GatewayBot=> "Gateway bot info",
+        Path::Guilds=> "Guilds",
+        Path::GuildsId(..)=> "Guild",
+        Path::GuildsIdBans(..)=> "Guild bans",
+        Path::GuildsIdAuditLogs(..)=> "Guild audit logs",
+        Path::GuildsIdBansUserId(..)=> "Guild ban for user",
+        Path::GuildsIdChannels(..)=> "Guild channel",
+        Path::GuildsIdWidget(..)=> "Guild widget",
+        Path::GuildsIdEmojis(..)=> "Guild emoji",
+        Path::GuildsIdEmojisId(..)=> "Specific guild emoji",
+        Path::GuildsIdIntegrations(..)=> "Guild integrations",
+        Path::GuildsIdIntegrationsId(..)=> "Specific guild integration",
+        Path::GuildsIdIntegrationsIdSync(..)=> "Sync guild integration",
+        Path::GuildsIdInvites(..)=> "Guild invites",
+        Path::GuildsIdMembers(..)=> "Guild members",
+        Path::GuildsIdMembersId(..)=> "Specific guild member",
+        Path::GuildsIdMembersIdRolesId(..)=> "Guild member role",
+        Path::GuildsIdMembersMeNick(..)=> "Modify own nickname",
+        Path::GuildsIdPreview(..)=> "Guild preview",
+        Path::GuildsIdPrune(..)=> "Guild prune",
+        Path::GuildsIdRegions(..)=> "Guild region",
+        Path::GuildsIdRoles(..)=> "Guild roles",
+        Path::GuildsIdRolesId(..)=> "Specific guild role",
+        Path::GuildsIdVanityUrl(..)=> "Guild vanity invite",
+        Path::GuildsIdWebhooks(..)=> "Guild webhooks",
+        Path::InvitesCode=> "Invite info",
+        Path::UsersId=> "User info",
+        Path::UsersIdConnections=> "User connections",
+        Path::UsersIdChannels=> "User channels",
+        Path::UsersIdGuilds=> "User in guild",
+        Path::UsersIdGuildsId=> "Guild from user",
+        Path::VoiceRegions=> "Voice region list",
+        Path::WebhooksId(..)=> "Webhook",
+        Path::OauthApplicationsMe => "Current application info",
+        _ => "Unknown path!"
+    }
+}
+
+async fn handle_request(
+    state: Arc<State>,
+    mut request: Request<Body>,
+) -> Result<Response<Body>, RequestError> {
+    let id = state.request_ids.take(request.headers_mut());
+    let span_id = state.request_ids.next_span();
+    let trace = match TraceParent::from_headers(request.headers()) {
+        Some(parent) => parent.child(span_id),
+        None => {
+            request.headers_mut().remove(trace::TRACESTATE);
+            // Generated request ids are valid trace ids, so they can be
+            // looked up either way.
+            let trace_id = if trace::is_trace_id(&id) {
+                id.clone()
+            } else {
+                state.request_ids.next()
+            };
+
+            TraceParent::root(trace_id, span_id)
+        }
+    };
+
+    // Requests sent on to Discord are children of this span.
+    request
+        .headers_mut()
+        .insert(trace::TRACEPARENT, trace.to_header());
+
+    let span = info_span!(
+        "request",
+        id = %id,
+        trace_id = %trace.trace_id,
+        span_id = %trace.parent_id,
+    );
+    let mut response = handle(state, request).instrument(span).await?;
+
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(request_id::HEADER, value);
+    }
+
+    response
+        .headers_mut()
+        .insert(trace::TRACERESPONSE, trace.to_header());
+
+    Ok(response)
+}
+
+async fn handle(state: Arc<State>, request: Request<Body>) -> Result<Response<Body>, RequestError> {
+    debug!("Incoming request: {:?}", request);
+
+    if let Some(scraper) = state
+        .scraper
+        .as_ref()
+        .filter(|scraper| scraper.handles(request.uri().path()))
+    {
+        let peer = match request.extensions().get::<Peer>() {
+            Some(Peer::Tcp(address)) => Some(*address),
+            _ => None,
+        };
+
+        return Ok(scraper.scrape(peer, request.headers()));
+    }
+
+    if admin::is_admin_path(request.uri().path()) {
+        return admin::handle(state, request).await;
+    }
+
+    if let Some(response) = state.maintenance.response() {
+        return response.into_response();
+    }
+
+    if state.config().gateway.enabled && gateway::is_upgrade(&request) {
+        return gateway::proxy(state, request).await;
+    }
+
+    if let Some(cdn) = state
+        .cdn
+        .as_ref()
+        .filter(|_| request.uri().path().starts_with(cdn::PREFIX))
+    {
+        return cdn
+            .handle(&state.config().cdn, request.method(), request.uri())
+            .await;
+    }
+
+    let peer = request.extensions().get::<Peer>().copied();
+    let (parts, body) = request.into_parts();
+    let origin = Origin::of(&state.config().trusted_proxies, &parts);
+    debug!("Request reached the proxy at {}", origin);
+
+    let Parts {
+        method,
+        uri,
+        mut headers,
+        ..
+    } = parts;
+
+    let body = hyper::body::to_bytes(body).await.context(ChunkingRequest)?;
+
+    if let Some(declared) = proxy::length_mismatch(&headers, body.len()) {
+        warn!(
+            "Request declared a length of {:?} but had {} bytes",
+            declared,
+            body.len()
+        );
+        counter!("gearbot_proxy_length_mismatches", 1, "direction" => "request");
+
+        return admin::error(
+            StatusCode::BAD_REQUEST,
+            "Content-Length doesn't match the length of the body",
+        );
+    }
+
+    let config = state.config();
+    let tenant = match tenant::identify(&config, &mut headers) {
+        Identity::Anonymous => None,
+        Identity::Tenant(tenant) => Some(tenant),
+        Identity::Unknown => return admin::error(StatusCode::UNAUTHORIZED, "unknown API key"),
+    };
+    let body = match tenant {
+        Some(tenant) if tenant.minify_json => tenant::minify(tenant, &headers, body),
+        _ => body,
+    };
+
+    if let Err(message) = headers::normalize_audit_log_reason(&mut headers) {
+        return admin::error(StatusCode::BAD_REQUEST, message);
+    }
+
+    let encoding = compression::take_accepted(&mut headers);
+    let dispatch = proxy::take_dispatch(&mut headers);
+    let execute_at = match proxy::take_execute_at(&mut headers) {
+        Ok(execute_at) => execute_at,
+        Err(message) => return admin::error(StatusCode::BAD_REQUEST, message),
+    };
+    let idempotency_key = idempotency::Store::take_key(&mut headers)
+        .map(|key| idempotency::scoped(tenant, key));
+    let passthrough_unknown = config.unknown_routes.passthrough;
+    let mut request = match ProxyRequest::from_parts(
+        method.clone(),
+        &uri,
+        headers,
+        body,
+        passthrough_unknown,
+    ) {
+        Ok(request) => request,
+        Err(RequestError::TraversingPath { .. }) => {
+            return admin::error(StatusCode::BAD_REQUEST, "path climbs above the API root")
+        }
+        Err(source) => {
+            if let RequestError::InvalidPath { .. } = source {
+                state.unknown_paths.record(&method, uri.path());
+            }
+
+            return Err(source);
+        }
+    };
+
+    if request.route == unknown_routes::ROUTE {
+        state.unknown_paths.record(&method, uri.path());
+    }
+
+    request.tenant = tenant.map(|tenant| tenant.name.clone());
+    request.peer = peer;
+    request.execute_at = execute_at;
+    request.accept_encoding = encoding;
+
+    let guard = match proxy::check(&state, &request, idempotency_key).await? {
+        Checked::Answered(response) => return response.into_response(),
+        Checked::Forward(guard) => guard,
+    };
+
+    let estimated_wait = match dispatch {
+        Dispatch::Auto => Some(state.queue.estimate(&request.path, request.priority))
+            .filter(|wait| *wait > config.async_wait_threshold),
+        _ => None,
+    };
+
+    let mut response = if dispatch == Dispatch::Background
+        || estimated_wait.is_some()
+        || execute_at.is_some()
+    {
+        let id = proxy::forward_in_background(Arc::clone(&state), request).await?;
+        let location = origin.url(&format!("{}jobs/{}", admin::PREFIX, id));
+        let accepted = Accepted {
+            id,
+            estimated_wait_ms: estimated_wait.map(|wait| wait.as_millis() as u64),
+            execute_at: execute_at
+                .map(|execute_at| humantime::format_rfc3339_seconds(execute_at).to_string()),
+        };
+
+        let mut response = ProxyResponse::json(StatusCode::ACCEPTED, &accepted)?;
+        response
+            .headers
+            .insert(LOCATION, HeaderValue::from_str(&location).context(InvalidHeader)?);
+
+        response
+    } else {
+        response_cache::forward(&state, request).await?
+    };
+
+    if let Some(guard) = guard {
+        guard.complete(&response);
+    }
+
+    compression::compress(&mut response, encoding, &config.compression);
+
+    response.into_response()
+}
@@ -0,0 +1,63 @@
+//! Stopping the proxy's subsystems in order when it shuts down.
+
+use std::{
+    future::Future,
+    pin::Pin,
+    time::{Duration, Instant},
+};
+use tokio::time;
+use tracing::{info, warn};
+
+type Stop = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+struct Subsystem {
+    name: &'static str,
+    timeout: Duration,
+    stop: Stop,
+}
+
+/// Subsystems of the proxy in the order they were started.
+///
+/// They're stopped in reverse, so nothing is stopped before what depends on
+/// it, and each gets a timeout of its own, so one that hangs can't keep the
+/// rest from stopping before the container is killed.
+#[derive(Default)]
+pub struct Lifecycle {
+    subsystems: Vec<Subsystem>,
+}
+
+impl Lifecycle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a subsystem that was just started, with what to run to stop
+    /// it.
+    pub fn started(
+        &mut self,
+        name: &'static str,
+        timeout: Duration,
+        stop: impl Future<Output = ()> + Send + 'static,
+    ) {
+        self.subsystems.push(Subsystem {
+            name,
+            timeout,
+            stop: Box::pin(stop),
+        });
+    }
+
+    pub async fn shutdown(self) {
+        for subsystem in self.subsystems.into_iter().rev() {
+            let start = Instant::now();
+            info!("Stopping {}", subsystem.name);
+
+            match time::timeout(subsystem.timeout, subsystem.stop).await {
+                Ok(()) => info!("Stopped {} in {:?}", subsystem.name, start.elapsed()),
+                Err(_) => warn!(
+                    "Gave up on stopping {} after {:?}",
+                    subsystem.name, subsystem.timeout
+                ),
+            }
+        }
+    }
+}
@@ -0,0 +1,337 @@
+use crate::config::Config;
+use hyper::server::accept::Accept;
+use metrics::{counter, gauge};
+use std::{
+    future::Future,
+    io::Result as IoResult,
+    mem::MaybeUninit,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::{self, Delay, Instant},
+};
+
+type Acquire = Pin<Box<dyn Future<Output = OwnedSemaphorePermit> + Send>>;
+
+/// Connections and requests clients have open, capped by `MAX_CONNECTIONS`
+/// and `MAX_REQUESTS`, and how long connections are kept open for.
+#[derive(Debug)]
+pub struct Limits {
+    connections: Option<Arc<Semaphore>>,
+    requests: Option<Arc<Semaphore>>,
+    open: Arc<AtomicUsize>,
+    idle_timeout: Option<Duration>,
+    max_requests: usize,
+}
+
+impl Limits {
+    pub fn new(config: &Config) -> Self {
+        let semaphore = |max| match max {
+            0 => None,
+            max => Some(Arc::new(Semaphore::new(max))),
+        };
+
+        Self {
+            connections: semaphore(config.limits.max_connections),
+            requests: semaphore(config.limits.max_requests),
+            open: Arc::default(),
+            idle_timeout: config.keep_alive.idle_timeout,
+            max_requests: config.keep_alive.max_requests,
+        }
+    }
+
+    /// Stop accepting connections from the acceptor while `MAX_CONNECTIONS`
+    /// are open, leaving new ones waiting in the listen backlog instead of
+    /// running out of file descriptors.
+    pub fn accept<A: Accept>(&self, inner: A) -> Limited<A> {
+        Limited {
+            inner: Box::pin(inner),
+            connections: self.connections.clone(),
+            acquiring: None,
+            permit: None,
+            open: Arc::clone(&self.open),
+            idle_timeout: self.idle_timeout,
+            max_requests: self.max_requests,
+        }
+    }
+
+    /// A slot for a request, or `None` if `MAX_REQUESTS` are already being
+    /// handled.
+    pub fn request(&self) -> Option<Slot> {
+        let requests = match &self.requests {
+            Some(requests) => requests,
+            None => return Some(Slot { _permit: None }),
+        };
+
+        match Arc::clone(requests).try_acquire_owned() {
+            Ok(permit) => Some(Slot {
+                _permit: Some(permit),
+            }),
+            Err(_) => {
+                counter!("gearbot_proxy_requests_over_limit", 1);
+
+                None
+            }
+        }
+    }
+}
+
+/// Room for a request, held until it's answered.
+pub struct Slot {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+/// Accepts connections only while there's room for them.
+pub struct Limited<A> {
+    inner: Pin<Box<A>>,
+    connections: Option<Arc<Semaphore>>,
+    acquiring: Option<Acquire>,
+    /// Taken before accepting, so the connection that's accepted next has
+    /// its slot.
+    permit: Option<OwnedSemaphorePermit>,
+    open: Arc<AtomicUsize>,
+    idle_timeout: Option<Duration>,
+    max_requests: usize,
+}
+
+impl<A: Accept> Accept for Limited<A> {
+    type Conn = Counted<A::Conn>;
+    type Error = A::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        let this = self.get_mut();
+
+        if let (Some(connections), None) = (&this.connections, &this.permit) {
+            let acquiring = this
+                .acquiring
+                .get_or_insert_with(|| Box::pin(Arc::clone(connections).acquire_owned()));
+
+            match acquiring.as_mut().poll(cx) {
+                Poll::Ready(permit) => {
+                    this.acquiring = None;
+                    this.permit = Some(permit);
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        match this.inner.as_mut().poll_accept(cx) {
+            Poll::Ready(Some(Ok(connection))) => {
+                let open = this.open.fetch_add(1, Ordering::Relaxed) + 1;
+                gauge!("gearbot_proxy_connections", open as i64);
+
+                Poll::Ready(Some(Ok(Counted {
+                    inner: connection,
+                    _permit: this.permit.take(),
+                    open: Arc::clone(&this.open),
+                    activity: Arc::new(Activity {
+                        in_flight: AtomicUsize::new(0),
+                        served: AtomicUsize::new(0),
+                        max_requests: this.max_requests,
+                    }),
+                    idle: this.idle_timeout.map(Idle::new),
+                })))
+            }
+            Poll::Ready(Some(Err(why))) => Poll::Ready(Some(Err(why))),
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Requests on a connection, shared with its service.
+#[derive(Debug)]
+pub struct Activity {
+    in_flight: AtomicUsize,
+    served: AtomicUsize,
+    max_requests: usize,
+}
+
+impl Activity {
+    /// Count a request as being handled until the returned guard is dropped.
+    pub fn start(self: &Arc<Self>) -> Busy {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        let served = self.served.fetch_add(1, Ordering::Relaxed) + 1;
+
+        Busy {
+            activity: Arc::clone(self),
+            last: self.max_requests != 0 && served >= self.max_requests,
+        }
+    }
+}
+
+/// A request being handled on a connection.
+pub struct Busy {
+    activity: Arc<Activity>,
+    last: bool,
+}
+
+impl Busy {
+    /// Whether the connection should be closed after answering the request,
+    /// having reached `KEEP_ALIVE_MAX_REQUESTS`.
+    pub fn is_last(&self) -> bool {
+        self.last
+    }
+}
+
+impl Drop for Busy {
+    fn drop(&mut self) {
+        self.activity.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// When a connection was last read from or written to, to close it once it
+/// has been idle for `KEEP_ALIVE_TIMEOUT`.
+struct Idle {
+    timeout: Duration,
+    last_active: Instant,
+    delay: Delay,
+    expired: bool,
+}
+
+impl Idle {
+    fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            last_active: Instant::now(),
+            delay: time::delay_for(timeout),
+            expired: false,
+        }
+    }
+}
+
+/// An accepted connection, counted until dropped, and read as closed by the
+/// client once it's been idle for too long.
+pub struct Counted<C> {
+    inner: C,
+    _permit: Option<OwnedSemaphorePermit>,
+    open: Arc<AtomicUsize>,
+    activity: Arc<Activity>,
+    idle: Option<Idle>,
+}
+
+impl<C> Counted<C> {
+    pub fn get_ref(&self) -> &C {
+        &self.inner
+    }
+
+    pub fn activity(&self) -> Arc<Activity> {
+        Arc::clone(&self.activity)
+    }
+
+    fn active(&mut self) {
+        if let Some(idle) = &mut self.idle {
+            idle.last_active = Instant::now();
+        }
+    }
+
+    /// Whether the connection has gone without requests for too long, waking
+    /// the task when it will have otherwise.
+    ///
+    /// The timer is only moved when it fires, rather than on every read and
+    /// write.
+    fn poll_idle(&mut self, cx: &mut Context<'_>) -> bool {
+        let idle = match &mut self.idle {
+            Some(idle) => idle,
+            None => return false,
+        };
+
+        if idle.expired {
+            return true;
+        }
+
+        // Requests can take long without anything being read or written, like
+        // when waiting on a ratelimit.
+        if self.activity.in_flight.load(Ordering::Relaxed) > 0 {
+            return false;
+        }
+
+        let deadline = idle.last_active + idle.timeout;
+
+        if deadline <= Instant::now() {
+            counter!("gearbot_proxy_idle_connections_closed", 1);
+            idle.expired = true;
+
+            return true;
+        }
+
+        if idle.delay.deadline() != deadline {
+            idle.delay.reset(deadline);
+        }
+
+        Pin::new(&mut idle.delay).poll(cx).is_ready()
+    }
+}
+
+impl<C> Drop for Counted<C> {
+    fn drop(&mut self) {
+        let open = self.open.fetch_sub(1, Ordering::Relaxed) - 1;
+        gauge!("gearbot_proxy_connections", open as i64);
+    }
+}
+
+impl<C: AsyncRead + Unpin> AsyncRead for Counted<C> {
+    unsafe fn prepare_uninitialized_buffer(&self, buf: &mut [MaybeUninit<u8>]) -> bool {
+        self.inner.prepare_uninitialized_buffer(buf)
+    }
+
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<IoResult<usize>> {
+        let this = self.get_mut();
+
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Pending => {}
+            ready => {
+                this.active();
+
+                return ready;
+            }
+        }
+
+        // Reading nothing has the connection closed as if by the client.
+        if this.poll_idle(cx) {
+            Poll::Ready(Ok(0))
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl<C: AsyncWrite + Unpin> AsyncWrite for Counted<C> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<IoResult<usize>> {
+        let this = self.get_mut();
+        let written = Pin::new(&mut this.inner).poll_write(cx, buf);
+
+        if written.is_ready() {
+            this.active();
+        }
+
+        written
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,419 @@
+use crate::{
+    admin,
+    config::{Http2Config, Listener},
+    drill::Fault,
+    error::{Binding, DroppedByDrill, ListenError, RequestError},
+    handle_request,
+    limits::{Activity, Counted},
+    proxy, State,
+};
+use http::{
+    header::{CONNECTION, HOST, LOCATION},
+    HeaderValue, StatusCode, Version,
+};
+use hyper::{
+    body::Body,
+    server::{
+        accept::{self, Accept},
+        Builder, Server,
+    },
+    service, Request, Response,
+};
+use snafu::ResultExt;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    convert::Infallible,
+    error::Error,
+    fmt::{Display, Formatter, Result as FmtResult},
+    future::{self, Future},
+    io::{self, ErrorKind},
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+    time::{self, Delay},
+};
+use tracing::{debug, error, info};
+
+/// Connections waiting to be accepted before new ones are refused, the same
+/// as the standard library's.
+const BACKLOG: i32 = 128;
+
+/// How long to wait before accepting again after failing to.
+const ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Who a request came in from, stored in its extensions.
+#[derive(Clone, Copy, Debug)]
+pub enum Peer {
+    Tcp(IpAddr),
+    Unix,
+}
+
+impl Display for Peer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Peer::Tcp(address) => address.fmt(f),
+            Peer::Unix => f.write_str("unix"),
+        }
+    }
+}
+
+/// Handle a request, closing the connection after it if it was the last one
+/// allowed on it.
+///
+/// HTTP/2 connections are left open, as they can't be asked to close through
+/// a header.
+async fn handle(
+    state: Arc<State>,
+    incoming: Request<Body>,
+    host: HeaderValue,
+    activity: Arc<Activity>,
+) -> Result<Response<Body>, RequestError> {
+    let busy = activity.start();
+    let version = incoming.version();
+    let mut response = respond(state, incoming, host).await?;
+
+    if busy.is_last() && version != Version::HTTP_2 {
+        response
+            .headers_mut()
+            .insert(CONNECTION, HeaderValue::from_static("close"));
+    }
+
+    Ok(response)
+}
+
+/// Respond to a request, filling in what HTTP/1.0 clients leave out, unless a
+/// drill has it fail or `MAX_REQUESTS` are already being handled.
+///
+/// HTTP/1.0 clients don't have to send a `Host`, so the listener's own address stands in
+/// for it, and their connections are closed after each response, as older
+/// clients often get persistent connections wrong even when asking for them.
+async fn respond(
+    state: Arc<State>,
+    mut incoming: Request<Body>,
+    host: HeaderValue,
+) -> Result<Response<Body>, RequestError> {
+    // Admin requests get through regardless, so an overloaded proxy can
+    // still be looked into.
+    let _slot = if admin::is_admin_path(incoming.uri().path()) {
+        None
+    } else {
+        match state.limits.request() {
+            Some(slot) => Some(slot),
+            None => return proxy::backpressure(1)?.into_response(),
+        }
+    };
+
+    if !admin::is_admin_path(incoming.uri().path()) {
+        match state.drill.fault() {
+            // Failing the service makes hyper close the connection without
+            // answering.
+            Some(Fault::Drop) => return DroppedByDrill.fail(),
+            Some(Fault::Respond(response)) => return response.into_response(),
+            None => {}
+        }
+    }
+
+    if incoming.version() != Version::HTTP_10 {
+        return handle_request(state, incoming).await;
+    }
+
+    debug!("Handling an HTTP/1.0 request");
+    incoming.headers_mut().entry(HOST).or_insert(host);
+
+    // Hyper only closes HTTP/1.0 connections after responses of the same
+    // version.
+    let mut response = handle_request(state, incoming).await?;
+    *response.version_mut() = Version::HTTP_10;
+    response
+        .headers_mut()
+        .insert(CONNECTION, HeaderValue::from_static("close"));
+
+    Ok(response)
+}
+
+/// A listener bound to its address, ready to accept connections.
+pub enum Bound {
+    Tcp(SocketAddr, TcpListener),
+    Unix(PathBuf, UnixListener),
+}
+
+/// Bind the listener, so it fails before anything is served if the address
+/// can't be listened on.
+pub fn bind(listener: &Listener) -> Result<Bound, ListenError> {
+    let bound = match listener {
+        Listener::Tcp(address) => bind_tcp(*address).map(|bound| Bound::Tcp(*address, bound)),
+        Listener::Unix(path) => bind_unix(path).map(|bound| Bound::Unix(path.clone(), bound)),
+    };
+
+    bound.context(Binding {
+        listener: listener.to_string(),
+    })
+}
+
+/// Bind a TCP listener, only for IPv6 if the address is an IPv6 one, so the
+/// same port can be listened on for IPv4 as well. Linux otherwise takes `[::]`
+/// to cover both.
+pub fn bind_tcp(address: SocketAddr) -> io::Result<TcpListener> {
+    let domain = if address.is_ipv4() {
+        Domain::ipv4()
+    } else {
+        Domain::ipv6()
+    };
+    let socket = Socket::new(domain, Type::stream(), Some(Protocol::tcp()))?;
+
+    if address.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+
+    // Like the standard library does, so a restarted proxy doesn't have to
+    // wait for connections of the last run to time out.
+    socket.set_reuse_address(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(BACKLOG)?;
+
+    TcpListener::from_std(socket.into_tcp_listener())
+}
+
+fn bind_unix(path: &Path) -> io::Result<UnixListener> {
+    // A socket left over from a previous run would make binding fail.
+    match std::fs::remove_file(path) {
+        Err(source) if source.kind() != ErrorKind::NotFound => return Err(source),
+        _ => {}
+    }
+
+    UnixListener::bind(path)
+}
+
+/// Accept connections on the listener until the server fails.
+pub async fn serve(state: Arc<State>, bound: Bound) {
+    let result = match bound {
+        Bound::Tcp(address, listener) => serve_tcp(state, address, listener).await,
+        Bound::Unix(path, listener) => serve_unix(state, &path, listener).await,
+    };
+
+    if let Err(why) = result {
+        error!("Fatal server error: {}", why);
+    }
+}
+
+/// Answer every request with a permanent redirect to its path under the URL,
+/// for clients still connecting over plain HTTP.
+pub async fn serve_redirect(address: SocketAddr, listener: TcpListener, url: String) {
+    let target = Arc::new(url.clone());
+    let service = service::make_service_fn(move |_: &TcpStream| {
+        let url = Arc::clone(&target);
+        async move {
+            Ok::<_, Infallible>(service::service_fn(move |incoming: Request<Body>| {
+                future::ready(Ok::<_, Infallible>(redirect(&url, &incoming)))
+            }))
+        }
+    });
+
+    let server = Server::builder(Incoming::new(listener, false)).serve(service);
+
+    info!("Redirecting http://{} to {}", address, url);
+
+    if let Err(why) = server.await {
+        error!("Fatal server error on http://{}: {}", address, why);
+    }
+}
+
+fn redirect(url: &str, request: &Request<Body>) -> Response<Body> {
+    let path = request
+        .uri()
+        .path_and_query()
+        .map_or("/", |path| path.as_str());
+
+    Response::builder()
+        .status(StatusCode::PERMANENT_REDIRECT)
+        .header(LOCATION, format!("{}{}", url, path))
+        .body(Body::empty())
+        .expect("URL and path are valid in a header")
+}
+
+/// Apply the HTTP/2 settings to a server.
+///
+/// Connections are HTTP/1.1 unless they open with the HTTP/2 preface, so
+/// plaintext HTTP/2 works for clients with prior knowledge. Upgrading an
+/// HTTP/1.1 connection with `Upgrade: h2c` isn't supported.
+fn configure<I>(builder: Builder<I>, config: &Http2Config) -> Builder<I> {
+    if !config.enabled {
+        return builder.http1_only(true);
+    }
+
+    builder
+        .http2_max_concurrent_streams(config.max_streams)
+        .http2_keep_alive_interval(config.keep_alive)
+}
+
+async fn serve_tcp(
+    state: Arc<State>,
+    address: SocketAddr,
+    listener: TcpListener,
+) -> Result<(), Box<dyn Error>> {
+    let http2 = state.config().http2;
+    let host = HeaderValue::from_str(&address.to_string())?;
+    let incoming = Incoming::new(listener, state.config().keep_alive.nodelay);
+    let incoming = state.limits.accept(incoming);
+
+    // The closure inside `make_service_fn` is run for each connection,
+    // creating a 'service' to handle requests for that specific connection.
+    let service = service::make_service_fn(move |stream: &Counted<TcpStream>| {
+        let activity = stream.activity();
+        let remote = stream.get_ref().peer_addr();
+        debug!("Connection from: {:?}", remote);
+        let state = Arc::clone(&state);
+        let host = host.clone();
+        async move {
+            // Only fails once the client is gone, which drops the connection.
+            let peer = Peer::Tcp(remote?.ip());
+
+            Ok::<_, io::Error>(service::service_fn(move |mut incoming: Request<Body>| {
+                incoming.extensions_mut().insert(peer);
+                handle(
+                    Arc::clone(&state),
+                    incoming,
+                    host.clone(),
+                    Arc::clone(&activity),
+                )
+            }))
+        }
+    });
+
+    let server = configure(Server::builder(incoming), &http2).serve(service);
+
+    info!("Listening on http://{}", address);
+    server.await?;
+
+    Ok(())
+}
+
+async fn serve_unix(
+    state: Arc<State>,
+    path: &Path,
+    mut listener: UnixListener,
+) -> Result<(), Box<dyn Error>> {
+    let http2 = state.config().http2;
+    let incoming = state.limits.accept(accept::from_stream(listener.incoming()));
+
+    let service = service::make_service_fn(move |stream: &Counted<UnixStream>| {
+        debug!("Connection from: {:?}", stream.get_ref().peer_addr());
+        let activity = stream.activity();
+        let state = Arc::clone(&state);
+        async move {
+            Ok::<_, RequestError>(service::service_fn(move |mut incoming: Request<Body>| {
+                incoming.extensions_mut().insert(Peer::Unix);
+                handle(
+                    Arc::clone(&state),
+                    incoming,
+                    HeaderValue::from_static("localhost"),
+                    Arc::clone(&activity),
+                )
+            }))
+        }
+    });
+
+    let server = configure(Server::builder(incoming), &http2).serve(service);
+
+    info!("Listening on unix:{}", path.display());
+    server.await?;
+
+    Ok(())
+}
+
+/// Connections accepted on a TCP listener.
+///
+/// Stands in for hyper's own `AddrIncoming`, which can only bind by itself:
+/// failing to accept a connection, like when out of file descriptors, is
+/// logged and waited out rather than stopping the server.
+struct Incoming {
+    listener: TcpListener,
+    nodelay: bool,
+    /// Set after failing to accept, until accepting is tried again.
+    backoff: Option<Delay>,
+}
+
+impl Incoming {
+    fn new(listener: TcpListener, nodelay: bool) -> Self {
+        Self {
+            listener,
+            nodelay,
+            backoff: None,
+        }
+    }
+}
+
+impl Accept for Incoming {
+    type Conn = TcpStream;
+    type Error = io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        let this = self.get_mut();
+
+        if let Some(backoff) = &mut this.backoff {
+            match Pin::new(backoff).poll(cx) {
+                Poll::Ready(()) => this.backoff = None,
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        loop {
+            match this.listener.poll_accept(cx) {
+                Poll::Ready(Ok((stream, _))) => {
+                    if let Err(why) = stream.set_nodelay(this.nodelay) {
+                        debug!("Failed to set TCP_NODELAY: {}", why);
+                    }
+
+                    return Poll::Ready(Some(Ok(stream)));
+                }
+                // The client gave up on the connection before it was
+                // accepted.
+                Poll::Ready(Err(why))
+                    if [
+                        ErrorKind::ConnectionAborted,
+                        ErrorKind::ConnectionRefused,
+                        ErrorKind::ConnectionReset,
+                    ]
+                    .contains(&why.kind()) =>
+                {
+                    debug!("Failed to accept a connection: {}", why);
+                }
+                Poll::Ready(Err(why)) => {
+                    error!("Failed to accept a connection: {}", why);
+                    let mut backoff = time::delay_for(ACCEPT_BACKOFF);
+
+                    if Pin::new(&mut backoff).poll(cx).is_pending() {
+                        this.backoff = Some(backoff);
+
+                        return Poll::Pending;
+                    }
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::bind_tcp;
+    use std::net::SocketAddr;
+
+    #[tokio::test]
+    async fn binds_both_families_to_a_port() {
+        let v4 = bind_tcp(([0, 0, 0, 0], 0).into()).expect("failed to bind IPv4");
+        let port = v4.local_addr().unwrap().port();
+
+        let v6: SocketAddr = format!("[::]:{}", port).parse().unwrap();
+        bind_tcp(v6).expect("failed to bind IPv6 next to IPv4");
+    }
+}
@@ -0,0 +1,119 @@
+use crate::{config::SheddingConfig, queue::Priority};
+use metrics::gauge;
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::time;
+
+/// Requests currently being handled and the size of their buffered bodies.
+///
+/// Once either passes its threshold, low priority requests are shed so a
+/// traffic spike doesn't take the whole process down.
+#[derive(Debug, Default)]
+pub struct Load {
+    counters: Arc<Counters>,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    in_flight: AtomicUsize,
+    queued_bytes: AtomicUsize,
+}
+
+impl Load {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether a request with the given priority should be turned away.
+    ///
+    /// Past the start of shedding, a share of low priority requests growing
+    /// with the load is turned away, so load levels off before reaching the
+    /// limits instead of every request being rejected at once.
+    pub fn should_shed(&self, priority: Priority, config: &SheddingConfig) -> bool {
+        priority == Priority::Low && rand::random::<f64>() < self.shed_ratio(config)
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.counters.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// Wait until no request is being handled anymore.
+    pub async fn drain(&self) {
+        while self.in_flight() > 0 {
+            time::delay_for(Duration::from_millis(50)).await;
+        }
+    }
+
+    /// Share of low priority requests to turn away.
+    fn shed_ratio(&self, config: &SheddingConfig) -> f64 {
+        let pressure = self.pressure(config);
+        let start = f64::from(config.start_percent) / 100.0;
+
+        if pressure >= 1.0 {
+            1.0
+        } else if pressure < start {
+            0.0
+        } else {
+            (pressure - start) / (1.0 - start)
+        }
+    }
+
+    /// How close the load is to the nearest limit, `1.0` being at it.
+    fn pressure(&self, config: &SheddingConfig) -> f64 {
+        let ratio = |max: usize, counter: &AtomicUsize| {
+            if max == 0 {
+                0.0
+            } else {
+                counter.load(Ordering::Relaxed) as f64 / max as f64
+            }
+        };
+
+        ratio(config.max_in_flight, &self.counters.in_flight)
+            .max(ratio(config.max_queued_bytes, &self.counters.queued_bytes))
+    }
+
+    /// Count a request until the returned guard is dropped.
+    pub fn track(&self, bytes: usize) -> Tracked {
+        let in_flight = self.counters.in_flight.fetch_add(1, Ordering::Relaxed) + 1;
+        let queued_bytes = self
+            .counters
+            .queued_bytes
+            .fetch_add(bytes, Ordering::Relaxed)
+            + bytes;
+        report(in_flight, queued_bytes);
+
+        Tracked {
+            counters: Arc::clone(&self.counters),
+            bytes,
+        }
+    }
+}
+
+/// A request counted towards the load.
+#[derive(Debug)]
+pub struct Tracked {
+    counters: Arc<Counters>,
+    bytes: usize,
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        let in_flight = self.counters.in_flight.fetch_sub(1, Ordering::Relaxed) - 1;
+        let queued_bytes = self
+            .counters
+            .queued_bytes
+            .fetch_sub(self.bytes, Ordering::Relaxed)
+            - self.bytes;
+        report(in_flight, queued_bytes);
+    }
+}
+
+fn report(in_flight: usize, queued_bytes: usize) {
+    gauge!("gearbot_proxy_in_flight", in_flight as i64);
+    gauge!("gearbot_proxy_queued_bytes", queued_bytes as i64);
+}
@@ -0,0 +1,131 @@
+//! Files the proxy appends lines to, rotated by size and age.
+
+use crate::config::LogFileConfig;
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Instant,
+};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::AsyncWriteExt,
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    task::JoinHandle,
+};
+use tracing::{error, warn};
+
+/// Handle to the task writing a file.
+#[derive(Debug)]
+pub struct LogFile {
+    /// Gone once the file is closed.
+    lines: Mutex<Option<UnboundedSender<String>>>,
+    writer: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl LogFile {
+    /// Open the file, creating it if needed, and start writing to it.
+    pub async fn open(config: &LogFileConfig) -> io::Result<Self> {
+        let file = append(&config.path).await?;
+        let size = file.metadata().await?.len();
+        let (lines, receiver) = mpsc::unbounded_channel();
+        let writer = tokio::spawn(write(config.clone(), file, size, receiver));
+
+        Ok(Self {
+            lines: Mutex::new(Some(lines)),
+            writer: Mutex::new(Some(writer)),
+        })
+    }
+
+    /// Queue a line to be written, which has to end in a newline.
+    pub fn write_line(&self, line: String) {
+        if let Some(lines) = &*self.lines.lock().expect("log file poisoned") {
+            // The writer only stops when the sender is dropped.
+            let _ = lines.send(line);
+        }
+    }
+
+    /// Stop taking new lines and wait for the ones already taken to be
+    /// written.
+    pub async fn close(&self) {
+        self.lines.lock().expect("log file poisoned").take();
+        let writer = self.writer.lock().expect("log file poisoned").take();
+
+        if let Some(writer) = writer {
+            let _ = writer.await;
+        }
+    }
+}
+
+async fn append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path).await
+}
+
+async fn write(
+    config: LogFileConfig,
+    mut file: File,
+    mut size: u64,
+    mut lines: UnboundedReceiver<String>,
+) {
+    let mut opened_at = Instant::now();
+
+    while let Some(line) = lines.recv().await {
+        let too_big = config.max_size != 0 && size + line.len() as u64 > config.max_size;
+        let too_old = config
+            .rotate_interval
+            .is_some_and(|interval| opened_at.elapsed() >= interval);
+
+        if (too_big && size > 0) || too_old {
+            if let Err(source) = file.flush().await {
+                error!("Failed to write to {:?}: {}", config.path, source);
+            }
+
+            match rotate(&config).await {
+                Ok(rotated) => {
+                    file = rotated;
+                    size = 0;
+                    opened_at = Instant::now();
+                }
+                // Better to keep writing to a file that's too long than to
+                // lose lines.
+                Err(source) => warn!("Failed to rotate {:?}: {}", config.path, source),
+            }
+        }
+
+        match file.write_all(line.as_bytes()).await {
+            Ok(()) => size += line.len() as u64,
+            Err(source) => error!("Failed to write to {:?}: {}", config.path, source),
+        }
+    }
+
+    if let Err(source) = file.flush().await {
+        error!("Failed to write to {:?}: {}", config.path, source);
+    }
+}
+
+/// Shift the rotated files along, dropping the oldest, and start a new one.
+async fn rotate(config: &LogFileConfig) -> io::Result<File> {
+    if config.keep == 0 {
+        fs::remove_file(&config.path).await?;
+    } else {
+        for index in (1..config.keep).rev() {
+            let from = rotated(&config.path, index);
+
+            if fs::metadata(&from).await.is_ok() {
+                fs::rename(&from, rotated(&config.path, index + 1)).await?;
+            }
+        }
+
+        fs::rename(&config.path, rotated(&config.path, 1)).await?;
+    }
+
+    append(&config.path).await
+}
+
+/// Path of the file rotated `index` times ago, e.g. `access.log.1`.
+fn rotated(path: &Path, index: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", index));
+
+    PathBuf::from(name)
+}
@@ -0,0 +1,82 @@
+use crate::{config::Config, proxy::ProxyResponse, State};
+use http::{
+    header::{HeaderMap, HeaderValue, CONTENT_TYPE, RETRY_AFTER},
+    StatusCode,
+};
+use hyper::body::Bytes;
+use std::sync::{Arc, Mutex};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info, warn};
+
+/// Switch making the proxy turn away new requests, e.g. while Discord is down
+/// for planned maintenance or the token is being rotated.
+///
+/// Requests already in flight are left to finish.
+#[derive(Debug, Default)]
+pub struct Maintenance {
+    /// Body new requests get while in maintenance mode.
+    body: Mutex<Option<Bytes>>,
+}
+
+const DEFAULT_BODY: &[u8] = br#"{"message":"the proxy is down for maintenance, try again later"}"#;
+
+impl Maintenance {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Turn maintenance mode on, with the given body or the configured one.
+    pub fn enable(&self, body: Option<Bytes>, config: &Config) {
+        let body = body.unwrap_or_else(|| match &config.maintenance_body {
+            Some(body) => Bytes::from(body.clone()),
+            None => Bytes::from_static(DEFAULT_BODY),
+        });
+        *self.body.lock().expect("maintenance poisoned") = Some(body);
+
+        warn!("Entering maintenance mode");
+    }
+
+    pub fn disable(&self) {
+        *self.body.lock().expect("maintenance poisoned") = None;
+
+        info!("Leaving maintenance mode");
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.body.lock().expect("maintenance poisoned").is_some()
+    }
+
+    /// Response for new requests, if in maintenance mode.
+    pub fn response(&self) -> Option<ProxyResponse> {
+        let body = self.body.lock().expect("maintenance poisoned").clone()?;
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        headers.insert(RETRY_AFTER, HeaderValue::from(60));
+
+        Some(ProxyResponse {
+            status: StatusCode::SERVICE_UNAVAILABLE,
+            headers,
+            body,
+        })
+    }
+}
+
+/// Toggle maintenance mode whenever the process receives `SIGUSR1`.
+pub async fn watch_signal(state: Arc<State>) {
+    let mut signals = match signal(SignalKind::user_defined1()) {
+        Ok(signals) => signals,
+        Err(source) => {
+            error!("Failed to listen for SIGUSR1: {}", source);
+
+            return;
+        }
+    };
+
+    while signals.recv().await.is_some() {
+        if state.maintenance.is_enabled() {
+            state.maintenance.disable();
+        } else {
+            state.maintenance.enable(None, &state.config());
+        }
+    }
+}
@@ -0,0 +1,62 @@
+//! Copies of requests sent elsewhere, without the client waiting on them.
+
+use crate::{config::MirrorConfig, headers, proxy::ProxyRequest};
+use http::{header::AUTHORIZATION, Method};
+use metrics::{counter, timing};
+use std::time::{Duration, Instant};
+use tokio::time;
+use tracing::{debug, info};
+
+/// Longest a mirrored request may take before it's given up on.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Send a copy of the request to the mirror, if it's read-only and picked.
+pub fn mirror(config: &MirrorConfig, request: &ProxyRequest) {
+    let read_only = request.method == Method::GET || request.method == Method::HEAD;
+
+    if !read_only
+        || config.percent == 0
+        || rand::random::<f64>() * 100.0 >= f64::from(config.percent)
+    {
+        return;
+    }
+
+    let route = request.route;
+    counter!("gearbot_proxy_mirrored", 1, "route" => route);
+
+    let url = match &config.url {
+        Some(url) => format!("{}/{}", url, request.path_and_query),
+        None => {
+            info!("Mirrored {} {}", request.method, request.path_and_query);
+
+            return;
+        }
+    };
+
+    let mut request_headers = request.headers.clone();
+    headers::strip_hop_by_hop(&mut request_headers);
+    // The mirror has its own token, if it needs one.
+    request_headers.remove(AUTHORIZATION);
+
+    let method = request.method.clone();
+
+    tokio::spawn(async move {
+        let start = Instant::now();
+        let send = reqwest::Client::new()
+            .request(method, &url)
+            .headers(request_headers)
+            .send();
+
+        let status = match time::timeout(TIMEOUT, send).await {
+            Ok(Ok(response)) => response.status().as_u16().to_string(),
+            Ok(Err(source)) => {
+                debug!("Mirrored request to {} failed: {}", url, source);
+
+                "error".to_owned()
+            }
+            Err(_) => "timeout".to_owned(),
+        };
+
+        timing!("gearbot_proxy_mirror_latency", start, Instant::now(), "route" => route, "status" => status);
+    });
+}
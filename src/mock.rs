@@ -0,0 +1,188 @@
+//! Canned responses standing in for Discord, so bots can be tested against
+//! the real proxy without reaching Discord.
+
+use http::{
+    header::{HeaderName, HeaderValue, CONTENT_TYPE},
+    Response, StatusCode,
+};
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    path::Path,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use tokio::fs;
+use tracing::{info, warn};
+
+/// One response in a fixture file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Fixture {
+    #[serde(default = "ok")]
+    status: u16,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    /// Sent as JSON, or not at all if missing.
+    #[serde(default)]
+    body: Option<serde_json::Value>,
+}
+
+fn ok() -> u16 {
+    200
+}
+
+/// A fixture file holds either a single response or several answered in
+/// turn, the last one repeating once they run out.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Fixtures {
+    One(Fixture),
+    Many(Vec<Fixture>),
+}
+
+#[derive(Debug)]
+struct Responses {
+    fixtures: Vec<Fixture>,
+    served: AtomicUsize,
+}
+
+/// Fixtures by route, read once at startup.
+///
+/// Files are named after the route, lowercased with anything but letters and
+/// digits replaced by `_`, e.g. `channel_message.json`. A file with the method
+/// added, e.g. `channel_message.delete.json`, takes precedence for requests
+/// with that method.
+#[derive(Debug, Default)]
+pub struct Mock {
+    responses: HashMap<String, Responses>,
+}
+
+impl Mock {
+    pub async fn load(dir: Option<&Path>) -> io::Result<Self> {
+        let mut mock = Self::default();
+        let dir = match dir {
+            Some(dir) => dir,
+            None => return Ok(mock),
+        };
+        let mut entries = fs::read_dir(dir).await?;
+
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+
+            let key = match path.file_stem().and_then(|stem| stem.to_str()) {
+                Some(stem) => stem.to_ascii_lowercase(),
+                None => continue,
+            };
+            let fixtures = match serde_json::from_slice(&fs::read(&path).await?) {
+                Ok(Fixtures::One(fixture)) => vec![fixture],
+                Ok(Fixtures::Many(fixtures)) if !fixtures.is_empty() => fixtures,
+                Ok(Fixtures::Many(_)) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("fixture {:?} has no responses", path),
+                    ))
+                }
+                Err(source) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("fixture {:?} is invalid: {}", path, source),
+                    ))
+                }
+            };
+
+            mock.responses.insert(
+                key,
+                Responses {
+                    fixtures,
+                    served: AtomicUsize::new(0),
+                },
+            );
+        }
+
+        info!("Loaded {} mock fixtures", mock.responses.len());
+
+        Ok(mock)
+    }
+
+    /// Response to a request for the route, or a `404` if there's no fixture
+    /// for it.
+    pub fn respond(&self, method: &str, route: &str) -> Response<Vec<u8>> {
+        let key = slug(route);
+        let responses = self
+            .responses
+            .get(&format!("{}.{}", key, method.to_ascii_lowercase()))
+            .or_else(|| self.responses.get(&key));
+
+        let responses = match responses {
+            Some(responses) => responses,
+            None => {
+                warn!("No mock fixture for {} {} ({}.json)", method, route, key);
+
+                return json(
+                    StatusCode::NOT_FOUND,
+                    &serde_json::json!({
+                        "message": format!("No fixture for {}", route),
+                        "code": 0,
+                    }),
+                );
+            }
+        };
+
+        let served = responses.served.fetch_add(1, Ordering::Relaxed);
+        let fixture = &responses.fixtures[served.min(responses.fixtures.len() - 1)];
+        let status = StatusCode::from_u16(fixture.status).unwrap_or(StatusCode::OK);
+
+        let mut response = match &fixture.body {
+            Some(body) => json(status, body),
+            None => {
+                let mut response = Response::new(Vec::new());
+                *response.status_mut() = status;
+
+                response
+            }
+        };
+
+        for (name, value) in &fixture.headers {
+            match (
+                HeaderName::from_bytes(name.as_bytes()),
+                HeaderValue::from_str(value),
+            ) {
+                (Ok(name), Ok(value)) => {
+                    response.headers_mut().insert(name, value);
+                }
+                _ => warn!("Skipping invalid mock header {:?} for {}", name, route),
+            }
+        }
+
+        response
+    }
+}
+
+fn json(status: StatusCode, value: &serde_json::Value) -> Response<Vec<u8>> {
+    let mut response = Response::new(serde_json::to_vec(value).unwrap_or_default());
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+
+    response
+}
+
+/// Name of the fixture file for a route, without the extension.
+fn slug(route: &str) -> String {
+    route
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
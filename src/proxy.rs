@@ -0,0 +1,1172 @@
+use crate::{
+    access_log::Entry,
+    admin::ErrorBody,
+    audit,
+    breaker::{self, Admission, Transition},
+    cloudflare,
+    compression::{self, Encoding},
+    config::HeaderLogConfig,
+    headers,
+    hooks::{self, Event},
+    idempotency::{self, Claim, Guard},
+    error::{
+        ChunkingResponse, InvalidPath, MakingResponseBody, RequestError, RequestIssue,
+        SerializingJson, Spooling, TraversingPath,
+    },
+    jobs::JobStatus,
+    listener::Peer,
+    mirror,
+    queue::{Priority, Ticket},
+    response_cache, retry,
+    spool::Record,
+    trace::{self, TraceParent},
+    unknown_routes,
+    upstream::Upstream,
+    State,
+};
+use http::{
+    header::{
+        HeaderMap, HeaderValue, ACCEPT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, RETRY_AFTER,
+        SET_COOKIE,
+    },
+    Method, StatusCode, Uri,
+};
+use hyper::{
+    body::{Body, Bytes},
+    Response,
+};
+use metrics::{counter, timing};
+use serde::Serialize;
+use snafu::ResultExt;
+use std::{
+    convert::TryFrom,
+    mem,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::time;
+use tracing::{debug, error, info, info_span, warn};
+use tracing_futures::Instrument;
+use twilight_http::{
+    error::Error as TwilightError, request::Request as TwilightRequest, routing::{Path, PathParseError},
+};
+
+/// Header asking the proxy to run the request in the background and reply
+/// with `202 Accepted` straight away.
+pub const ASYNC_HEADER: &str = "x-proxy-async";
+
+/// Header asking the proxy to go through the motions without sending the
+/// request to Discord, also set on the responses to such requests.
+pub const DRY_RUN_HEADER: &str = "x-proxy-dry-run";
+
+/// Header asking the proxy to run the request in the background at a given
+/// time, in RFC 3339.
+pub const EXECUTE_AT_HEADER: &str = "x-proxy-execute-at";
+
+/// Header asking the proxy to run the request in the background after a
+/// delay, like `10m`.
+pub const DELAY_HEADER: &str = "x-proxy-delay";
+
+/// Response header with how long Discord took to answer, in milliseconds.
+pub const UPSTREAM_TIME_HEADER: &str = "x-proxy-upstream-time";
+
+/// Response header with how long the request waited in the queue and on its
+/// ratelimit before being sent, in milliseconds.
+pub const QUEUE_TIME_HEADER: &str = "x-proxy-queue-time";
+
+/// Response header with how long the proxy took in all, including retries,
+/// in milliseconds.
+pub const TOTAL_TIME_HEADER: &str = "x-proxy-total-time";
+
+/// Headers about how long a response took, which don't apply to a cached
+/// copy of it.
+pub const TIMING_HEADERS: &[&str] = &[UPSTREAM_TIME_HEADER, QUEUE_TIME_HEADER, TOTAL_TIME_HEADER];
+
+/// Furthest ahead a request can be scheduled.
+const MAX_SCHEDULE_AHEAD: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// A request to Discord, fully buffered so it can be executed away from the
+/// connection it came in on.
+#[derive(Clone, Debug)]
+pub struct ProxyRequest {
+    pub method: Method,
+    pub path: Path,
+    pub path_and_query: String,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+    pub priority: Priority,
+    pub retry: retry::Override,
+    pub route: &'static str,
+    /// Name of the tenant that sent the request, if it sent a key.
+    pub tenant: Option<String>,
+    /// Who sent the request, unless it was replayed from the spool.
+    pub peer: Option<Peer>,
+    /// Whether to answer with a made up response instead of sending the
+    /// request to Discord.
+    pub dry_run: bool,
+    /// When to run the request, if it was scheduled.
+    pub execute_at: Option<SystemTime>,
+    /// Encoding the client accepts, in which Discord's response can be passed
+    /// through compressed.
+    pub accept_encoding: Option<Encoding>,
+}
+
+impl ProxyRequest {
+    /// Build a request from what a client sent, working out which route it is
+    /// for.
+    ///
+    /// Paths of routes that aren't known fail, unless `passthrough_unknown` is
+    /// set and they look like they're for Discord's API.
+    pub fn from_parts(
+        method: Method,
+        uri: &Uri,
+        mut headers: HeaderMap,
+        body: Bytes,
+        passthrough_unknown: bool,
+    ) -> Result<Self, RequestError> {
+        let (trimmed_path, path_and_query) = match split_uri(uri) {
+            Some(parts) => parts,
+            None => {
+                counter!("gearbot_proxy_invalid_paths", 1, "reason" => "traversal");
+
+                return TraversingPath { path: uri.path() }.fail();
+            }
+        };
+        let path = match parse_path(&method, &trimmed_path).context(InvalidPath) {
+            Ok(path) => path,
+            Err(_) if passthrough_unknown && unknown_routes::is_api_path(uri.path()) => {
+                debug!("Passing through {}, an unknown route", trimmed_path);
+                counter!("gearbot_proxy_unknown_routes", 1);
+
+                unknown_routes::BUCKET
+            }
+            Err(e) => {
+                error!("Error determining path for {}: {:?}", trimmed_path, e);
+                counter!("gearbot_proxy_invalid_paths", 1, "reason" => "unknown");
+                return Err(e);
+            }
+        };
+
+        // The body is sent as buffered, so its length is worked out again
+        // rather than trusting the client's.
+        headers.remove(CONTENT_LENGTH);
+
+        Ok(Self {
+            route: crate::path_name(&path),
+            priority: Priority::take_from(&mut headers),
+            retry: retry::Override::take_from(&mut headers),
+            dry_run: headers
+                .remove(DRY_RUN_HEADER)
+                .is_some_and(|value| value == "true" || value == "1"),
+            method,
+            path,
+            path_and_query,
+            headers,
+            body,
+            tenant: None,
+            peer: None,
+            execute_at: None,
+            accept_encoding: None,
+        })
+    }
+}
+
+/// Work out the route of a path below the API root.
+///
+/// twilight's parser misses the users who reacted with an emoji and removing
+/// every reaction with one, although its own requests for them share the
+/// bucket of the message's reactions, so those are filled in.
+fn parse_path(method: &Method, path: &str) -> Result<Path, PathParseError> {
+    Path::try_from((method.clone(), path)).or_else(|source| {
+        let segments = path.trim_start_matches('/').split('/').collect::<Vec<_>>();
+
+        match segments.as_slice() {
+            ["channels", id, "messages", _, "reactions", _] => id
+                .parse()
+                .map(Path::ChannelsIdMessagesIdReactions)
+                .map_err(|_| source),
+            _ => Err(source),
+        }
+    })
+}
+
+/// Split a URI into its path below the API root and that path joined with the
+/// query, as sent to Discord without a leading slash.
+///
+/// Only the path is normalized: the query and percent-encoded characters,
+/// like the emojis of reaction routes, are passed on as the client sent them.
+fn split_uri(uri: &Uri) -> Option<(String, String)> {
+    let path = normalize_path(uri.path())?;
+    let path_and_query = match uri.query() {
+        Some(query) => format!("{}?{}", path.trim_start_matches('/'), query),
+        None => path.trim_start_matches('/').to_owned(),
+    };
+
+    Some((path, path_and_query))
+}
+
+/// Work out the path below the API root, resolving `.` and `..` segments and
+/// dropping empty ones, so the route is worked out from the same path Discord
+/// ends up seeing. Repeated and trailing slashes make empty segments, and
+/// the root is found whether or not a slash follows `/api/v6`.
+///
+/// Percent-encoded dots count as dots, as the URL parser decodes them when
+/// sending. Returns `None` if a `..` would climb above the API root.
+pub fn normalize_path(path: &str) -> Option<String> {
+    let mut segments = path
+        .split('/')
+        .filter(|segment| !segment.is_empty() && !is_dots(segment, "."));
+    let mut below_root = segments.clone();
+
+    if below_root.next() == Some("api") && below_root.next() == Some("v6") {
+        segments = below_root;
+    }
+
+    let mut resolved = Vec::new();
+
+    for segment in segments {
+        if is_dots(segment, "..") {
+            resolved.pop()?;
+        } else {
+            resolved.push(segment);
+        }
+    }
+
+    let mut normalized = String::with_capacity(path.len() + 1);
+
+    for segment in &resolved {
+        normalized.push('/');
+        normalized.push_str(segment);
+    }
+
+    if normalized.is_empty() {
+        normalized.push('/');
+    }
+
+    Some(normalized)
+}
+
+fn is_dots(segment: &str, dots: &str) -> bool {
+    let mut rest = segment;
+    let mut count = 0;
+
+    while !rest.is_empty() {
+        rest = match rest.strip_prefix('.') {
+            Some(rest) => rest,
+            None if rest.get(..3).is_some_and(|dot| dot.eq_ignore_ascii_case("%2e")) => &rest[3..],
+            None => return false,
+        };
+        count += 1;
+    }
+
+    count == dots.len()
+}
+
+/// Buffered response from Discord.
+#[derive(Clone, Debug)]
+pub struct ProxyResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+impl ProxyResponse {
+    /// Response made up by the proxy itself rather than coming from Discord.
+    pub fn json<T: Serialize>(status: StatusCode, value: &T) -> Result<Self, RequestError> {
+        let body = serde_json::to_vec(value).context(SerializingJson)?;
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+
+        Ok(Self {
+            status,
+            headers,
+            body: body.into(),
+        })
+    }
+
+    pub fn into_response(self) -> Result<Response<Body>, RequestError> {
+        let mut builder = Response::builder().status(self.status);
+
+        if let Some(headers) = builder.headers_mut() {
+            headers.extend(self.headers);
+        }
+
+        builder
+            .body(Body::from(self.body))
+            .context(MakingResponseBody)
+    }
+}
+
+/// The `Content-Length` declared in the headers, if it doesn't match the
+/// length of the body actually received.
+pub fn length_mismatch(headers: &HeaderMap, actual: usize) -> Option<&HeaderValue> {
+    let declared = headers.get(CONTENT_LENGTH)?;
+    let matches = declared
+        .to_str()
+        .ok()
+        .and_then(|value| value.trim().parse::<usize>().ok())
+        .is_some_and(|length| length == actual);
+
+    if matches {
+        None
+    } else {
+        Some(declared)
+    }
+}
+
+/// How the client asked for the request to be run.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Dispatch {
+    /// Hold the connection open until Discord replies.
+    Inline,
+    /// Reply with `202 Accepted` straight away.
+    Background,
+    /// Run inline unless the request would wait long in the queue.
+    Auto,
+}
+
+/// Take the async flag out of the request headers, so it isn't forwarded to
+/// Discord.
+pub fn take_dispatch(headers: &mut HeaderMap) -> Dispatch {
+    match headers.remove(ASYNC_HEADER) {
+        Some(value) if value == "true" || value == "1" => Dispatch::Background,
+        Some(value) if value == "auto" => Dispatch::Auto,
+        _ => Dispatch::Inline,
+    }
+}
+
+/// Take the time to run the request at out of the request headers, so they
+/// aren't forwarded to Discord, failing with the reason if it's invalid.
+pub fn take_execute_at(headers: &mut HeaderMap) -> Result<Option<SystemTime>, &'static str> {
+    let at = headers.remove(EXECUTE_AT_HEADER);
+    let delay = headers.remove(DELAY_HEADER);
+
+    let execute_at = match (at, delay) {
+        (None, None) => return Ok(None),
+        (Some(_), Some(_)) => return Err("X-Proxy-Execute-At and X-Proxy-Delay can't both be set"),
+        (Some(at), None) => at
+            .to_str()
+            .ok()
+            .and_then(|at| humantime::parse_rfc3339_weak(at).ok())
+            .ok_or("X-Proxy-Execute-At isn't an RFC 3339 timestamp")?,
+        (None, Some(delay)) => {
+            SystemTime::now()
+                + delay
+                    .to_str()
+                    .ok()
+                    .and_then(|delay| humantime::parse_duration(delay).ok())
+                    .ok_or("X-Proxy-Delay isn't a duration")?
+        }
+    };
+
+    match execute_at.duration_since(SystemTime::now()) {
+        Ok(ahead) if ahead > MAX_SCHEDULE_AHEAD => {
+            Err("requests can't be scheduled more than 30 days ahead")
+        }
+        _ => Ok(Some(execute_at)),
+    }
+}
+
+/// Send the request to Discord, retrying idempotent requests that failed for
+/// transient reasons.
+///
+/// Requests for a route family whose circuit breaker is open get a `503`
+/// straight away, and successful mutations drop the cached responses for the
+/// resource they were made on.
+pub async fn forward(state: &State, request: ProxyRequest) -> Result<ProxyResponse, RequestError> {
+    let start = Instant::now();
+    let config = state.config();
+    let _tracked = state.load.track(request.body.len());
+    state.distinct.record(&request.path);
+    state.heatmap.record(request.route);
+    let family = breaker::family(&request.path_and_query);
+
+    if let Admission::Rejected { retry_after } = state.breakers.admit(family, &config.breaker) {
+        counter!("gearbot_proxy_circuit_rejections", 1, "family" => family);
+        state
+            .digest
+            .record(request.route, Some(StatusCode::SERVICE_UNAVAILABLE));
+
+        let result = circuit_open(family, retry_after);
+        log_outcome(state, &request, &result, start);
+
+        return result;
+    }
+
+    let policy = retry::Policy::new(config.retry, &request.method, &request.retry);
+    let route = request.route;
+    let mut retries = 0;
+
+    loop {
+        let result = send(state, request.clone()).await;
+
+        if retries >= policy.max_retries || !policy.should_retry(&result) {
+            let healthy = match &result {
+                Ok(response) => !response.status.is_server_error(),
+                Err(source) => !retry::is_transient_error(source),
+            };
+            match state.breakers.record(family, healthy, &config.breaker) {
+                Some(Transition::Opened) => {
+                    state
+                        .digest
+                        .incident(format!("Circuit breaker for {} opened", family));
+                    hooks::fire(&config, Event::CircuitOpen { family });
+                }
+                Some(Transition::Closed) => {
+                    state
+                        .digest
+                        .incident(format!("Circuit breaker for {} closed", family));
+                    hooks::fire(&config, Event::CircuitClose { family });
+                }
+                None => {}
+            }
+            state
+                .digest
+                .record(route, result.as_ref().ok().map(|response| response.status));
+            log_outcome(state, &request, &result, start);
+
+            // Whichever way the request came in, cached copies of what it
+            // changed are out of date now.
+            let dry_run = request.dry_run || config.dry_run;
+
+            if response_cache::is_mutation(&request)
+                && !dry_run
+                && result
+                    .as_ref()
+                    .is_ok_and(|response| response.status.is_success())
+            {
+                response_cache::invalidate(state, &request.path_and_query, "mutation").await;
+            }
+
+            return result.map(|mut response| {
+                response
+                    .headers
+                    .insert(retry::RETRIES_HEADER, HeaderValue::from(retries));
+                response
+                    .headers
+                    .insert(TOTAL_TIME_HEADER, milliseconds(start.elapsed()));
+
+                response
+            });
+        }
+
+        retries += 1;
+        let delay = policy.delay(retries, &result);
+        debug!("Retrying {} in {:?} (retry {})", route, delay, retries);
+        counter!("gearbot_proxy_retries", 1, "route" => route);
+
+        time::delay_for(delay).await;
+    }
+}
+
+/// Log every header of a sample of the responses on the configured routes.
+///
+/// Cookie values are left out, as they're of no use for this and may identify
+/// the bot's session.
+fn log_headers(config: &HeaderLogConfig, route: &str, status: StatusCode, headers: &HeaderMap) {
+    if !config.routes.iter().any(|logged| logged == route)
+        || rand::random::<f64>() * 100.0 >= f64::from(config.percent)
+    {
+        return;
+    }
+
+    let headers = headers
+        .iter()
+        .map(|(name, value)| {
+            if name == SET_COOKIE {
+                format!("{}: <redacted>", name)
+            } else {
+                format!("{}: {}", name, String::from_utf8_lossy(value.as_bytes()))
+            }
+        })
+        .collect::<Vec<_>>();
+
+    info!(
+        "Response headers for {} ({}): {}",
+        route,
+        status.as_u16(),
+        headers.join(", ")
+    );
+}
+
+/// Made up response to a request that was only pretended to be sent.
+///
+/// Deletions and bodyless `PUT`s get a `204 No Content`, like they would from
+/// Discord; everything else gets a `200 OK` echoing the request's JSON body,
+/// or an empty object.
+fn dry_run_response(method: &str, body: &[u8]) -> Result<ProxyResponse, RequestError> {
+    let mut response = if method == "DELETE" || (method == "PUT" && body.is_empty()) {
+        ProxyResponse {
+            status: StatusCode::NO_CONTENT,
+            headers: HeaderMap::new(),
+            body: Bytes::new(),
+        }
+    } else {
+        match serde_json::from_slice::<serde_json::Value>(body) {
+            Ok(value) => ProxyResponse::json(StatusCode::OK, &value)?,
+            Err(_) => ProxyResponse::json(StatusCode::OK, &serde_json::json!({}))?,
+        }
+    };
+    response
+        .headers
+        .insert(DRY_RUN_HEADER, HeaderValue::from_static("true"));
+
+    Ok(response)
+}
+
+/// Header marking a `429` as coming from the proxy's own backpressure rather
+/// than from Discord.
+pub const BACKPRESSURE_HEADER: &str = "x-proxy-backpressure";
+
+/// Body of a `429` the proxy sends when it's under load, shaped like
+/// Discord's so client libraries back off the same way.
+#[derive(Serialize)]
+struct Backpressure<'a> {
+    message: &'a str,
+    /// Tells these apart from Discord's ratelimits, which have no code.
+    code: &'static str,
+    retry_after: f64,
+    global: bool,
+}
+
+/// Write the request to the access and audit logs.
+fn log_outcome(
+    state: &State,
+    request: &ProxyRequest,
+    result: &Result<ProxyResponse, RequestError>,
+    start: Instant,
+) {
+    let response = result.as_ref().ok();
+    audit::record(state, request, response.map(|response| response.status));
+
+    if let Some(access_log) = &state.access_log {
+        access_log.write_line(Entry {
+            peer: request.peer,
+            tenant: request.tenant.as_deref(),
+            method: &request.method,
+            route: request.route,
+            status: response.map(|response| response.status),
+            bytes: response.map_or(0, |response| response.body.len()),
+            latency: start.elapsed(),
+        }
+        .line());
+    }
+}
+
+/// What's to be done with a request, once it's been through the checks every
+/// request goes through on its way to Discord.
+pub enum Checked {
+    /// Answered by the proxy without going to Discord.
+    Answered(ProxyResponse),
+    /// To be forwarded, handing its response to the guard if it has an
+    /// idempotency key.
+    Forward(Option<Guard>),
+}
+
+/// Shed load, inject faults, claim the request's idempotency key and mirror
+/// it, whether it came in on its own or as part of a batch.
+pub async fn check(
+    state: &State,
+    request: &ProxyRequest,
+    idempotency_key: Option<String>,
+) -> Result<Checked, RequestError> {
+    if let Some(response) = shed(state, request)? {
+        return Ok(Checked::Answered(response));
+    }
+
+    if let Some(response) = state.faults.inject(request).await {
+        return Ok(Checked::Answered(response));
+    }
+
+    let config = state.config();
+    let guard = match idempotency_key {
+        Some(key) => match state.idempotency.claim(
+            key,
+            &request.method,
+            &request.path_and_query,
+            config.idempotency_ttl,
+        ) {
+            Claim::New(guard) => Some(guard),
+            Claim::Replay(mut response) => {
+                // Stored as Discord sent it, which may be in an encoding
+                // only the first client accepted.
+                compression::decode(&mut response, request.accept_encoding);
+                response
+                    .headers
+                    .insert(idempotency::REPLAY_HEADER, HeaderValue::from_static("true"));
+
+                return Ok(Checked::Answered(response));
+            }
+            Claim::InProgress => {
+                let message = "a request with this idempotency key is in progress";
+                let response = ProxyResponse::json(StatusCode::CONFLICT, &ErrorBody { message })?;
+
+                return Ok(Checked::Answered(response));
+            }
+            Claim::Mismatch => {
+                let message = "idempotency key was already used for a different request";
+                let response =
+                    ProxyResponse::json(StatusCode::UNPROCESSABLE_ENTITY, &ErrorBody { message })?;
+
+                return Ok(Checked::Answered(response));
+            }
+        },
+        None => None,
+    };
+
+    mirror::mirror(&config.mirror, request);
+
+    Ok(Checked::Forward(guard))
+}
+
+/// Turn the request away if the proxy is under load and it can wait.
+pub fn shed(state: &State, request: &ProxyRequest) -> Result<Option<ProxyResponse>, RequestError> {
+    if !state
+        .load
+        .should_shed(request.priority, &state.config().shedding) {
+        return Ok(None);
+    }
+
+    counter!("gearbot_proxy_shed", 1, "route" => request.route);
+
+    // The bucket's queue is the best guess at when things calm down, but
+    // clients are asked to wait at least a second.
+    let estimate = state.queue.estimate(&request.path, request.priority);
+    let secs = (estimate.as_secs() + u64::from(estimate.subsec_nanos() > 0)).max(1);
+
+    backpressure(secs).map(Some)
+}
+
+/// A `429` asking the client to come back in `secs` seconds, as the proxy is
+/// under load.
+pub fn backpressure(secs: u64) -> Result<ProxyResponse, RequestError> {
+    let mut response = ProxyResponse::json(
+        StatusCode::TOO_MANY_REQUESTS,
+        &Backpressure {
+            message: "the proxy is under load, try again later",
+            code: "proxy_backpressure",
+            retry_after: secs as f64,
+            global: false,
+        },
+    )?;
+    response.headers.insert(RETRY_AFTER, HeaderValue::from(secs));
+    response
+        .headers
+        .insert(BACKPRESSURE_HEADER, HeaderValue::from_static("true"));
+
+    Ok(response)
+}
+
+fn circuit_open(family: &str, retry_after: Duration) -> Result<ProxyResponse, RequestError> {
+    let message = format!("Discord is failing requests for {}, try again later", family);
+    let mut response = ProxyResponse::json(
+        StatusCode::SERVICE_UNAVAILABLE,
+        &ErrorBody { message: &message },
+    )?;
+
+    // Round up, so clients don't come back just before the breaker lets
+    // requests through again.
+    let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    response.headers.insert(RETRY_AFTER, HeaderValue::from(secs));
+
+    Ok(response)
+}
+
+/// Make a single attempt at sending the request to Discord.
+async fn send(state: &State, request: ProxyRequest) -> Result<ProxyResponse, RequestError> {
+    let ProxyRequest {
+        method,
+        path,
+        path_and_query,
+        mut headers,
+        body,
+        priority,
+        route,
+        tenant,
+        dry_run,
+        accept_encoding,
+        ..
+    } = request;
+    let config = state.config();
+    let dry_run = dry_run || config.dry_run;
+    // Requests without a key are left unlabeled, which Prometheus treats the
+    // same as not having the label.
+    let tenant = tenant.unwrap_or_default();
+    headers::sanitize_for(&config, &path_and_query, &mut headers);
+
+    if config.compression.upstream {
+        headers.insert(
+            ACCEPT_ENCODING,
+            compression::upstream_accepted(accept_encoding),
+        );
+    }
+
+    let trace = TraceParent::from_headers(&headers)
+        .map(|parent| parent.child(state.request_ids.next_span()));
+
+    match &trace {
+        Some(trace) if config.trace_upstream => {
+            headers.insert(trace::TRACEPARENT, trace.to_header());
+        }
+        _ => {
+            headers.remove(trace::TRACEPARENT);
+            headers.remove(trace::TRACESTATE);
+        }
+    }
+
+    let m = method.to_string();
+    let bucket = path.clone();
+    let raw_request = TwilightRequest {
+        // Twilight wants a body of its own, so it's only copied now, after
+        // any retries were cloned from the buffered one.
+        body: Some(body.to_vec()),
+        form: None,
+        headers: Some(headers),
+        method,
+        path,
+        path_str: path_and_query.into(),
+    };
+
+    let start = Instant::now();
+    let _slow = if config.slow_routes.contains(route) {
+        Some(state.slow_routes.acquire().await)
+    } else {
+        None
+    };
+    let ticket = Ticket {
+        route,
+        tenant: Some(tenant.clone()).filter(|tenant| !tenant.is_empty()),
+    };
+    if route == unknown_routes::ROUTE {
+        state
+            .unknown_routes
+            .wait(&raw_request.path_str, config.unknown_routes.interval)
+            .await;
+    }
+
+    let permit = state.queue.acquire(bucket.clone(), priority, ticket).await;
+    let acquired = Instant::now();
+
+    // Twilight would hold the request back as well, but waiting here keeps
+    // that out of the upstream latency, and covers buckets a new client
+    // hasn't learned yet.
+    if let Some(wait) = state.ratelimits.wait_time(&bucket) {
+        debug!("Waiting {:?} for the ratelimit of {} to reset", wait, route);
+        counter!("gearbot_proxy_ratelimit_waits", 1, "route" => route);
+        time::delay_for(wait).await;
+    }
+
+    if dry_run {
+        info!(
+            "Dry run: would send {} {} ({})",
+            m, raw_request.path_str, route
+        );
+        counter!("gearbot_proxy_dry_runs", 1, "route" => route);
+        let mut response =
+            dry_run_response(&m, raw_request.body.as_deref().unwrap_or_default())?;
+
+        if let Some(emulated) = state
+            .emulated_ratelimits
+            .take(&config.emulated_ratelimits, &bucket, route)
+        {
+            let ProxyResponse {
+                status,
+                headers,
+                body,
+            } = &mut response;
+            emulated.apply(status, headers, body);
+            state.ratelimits.record(&bucket, *status, headers);
+        }
+
+        return Ok(response);
+    }
+
+    let sent = Instant::now();
+    timing!("gearbot_proxy_queue_wait", start, sent, "route" => route, "priority" => priority.name(), "tenant" => tenant.clone());
+
+    let span = info_span!(
+        "upstream",
+        span_id = %trace.as_ref().map_or("", |trace| trace.parent_id.as_str()),
+    );
+    // Mocked responses go through the same handling as real ones, so
+    // fixtures can exercise ratelimits and retries as well.
+    let mut resp = match &state.mock {
+        Some(mock) => {
+            let (mut parts, mut body) = mock.respond(&m, route).into_parts();
+
+            // Fixtures with ratelimit headers of their own keep them.
+            if !parts.headers.contains_key("x-ratelimit-limit") {
+                if let Some(emulated) =
+                    state
+                        .emulated_ratelimits
+                        .take(&config.emulated_ratelimits, &bucket, route)
+                {
+                    emulated.apply(&mut parts.status, &mut parts.headers, &mut body);
+                }
+            }
+
+            reqwest::Response::from(http::Response::from_parts(parts, body))
+        }
+        None => {
+            state.rotation.record();
+            let client = state.client();
+            // The second attempt uses up another request of the bucket, so
+            // it's only sent when there's one to spare.
+            let hedge_after = config.hedge_after.filter(|_| {
+                m == "GET" && state.ratelimits.remaining(&bucket).is_some_and(|left| left >= 2)
+            });
+
+            match hedge_after {
+                Some(after) => hedged(&client, raw_request, after, route)
+                    .instrument(span.clone())
+                    .await,
+                None => client.raw(raw_request).instrument(span.clone()).await,
+            }
+            .context(RequestIssue)?
+        }
+    };
+    let answered = Instant::now();
+    let status = resp.status();
+    log_headers(&config.header_log, route, status, resp.headers());
+
+    if let Some(scope) = state.ratelimits.record(&bucket, status, resp.headers()) {
+        warn!("Discord ratelimited {} ({} ratelimit)", route, scope.name());
+        counter!("gearbot_proxy_upstream_ratelimited", 1, "route" => route, "scope" => scope.name());
+    }
+
+    drop(permit);
+
+    let from_cloudflare = cloudflare::is_error(status, resp.headers());
+
+    if status.is_server_error() {
+        let origin = if from_cloudflare { "cloudflare" } else { "discord" };
+        counter!("gearbot_proxy_upstream_errors", 1, "route" => route, "status" => status.as_u16().to_string(), "origin" => origin);
+    }
+    let mut headers = mem::take(resp.headers_mut());
+    headers::strip_hop_by_hop(&mut headers);
+
+    let mut body = resp
+        .bytes()
+        .instrument(span)
+        .await
+        .context(ChunkingResponse)?;
+    let end = Instant::now();
+
+    if config
+        .slow_request_threshold
+        .is_some_and(|threshold| end - sent >= threshold)
+    {
+        warn!(
+            "Slow request: Discord took {:?} to answer {} {} with {} ({:?} until the headers, {:?} for the body), after {:?} in the queue and {:?} waiting on the ratelimit",
+            end - sent,
+            m,
+            route,
+            status,
+            answered - sent,
+            end - answered,
+            acquired - start,
+            sent - acquired,
+        );
+        counter!("gearbot_proxy_slow_requests", 1, "route" => route);
+    }
+
+    if from_cloudflare {
+        warn!("Cloudflare answered {} with {}", route, status);
+        body = cloudflare::translate(status, &mut headers);
+    }
+
+    // Responses without a body may still say how long it would have been.
+    let bodyless = m == "HEAD"
+        || status.is_informational()
+        || status == StatusCode::NO_CONTENT
+        || status == StatusCode::NOT_MODIFIED;
+
+    if !bodyless {
+        if let Some(declared) = length_mismatch(&headers, body.len()) {
+            warn!(
+                "Response for {} declared a length of {:?} but had {} bytes",
+                route,
+                declared,
+                body.len()
+            );
+            counter!("gearbot_proxy_length_mismatches", 1, "direction" => "response", "route" => route);
+        }
+    }
+
+    headers.insert(UPSTREAM_TIME_HEADER, milliseconds(end - sent));
+    headers.insert(QUEUE_TIME_HEADER, milliseconds(sent - start));
+
+    let mut response = ProxyResponse {
+        status,
+        headers,
+        body,
+    };
+    compression::decode(&mut response, accept_encoding);
+
+    debug!("Response: {} {:?}", status, response.headers);
+
+    timing!("gearbot_proxy_upstream_latency", sent, end, "method" => m.to_string(), "route" => route, "status" => status.to_string(), "tenant" => tenant.clone());
+    timing!("gearbot_proxy_requests", start, end, "method"=>m.to_string(), "route"=>route, "status"=>status.to_string(), "priority"=>priority.name(), "tenant"=>tenant);
+    info!("{} {}: {}", m, route, status);
+
+    Ok(response)
+}
+
+/// A duration as a header value in milliseconds, to the microsecond.
+fn milliseconds(duration: Duration) -> HeaderValue {
+    HeaderValue::from_str(&format!("{:.3}", duration.as_secs_f64() * 1000.0))
+        .expect("numbers are valid in a header")
+}
+
+/// Send the request, and again if it isn't answered within `after`, taking
+/// whichever response comes first.
+///
+/// The second attempt skips twilight's ratelimiter, which would hold it back
+/// until the first is answered. If either attempt fails, the other one is
+/// waited for instead.
+async fn hedged(
+    client: &Upstream,
+    request: TwilightRequest,
+    after: Duration,
+    route: &'static str,
+) -> Result<reqwest::Response, TwilightError> {
+    let copy = TwilightRequest {
+        body: request.body.clone(),
+        form: None,
+        headers: request.headers.clone(),
+        method: request.method.clone(),
+        path: request.path.clone(),
+        path_str: request.path_str.clone(),
+    };
+    let first = client.raw(request);
+    tokio::pin!(first);
+
+    if let Ok(result) = time::timeout(after, &mut first).await {
+        return result;
+    }
+
+    debug!("Hedging {} after {:?}", route, after);
+    let second = client.direct(copy);
+    tokio::pin!(second);
+
+    let (result, winner) = tokio::select! {
+        result = &mut first => match result {
+            Ok(response) => (Ok(response), "first"),
+            Err(_) => (second.await, "hedge"),
+        },
+        result = &mut second => match result {
+            Ok(response) => (Ok(response), "hedge"),
+            Err(_) => (first.await, "first"),
+        },
+    };
+    counter!("gearbot_proxy_hedged_requests", 1, "route" => route, "winner" => winner);
+
+    result
+}
+
+/// Run the request in the background, returning the id of the job tracking it.
+///
+/// When a spool is configured the request is written to disk before this
+/// returns, so it isn't lost if the proxy restarts before running it.
+pub async fn forward_in_background(
+    state: Arc<State>,
+    request: ProxyRequest,
+) -> Result<String, RequestError> {
+    let id = state.request_ids.next();
+    state.jobs.insert(&id, request.tenant.as_deref());
+
+    // Marked straight away rather than once the job starts waiting, so it can
+    // be cancelled as soon as the client hears of it.
+    if let Some(status) = request.execute_at.and_then(scheduled) {
+        state.jobs.set(&id, status);
+    }
+
+    if let Some(spool) = state.spool.as_ref() {
+        spool
+            .write(&Record::new(&id, &request))
+            .await
+            .context(Spooling)?;
+    }
+
+    spawn_job(state, id.clone(), request);
+
+    Ok(id)
+}
+
+/// Status of a job held back until `execute_at`, unless that already passed.
+fn scheduled(execute_at: SystemTime) -> Option<JobStatus> {
+    execute_at
+        .duration_since(SystemTime::now())
+        .ok()
+        .map(|_| JobStatus::Scheduled {
+            execute_at: humantime::format_rfc3339_seconds(execute_at).to_string(),
+        })
+}
+
+/// Run a request registered as a job in the background.
+pub fn spawn_job(state: Arc<State>, id: String, request: ProxyRequest) {
+    tokio::spawn(async move {
+        let route = request.route;
+
+        if let Some(execute_at) = request.execute_at {
+            // Times that already passed, e.g. while the proxy was down, run
+            // straight away.
+            if let Ok(wait) = execute_at.duration_since(SystemTime::now()) {
+                if let Some(status) = scheduled(execute_at) {
+                    state.jobs.set_unless_cancelled(&id, status);
+                }
+
+                time::delay_for(wait).await;
+            }
+        }
+
+        if !state.jobs.start(&id) {
+            debug!("Scheduled request {} to {} was cancelled", id, route);
+
+            return;
+        }
+
+        let status = match forward(&state, request).await {
+            Ok(response) => {
+                if !response.status.is_success() {
+                    warn!("Background request {} to {} got {}", id, route, response.status);
+                    counter!("gearbot_proxy_background_failures", 1, "route" => route);
+                }
+
+                JobStatus::Completed {
+                    status: response.status.as_u16(),
+                }
+            }
+            Err(source) => {
+                warn!("Background request {} to {} failed: {:?}", id, route, source);
+                counter!("gearbot_proxy_background_failures", 1, "route" => route);
+
+                JobStatus::Failed {
+                    error: source.to_string(),
+                }
+            }
+        };
+
+        if let Some(spool) = state.spool.as_ref() {
+            let succeeded = matches!(status, JobStatus::Completed { status } if status < 400);
+
+            if succeeded {
+                spool.remove(&id).await;
+            } else {
+                counter!("gearbot_proxy_dead_letters", 1, "route" => route);
+                spool.dead_letter(&id).await;
+            }
+        }
+
+        state.jobs.set(&id, status);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(uri: &str) -> Option<(String, String)> {
+        split_uri(&uri.parse::<Uri>().unwrap())
+    }
+
+    fn parts(path: &str, path_and_query: &str) -> Option<(String, String)> {
+        Some((path.to_owned(), path_and_query.to_owned()))
+    }
+
+    #[test]
+    fn api_root_is_stripped_from_the_path_only() {
+        assert_eq!(
+            split("/api/v6/channels/1/messages?around=/api/v6&limit=5"),
+            parts(
+                "/channels/1/messages",
+                "channels/1/messages?around=/api/v6&limit=5"
+            )
+        );
+        assert_eq!(
+            split("/api/v6/channels/1/messages?before=1&after=%2Fapi%2Fv6%2F"),
+            parts(
+                "/channels/1/messages",
+                "channels/1/messages?before=1&after=%2Fapi%2Fv6%2F"
+            )
+        );
+        assert_eq!(
+            split("/channels/1?a=b"),
+            parts("/channels/1", "channels/1?a=b")
+        );
+        assert_eq!(split("/api/v6"), parts("/", ""));
+        assert_eq!(split("/api/v6/"), parts("/", ""));
+        assert_eq!(
+            split("/api/v6?with_counts=true"),
+            parts("/", "?with_counts=true")
+        );
+    }
+
+    #[test]
+    fn queries_are_passed_on_as_sent() {
+        for query in &[
+            "",
+            "a=",
+            "a=%20b",
+            "q=%E2%9C%85",
+            "a=1&a=2",
+            "x=../..",
+            "y=%2e%2e",
+        ] {
+            let (_, path_and_query) = split(&format!("/api/v6/guilds/1?{}", query)).unwrap();
+
+            assert_eq!(path_and_query, format!("guilds/1?{}", query));
+        }
+    }
+
+    #[test]
+    fn paths_are_normalized() {
+        assert_eq!(
+            normalize_path("/api/v6//guilds/1/").as_deref(),
+            Some("/guilds/1")
+        );
+        assert_eq!(
+            normalize_path("/api/v6/guilds/./1/../2").as_deref(),
+            Some("/guilds/2")
+        );
+        assert_eq!(
+            normalize_path("/api/v6/guilds/1/%2e%2E/2").as_deref(),
+            Some("/guilds/2")
+        );
+        assert_eq!(normalize_path("/api/v6/..../x").as_deref(), Some("/..../x"));
+    }
+
+    #[test]
+    fn paths_cant_climb_above_the_api_root() {
+        assert_eq!(normalize_path("/api/v6/.."), None);
+        assert_eq!(normalize_path("/api/v6/guilds/../../gateway"), None);
+        assert_eq!(normalize_path("/api/v6/%2E%2E/oauth2"), None);
+        assert!(split("/api/v6/channels/1/../../..").is_none());
+    }
+
+    #[test]
+    fn encoded_characters_are_left_alone() {
+        assert_eq!(
+            split("/api/v6/channels/1/messages/2/reactions/%F0%9F%91%8D/@me"),
+            parts(
+                "/channels/1/messages/2/reactions/%F0%9F%91%8D/@me",
+                "channels/1/messages/2/reactions/%F0%9F%91%8D/@me"
+            )
+        );
+        assert_eq!(
+            split("/api/v6/channels/1/messages/2/reactions/gear:123/@me"),
+            parts(
+                "/channels/1/messages/2/reactions/gear:123/@me",
+                "channels/1/messages/2/reactions/gear:123/@me"
+            )
+        );
+        // Encoded slashes aren't path separators.
+        assert_eq!(
+            split("/api/v6/channels/1/messages/2/reactions/%2F/@me?limit=1"),
+            parts(
+                "/channels/1/messages/2/reactions/%2F/@me",
+                "channels/1/messages/2/reactions/%2F/@me?limit=1"
+            )
+        );
+    }
+}
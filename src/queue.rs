@@ -0,0 +1,342 @@
+use http::header::HeaderMap;
+use serde::Serialize;
+use std::{
+    cmp::Ordering,
+    collections::{BinaryHeap, HashMap},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering as AtomicOrdering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::oneshot::{self, Receiver, Sender};
+use twilight_http::routing::Path;
+
+/// Header clients use to classify a request.
+pub const PRIORITY_HEADER: &str = "x-proxy-priority";
+
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Priority {
+    Low,
+    Normal,
+    High,
+}
+
+impl Priority {
+    /// Take the priority out of the request headers, so it isn't forwarded to
+    /// Discord.
+    ///
+    /// Missing or unrecognized values are treated as normal priority.
+    pub fn take_from(headers: &mut HeaderMap) -> Self {
+        headers
+            .remove(PRIORITY_HEADER)
+            .and_then(|value| value.to_str().ok()?.parse().ok())
+            .unwrap_or(Priority::Normal)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+        }
+    }
+}
+
+impl FromStr for Priority {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "low" => Ok(Priority::Low),
+            "normal" => Ok(Priority::Normal),
+            "high" => Ok(Priority::High),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Who a request holding or waiting on a bucket is, for diagnostics.
+#[derive(Clone, Debug)]
+pub struct Ticket {
+    pub route: &'static str,
+    pub tenant: Option<String>,
+}
+
+/// Requests holding and waiting on a bucket, in the order they go.
+#[derive(Debug, Serialize)]
+pub struct Pending {
+    pub bucket: String,
+    pub holder: PendingRequest,
+    pub waiting: Vec<PendingRequest>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PendingRequest {
+    pub route: &'static str,
+    pub tenant: Option<String>,
+    pub priority: &'static str,
+    /// How long the request has been holding or waiting on the bucket.
+    pub age_ms: u64,
+}
+
+impl PendingRequest {
+    fn new(ticket: &Ticket, priority: Priority, since: Instant) -> Self {
+        Self {
+            route: ticket.route,
+            tenant: ticket.tenant.clone(),
+            priority: priority.name(),
+            age_ms: since.elapsed().as_millis() as u64,
+        }
+    }
+}
+
+/// Per-bucket gate in front of twilight's ratelimiter.
+///
+/// Twilight queues requests for a bucket in arrival order. By only letting one
+/// request per bucket through to twilight at a time, the order in which
+/// contended requests reach Discord is decided here instead, highest priority
+/// first and arrival order within a priority.
+#[derive(Clone, Debug, Default)]
+pub struct Queue {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    buckets: Mutex<HashMap<Path, Bucket>>,
+    /// Moving average of how long requests hold each bucket, kept after the
+    /// bucket goes idle.
+    hold_times: Mutex<HashMap<Path, Duration>>,
+    sequence: AtomicU64,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    holder: Holder,
+    waiting: BinaryHeap<Waiter>,
+}
+
+#[derive(Debug)]
+struct Holder {
+    priority: Priority,
+    ticket: Ticket,
+    since: Instant,
+}
+
+#[derive(Debug)]
+struct Waiter {
+    priority: Priority,
+    sequence: u64,
+    ticket: Ticket,
+    since: Instant,
+    notify: Sender<()>,
+}
+
+impl Ord for Waiter {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.sequence.cmp(&self.sequence))
+    }
+}
+
+impl PartialOrd for Waiter {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Waiter {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Waiter {}
+
+impl Queue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Wait until the request is allowed to be dispatched for the bucket.
+    ///
+    /// The bucket stays reserved until the returned permit is dropped.
+    pub async fn acquire(&self, path: Path, priority: Priority, ticket: Ticket) -> Permit {
+        let rx = {
+            let mut buckets = self.inner.buckets.lock().expect("queue poisoned");
+
+            match buckets.get_mut(&path) {
+                Some(bucket) => {
+                    let (tx, rx) = oneshot::channel();
+                    bucket.waiting.push(Waiter {
+                        priority,
+                        sequence: self.inner.sequence.fetch_add(1, AtomicOrdering::Relaxed),
+                        ticket,
+                        since: Instant::now(),
+                        notify: tx,
+                    });
+
+                    Some(rx)
+                }
+                None => {
+                    buckets.insert(
+                        path.clone(),
+                        Bucket {
+                            holder: Holder {
+                                priority,
+                                ticket,
+                                since: Instant::now(),
+                            },
+                            waiting: BinaryHeap::new(),
+                        },
+                    );
+
+                    None
+                }
+            }
+        };
+
+        if let Some(rx) = rx {
+            let mut waiting = Waiting {
+                queue: self,
+                path: &path,
+                rx: Some(rx),
+            };
+
+            // The sender is only dropped without sending if the queue itself
+            // is gone, at which point there's nothing left to wait on.
+            let _ = waiting.rx.as_mut().expect("receiver present").await;
+            waiting.rx.take();
+        }
+
+        Permit {
+            queue: self.clone(),
+            path,
+            acquired_at: Instant::now(),
+        }
+    }
+
+    /// Rough estimate of how long a request would wait for the bucket, based
+    /// on the requests ahead of it and how long requests usually hold it.
+    pub fn estimate(&self, path: &Path, priority: Priority) -> Duration {
+        let ahead = {
+            let buckets = self.inner.buckets.lock().expect("queue poisoned");
+
+            match buckets.get(path) {
+                // The request holding the bucket plus everyone who'd go first.
+                Some(bucket) => {
+                    1 + bucket
+                        .waiting
+                        .iter()
+                        .filter(|waiter| waiter.priority >= priority)
+                        .count()
+                }
+                None => 0,
+            }
+        };
+        let hold_times = self.inner.hold_times.lock().expect("queue poisoned");
+        let hold_time = hold_times.get(path).copied().unwrap_or_default();
+
+        hold_time * ahead as u32
+    }
+
+    /// Requests waiting on every bucket in use, not counting the ones using
+    /// them.
+    pub fn queued(&self) -> HashMap<Path, usize> {
+        let buckets = self.inner.buckets.lock().expect("queue poisoned");
+
+        buckets
+            .iter()
+            .map(|(path, bucket)| (path.clone(), bucket.waiting.len()))
+            .collect()
+    }
+
+    /// Requests holding and waiting on the bucket with the given name, as in
+    /// `GET /proxy/buckets`, if it's in use.
+    pub fn pending(&self, bucket: &str) -> Option<Pending> {
+        let buckets = self.inner.buckets.lock().expect("queue poisoned");
+        let (path, queued) = buckets
+            .iter()
+            .find(|(path, _)| format!("{:?}", path) == bucket)?;
+        let mut waiting = queued.waiting.iter().collect::<Vec<_>>();
+        waiting.sort_by(|a, b| b.cmp(a));
+        let holder = &queued.holder;
+        Some(Pending {
+            bucket: format!("{:?}", path),
+            holder: PendingRequest::new(&holder.ticket, holder.priority, holder.since),
+            waiting: waiting
+                .into_iter()
+                .map(|waiter| PendingRequest::new(&waiter.ticket, waiter.priority, waiter.since))
+                .collect(),
+        })
+    }
+
+    fn record_hold_time(&self, path: &Path, held: Duration) {
+        let mut hold_times = self.inner.hold_times.lock().expect("queue poisoned");
+        let average = hold_times.entry(path.clone()).or_insert(held);
+
+        *average = average.mul_f64(0.8) + held.mul_f64(0.2);
+    }
+
+    fn release(&self, path: &Path) {
+        let mut buckets = self.inner.buckets.lock().expect("queue poisoned");
+
+        if let Some(bucket) = buckets.get_mut(path) {
+            while let Some(waiter) = bucket.waiting.pop() {
+                // Hand the bucket over; if the waiter has gone away in the
+                // meantime, try the next one.
+                if waiter.notify.send(()).is_ok() {
+                    bucket.holder = Holder {
+                        priority: waiter.priority,
+                        ticket: waiter.ticket,
+                        since: Instant::now(),
+                    };
+
+                    return;
+                }
+            }
+
+            buckets.remove(path);
+        }
+    }
+}
+
+/// Reservation of a bucket, released on drop.
+#[derive(Debug)]
+pub struct Permit {
+    queue: Queue,
+    path: Path,
+    acquired_at: Instant,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.queue.record_hold_time(&self.path, self.acquired_at.elapsed());
+        self.queue.release(&self.path);
+    }
+}
+
+/// Guard for a request waiting on a bucket.
+///
+/// If the request is dropped after the bucket was handed to it but before it
+/// noticed, the bucket is passed on instead of staying reserved forever.
+struct Waiting<'a> {
+    queue: &'a Queue,
+    path: &'a Path,
+    rx: Option<Receiver<()>>,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        if let Some(mut rx) = self.rx.take() {
+            rx.close();
+
+            if rx.try_recv().is_ok() {
+                self.queue.release(self.path);
+            }
+        }
+    }
+}
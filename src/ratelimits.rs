@@ -0,0 +1,255 @@
+//! What Discord's latest responses said about its ratelimits.
+
+use crate::{distinct, retry, State};
+use http::{header::HeaderMap, StatusCode};
+use metrics::gauge;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::time;
+use twilight_http::routing::Path;
+
+const GLOBAL_HEADER: &str = "x-ratelimit-global";
+const LIMIT_HEADER: &str = "x-ratelimit-limit";
+const REMAINING_HEADER: &str = "x-ratelimit-remaining";
+const RESET_AFTER_HEADER: &str = "x-ratelimit-reset-after";
+
+/// How long a bucket nothing was sent for is remembered.
+const RETENTION: Duration = Duration::from_secs(60 * 60);
+
+/// How often bucket states are exported as metrics.
+const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Which ratelimit a `429` was for.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Scope {
+    /// The limit on all requests made with the token.
+    Global,
+    /// The limit of the route's bucket.
+    Bucket,
+}
+
+impl Scope {
+    pub fn name(self) -> &'static str {
+        match self {
+            Scope::Global => "global",
+            Scope::Bucket => "bucket",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    limit: Option<u64>,
+    remaining: Option<u64>,
+    resets_at: Option<SystemTime>,
+    updated_at: Instant,
+}
+
+impl Bucket {
+    /// Requests left and when the bucket resets, as of `now`.
+    fn current(&self, now: SystemTime) -> (Option<u64>, Option<SystemTime>) {
+        match self.resets_at.filter(|at| *at > now) {
+            Some(at) => (self.remaining, Some(at)),
+            // A reset in the past means the bucket is full again.
+            None => (self.limit, None),
+        }
+    }
+}
+
+/// Tracks the state of ratelimit buckets, including which are used up and how
+/// long the next request has to wait for them to reset.
+#[derive(Debug, Default)]
+pub struct Ratelimits {
+    buckets: Mutex<HashMap<Path, Bucket>>,
+    /// When the global ratelimit resets, if it was hit.
+    global: Mutex<Option<SystemTime>>,
+}
+
+/// State of a bucket, as shown by the admin API.
+#[derive(Debug, Serialize)]
+pub struct BucketSnapshot {
+    /// The bucket's path, including its major parameter.
+    pub bucket: String,
+    pub route: &'static str,
+    pub limit: Option<u64>,
+    pub remaining: Option<u64>,
+    /// Unix timestamp in milliseconds.
+    pub resets_at: Option<u64>,
+    /// Requests waiting for the one using the bucket to finish.
+    pub queued: usize,
+}
+
+impl Ratelimits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remember what the response said about the bucket's ratelimit.
+    ///
+    /// Returns which ratelimit was hit if the response is a `429`.
+    pub fn record(&self, path: &Path, status: StatusCode, headers: &HeaderMap) -> Option<Scope> {
+        let now = SystemTime::now();
+        let reset_after = header_number::<f64>(headers, RESET_AFTER_HEADER)
+            .filter(|seconds| seconds.is_finite() && *seconds >= 0.0)
+            .map(Duration::from_secs_f64);
+
+        {
+            let mut buckets = self.buckets.lock().expect("ratelimits poisoned");
+            buckets.retain(|_, bucket| bucket.updated_at.elapsed() < RETENTION);
+            buckets.insert(
+                path.clone(),
+                Bucket {
+                    limit: header_number(headers, LIMIT_HEADER),
+                    remaining: header_number(headers, REMAINING_HEADER),
+                    resets_at: reset_after.map(|reset_after| now + reset_after),
+                    updated_at: Instant::now(),
+                },
+            );
+        }
+
+        if status != StatusCode::TOO_MANY_REQUESTS {
+            return None;
+        }
+
+        if headers.get(GLOBAL_HEADER).is_some_and(|value| value == "true") {
+            let retry_after = retry::retry_after(headers).unwrap_or_default();
+            *self.global.lock().expect("ratelimits poisoned") = Some(now + retry_after);
+
+            Some(Scope::Global)
+        } else {
+            Some(Scope::Bucket)
+        }
+    }
+
+    /// How long until the global ratelimit resets, if it's used up.
+    pub fn global_wait_time(&self) -> Option<Duration> {
+        let global = *self.global.lock().expect("ratelimits poisoned");
+
+        global?.duration_since(SystemTime::now()).ok()
+    }
+
+    /// How long a request for the bucket has to wait for the bucket or the
+    /// global ratelimit to reset, if it's used up.
+    pub fn wait_time(&self, path: &Path) -> Option<Duration> {
+        let now = SystemTime::now();
+        let global = *self.global.lock().expect("ratelimits poisoned");
+        let bucket = self
+            .buckets
+            .lock()
+            .expect("ratelimits poisoned")
+            .get(path)
+            .and_then(|bucket| match bucket.current(now) {
+                (Some(0), resets_at) => resets_at,
+                _ => None,
+            });
+
+        global
+            .into_iter()
+            .chain(bucket)
+            .filter_map(|resets_at| resets_at.duration_since(now).ok())
+            .max()
+    }
+
+    /// Requests left in the bucket until it resets, if known.
+    pub fn remaining(&self, path: &Path) -> Option<u64> {
+        self.buckets
+            .lock()
+            .expect("ratelimits poisoned")
+            .get(path)
+            .and_then(|bucket| bucket.current(SystemTime::now()).0)
+    }
+
+    /// State of every bucket requests were recently sent for or are waiting
+    /// on, fullest first.
+    pub fn snapshot(&self, queued: &HashMap<Path, usize>) -> Vec<BucketSnapshot> {
+        let now = SystemTime::now();
+        let buckets = self.buckets.lock().expect("ratelimits poisoned");
+        let mut snapshot = buckets
+            .iter()
+            .map(|(path, bucket)| {
+                let (remaining, reset) = bucket.current(now);
+
+                BucketSnapshot {
+                    bucket: format!("{:?}", path),
+                    route: crate::path_name(path),
+                    limit: bucket.limit,
+                    remaining,
+                    resets_at: reset.map(unix_millis),
+                    queued: queued.get(path).copied().unwrap_or_default(),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        // Buckets in use for the first time have no state yet.
+        snapshot.extend(
+            queued
+                .iter()
+                .filter(|(path, _)| !buckets.contains_key(path))
+                .map(|(path, queued)| BucketSnapshot {
+                    bucket: format!("{:?}", path),
+                    route: crate::path_name(path),
+                    limit: None,
+                    remaining: None,
+                    resets_at: None,
+                    queued: *queued,
+                }),
+        );
+        snapshot.sort_by(|a, b| {
+            b.queued
+                .cmp(&a.queued)
+                .then_with(|| a.remaining.unwrap_or(u64::MAX).cmp(&b.remaining.unwrap_or(u64::MAX)))
+        });
+
+        snapshot
+    }
+
+    fn report(&self, queued: &HashMap<Path, usize>) {
+        let now = SystemTime::now();
+        let buckets = self.buckets.lock().expect("ratelimits poisoned");
+
+        for (path, bucket) in buckets.iter() {
+            let route = crate::path_name(path);
+            let major = major(path);
+            let (remaining, reset) = bucket.current(now);
+            let waiting = queued.get(path).copied().unwrap_or_default();
+
+            if let Some(remaining) = remaining {
+                gauge!("gearbot_proxy_bucket_remaining", remaining as i64, "route" => route, "major" => major.clone());
+            }
+
+            let reset = reset.map_or(0, |at| unix_millis(at) / 1000);
+            gauge!("gearbot_proxy_bucket_reset_timestamp", reset as i64, "route" => route, "major" => major.clone());
+            gauge!("gearbot_proxy_bucket_queued", waiting as i64, "route" => route, "major" => major);
+        }
+    }
+}
+
+/// The major parameter of the bucket, which together with the route tells
+/// buckets apart.
+fn major(path: &Path) -> String {
+    distinct::major_parameter(path).map_or_else(String::new, |(_, id)| id.to_string())
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+fn header_number<T: std::str::FromStr>(headers: &HeaderMap, name: &str) -> Option<T> {
+    headers.get(name)?.to_str().ok()?.parse().ok()
+}
+
+/// Periodically report the state of buckets as metrics.
+pub async fn report(state: Arc<State>) {
+    let mut interval = time::interval(REPORT_INTERVAL);
+
+    loop {
+        interval.tick().await;
+        state.ratelimits.report(&state.queue.queued());
+    }
+}
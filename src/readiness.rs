@@ -0,0 +1,44 @@
+//! Whether the proxy should be sent requests, for Kubernetes readiness
+//! probes, so traffic moves to healthy replicas during partial Discord
+//! outages.
+
+use crate::State;
+use serde::Serialize;
+
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    /// Why the proxy isn't ready, if it isn't.
+    pub reasons: Vec<String>,
+}
+
+impl Readiness {
+    pub fn current(state: &State) -> Self {
+        let config = state.config();
+        let mut reasons = Vec::new();
+
+        if state.maintenance.is_enabled() {
+            reasons.push("maintenance mode is on".to_owned());
+        }
+
+        for family in state.breakers.open(&config.breaker) {
+            reasons.push(format!("circuit breaker for {} is open", family));
+        }
+
+        if let Some(wait) = state
+            .ratelimits
+            .global_wait_time()
+            .filter(|wait| *wait > config.readiness.global_ratelimit_threshold)
+        {
+            reasons.push(format!(
+                "global ratelimit is used up for another {}s",
+                wait.as_secs()
+            ));
+        }
+
+        Self {
+            ready: reasons.is_empty(),
+            reasons,
+        }
+    }
+}
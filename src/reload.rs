@@ -0,0 +1,194 @@
+use crate::{
+    config::{self, BuildingClient, Config, ConfigError},
+    hooks::{self, Event},
+    upstream::{self, Upstream},
+    State,
+};
+use snafu::ResultExt;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    time,
+};
+use tracing::{error, info, warn};
+use tracing_subscriber::EnvFilter;
+
+/// Load the configuration again and apply it to the running proxy.
+///
+/// Changing a setting that's only read on startup, as listed in the
+/// Reloading section of the README, logs a warning.
+pub fn reload(state: &State) -> Result<(), ConfigError> {
+    let config = Config::load()?;
+    let current = state.config();
+
+    if config.listen != current.listen
+        || config.host != current.host
+        || config.port != current.port
+        || config.redirect != current.redirect
+    {
+        warn!("Changing the address requires a restart");
+    }
+
+    if config.http2 != current.http2 {
+        warn!("Changing the HTTP/2 settings requires a restart");
+    }
+
+    if config.limits != current.limits {
+        warn!("Changing the connection or request limits requires a restart");
+    }
+
+    if config.keep_alive != current.keep_alive {
+        warn!("Changing the keep-alive settings requires a restart");
+    }
+
+    if config.slow_routes.concurrency != current.slow_routes.concurrency {
+        warn!("Changing the slow route concurrency requires a restart");
+    }
+
+    if config.queue_dir != current.queue_dir {
+        warn!("Changing the queue directory requires a restart");
+    }
+
+    if config.access_log != current.access_log || config.audit.file != current.audit.file {
+        warn!("Changing the access or audit log file requires a restart");
+    }
+
+    if config.mock != current.mock {
+        warn!("Changing the mock settings requires a restart");
+    }
+
+    if config.cache.size != current.cache.size {
+        warn!("Changing the cache size requires a restart");
+    }
+
+    if config.cdn != current.cdn {
+        warn!("Changing the CDN settings requires a restart");
+    }
+
+    if config.seed != current.seed {
+        warn!("Changing the seed requires a restart");
+    }
+
+    if config.metrics != current.metrics {
+        warn!("Changing the metrics settings requires a restart");
+    }
+
+    if config.verify_token != current.verify_token {
+        warn!("Changing whether the token is verified requires a restart");
+    }
+
+    // Built before anything is changed, so a failure leaves everything as it
+    // was.
+    let client = if config.token != current.token || config.upstream != current.upstream {
+        Some(upstream::client(&config.token, &config.upstream).context(BuildingClient)?)
+    } else {
+        None
+    };
+
+    if config.log_level != current.log_level {
+        let filter = match &config.log_level {
+            Some(level) => EnvFilter::new(level),
+            None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        };
+
+        // Embedders without the proxy's own logging have nothing to change.
+        if let Some(Err(source)) = state.log_filter.as_ref().map(|handle| handle.reload(filter)) {
+            error!("Failed to change the log level: {}", source);
+        }
+    }
+
+    if let Some(client) = client {
+        info!("Token or upstream settings changed, swapping the Discord client");
+        swap_client(state, client);
+    }
+
+    let token_rotated = config.token != current.token;
+    *state.config.write().expect("config poisoned") = Arc::new(config);
+    info!("Reloaded configuration");
+
+    if token_rotated {
+        hooks::fire(&state.config(), Event::TokenRotated);
+    }
+
+    Ok(())
+}
+
+/// Replace the Discord client, returning the old one.
+///
+/// Requests already sent keep using the old client. Ratelimit buckets are
+/// learned again by the new one.
+pub fn swap_client(state: &State, client: Upstream) -> Arc<Upstream> {
+    let mut current = state.client.write().expect("client poisoned");
+    state.rotation.reset();
+
+    std::mem::replace(&mut *current, Arc::new(client))
+}
+
+/// Wait until no request uses the client anymore, or the timeout elapses.
+///
+/// Returns whether the client was drained.
+pub async fn drain(client: Arc<Upstream>, timeout: Duration) -> bool {
+    let start = Instant::now();
+
+    while Arc::strong_count(&client) > 1 {
+        if start.elapsed() >= timeout {
+            return false;
+        }
+
+        time::delay_for(Duration::from_millis(50)).await;
+    }
+
+    true
+}
+
+/// How often the token file is checked for a new token.
+const TOKEN_FILE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Reload the configuration when the token file holds a different token, e.g.
+/// because the secret it's mounted from was updated.
+pub async fn watch_token_file(state: Arc<State>) {
+    let mut interval = time::interval(TOKEN_FILE_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let current = state.config();
+        let path = match &current.token_file {
+            Some(path) => path,
+            None => continue,
+        };
+
+        match config::read_token(path) {
+            Ok(token) if token != current.token => {
+                info!("Token file changed, reloading configuration");
+
+                if let Err(source) = reload(&state) {
+                    error!("Failed to reload configuration: {}", source);
+                }
+            }
+            Ok(_) => {}
+            Err(source) => warn!("Failed to check token file: {}", source),
+        }
+    }
+}
+
+/// Reload the configuration whenever the process receives `SIGHUP`.
+pub async fn watch_signal(state: Arc<State>) {
+    let mut signals = match signal(SignalKind::hangup()) {
+        Ok(signals) => signals,
+        Err(source) => {
+            error!("Failed to listen for SIGHUP: {}", source);
+
+            return;
+        }
+    };
+
+    while signals.recv().await.is_some() {
+        if let Err(source) = reload(&state) {
+            error!("Failed to reload configuration: {}", source);
+        }
+    }
+}
@@ -0,0 +1,230 @@
+//! Checking a new version of the proxy against recorded traffic before
+//! upgrading, by comparing how each version would handle it.
+
+use crate::{admin, breaker, config::Config, error::RequestError, headers, proxy::ProxyRequest};
+use http::{
+    header::{HeaderMap, HeaderName, HeaderValue, CONTENT_LENGTH, HOST},
+    Method, Uri,
+};
+use hyper::body::Bytes;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+
+/// How the proxy sorts a request, as far as it can tell from the method and
+/// path alone.
+#[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Classification {
+    /// Name of the route, as in the `route` label of metrics.
+    pub route: String,
+    /// Ratelimit bucket the request is queued in.
+    pub bucket: String,
+    /// Circuit breaker the request goes through.
+    pub breaker: String,
+    /// Whether the route is one of the `SLOW_ROUTES`.
+    pub slow: bool,
+    /// Whether the route is one of the `LOG_HEADERS_ROUTES`.
+    pub headers_logged: bool,
+}
+
+/// Query of the classification endpoint.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Query {
+    pub method: String,
+    /// Path as a client would request it, like `/api/v6/channels/1`.
+    pub path: String,
+}
+
+impl Classification {
+    pub fn of(config: &Config, query: &Query) -> Result<Self, String> {
+        let method = Method::from_bytes(query.method.as_bytes())
+            .map_err(|_| format!("invalid method {}", query.method))?;
+        let uri = query
+            .path
+            .parse::<Uri>()
+            .map_err(|_| format!("invalid path {}", query.path))?;
+        let request = match ProxyRequest::from_parts(
+            method,
+            &uri,
+            HeaderMap::new(),
+            Bytes::new(),
+            config.unknown_routes.passthrough,
+        ) {
+            Ok(request) => request,
+            Err(RequestError::TraversingPath { .. }) => {
+                return Err("path climbs above the API root".to_owned())
+            }
+            Err(_) => return Err(format!("unknown path {}", query.path)),
+        };
+
+        Ok(Self {
+            route: request.route.to_owned(),
+            bucket: format!("{:?}", request.path),
+            breaker: breaker::family(&request.path_and_query).to_owned(),
+            slow: config.slow_routes.contains(request.route),
+            headers_logged: config
+                .header_log
+                .routes
+                .iter()
+                .any(|route| route == request.route),
+        })
+    }
+}
+
+/// HTTP Archive, as saved by browsers' developer tools and most recording
+/// proxies. Only the parts needed to replay requests are read.
+#[derive(Debug, Deserialize)]
+struct Har {
+    log: Log,
+}
+
+#[derive(Debug, Deserialize)]
+struct Log {
+    entries: Vec<Entry>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Entry {
+    request: HarRequest,
+    response: HarResponse,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct HarRequest {
+    method: String,
+    url: String,
+    #[serde(default)]
+    headers: Vec<HarHeader>,
+    post_data: Option<PostData>,
+}
+
+#[derive(Debug, Deserialize)]
+struct HarHeader {
+    name: String,
+    value: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct PostData {
+    #[serde(default)]
+    text: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct HarResponse {
+    status: u16,
+}
+
+/// Run the `replay` command, sending the requests in a HAR file to the proxy
+/// at the target URL, which should be running with `MOCK_UPSTREAM` or
+/// `DRY_RUN`.
+///
+/// Each request is classified both by this build and by the target, and
+/// differences between them, as well as responses with a different status
+/// than recorded, are printed. Fails if there were any.
+pub async fn command(config: &Config, file: &str, target: &str) -> Result<(), Box<dyn Error>> {
+    let har = serde_json::from_slice::<Har>(&tokio::fs::read(file).await?)?;
+    let target = target.trim_end_matches('/');
+    let client = reqwest::Client::new();
+    let (mut classified, mut answered, mut failed) = (0, 0, 0);
+
+    for entry in &har.log.entries {
+        let request = &entry.request;
+        let uri = request.url.parse::<Uri>()?;
+        let path = uri.path_and_query().map_or("/", |path| path.as_str());
+        let query = Query {
+            method: request.method.clone(),
+            path: path.to_owned(),
+        };
+        let name = format!("{} {}", request.method, path);
+
+        let local = Classification::of(config, &query);
+        let remote = client
+            .get(&format!(
+                "{}{}classify?{}",
+                target,
+                admin::PREFIX,
+                serde_urlencoded::to_string(&query)?
+            ))
+            .send()
+            .await?;
+        let remote = if remote.status().is_success() {
+            Ok(serde_json::from_slice::<Classification>(
+                &remote.bytes().await?,
+            )?)
+        } else {
+            Err(remote.text().await?)
+        };
+
+        match (&local, &remote) {
+            (Ok(local), Ok(remote)) if local == remote => {}
+            (Err(_), Err(_)) => {}
+            _ => {
+                classified += 1;
+                println!(
+                    "{}: classified as {:?} here but {:?} by the target",
+                    name, local, remote
+                );
+            }
+        }
+
+        let mut request_headers = HeaderMap::new();
+
+        for header in &request.headers {
+            if let (Ok(name), Ok(value)) = (
+                HeaderName::from_bytes(header.name.as_bytes()),
+                HeaderValue::from_str(&header.value),
+            ) {
+                request_headers.append(name, value);
+            }
+        }
+
+        headers::strip_hop_by_hop(&mut request_headers);
+        request_headers.remove(HOST);
+        request_headers.remove(CONTENT_LENGTH);
+
+        let body = request
+            .post_data
+            .as_ref()
+            .map(|data| data.text.clone())
+            .unwrap_or_default();
+        let method = Method::from_bytes(request.method.as_bytes())?;
+        let response = client
+            .request(method, &format!("{}{}", target, path))
+            .headers(request_headers)
+            .body(body)
+            .send()
+            .await;
+
+        match response {
+            Ok(response) if response.status().as_u16() == entry.response.status => {}
+            Ok(response) => {
+                answered += 1;
+                println!(
+                    "{}: answered with {} instead of the recorded {}",
+                    name,
+                    response.status().as_u16(),
+                    entry.response.status
+                );
+            }
+            Err(source) => {
+                failed += 1;
+                println!("{}: failed: {}", name, source);
+            }
+        }
+    }
+
+    println!(
+        "Replayed {} requests: {} classified differently, {} answered differently, {} failed",
+        har.log.entries.len(),
+        classified,
+        answered,
+        failed
+    );
+
+    if classified + answered + failed > 0 {
+        return Err("the target handled some requests differently".into());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,57 @@
+//! Ids tying together the log lines of a request.
+
+use http::header::HeaderMap;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::sync::Mutex;
+
+/// Header carrying the id of a request, both from clients and back to them.
+pub const HEADER: &str = "x-request-id";
+
+/// Longest id accepted from a client.
+const MAX_LENGTH: usize = 128;
+
+/// Hands out request, job and span ids.
+///
+/// With a seed, which is only taken in mock mode, the same sequence of ids is
+/// handed out on every run, so logs of soak tests replaying the same traffic
+/// can be compared line by line.
+#[derive(Debug)]
+pub struct RequestIds {
+    rng: Mutex<StdRng>,
+}
+
+impl RequestIds {
+    pub fn new(seed: Option<u64>) -> Self {
+        let rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+
+        Self {
+            rng: Mutex::new(rng),
+        }
+    }
+
+    pub fn next(&self) -> String {
+        let id = self.rng.lock().expect("request ids poisoned").gen::<u128>();
+
+        format!("{:032x}", id)
+    }
+
+    /// A new id for a span of a trace, which is never all zeroes.
+    pub fn next_span(&self) -> String {
+        let id = self.rng.lock().expect("request ids poisoned").gen_range(1, u64::MAX);
+
+        format!("{:016x}", id)
+    }
+
+    /// Remove the id the client sent with the request, returning it if it's
+    /// usable and a new one otherwise.
+    pub fn take(&self, headers: &mut HeaderMap) -> String {
+        headers
+            .remove(HEADER)
+            .and_then(|value| value.to_str().ok().map(str::to_owned))
+            .filter(|id| !id.is_empty() && id.len() <= MAX_LENGTH)
+            .unwrap_or_else(|| self.next())
+    }
+}
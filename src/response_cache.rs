@@ -0,0 +1,217 @@
+//! Caching responses to `GET` requests on the routes configured, so bots
+//! polling the same guilds and channels don't each wait on Discord.
+//!
+//! Responses with an `ETag` are revalidated with Discord once they expire,
+//! and clients sending `If-None-Match` get a `304` when their copy is
+//! current.
+//!
+//! Responses are cached per tenant, as what Discord answers can depend on
+//! who's asking, unless the route is configured as shared.
+//!
+//! Successful writes drop the cached responses for the resource they were
+//! made on, so a `PATCH` to a channel isn't followed by its old version.
+//!
+//! Routes can also serve responses for a while after they expire, fetching
+//! them again in the background, so callers don't wait on Discord for hot
+//! resources.
+
+use crate::{
+    cache,
+    config::CacheConfig,
+    error::RequestError,
+    proxy::{self, ProxyRequest, ProxyResponse},
+    State,
+};
+use http::{
+    header::{HeaderMap, HeaderValue, AGE, CACHE_CONTROL, ETAG, IF_NONE_MATCH, VARY},
+    Method, StatusCode,
+};
+use hyper::body::Bytes;
+use metrics::counter;
+use std::{sync::Arc, time::Duration};
+use tracing::{debug, warn};
+
+/// Whether responses to the request are cached.
+pub fn cacheable(config: &CacheConfig, request: &ProxyRequest) -> bool {
+    request.method == Method::GET && config.contains(request.route)
+}
+
+/// Whether the request changes the resource it's made on.
+pub fn is_mutation(request: &ProxyRequest) -> bool {
+    request.method != Method::GET && request.method != Method::HEAD
+}
+
+/// Key the response to the request is cached under, scoped to the tenant
+/// that sent it unless the route is shared.
+fn key(config: &CacheConfig, request: &ProxyRequest) -> String {
+    match &request.tenant {
+        Some(tenant) if !config.is_shared(request.route) => {
+            format!("{}\0{}", tenant, request.path_and_query)
+        }
+        _ => request.path_and_query.clone(),
+    }
+}
+
+/// Path a key is for, without the tenant or query.
+fn path_of(key: &str) -> &str {
+    let path_and_query = key.rsplit('\0').next().unwrap_or_default();
+
+    path_and_query.split('?').next().unwrap_or_default()
+}
+
+/// Drop the cached responses for a path, whatever their query and for every
+/// tenant, returning how many there were.
+pub async fn invalidate(state: &State, path: &str, reason: &'static str) -> usize {
+    let path = path
+        .split('?')
+        .next()
+        .unwrap_or_default()
+        .trim_start_matches('/');
+    let removed = state
+        .response_cache
+        .remove_where(|key| path_of(key) == path)
+        .await;
+
+    if removed > 0 {
+        debug!("Dropped {} cached responses for {}", removed, path);
+        counter!("gearbot_proxy_cache_invalidations", removed as u64, "reason" => reason);
+    }
+
+    removed
+}
+
+/// Forward the request, answering from the cache if it can.
+pub async fn forward(
+    state: &Arc<State>,
+    mut request: ProxyRequest,
+) -> Result<ProxyResponse, RequestError> {
+    let config = state.config();
+
+    if !cacheable(&config.cache, &request) {
+        return proxy::forward(state, request).await;
+    }
+
+    let route = request.route;
+    let key = key(&config.cache, &request);
+    let if_none_match = request.headers.remove(IF_NONE_MATCH);
+    // Cached uncompressed, so they can be served to any client.
+    request.accept_encoding = None;
+
+    let (response, result) = match state.response_cache.get(&key).await {
+        Some(hit) if hit.fresh => (with_age(hit.response, hit.age), "hit"),
+        Some(hit) if hit.age < hit.ttl + config.cache.stale_while_revalidate(route) => {
+            if state.response_cache.start_refresh(&key) {
+                let state = Arc::clone(state);
+                let response = hit.response.clone();
+                let key = key.clone();
+
+                tokio::spawn(async move {
+                    let path = request.path_and_query.clone();
+
+                    if let Err(source) = fetch(&state, key.clone(), request, Some(response)).await {
+                        warn!("Failed to refresh the cached {}: {}", path, source);
+                    }
+
+                    state.response_cache.finish_refresh(&key);
+                });
+            }
+
+            (with_age(hit.response, hit.age), "stale")
+        }
+        hit => fetch(state, key, request, hit.map(|hit| hit.response)).await?,
+    };
+    counter!("gearbot_proxy_cache_requests", 1, "route" => route, "result" => result);
+
+    let mut response = match if_none_match {
+        Some(tags) if matches(&tags, response.headers.get(ETAG)) => not_modified(response),
+        _ => response,
+    };
+    response
+        .headers
+        .insert(cache::HEADER, HeaderValue::from_static(result));
+
+    Ok(response)
+}
+
+/// Fetch the response from Discord and cache it, revalidating the expired
+/// one if there is one.
+async fn fetch(
+    state: &State,
+    key: String,
+    mut request: ProxyRequest,
+    expired: Option<ProxyResponse>,
+) -> Result<(ProxyResponse, &'static str), RequestError> {
+    let ttl = state.config().cache.ttl;
+    let path = request.path_and_query.clone();
+    // Expired responses are only worth keeping if Discord can say they're
+    // still current.
+    let stale = expired.filter(|response| response.headers.contains_key(ETAG));
+
+    if let Some(etag) = stale
+        .as_ref()
+        .and_then(|response| response.headers.get(ETAG))
+    {
+        request.headers.insert(IF_NONE_MATCH, etag.clone());
+    }
+
+    let response = proxy::forward(state, request).await?;
+
+    match stale {
+        Some(stale) if response.status == StatusCode::NOT_MODIFIED => {
+            debug!("Discord says the cached {} is still current", path);
+            state.response_cache.insert(key, &stale, ttl).await;
+
+            Ok((stale, "revalidated"))
+        }
+        _ => {
+            if response.status == StatusCode::OK {
+                state.response_cache.insert(key, &response, ttl).await;
+            }
+
+            Ok((response, "miss"))
+        }
+    }
+}
+
+fn with_age(mut response: ProxyResponse, age: Duration) -> ProxyResponse {
+    response
+        .headers
+        .insert(AGE, HeaderValue::from(age.as_secs()));
+
+    for name in proxy::TIMING_HEADERS {
+        response.headers.remove(*name);
+    }
+
+    response
+}
+
+/// Whether an `If-None-Match` header matches the `ETag`, comparing weakly as
+/// is done for `GET` requests.
+fn matches(tags: &HeaderValue, etag: Option<&HeaderValue>) -> bool {
+    let etag = match etag.and_then(|etag| etag.to_str().ok()) {
+        Some(etag) => etag.trim_start_matches("W/"),
+        None => return false,
+    };
+
+    tags.to_str().is_ok_and(|tags| {
+        tags.split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+    })
+}
+
+fn not_modified(response: ProxyResponse) -> ProxyResponse {
+    let mut headers = HeaderMap::new();
+
+    for (name, value) in &response.headers {
+        if [ETAG, AGE, CACHE_CONTROL, VARY].contains(name) {
+            headers.append(name, value.clone());
+        }
+    }
+
+    ProxyResponse {
+        status: StatusCode::NOT_MODIFIED,
+        headers,
+        body: Bytes::new(),
+    }
+}
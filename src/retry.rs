@@ -0,0 +1,195 @@
+use crate::{cloudflare, config::RetryConfig, error::RequestError, proxy::ProxyResponse};
+use http::{
+    header::{HeaderMap, RETRY_AFTER},
+    Method, StatusCode,
+};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use twilight_http::error::Error as TwilightError;
+
+/// Request header asking for a number of retries, and response header telling
+/// clients how many times the proxy retried.
+pub const RETRIES_HEADER: &str = "x-proxy-retries";
+
+/// Request header listing what to retry on, e.g. `5xx,429`.
+pub const RETRY_ON_HEADER: &str = "x-proxy-retry-on";
+
+/// Something going wrong that may be worth retrying.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Condition {
+    /// The request failed before Discord answered it.
+    Connection,
+    /// Any 5xx response.
+    ServerError,
+    /// An error page from Cloudflare rather than a response from Discord.
+    Cloudflare,
+    Status(u16),
+}
+
+impl Condition {
+    fn parse(raw: &str) -> Option<Self> {
+        match raw.trim().to_ascii_lowercase().as_str() {
+            "connect" | "connection" => Some(Condition::Connection),
+            "5xx" => Some(Condition::ServerError),
+            "cloudflare" => Some(Condition::Cloudflare),
+            code => code.parse().ok().map(Condition::Status),
+        }
+    }
+
+    fn matches(self, result: &Result<ProxyResponse, RequestError>) -> bool {
+        match (self, result) {
+            (Condition::Connection, Err(source)) => is_transient_error(source),
+            (Condition::ServerError, Ok(response)) => response.status.is_server_error(),
+            (Condition::Cloudflare, Ok(response)) => response.headers.contains_key(cloudflare::HEADER),
+            (Condition::Status(code), Ok(response)) => response.status.as_u16() == code,
+            _ => false,
+        }
+    }
+}
+
+/// Conditions retried when the client doesn't say otherwise.
+const DEFAULT_CONDITIONS: &[Condition] = &[
+    Condition::Connection,
+    Condition::Cloudflare,
+    Condition::Status(502),
+    Condition::Status(503),
+];
+
+/// Retry behavior a client asked for on a single request.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Override {
+    pub retries: Option<u32>,
+    pub retry_on: Option<Vec<Condition>>,
+}
+
+impl Override {
+    /// Take the retry headers out of the request headers, so they aren't
+    /// forwarded to Discord.
+    ///
+    /// Values that can't be parsed are ignored.
+    pub fn take_from(headers: &mut HeaderMap) -> Self {
+        let retries = headers
+            .remove(RETRIES_HEADER)
+            .and_then(|value| value.to_str().ok()?.trim().parse().ok());
+        let retry_on = headers.remove(RETRY_ON_HEADER).and_then(|value| {
+            let conditions = value
+                .to_str()
+                .ok()?
+                .split(',')
+                .filter_map(Condition::parse)
+                .collect::<Vec<_>>();
+
+            Some(conditions)
+        });
+
+        Self { retries, retry_on }
+    }
+}
+
+/// Retry behavior for a single request, combining the configured defaults
+/// and what the client asked for.
+#[derive(Clone, Debug)]
+pub struct Policy {
+    config: RetryConfig,
+    pub max_retries: u32,
+    conditions: Vec<Condition>,
+}
+
+impl Policy {
+    pub fn new(config: RetryConfig, method: &Method, requested: &Override) -> Self {
+        let max_retries = match requested.retries {
+            Some(retries) => retries.min(config.override_limit),
+            None if is_idempotent(method) => config.max_attempts.saturating_sub(1),
+            None => 0,
+        };
+        let conditions = requested
+            .retry_on
+            .clone()
+            .unwrap_or_else(|| DEFAULT_CONDITIONS.to_vec());
+
+        Self {
+            config,
+            max_retries,
+            conditions,
+        }
+    }
+
+    pub fn should_retry(&self, result: &Result<ProxyResponse, RequestError>) -> bool {
+        self.conditions
+            .iter()
+            .any(|condition| condition.matches(result))
+    }
+
+    /// Delay before the given retry, counting from 1.
+    ///
+    /// Ratelimited responses are retried once Discord says the ratelimit is
+    /// over, and the first retry of a Cloudflare error straight away, as
+    /// those are usually over by the next request.
+    pub fn delay(&self, retry: u32, result: &Result<ProxyResponse, RequestError>) -> Duration {
+        if let Ok(response) = result {
+            if response.status == StatusCode::TOO_MANY_REQUESTS {
+                if let Some(retry_after) = retry_after(&response.headers) {
+                    return retry_after;
+                }
+            }
+
+            if retry == 1 && response.headers.contains_key(cloudflare::HEADER) {
+                return Duration::from_millis(0);
+            }
+        }
+
+        backoff(&self.config, retry)
+    }
+}
+
+/// Whether sending the request twice has the same effect as sending it once.
+pub fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::PUT | Method::DELETE
+    )
+}
+
+/// Whether the request failed before Discord got to answer it.
+pub fn is_transient_error(error: &RequestError) -> bool {
+    match error {
+        RequestError::RequestIssue { source } => matches!(
+            source,
+            TwilightError::RequestError { .. } | TwilightError::RequestCanceled { .. }
+        ),
+        RequestError::ChunkingResponse { .. } => true,
+        _ => false,
+    }
+}
+
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let seconds = headers
+        .get(RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .parse::<f64>()
+        .ok()?;
+
+    if seconds.is_finite() && seconds >= 0.0 {
+        Some(Duration::from_secs_f64(seconds))
+    } else {
+        None
+    }
+}
+
+fn backoff(config: &RetryConfig, retry: u32) -> Duration {
+    let exponential = config
+        .backoff
+        .checked_mul(1 << retry.saturating_sub(1).min(16))
+        .unwrap_or(config.max_backoff)
+        .min(config.max_backoff);
+
+    if config.jitter {
+        // "Full jitter": anywhere between nothing and the exponential delay.
+        exponential.mul_f64(rand::thread_rng().gen::<f64>())
+    } else {
+        exponential
+    }
+}
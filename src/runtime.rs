@@ -0,0 +1,32 @@
+//! Probes of how well the runtime keeps up with its tasks, for telling
+//! executor stalls apart from Discord being slow.
+//!
+//! Tokio doesn't expose counts of its own, so the probes time work the way
+//! the proxy's tasks experience it.
+
+use metrics::timing;
+use std::time::{Duration, Instant};
+use tokio::{task, time};
+
+/// How often the probes run.
+const PROBE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Measure how late the runtime wakes a sleeping task, and how long the
+/// blocking pool takes to start a job.
+///
+/// A task blocking a worker thread holds up every task scheduled on it, so
+/// wakeups get later, while the blocking pool running out of threads has
+/// file and DNS work queue up behind it.
+pub async fn report() {
+    loop {
+        let due = Instant::now() + PROBE_INTERVAL;
+        time::delay_for(PROBE_INTERVAL).await;
+        timing!("gearbot_proxy_scheduler_delay", due, Instant::now());
+
+        let queued = Instant::now();
+
+        if let Ok(started) = task::spawn_blocking(Instant::now).await {
+            timing!("gearbot_proxy_blocking_delay", queued, started);
+        }
+    }
+}
@@ -0,0 +1,117 @@
+//! Moving the proxy's unfinished work from one instance to another.
+
+use crate::{
+    spool::{self, Record, Spool},
+    State,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    io,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{fs, io::AsyncReadExt};
+
+/// Version of the snapshot format, bumped whenever it changes in a way older
+/// proxies can't read.
+pub const VERSION: u32 = 1;
+
+/// Unfinished work of a proxy, in a form another one can pick up.
+///
+/// Ratelimit buckets aren't included, as they're learned again from the first
+/// response for each.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Snapshot {
+    pub version: u32,
+    /// Unix timestamp in milliseconds.
+    pub exported_at: u128,
+    /// Background requests that haven't finished, oldest first.
+    pub jobs: Vec<Record>,
+}
+
+impl Snapshot {
+    pub async fn export(spool: &Spool) -> io::Result<Self> {
+        Ok(Self {
+            version: VERSION,
+            exported_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_millis()),
+            jobs: spool.pending().await?,
+        })
+    }
+
+    pub fn parse(bytes: &[u8]) -> io::Result<Self> {
+        let snapshot = serde_json::from_slice::<Self>(bytes)?;
+
+        if snapshot.version > VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "snapshot version {} is newer than the supported {}",
+                    snapshot.version, VERSION
+                ),
+            ));
+        }
+
+        Ok(snapshot)
+    }
+
+    /// Spool the jobs, so they're run the next time a proxy using the spool
+    /// starts.
+    pub async fn import(self, spool: &Spool) -> io::Result<usize> {
+        let count = self.jobs.len();
+
+        for record in &self.jobs {
+            spool.write(record).await?;
+        }
+
+        Ok(count)
+    }
+
+    /// Spool the jobs if there's a spool and start running them.
+    pub async fn import_live(self, state: &Arc<State>) -> io::Result<usize> {
+        let count = self.jobs.len();
+
+        for record in self.jobs {
+            if let Some(spool) = state.spool.as_ref() {
+                spool.write(&record).await?;
+            }
+
+            spool::resume(state, record).await;
+        }
+
+        Ok(count)
+    }
+}
+
+/// Run the `export-state` command, writing a snapshot of the spool in
+/// `QUEUE_DIR` to the file, or standard output without one.
+pub async fn export_command(spool: &Spool, file: Option<String>) -> io::Result<()> {
+    let snapshot = Snapshot::export(spool).await?;
+    let bytes = serde_json::to_vec_pretty(&snapshot)?;
+
+    match file {
+        Some(file) => fs::write(file, bytes).await,
+        None => {
+            println!("{}", String::from_utf8_lossy(&bytes));
+
+            Ok(())
+        }
+    }
+}
+
+/// Run the `import-state` command, spooling the jobs of a snapshot read from
+/// the file, or standard input without one.
+pub async fn import_command(spool: &Spool, file: Option<String>) -> io::Result<usize> {
+    let bytes = match file {
+        Some(file) => fs::read(file).await?,
+        None => {
+            let mut bytes = Vec::new();
+            tokio::io::stdin().read_to_end(&mut bytes).await?;
+
+            bytes
+        }
+    };
+
+    Snapshot::parse(&bytes)?.import(spool).await
+}
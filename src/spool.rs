@@ -0,0 +1,225 @@
+use crate::{
+    error::{InvalidHeader, RequestError},
+    proxy::{self, ProxyRequest},
+    queue::Priority,
+    retry, State,
+};
+use http::{
+    header::{HeaderMap, HeaderName, HeaderValue},
+    Method, Uri,
+};
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use std::{
+    io,
+    path::{Path as FsPath, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::fs;
+use tracing::{info, warn};
+
+/// Background request as stored on disk.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Record {
+    pub id: String,
+    pub queued_at: u128,
+    pub method: String,
+    pub path_and_query: String,
+    pub headers: Vec<(String, Vec<u8>)>,
+    pub body: Vec<u8>,
+    pub priority: String,
+    #[serde(default)]
+    pub retry: retry::Override,
+    #[serde(default)]
+    pub tenant: Option<String>,
+    #[serde(default)]
+    pub dry_run: bool,
+    /// Unix timestamp in milliseconds, if the request was scheduled.
+    #[serde(default)]
+    pub execute_at: Option<u128>,
+}
+
+impl Record {
+    pub fn new(id: &str, request: &ProxyRequest) -> Self {
+        Self {
+            id: id.to_owned(),
+            queued_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_millis()),
+            method: request.method.to_string(),
+            path_and_query: request.path_and_query.clone(),
+            headers: request
+                .headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.as_bytes().to_vec()))
+                .collect(),
+            body: request.body.to_vec(),
+            priority: request.priority.name().to_owned(),
+            retry: request.retry.clone(),
+            tenant: request.tenant.clone(),
+            dry_run: request.dry_run,
+            execute_at: request.execute_at.map(|execute_at| {
+                execute_at
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |since| since.as_millis())
+            }),
+        }
+    }
+
+    pub fn into_request(self) -> Result<ProxyRequest, RequestError> {
+        let Record {
+            id,
+            method,
+            path_and_query,
+            headers: raw_headers,
+            body,
+            priority,
+            retry,
+            tenant,
+            dry_run,
+            execute_at,
+            ..
+        } = self;
+        let invalid = || RequestError::InvalidRecord { id: id.clone() };
+
+        let method = Method::from_bytes(method.as_bytes()).map_err(|_| invalid())?;
+        let uri = format!("/api/v6/{}", path_and_query.trim_start_matches('/'))
+            .parse::<Uri>()
+            .map_err(|_| invalid())?;
+
+        let mut headers = HeaderMap::with_capacity(raw_headers.len());
+
+        for (name, value) in raw_headers {
+            let name = HeaderName::from_bytes(name.as_bytes()).map_err(|_| invalid())?;
+            let value = HeaderValue::from_bytes(&value).context(InvalidHeader)?;
+
+            headers.append(name, value);
+        }
+
+        // It was accepted before, even if its route wasn't known.
+        let mut request = ProxyRequest::from_parts(method, &uri, headers, body.into(), true)?;
+        request.priority = priority.parse().unwrap_or(Priority::Normal);
+        request.retry = retry;
+        request.tenant = tenant;
+        request.dry_run = dry_run;
+        request.execute_at =
+            execute_at.map(|millis| UNIX_EPOCH + Duration::from_millis(millis as u64));
+
+        Ok(request)
+    }
+}
+
+/// Directory of background requests that haven't finished yet, so they survive
+/// a restart.
+///
+/// Each pending request is a JSON file named after its job id. Requests that
+/// failed for good are moved into the `dead` subdirectory for inspection.
+#[derive(Debug)]
+pub struct Spool {
+    dir: PathBuf,
+}
+
+impl Spool {
+    pub async fn open(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(dir.join("dead")).await?;
+
+        Ok(Self { dir })
+    }
+
+    fn file(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+
+    pub async fn write(&self, record: &Record) -> io::Result<()> {
+        let bytes = serde_json::to_vec(record)?;
+        let tmp = self.dir.join(format!("{}.tmp", record.id));
+
+        // Write then rename, so a crash mid-write can't leave a truncated
+        // record behind.
+        fs::write(&tmp, bytes).await?;
+        fs::rename(&tmp, self.file(&record.id)).await
+    }
+
+    pub async fn remove(&self, id: &str) {
+        if let Err(source) = fs::remove_file(self.file(id)).await {
+            warn!("Failed to remove spooled request {}: {}", id, source);
+        }
+    }
+
+    pub async fn dead_letter(&self, id: &str) {
+        let to = self.dir.join("dead").join(format!("{}.json", id));
+
+        if let Err(source) = fs::rename(self.file(id), to).await {
+            warn!("Failed to dead-letter spooled request {}: {}", id, source);
+        }
+    }
+
+    /// Records left over from a previous run, oldest first.
+    pub async fn pending(&self) -> io::Result<Vec<Record>> {
+        let mut records = Vec::new();
+        let mut entries = fs::read_dir(&self.dir).await?;
+
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+
+            match read(&path).await {
+                Ok(record) => records.push(record),
+                Err(source) => warn!("Skipping unreadable spool file {:?}: {}", path, source),
+            }
+        }
+
+        records.sort_by_key(|record| record.queued_at);
+
+        Ok(records)
+    }
+}
+
+async fn read(path: &FsPath) -> io::Result<Record> {
+    let bytes = fs::read(path).await?;
+
+    Ok(serde_json::from_slice(&bytes)?)
+}
+
+/// Restart the background requests a previous run didn't get to finish.
+pub async fn replay(state: Arc<State>) -> io::Result<()> {
+    let spool = match state.spool.as_ref() {
+        Some(spool) => spool,
+        None => return Ok(()),
+    };
+    let records = spool.pending().await?;
+
+    if !records.is_empty() {
+        info!("Replaying {} spooled background requests", records.len());
+    }
+
+    for record in records {
+        resume(&state, record).await;
+    }
+
+    Ok(())
+}
+
+/// Start running a spooled request as a job again.
+pub async fn resume(state: &Arc<State>, record: Record) {
+    let id = record.id.clone();
+
+    match record.into_request() {
+        Ok(request) => {
+            state.jobs.insert(&id, request.tenant.as_deref());
+            proxy::spawn_job(Arc::clone(state), id, request);
+        }
+        Err(source) => {
+            warn!("Spooled request {} can't be replayed: {:?}", id, source);
+
+            if let Some(spool) = state.spool.as_ref() {
+                spool.dead_letter(&id).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use crate::{
+    config::{Config, Tenant},
+    exporters::constant_time_eq,
+};
+use http::header::{HeaderMap, CONTENT_TYPE};
+use hyper::body::Bytes;
+use metrics::counter;
+use serde::de::IgnoredAny;
+
+/// Header clients identify themselves with, holding their tenant's key.
+pub const KEY_HEADER: &str = "x-proxy-key";
+
+/// Who a request is from.
+#[derive(Debug)]
+pub enum Identity<'a> {
+    Anonymous,
+    Tenant(&'a Tenant),
+    /// The request carried a key no tenant has.
+    Unknown,
+}
+
+/// Take the key out of the request headers, so it isn't forwarded to Discord,
+/// and find the tenant it belongs to.
+pub fn identify<'a>(config: &'a Config, headers: &mut HeaderMap) -> Identity<'a> {
+    let key = match headers.remove(KEY_HEADER) {
+        Some(key) => key,
+        None => return Identity::Anonymous,
+    };
+    let key = match key.to_str() {
+        Ok(key) => key,
+        Err(_) => return Identity::Unknown,
+    };
+
+    config
+        .tenants
+        .iter()
+        .find(|tenant| constant_time_eq(&tenant.key, key))
+        .map_or(Identity::Unknown, Identity::Tenant)
+}
+
+/// Strip insignificant whitespace from a JSON body, leaving anything else
+/// alone.
+pub fn minify(tenant: &Tenant, headers: &HeaderMap, body: Bytes) -> Bytes {
+    let is_json = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"));
+
+    // Whitespace can only be stripped safely if it really is JSON.
+    if !is_json || serde_json::from_slice::<IgnoredAny>(&body).is_err() {
+        return body;
+    }
+
+    let mut minified = Vec::with_capacity(body.len());
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for byte in &body {
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if *byte == b'\\' {
+                escaped = true;
+            } else if *byte == b'"' {
+                in_string = false;
+            }
+        } else if byte.is_ascii_whitespace() {
+            continue;
+        } else if *byte == b'"' {
+            in_string = true;
+        }
+
+        minified.push(*byte);
+    }
+
+    let saved = body.len() - minified.len();
+
+    if saved > 0 {
+        counter!("gearbot_proxy_minified_bytes", saved as u64, "tenant" => tenant.name.clone());
+    }
+
+    minified.into()
+}
@@ -0,0 +1,89 @@
+//! W3C trace context, tying the proxy's spans into the traces of its clients.
+
+use http::header::{HeaderMap, HeaderValue};
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+pub const TRACEPARENT: &str = "traceparent";
+pub const TRACESTATE: &str = "tracestate";
+/// Header telling the client which span of the proxy handled its request.
+pub const TRACERESPONSE: &str = "traceresponse";
+
+/// Position in a trace, as carried by `traceparent`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TraceParent {
+    pub trace_id: String,
+    /// Span the receiver's spans are children of.
+    pub parent_id: String,
+    pub flags: u8,
+}
+
+impl TraceParent {
+    /// Start a new trace.
+    pub fn root(trace_id: String, span_id: String) -> Self {
+        Self {
+            trace_id,
+            parent_id: span_id,
+            flags: 0,
+        }
+    }
+
+    /// Parse a `traceparent` header. Invalid values are ignored, as the
+    /// specification asks, starting a new trace instead.
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut fields = value.trim().splitn(5, '-');
+        let version = fields.next().filter(|version| is_hex(version, 2) && *version != "ff")?;
+        let trace_id = fields.next().filter(|id| is_trace_id(id))?;
+        let parent_id = fields.next().filter(|id| is_hex(id, 16) && !is_zero(id))?;
+        let flags = fields.next().filter(|flags| is_hex(flags, 2))?;
+
+        // Later versions may add fields, but the first version has none.
+        if version == "00" && fields.next().is_some() {
+            return None;
+        }
+
+        Some(Self {
+            trace_id: trace_id.to_owned(),
+            parent_id: parent_id.to_owned(),
+            flags: u8::from_str_radix(flags, 16).ok()?,
+        })
+    }
+
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        Self::parse(headers.get(TRACEPARENT)?.to_str().ok()?)
+    }
+
+    /// Context for a span that's a child of this one.
+    pub fn child(&self, span_id: String) -> Self {
+        Self {
+            trace_id: self.trace_id.clone(),
+            parent_id: span_id,
+            flags: self.flags,
+        }
+    }
+
+    pub fn to_header(&self) -> HeaderValue {
+        HeaderValue::from_str(&self.to_string()).expect("trace context is hex")
+    }
+}
+
+impl Display for TraceParent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "00-{}-{}-{:02x}", self.trace_id, self.parent_id, self.flags)
+    }
+}
+
+pub fn is_trace_id(value: &str) -> bool {
+    is_hex(value, 32) && !is_zero(value)
+}
+
+/// Whether the value is lowercase hex of the given length.
+fn is_hex(value: &str, length: usize) -> bool {
+    value.len() == length
+        && value
+            .bytes()
+            .all(|byte| byte.is_ascii_digit() || (b'a'..=b'f').contains(&byte))
+}
+
+fn is_zero(value: &str) -> bool {
+    value.bytes().all(|byte| byte == b'0')
+}
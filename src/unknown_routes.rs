@@ -0,0 +1,290 @@
+//! Passing through requests for routes the proxy doesn't know yet, like ones
+//! Discord just shipped, so it doesn't have to be redeployed for them.
+//!
+//! Without a route there's no ratelimit bucket to go by, so they're all queued
+//! as a single bucket and requests for the same path are spaced out on top.
+//!
+//! Either way, the paths are counted with their ids left out and summarized
+//! in the logs now and then, showing which routes the proxy should learn
+//! next.
+
+use crate::{cardinality, proxy, State};
+use http::Method;
+use metrics::counter;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::time;
+use tracing::info;
+use twilight_http::routing::Path;
+
+/// Name of the route in metrics and logs.
+pub const ROUTE: &str = "Unknown";
+
+/// Bucket the requests share. Webhook ids are never 0, so no real request
+/// lands in it.
+pub const BUCKET: Path = Path::WebhooksId(0);
+
+/// How often the unknown paths seen are logged.
+const SUMMARY_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Most paths told apart; others are counted as one, keeping a client
+/// sending garbage from growing the metrics and summaries without limit.
+const MAX_PATHS: usize = 100;
+
+/// Most paths listed in a summary.
+const SUMMARY_LENGTH: usize = 10;
+
+/// Whether the path looks like it's for Discord's API, so it's worth
+/// forwarding even though its route isn't known.
+///
+/// Empty segments are ignored, as they are when the path is normalized, and
+/// paths resolving to the API root itself don't count.
+/// Percent-encoded characters and colons are allowed for the emojis of
+/// reaction routes.
+pub fn is_api_path(path: &str) -> bool {
+    let segments = path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect::<Vec<_>>();
+
+    match segments.as_slice() {
+        ["api", "v6", rest @ ..] => {
+            rest.iter().all(|segment| {
+                segment
+                    .bytes()
+                    .all(|byte| byte.is_ascii_alphanumeric() || b"@._-%:".contains(&byte))
+            }) && proxy::normalize_path(path).is_some_and(|below_root| below_root != "/")
+        }
+        _ => false,
+    }
+}
+
+/// When the next request for every unknown path may be sent.
+#[derive(Debug, Default)]
+pub struct Pacer {
+    next: Mutex<HashMap<String, Instant>>,
+}
+
+impl Pacer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Wait until a request for the path may be sent, at least `interval`
+    /// after the last one.
+    pub async fn wait(&self, path: &str, interval: Duration) {
+        let path = path.split('?').next().unwrap_or_default();
+        let at = {
+            let now = Instant::now();
+            let mut next = self.next.lock().expect("pacer poisoned");
+            next.retain(|_, at| *at > now);
+
+            let at = next.get(path).copied().unwrap_or(now).max(now);
+            next.insert(path.to_owned(), at + interval);
+
+            at
+        };
+
+        time::delay_until(at.into()).await;
+    }
+}
+
+/// The path below the API root with ids, tokens and emojis replaced, so
+/// requests for the same route are counted together, e.g.
+/// `applications/{id}/commands`.
+///
+/// Emojis are left percent-encoded in paths, like `%F0%9F%91%8D`, or are
+/// custom ones written as `name:id`.
+pub fn normalize(path: &str) -> String {
+    let path = path.split('?').next().unwrap_or_default();
+    let path = proxy::normalize_path(path).unwrap_or_default();
+
+    path.split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| {
+            if segment.bytes().all(|byte| byte.is_ascii_digit()) {
+                "{id}"
+            } else if segment.contains('%') || segment.contains(':') {
+                "{emoji}"
+            } else if segment.len() >= 32 {
+                // Webhook and interaction tokens.
+                "{token}"
+            } else {
+                segment
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Unknown paths seen since the last summary.
+#[derive(Debug, Default)]
+pub struct Seen {
+    /// Normalized paths, and every one past the limit as
+    /// [`cardinality::OTHER`], by method.
+    counts: Mutex<HashMap<(Method, String), u64>>,
+    /// Paths told apart so far, kept across summaries so the metric's labels
+    /// stay bounded.
+    known: Mutex<Vec<String>>,
+}
+
+impl Seen {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, method: &Method, path: &str) {
+        let path = {
+            let normalized = normalize(path);
+            let mut known = self.known.lock().expect("unknown paths poisoned");
+
+            if known.contains(&normalized) {
+                normalized
+            } else if known.len() < MAX_PATHS {
+                known.push(normalized.clone());
+
+                normalized
+            } else {
+                cardinality::OTHER.to_owned()
+            }
+        };
+
+        counter!("gearbot_proxy_unknown_paths", 1, "method" => method.to_string(), "path" => path.clone());
+        *self
+            .counts
+            .lock()
+            .expect("unknown paths poisoned")
+            .entry((method.clone(), path))
+            .or_default() += 1;
+    }
+
+    /// Log the paths seen since the last summary, the most common first.
+    fn summarize(&self) {
+        let counts = std::mem::take(&mut *self.counts.lock().expect("unknown paths poisoned"));
+
+        if counts.is_empty() {
+            return;
+        }
+
+        let mut counts = counts.into_iter().collect::<Vec<_>>();
+        counts.sort_by(|((a_method, a), a_count), ((b_method, b), b_count)| {
+            b_count
+                .cmp(a_count)
+                .then_with(|| a.cmp(b))
+                .then_with(|| a_method.as_str().cmp(b_method.as_str()))
+        });
+        let listed = counts
+            .iter()
+            .take(SUMMARY_LENGTH)
+            .map(|((method, path), count)| format!("{} {} ({})", method, path, count))
+            .collect::<Vec<_>>();
+
+        info!(
+            "Requests for unknown routes in the last {}: {}{}",
+            humantime::format_duration(SUMMARY_INTERVAL),
+            listed.join(", "),
+            if counts.len() > SUMMARY_LENGTH {
+                format!(" and {} more", counts.len() - SUMMARY_LENGTH)
+            } else {
+                String::new()
+            }
+        );
+    }
+}
+
+/// Periodically log a summary of the unknown paths seen.
+pub async fn report(state: Arc<State>) {
+    let mut interval = time::interval(SUMMARY_INTERVAL);
+
+    loop {
+        interval.tick().await;
+        state.unknown_paths.summarize();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::ProxyRequest;
+    use http::{HeaderMap, Uri};
+    use hyper::body::Bytes;
+
+    const UNICODE: &str = "%F0%9F%91%8D";
+    const CUSTOM: &str = "gearbot:742371529024421908";
+
+    fn route(method: Method, path: &str) -> (&'static str, Path, String) {
+        let uri = path.parse::<Uri>().unwrap();
+        let request =
+            ProxyRequest::from_parts(method, &uri, HeaderMap::new(), Bytes::new(), false).unwrap();
+
+        (request.route, request.path, request.path_and_query)
+    }
+
+    #[test]
+    fn own_reactions_go_to_their_bucket() {
+        for emoji in &[UNICODE, CUSTOM] {
+            for method in &[Method::PUT, Method::DELETE] {
+                let path = format!("/api/v6/channels/1/messages/2/reactions/{}/@me", emoji);
+
+                assert_eq!(
+                    route(method.clone(), &path),
+                    (
+                        "Message reaction for user",
+                        Path::ChannelsIdMessagesIdReactionsUserIdType(1),
+                        format!("channels/1/messages/2/reactions/{}/@me", emoji),
+                    )
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn reactions_of_others_go_to_their_bucket() {
+        for emoji in &[UNICODE, CUSTOM] {
+            let path = format!("/api/v6/channels/1/messages/2/reactions/{}/3", emoji);
+
+            assert_eq!(
+                route(Method::DELETE, &path),
+                (
+                    "Message reaction for user",
+                    Path::ChannelsIdMessagesIdReactionsUserIdType(1),
+                    format!("channels/1/messages/2/reactions/{}/3", emoji),
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn reactions_with_an_emoji_go_to_their_bucket() {
+        for emoji in &[UNICODE, CUSTOM] {
+            let path = format!("/api/v6/channels/1/messages/2/reactions/{}?limit=5", emoji);
+
+            for method in &[Method::GET, Method::DELETE] {
+                assert_eq!(
+                    route(method.clone(), &path),
+                    (
+                        "Message reaction",
+                        Path::ChannelsIdMessagesIdReactions(1),
+                        format!("channels/1/messages/2/reactions/{}?limit=5", emoji),
+                    )
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn reaction_paths_look_like_api_paths() {
+        for emoji in &[UNICODE, CUSTOM] {
+            let path = format!("/api/v6/channels/1/messages/2/reactions/{}/@me", emoji);
+
+            assert!(is_api_path(&path));
+            assert_eq!(
+                normalize(&path),
+                "channels/{id}/messages/{id}/reactions/{emoji}/@me"
+            );
+        }
+    }
+}
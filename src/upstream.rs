@@ -0,0 +1,603 @@
+//! The client used to reach Discord.
+
+use crate::{
+    config::{UpstreamConfig, UpstreamHttpVersion},
+    error::{BuildingHttpClient, CheckingToken, ClientError, InvalidToken, RejectedToken},
+    reload, State,
+};
+use http::{
+    header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE, USER_AGENT},
+    Method, StatusCode, Uri,
+};
+use metrics::counter;
+use reqwest::{Certificate, Client as HttpClient, ClientBuilder, Proxy, Response};
+use serde::Deserialize;
+use snafu::ResultExt;
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{net, time};
+use tokio_rustls::rustls::ClientConfig;
+use tracing::{debug, error, info, warn};
+use twilight_http::{
+    client::Client, error::Error as TwilightError, request::Request, routing::Path,
+};
+
+/// Same as twilight's own default.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often the age and use of the client's connections are checked.
+const ROTATION_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often `UPSTREAM_DNS_REFRESH` is checked for being set while it isn't.
+const DNS_IDLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Base URL twilight sends requests to.
+const DISCORD_URL: &str = "https://discord.com/api/v6";
+
+/// Same as twilight sends.
+const TWILIGHT_USER_AGENT: &str =
+    "DiscordBot (https://twilight.rs/chapter_1_crates/section_2_http.html, 0.1.4) Twilight-rs";
+
+/// Discord's API, or whatever `UPSTREAM_URL` points at instead.
+///
+/// Twilight always sends requests to discord.com, so requests for another
+/// base URL are sent with the HTTP client directly, the way twilight would.
+pub struct Upstream {
+    discord: Client,
+    /// The client twilight sends requests with.
+    http: HttpClient,
+    /// Base URL to send requests to instead.
+    url: Option<String>,
+}
+
+/// The user a token belongs to.
+#[derive(Debug, Deserialize)]
+pub struct User {
+    pub id: String,
+    pub username: String,
+    pub discriminator: String,
+}
+
+impl Upstream {
+    pub async fn raw(&self, request: Request) -> Result<Response, TwilightError> {
+        match &self.url {
+            Some(_) => self.direct(request).await,
+            None => self.discord.raw(request).await,
+        }
+    }
+
+    /// Send the request the way twilight would, but without waiting on its
+    /// ratelimiter.
+    pub async fn direct(&self, request: Request) -> Result<Response, TwilightError> {
+        let url = self.url.as_deref().unwrap_or(DISCORD_URL);
+
+        let mut builder = self
+            .http
+            .request(request.method.clone(), &format!("{}/{}", url, request.path_str))
+            .header("X-RateLimit-Precision", "millisecond")
+            .header(USER_AGENT, TWILIGHT_USER_AGENT);
+
+        if let Some(body) = request.body {
+            builder = builder
+                .header(CONTENT_TYPE, "application/json")
+                .header(CONTENT_LENGTH, body.len())
+                .body(body);
+        } else if [Method::PUT, Method::POST, Method::PATCH].contains(&request.method) {
+            builder = builder.header(CONTENT_LENGTH, 0);
+        }
+
+        if let Some(headers) = request.headers {
+            builder = builder.headers(headers);
+        }
+
+        builder
+            .send()
+            .await
+            .map_err(|source| TwilightError::RequestError { source })
+    }
+
+    /// Ask Discord who the token belongs to.
+    pub async fn current_user(&self) -> Result<User, ClientError> {
+        let request = Request {
+            body: None,
+            form: None,
+            headers: None,
+            method: Method::GET,
+            path: Path::UsersId,
+            path_str: "users/@me".into(),
+        };
+        let response = self.raw(request).await.context(CheckingToken)?;
+        let status = response.status();
+
+        if !status.is_success() {
+            return RejectedToken { status }.fail();
+        }
+
+        let body = response
+            .bytes()
+            .await
+            .map_err(|source| TwilightError::ChunkingResponse { source })
+            .context(CheckingToken)?;
+
+        serde_json::from_slice(&body)
+            .map_err(|source| TwilightError::Parsing {
+                body: body.to_vec(),
+                source,
+            })
+            .context(CheckingToken)
+    }
+}
+
+/// Create a Discord client for the token.
+///
+/// Twilight's builder can't be given root certificates, so the HTTP client is
+/// built here and the token is sent as one of its default headers instead.
+pub fn client(token: &str, config: &UpstreamConfig) -> Result<Upstream, ClientError> {
+    let token = if token.starts_with("Bot ") || token.starts_with("Bearer ") {
+        token.to_owned()
+    } else {
+        format!("Bot {}", token)
+    };
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        AUTHORIZATION,
+        HeaderValue::from_str(&token).context(InvalidToken)?,
+    );
+
+    let client = http_client(config)?
+        .default_headers(headers)
+        .build()
+        .context(BuildingHttpClient)?;
+
+    Ok(Upstream {
+        discord: Client::from(client.clone()),
+        http: client,
+        url: config.url.clone(),
+    })
+}
+
+/// Check Discord accepts the client's token, failing if it's rejected.
+///
+/// Discord being unreachable isn't a reason to fail, as requests are retried
+/// once it's back.
+pub async fn verify_token(upstream: &Upstream) -> Result<(), ClientError> {
+    match upstream.current_user().await {
+        Ok(user) => info!(
+            "Discord token belongs to {}#{} ({})",
+            user.username, user.discriminator, user.id
+        ),
+        Err(ClientError::RejectedToken { status }) if status == StatusCode::UNAUTHORIZED => {
+            return RejectedToken { status }.fail();
+        }
+        Err(source) => warn!("Couldn't check the Discord token: {}", source),
+    }
+
+    Ok(())
+}
+
+/// HTTP client for reaching Discord as the upstream settings say, without
+/// the token.
+pub fn http_client(config: &UpstreamConfig) -> Result<ClientBuilder, ClientError> {
+    let mut builder = HttpClient::builder()
+        .timeout(TIMEOUT)
+        .pool_idle_timeout(config.pool_idle_timeout);
+
+    if let Some(max) = config.pool_max_idle {
+        builder = builder.pool_max_idle_per_host(max);
+    }
+
+    if let Some(timeout) = config.connect_timeout {
+        builder = builder.connect_timeout(timeout);
+    }
+
+    match config.http_version {
+        UpstreamHttpVersion::Auto => {}
+        // Reqwest always offers HTTP/2 when connecting over TLS, so it's
+        // given a TLS config of its own that only offers HTTP/1.1. It
+        // already trusts the extra certificates, so adding them to the
+        // builder below has no effect.
+        UpstreamHttpVersion::Http1 => {
+            let mut tls = tls_config(config);
+            tls.set_protocols(&[b"http/1.1".to_vec()]);
+            builder = builder.use_preconfigured_tls(tls);
+        }
+        UpstreamHttpVersion::Http2 => builder = builder.http2_prior_knowledge(),
+    }
+
+    if let Some(proxy) = &config.proxy {
+        builder = builder.proxy(Proxy::all(proxy).context(BuildingHttpClient)?);
+    }
+
+    for pem in &config.ca_certificates {
+        builder =
+            builder.add_root_certificate(Certificate::from_pem(pem).context(BuildingHttpClient)?);
+    }
+
+    Ok(builder)
+}
+
+/// TLS settings for connecting to Discord, trusting the extra certificates.
+pub fn tls_config(config: &UpstreamConfig) -> ClientConfig {
+    let mut tls = ClientConfig::new();
+    tls.root_store
+        .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+
+    for pem in &config.ca_certificates {
+        // Already checked to hold certificates when the config was read.
+        let _ = tls.root_store.add_pem_file(&mut pem.as_slice());
+    }
+
+    tls
+}
+
+/// Age and use of the current client.
+#[derive(Debug)]
+pub struct Rotation {
+    since: Mutex<Instant>,
+    requests: AtomicU64,
+}
+
+impl Rotation {
+    pub fn new() -> Self {
+        Self {
+            since: Mutex::new(Instant::now()),
+            requests: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Start over for a new client.
+    pub fn reset(&self) {
+        *self.since.lock().expect("rotation poisoned") = Instant::now();
+        self.requests.store(0, Ordering::Relaxed);
+    }
+
+    fn is_due(&self, config: &UpstreamConfig) -> bool {
+        let age = self.since.lock().expect("rotation poisoned").elapsed();
+        let requests = self.requests.load(Ordering::Relaxed);
+
+        config.max_connection_age.is_some_and(|max| age >= max)
+            || config.max_connection_requests.is_some_and(|max| requests >= max)
+    }
+}
+
+/// Replace the client once it's older than or has sent more requests than
+/// configured, so its connections are opened again and can land on another
+/// Cloudflare edge.
+///
+/// Requests already sent finish on the old connections, which are closed once
+/// they're done.
+pub async fn rotate(state: Arc<State>) {
+    let mut interval = time::interval(ROTATION_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let config = state.config();
+
+        // New connections can't be opened while Discord's host can't be
+        // looked up, so the ones already open are kept until it can.
+        if !state.rotation.is_due(&config.upstream) || state.resolution.is_failing() {
+            continue;
+        }
+
+        match client(&config.token, &config.upstream) {
+            Ok(client) => {
+                debug!("Replacing the connections to Discord");
+                counter!("gearbot_proxy_upstream_rotations", 1);
+                reload::swap_client(&state, client);
+            }
+            Err(source) => {
+                error!("Failed to replace the Discord client: {}", source);
+                state.rotation.reset();
+            }
+        }
+    }
+}
+
+/// Hosts the proxy looks up itself, for `UPSTREAM_DNS_REFRESH` and gateway
+/// connects, as last looked up. Requests to the API don't go through it, as
+/// the client resolves Discord's host by itself for every connection it
+/// opens.
+///
+/// Addresses are cached for `UPSTREAM_DNS_TTL`, and failed lookups for the
+/// shorter `UPSTREAM_DNS_NEGATIVE_TTL`. A host that can't be looked up keeps
+/// the addresses it had before, if any, so a resolver that's briefly down
+/// doesn't stop connections from being opened.
+#[derive(Debug, Default)]
+pub struct Resolution {
+    entries: Mutex<HashMap<(String, u16), Entry>>,
+    /// Discord's host along with its addresses, sorted, to notice it moving.
+    last: Mutex<Option<(String, Vec<SocketAddr>)>>,
+    failing: AtomicBool,
+}
+
+#[derive(Debug)]
+struct Entry {
+    /// Why the last lookup failed, if it did.
+    error: Option<String>,
+    /// Addresses from the last lookup that succeeded.
+    addresses: Option<Vec<SocketAddr>>,
+    expires_at: Instant,
+}
+
+/// Addresses a host was looked up to.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Lookup {
+    pub addresses: Vec<SocketAddr>,
+    /// Whether they're from before a lookup that failed.
+    pub stale: bool,
+}
+
+impl Resolution {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether the last lookup of Discord's host failed.
+    pub fn is_failing(&self) -> bool {
+        self.failing.load(Ordering::Relaxed)
+    }
+
+    /// Addresses of the host, from the cache unless they've expired.
+    pub async fn lookup(
+        &self,
+        config: &UpstreamConfig,
+        host: &str,
+        port: u16,
+    ) -> io::Result<Lookup> {
+        let key = (host.to_owned(), port);
+
+        if let Some(cached) = self.cached(&key, Instant::now()) {
+            counter!("gearbot_proxy_upstream_dns_cache", 1, "result" => "hit");
+
+            return cached;
+        }
+
+        counter!("gearbot_proxy_upstream_dns_cache", 1, "result" => "miss");
+        let looked_up = net::lookup_host((host, port)).await.map(|addresses| {
+            let mut addresses = addresses.collect::<Vec<_>>();
+            addresses.sort();
+            addresses.dedup();
+
+            addresses
+        });
+
+        if let Err(source) = &looked_up {
+            warn!("Failed to look up {}: {}", host, source);
+            counter!("gearbot_proxy_upstream_dns_failures", 1);
+        }
+
+        self.store(key, looked_up, config, Instant::now())
+    }
+
+    fn cached(&self, key: &(String, u16), now: Instant) -> Option<io::Result<Lookup>> {
+        let entries = self.entries.lock().expect("resolution poisoned");
+        let entry = entries.get(key).filter(|entry| entry.expires_at > now)?;
+
+        Some(entry.answer())
+    }
+
+    fn store(
+        &self,
+        key: (String, u16),
+        looked_up: io::Result<Vec<SocketAddr>>,
+        config: &UpstreamConfig,
+        now: Instant,
+    ) -> io::Result<Lookup> {
+        let mut entries = self.entries.lock().expect("resolution poisoned");
+        let previous = entries.remove(&key).and_then(|entry| entry.addresses);
+
+        let entry = match looked_up {
+            Ok(addresses) => Entry {
+                error: None,
+                addresses: Some(addresses),
+                expires_at: now + config.dns_ttl,
+            },
+            Err(source) => Entry {
+                error: Some(source.to_string()),
+                addresses: previous,
+                expires_at: now + config.dns_negative_ttl,
+            },
+        };
+        let answer = entry.answer();
+        entries.insert(key, entry);
+
+        answer
+    }
+
+    /// Record the addresses of Discord's host, returning the ones it had
+    /// before if they're different.
+    fn update(&self, host: &str, addresses: Vec<SocketAddr>) -> Option<Vec<SocketAddr>> {
+        self.failing.store(false, Ordering::Relaxed);
+        let mut last = self.last.lock().expect("resolution poisoned");
+
+        let changed = match &*last {
+            Some((previous_host, previous)) => previous_host == host && *previous != addresses,
+            // There is nothing to compare the first lookup with.
+            None => false,
+        };
+        let previous = last.replace((host.to_owned(), addresses));
+
+        previous.filter(|_| changed).map(|(_, previous)| previous)
+    }
+}
+
+impl Entry {
+    fn answer(&self) -> io::Result<Lookup> {
+        match (&self.addresses, &self.error) {
+            (Some(addresses), error) => Ok(Lookup {
+                addresses: addresses.clone(),
+                stale: error.is_some(),
+            }),
+            (None, error) => Err(io::Error::other(error.clone().unwrap_or_default())),
+        }
+    }
+}
+
+/// Host and port the client connects to for Discord's API.
+fn host(config: &UpstreamConfig) -> (String, u16) {
+    let url = config.url.as_ref().and_then(|url| url.parse::<Uri>().ok());
+
+    match url.as_ref().and_then(|url| Some((url, url.host()?))) {
+        Some((url, host)) => {
+            let port = url
+                .port_u16()
+                .unwrap_or(if url.scheme_str() == Some("http") { 80 } else { 443 });
+
+            (host.to_owned(), port)
+        }
+        None => ("discord.com".to_owned(), 443),
+    }
+}
+
+/// Look up Discord's host every `UPSTREAM_DNS_REFRESH`, replacing the client
+/// when it has moved so new connections go to where it is now.
+///
+/// Lookups go through the cache, so the host is only asked for again once
+/// its addresses expire. The client still looks the host up by itself for
+/// every connection it opens, including all of them again after it's
+/// replaced, so this doesn't spare the resolver those. A failed lookup keeps
+/// the connections already open.
+pub async fn re_resolve(state: Arc<State>) {
+    loop {
+        let config = state.config();
+
+        let interval = match config.upstream.dns_refresh {
+            Some(interval) if state.mock.is_none() => interval,
+            _ => {
+                time::delay_for(DNS_IDLE_INTERVAL).await;
+
+                continue;
+            }
+        };
+
+        time::delay_for(interval).await;
+
+        let config = state.config();
+        let (host, port) = host(&config.upstream);
+
+        // Addresses kept from before a failed lookup can't tell whether the
+        // host moved.
+        let addresses = match state.resolution.lookup(&config.upstream, &host, port).await {
+            Ok(lookup) if !lookup.stale => lookup.addresses,
+            _ => {
+                state.resolution.failing.store(true, Ordering::Relaxed);
+
+                continue;
+            }
+        };
+
+        let previous = match state.resolution.update(&host, addresses.clone()) {
+            Some(previous) => previous,
+            None => continue,
+        };
+
+        info!(
+            "{} moved from {:?} to {:?}, replacing the connections to it",
+            host, previous, addresses
+        );
+        counter!("gearbot_proxy_upstream_dns_changes", 1);
+
+        match client(&config.token, &config.upstream) {
+            Ok(client) => {
+                reload::swap_client(&state, client);
+            }
+            Err(source) => error!("Failed to replace the Discord client: {}", source),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Lookup, Resolution};
+    use crate::config::{Config, UpstreamConfig};
+    use std::{
+        io,
+        net::SocketAddr,
+        time::{Duration, Instant},
+    };
+
+    fn config() -> UpstreamConfig {
+        Config::from_settings(vec![
+            ("DISCORD_TOKEN", "test"),
+            ("UPSTREAM_DNS_TTL", "30s"),
+            ("UPSTREAM_DNS_NEGATIVE_TTL", "5s"),
+        ])
+        .expect("invalid config")
+        .upstream
+    }
+
+    fn key() -> (String, u16) {
+        ("discord.com".to_owned(), 443)
+    }
+
+    fn addresses() -> Vec<SocketAddr> {
+        vec!["162.159.128.233:443".parse().unwrap()]
+    }
+
+    fn failed() -> io::Result<Vec<SocketAddr>> {
+        Err(io::Error::other("no resolver"))
+    }
+
+    #[test]
+    fn caches_addresses() {
+        let resolution = Resolution::new();
+        let now = Instant::now();
+        let stored = resolution.store(key(), Ok(addresses()), &config(), now);
+        let fresh = Lookup {
+            addresses: addresses(),
+            stale: false,
+        };
+        assert_eq!(stored.unwrap(), fresh);
+
+        let cached = resolution.cached(&key(), now + Duration::from_secs(29));
+        assert_eq!(cached.unwrap().unwrap(), fresh);
+        assert!(resolution
+            .cached(&key(), now + Duration::from_secs(30))
+            .is_none());
+    }
+
+    #[test]
+    fn caches_failures() {
+        let resolution = Resolution::new();
+        let now = Instant::now();
+        assert!(resolution.store(key(), failed(), &config(), now).is_err());
+
+        let cached = resolution.cached(&key(), now + Duration::from_secs(4));
+        assert!(cached.unwrap().is_err());
+        assert!(resolution
+            .cached(&key(), now + Duration::from_secs(5))
+            .is_none());
+    }
+
+    #[test]
+    fn keeps_addresses_after_failures() {
+        let resolution = Resolution::new();
+        let now = Instant::now();
+        resolution
+            .store(key(), Ok(addresses()), &config(), now)
+            .unwrap();
+
+        let later = now + Duration::from_secs(60);
+        let stale = Lookup {
+            addresses: addresses(),
+            stale: true,
+        };
+        let stored = resolution.store(key(), failed(), &config(), later);
+        assert_eq!(stored.unwrap(), stale);
+        let cached = resolution.cached(&key(), later + Duration::from_secs(1));
+        assert_eq!(cached.unwrap().unwrap(), stale);
+    }
+}
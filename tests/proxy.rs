@@ -0,0 +1,539 @@
+//! Requests through the proxy, answered from fixtures instead of by Discord.
+//!
+//! The metrics recorder can only be installed once per process, so a single
+//! proxy is started and every check runs against it in turn. Checks that
+//! depend on what the cache or a fixture served use paths of their own.
+
+use http::{header::HeaderValue, Method, Request, Response, StatusCode};
+use hyper::{body, Body};
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UnixStream,
+    time,
+};
+use tower_service::Service;
+use twilight_http_proxy::{Config, Proxy, ProxyService, RequestError};
+
+const CHANNEL: &str = r#"{
+    "status": 200,
+    "headers": {
+        "x-ratelimit-limit": "5",
+        "x-ratelimit-remaining": "4",
+        "x-ratelimit-reset-after": "1.0",
+        "x-ratelimit-bucket": "channel",
+        "x-fixture": "channel",
+        "keep-alive": "timeout=5"
+    },
+    "body": { "id": "1", "name": "general" }
+}"#;
+
+const GUILD: &str = r#"{
+    "status": 403,
+    "body": { "message": "Missing Access", "code": 50001 }
+}"#;
+
+/// Answered in turn, so replayed responses can be told from new ones.
+const MESSAGES: &str = r#"[
+    { "status": 200, "body": { "id": "10" } },
+    { "status": 200, "body": { "id": "11" } },
+    { "status": 200, "body": { "id": "12" } }
+]"#;
+
+const TYPING: &str = r#"{ "status": 204 }"#;
+
+const GATEWAY: &str = r#"{
+    "status": 502,
+    "body": { "message": "Bad Gateway" }
+}"#;
+
+const ALPHA_KEY: &str = "alpha-key";
+const BETA_KEY: &str = "beta-key";
+const VIEWER_TOKEN: &str = "viewer-token";
+const OPERATOR_TOKEN: &str = "operator-token";
+
+#[tokio::test]
+async fn proxies_requests() {
+    let dir = fixtures();
+    let proxy = Proxy::start(config(&dir), None)
+        .await
+        .expect("proxy failed to start");
+    let mut service = proxy.service();
+    proxy.listen().expect("proxy failed to listen");
+
+    routes_requests(&mut service).await;
+    forwards_headers(&mut service).await;
+    passes_on_ratelimit_headers(&mut service).await;
+    emulates_ratelimits(&mut service).await;
+    maps_errors(&mut service).await;
+    exports_metrics(&mut service).await;
+    speaks_http_1_0(&dir.join("proxy.sock")).await;
+    caches_responses_per_tenant(&mut service).await;
+    identifies_tenants(&mut service).await;
+    runs_batches(&mut service).await;
+    runs_jobs(&mut service).await;
+    opens_circuit_breakers(&mut service).await;
+
+    proxy.shutdown().await;
+    let _ = fs::remove_dir_all(dir);
+}
+
+async fn routes_requests(service: &mut ProxyService) {
+    let response = send(service, Method::GET, "/api/v6/channels/1").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(text(response).await, r#"{"id":"1","name":"general"}"#);
+
+    // Normalized to the same route.
+    let response = send(service, Method::GET, "//api/v6//channels/1/").await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = send(
+        service,
+        Method::GET,
+        "/proxy/classify?method=GET&path=/api/v6/channels/1/messages/2",
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let classified = text(response).await;
+    assert!(
+        classified.contains(r#""route":"Channel message""#),
+        "classified as {}",
+        classified
+    );
+}
+
+async fn forwards_headers(service: &mut ProxyService) {
+    let response = send(service, Method::GET, "/api/v6/channels/1").await;
+    let headers = response.headers();
+
+    assert_eq!(
+        headers.get("x-fixture"),
+        Some(&HeaderValue::from_static("channel"))
+    );
+    assert!(
+        !headers.contains_key("keep-alive"),
+        "hop-by-hop header passed on"
+    );
+}
+
+async fn passes_on_ratelimit_headers(service: &mut ProxyService) {
+    let response = send(service, Method::GET, "/api/v6/channels/1").await;
+    let headers = response.headers();
+
+    assert_eq!(
+        headers.get("x-ratelimit-limit"),
+        Some(&HeaderValue::from_static("5"))
+    );
+    assert_eq!(
+        headers.get("x-ratelimit-remaining"),
+        Some(&HeaderValue::from_static("4"))
+    );
+    assert_eq!(
+        headers.get("x-ratelimit-bucket"),
+        Some(&HeaderValue::from_static("channel"))
+    );
+}
+
+async fn emulates_ratelimits(service: &mut ProxyService) {
+    // The guild fixture has no ratelimit headers of its own.
+    let response = send(service, Method::GET, "/api/v6/guilds/5").await;
+    assert_eq!(remaining(&response), ("5", "4"));
+
+    for left in &["4", "3"] {
+        let mut sent = json_request(Method::POST, "/api/v6/channels/7/messages", "{}");
+        sent.headers_mut()
+            .insert("x-proxy-dry-run", HeaderValue::from_static("true"));
+        let response = call(service, sent).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(remaining(&response), ("5", *left));
+    }
+}
+
+/// The limit and what's left of it a response says its bucket has.
+fn remaining(response: &Response<Body>) -> (&str, &str) {
+    let header = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value: &HeaderValue| value.to_str().ok())
+            .unwrap_or_default()
+    };
+
+    (header("x-ratelimit-limit"), header("x-ratelimit-remaining"))
+}
+
+async fn maps_errors(service: &mut ProxyService) {
+    // Discord's errors are passed on as they are.
+    let response = send(service, Method::GET, "/api/v6/guilds/1").await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert!(text(response).await.contains("50001"));
+
+    // Routes without a fixture.
+    let response = send(service, Method::GET, "/api/v6/users/1").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let response = send(service, Method::GET, "/api/v6/channels/1/../../../gateway").await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let request = request(Method::GET, "/api/v6/nothing/here");
+    match service.call(request).await {
+        Err(RequestError::InvalidPath { .. }) => {}
+        other => panic!("unknown path answered with {:?}", other.map(|r| r.status())),
+    }
+}
+
+async fn exports_metrics(service: &mut ProxyService) {
+    let response = send(service, Method::GET, "/metrics").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let metrics = text(response).await;
+
+    for name in &["gearbot_proxy_requests", "gearbot_proxy_invalid_paths"] {
+        assert!(metrics.contains(name), "{} missing from {}", name, metrics);
+    }
+}
+
+async fn speaks_http_1_0(socket: &Path) {
+    let response = raw(socket, "GET /api/v6/channels/1 HTTP/1.0\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.0 200 OK\r\n"), "{}", response);
+    assert!(
+        response.ends_with(r#"{"id":"1","name":"general"}"#),
+        "{}",
+        response
+    );
+
+    let head = response.to_ascii_lowercase();
+    assert!(!head.contains("transfer-encoding"), "{}", response);
+    assert!(head.contains("content-length: 27\r\n"), "{}", response);
+    assert!(head.contains("connection: close\r\n"), "{}", response);
+
+    // Closed after the response even when the client asks to keep it open.
+    let response = raw(
+        socket,
+        "GET /api/v6/channels/1 HTTP/1.0\r\nHost: proxy\r\nConnection: keep-alive\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.0 200 OK\r\n"), "{}", response);
+    assert!(
+        response
+            .to_ascii_lowercase()
+            .contains("connection: close\r\n"),
+        "{}",
+        response
+    );
+}
+
+/// Send a request as written on a new connection to the listener, reading
+/// the response until the proxy closes the connection.
+async fn raw(socket: &Path, request: &str) -> String {
+    let mut stream = connect(socket).await;
+    stream
+        .write_all(request.as_bytes())
+        .await
+        .expect("failed to send the request");
+
+    let mut response = Vec::new();
+    time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+        .await
+        .expect("the connection wasn't closed")
+        .expect("failed to read the response");
+
+    String::from_utf8_lossy(&response).into_owned()
+}
+
+/// Connect to the listener, waiting for it to be bound.
+async fn connect(socket: &Path) -> UnixStream {
+    for _ in 0..50 {
+        if let Ok(stream) = UnixStream::connect(socket).await {
+            return stream;
+        }
+
+        time::delay_for(Duration::from_millis(20)).await;
+    }
+
+    panic!("the listener at {} wasn't bound", socket.display());
+}
+
+async fn caches_responses_per_tenant(service: &mut ProxyService) {
+    let path = "/api/v6/channels/2";
+
+    assert_eq!(cached(service, path, Some(ALPHA_KEY)).await, "miss");
+    assert_eq!(cached(service, path, Some(ALPHA_KEY)).await, "hit");
+    // Other tenants and anonymous clients don't get the first tenant's copy.
+    assert_eq!(cached(service, path, Some(BETA_KEY)).await, "miss");
+    assert_eq!(cached(service, path, None).await, "miss");
+    assert_eq!(cached(service, path, None).await, "hit");
+
+    // A change by any tenant drops every tenant's copy.
+    let patch = with_key(
+        json_request(Method::PATCH, path, r#"{"name":"renamed"}"#),
+        Some(BETA_KEY),
+    );
+    assert_eq!(call(service, patch).await.status(), StatusCode::OK);
+
+    assert_eq!(cached(service, path, Some(ALPHA_KEY)).await, "miss");
+    assert_eq!(cached(service, path, None).await, "miss");
+}
+
+async fn identifies_tenants(service: &mut ProxyService) {
+    let sent = with_key(request(Method::GET, "/api/v6/channels/1"), Some("wrong"));
+    let response = call(service, sent).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert!(text(response).await.contains("unknown API key"));
+
+    let sent = with_key(
+        request(
+            Method::GET,
+            "/proxy/classify?method=GET&path=/api/v6/gateway",
+        ),
+        Some("wrong"),
+    );
+    // Only requests for Discord, and batches sent on a tenant's behalf,
+    // look at keys.
+    assert_eq!(call(service, sent).await.status(), StatusCode::OK);
+}
+
+async fn runs_batches(service: &mut ProxyService) {
+    let batch = r#"[
+        { "method": "GET", "path": "/api/v6/channels/3" },
+        { "method": "GET", "path": "/api/v6/users/1" },
+        { "method": "GET", "path": "/api/v6/channels/3" }
+    ]"#;
+    let sent = json_request(Method::POST, "/proxy/batch?atomic=stop_on_error", batch);
+    let response = call(service, with_key(sent, Some(ALPHA_KEY))).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let batch = json(response).await;
+
+    assert_eq!(batch["results"][0]["outcome"], "completed");
+    assert_eq!(batch["results"][0]["status"], 200);
+    assert_eq!(batch["results"][0]["body"]["name"], "general");
+    assert_eq!(batch["results"][1]["status"], 404);
+    assert_eq!(batch["results"][2]["outcome"], "skipped");
+    assert_eq!(
+        batch["summary"],
+        serde_json::json!({ "total": 3, "succeeded": 1, "failed": 1, "skipped": 1 })
+    );
+
+    // Sub-requests are cached for the tenant that sent the batch.
+    assert_eq!(
+        cached(service, "/api/v6/channels/3", Some(ALPHA_KEY)).await,
+        "hit"
+    );
+
+    // Idempotency keys of sub-requests are honored, and the proxy's own
+    // headers are taken out of them.
+    let batch = r#"[
+        {
+            "method": "POST",
+            "path": "/api/v6/channels/1/messages",
+            "headers": { "idempotency-key": "first", "x-proxy-async": "true" },
+            "body": { "content": "hi" }
+        },
+        {
+            "method": "POST",
+            "path": "/api/v6/channels/1/messages",
+            "headers": { "idempotency-key": "first" },
+            "body": { "content": "hi" }
+        },
+        {
+            "method": "POST",
+            "path": "/api/v6/channels/1/messages",
+            "body": { "content": "hi" }
+        }
+    ]"#;
+    let response = call(service, json_request(Method::POST, "/proxy/batch", batch)).await;
+    let batch = json(response).await;
+    let ids = (0..3)
+        .map(|index| batch["results"][index]["body"]["id"].clone())
+        .collect::<Vec<_>>();
+    assert_eq!(ids, ["10", "10", "11"]);
+
+    let sent = with_key(
+        json_request(Method::POST, "/proxy/batch", "[]"),
+        Some("wrong"),
+    );
+    assert_eq!(call(service, sent).await.status(), StatusCode::UNAUTHORIZED);
+}
+
+async fn runs_jobs(service: &mut ProxyService) {
+    let mut sent = json_request(Method::POST, "/api/v6/channels/1/typing", "");
+    sent.headers_mut()
+        .insert("x-proxy-async", HeaderValue::from_static("true"));
+    let response = call(service, sent).await;
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    let id = job_id(response).await;
+
+    // Clients follow the job's Location without a token.
+    let wait = format!("/proxy/jobs/{}/wait?timeout=5s", id);
+    let job = json(call(service, request(Method::GET, &wait)).await).await;
+    assert_eq!(job["state"], "completed");
+    assert_eq!(job["status"], 204);
+    let cancel = request(Method::DELETE, &format!("/proxy/jobs/{}", id));
+    assert_eq!(
+        call(service, cancel).await.status(),
+        StatusCode::UNAUTHORIZED
+    );
+
+    // Scheduled jobs can be cancelled until they run, by an operator or the
+    // tenant that sent them, and other tenants don't see them.
+    let mut sent = json_request(Method::POST, "/api/v6/channels/1/typing", "");
+    sent.headers_mut()
+        .insert("x-proxy-delay", HeaderValue::from_static("10m"));
+    let response = call(service, with_key(sent, Some(ALPHA_KEY))).await;
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    let job = format!("/proxy/jobs/{}", job_id(response).await);
+
+    let looking = with_key(request(Method::GET, &job), Some(BETA_KEY));
+    assert_eq!(call(service, looking).await.status(), StatusCode::NOT_FOUND);
+    let cancel = with_token(request(Method::DELETE, &job), VIEWER_TOKEN);
+    assert_eq!(call(service, cancel).await.status(), StatusCode::NOT_FOUND);
+
+    let cancel = with_key(request(Method::DELETE, &job), Some(ALPHA_KEY));
+    let cancelled = json(call(service, cancel).await).await;
+    assert_eq!(cancelled["state"], "cancelled", "{}", cancelled);
+
+    let cancel = with_token(request(Method::DELETE, &job), OPERATOR_TOKEN);
+    assert_eq!(call(service, cancel).await.status(), StatusCode::CONFLICT);
+}
+
+async fn opens_circuit_breakers(service: &mut ProxyService) {
+    for _ in 0..3 {
+        let response = send(service, Method::GET, "/api/v6/gateway").await;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    // Requests for the failing family aren't sent anymore...
+    let response = send(service, Method::GET, "/api/v6/gateway").await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert!(response.headers().contains_key("retry-after"));
+    assert!(text(response).await.contains("gateway"));
+
+    // ...while other families are unaffected.
+    let response = send(service, Method::GET, "/api/v6/channels/1").await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let breakers = with_token(request(Method::GET, "/proxy/breakers"), VIEWER_TOKEN);
+    let breakers = text(call(service, breakers).await).await;
+    assert!(breakers.contains("open"), "breakers: {}", breakers);
+}
+
+/// Directory with the fixtures the proxy answers from.
+fn fixtures() -> PathBuf {
+    let dir = env::temp_dir().join(format!("twilight-http-proxy-tests-{}", process::id()));
+    fs::create_dir_all(&dir).expect("failed to create the fixture directory");
+    fs::write(dir.join("channel.json"), CHANNEL).expect("failed to write a fixture");
+    fs::write(dir.join("guild.json"), GUILD).expect("failed to write a fixture");
+    fs::write(dir.join("channel_message.json"), MESSAGES).expect("failed to write a fixture");
+    fs::write(dir.join("typing_indicator.json"), TYPING).expect("failed to write a fixture");
+    fs::write(dir.join("gateway.json"), GATEWAY).expect("failed to write a fixture");
+
+    dir
+}
+
+fn config(dir: &Path) -> Config {
+    let fixtures = dir.display().to_string();
+    let listen = format!("unix:{}", dir.join("proxy.sock").display());
+
+    Config::from_settings(vec![
+        ("DISCORD_TOKEN", "test"),
+        ("MOCK_UPSTREAM", "true"),
+        ("MOCK_FIXTURES", &fixtures),
+        ("METRICS_ON_LISTENER", "true"),
+        ("LISTEN", &listen),
+        ("CACHE_ROUTES", "Channel"),
+        ("TENANT_ALPHA_KEY", ALPHA_KEY),
+        ("TENANT_BETA_KEY", BETA_KEY),
+        ("ADMIN_DASHBOARD_TOKEN", VIEWER_TOKEN),
+        ("ADMIN_OPS_TOKEN", OPERATOR_TOKEN),
+        ("ADMIN_OPS_ROLE", "operator"),
+        ("BREAKER_THRESHOLD", "3"),
+        ("RETRY_MAX_ATTEMPTS", "1"),
+    ])
+    .expect("invalid config")
+}
+
+fn request(method: Method, uri: &str) -> Request<Body> {
+    Request::builder()
+        .method(method)
+        .uri(uri)
+        .body(Body::empty())
+        .expect("invalid request")
+}
+
+fn json_request(method: Method, uri: &str, body: &'static str) -> Request<Body> {
+    Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("content-type", "application/json")
+        .body(Body::from(body))
+        .expect("invalid request")
+}
+
+fn with_key(mut request: Request<Body>, key: Option<&str>) -> Request<Body> {
+    if let Some(key) = key {
+        let key = HeaderValue::from_str(key).expect("invalid key");
+        request.headers_mut().insert("x-proxy-key", key);
+    }
+
+    request
+}
+
+fn with_token(mut request: Request<Body>, token: &str) -> Request<Body> {
+    let token = HeaderValue::from_str(&format!("Bearer {}", token)).expect("invalid token");
+    request.headers_mut().insert("authorization", token);
+
+    request
+}
+
+async fn send(service: &mut ProxyService, method: Method, uri: &str) -> Response<Body> {
+    call(service, request(method, uri)).await
+}
+
+async fn call(service: &mut ProxyService, request: Request<Body>) -> Response<Body> {
+    let uri = request.uri().clone();
+
+    service
+        .call(request)
+        .await
+        .unwrap_or_else(|source| panic!("{} failed: {:?}", uri, source))
+}
+
+/// How the cache answered a `GET` of the path by the tenant with the key.
+async fn cached(service: &mut ProxyService, path: &str, key: Option<&str>) -> String {
+    let response = call(service, with_key(request(Method::GET, path), key)).await;
+
+    response
+        .headers()
+        .get("x-proxy-cache")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_owned()
+}
+
+/// Id of the job a `202 Accepted` response is for.
+async fn job_id(response: Response<Body>) -> String {
+    json(response).await["id"]
+        .as_str()
+        .expect("no job id")
+        .to_owned()
+}
+
+async fn json(response: Response<Body>) -> serde_json::Value {
+    let body = body::to_bytes(response.into_body())
+        .await
+        .expect("failed to read the body");
+
+    serde_json::from_slice(&body).expect("body isn't JSON")
+}
+
+async fn text(response: Response<Body>) -> String {
+    let body = body::to_bytes(response.into_body())
+        .await
+        .expect("failed to read the body");
+
+    String::from_utf8_lossy(&body).into_owned()
+}